@@ -0,0 +1,3 @@
+ALTER TABLE users DROP COLUMN is_guest;
+ALTER TABLE users ALTER COLUMN encrypted_password SET NOT NULL;
+ALTER TABLE users ALTER COLUMN email SET NOT NULL;
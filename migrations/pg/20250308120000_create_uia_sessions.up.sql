@@ -0,0 +1,10 @@
+CREATE TABLE uia_sessions (
+    id                 BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    session_identifier VARCHAR(256)             NOT NULL UNIQUE,
+    endpoint           VARCHAR(1024)            NOT NULL,
+    user_id            BIGINT
+        REFERENCES users (id),
+    completed_stages   VARCHAR(256)[]           NOT NULL DEFAULT '{}',
+    created_at         TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at         TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
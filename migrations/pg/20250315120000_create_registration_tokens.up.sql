@@ -0,0 +1,10 @@
+CREATE TABLE registration_tokens (
+    id           BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    token        VARCHAR(64)              NOT NULL UNIQUE,
+    uses_allowed INTEGER,
+    pending      INTEGER                  NOT NULL DEFAULT 0,
+    completed    INTEGER                  NOT NULL DEFAULT 0,
+    expiry_time  TIMESTAMP WITH TIME ZONE,
+    created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,11 @@
+CREATE TABLE refresh_tokens (
+    id            BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    session_id    BIGINT                   NOT NULL
+        REFERENCES sessions (id) ON DELETE CASCADE,
+    token_hash    VARCHAR(64)              NOT NULL UNIQUE,
+    next_token_id BIGINT
+        REFERENCES refresh_tokens (id) ON DELETE SET NULL,
+    used_at       TIMESTAMP WITH TIME ZONE,
+    created_at    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1 @@
+DROP TABLE threepid_validation_sessions;
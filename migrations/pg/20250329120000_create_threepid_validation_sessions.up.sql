@@ -0,0 +1,15 @@
+CREATE TABLE threepid_validation_sessions (
+    id            BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    sid           VARCHAR(255)             NOT NULL UNIQUE,
+    client_secret VARCHAR(255)             NOT NULL,
+    medium        VARCHAR(32)              NOT NULL,
+    address       VARCHAR(255)             NOT NULL,
+    token         VARCHAR(64)              NOT NULL,
+    send_attempt  BIGINT                   NOT NULL,
+    validated_at  TIMESTAMP WITH TIME ZONE,
+    created_at    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX threepid_validation_sessions_client_secret_idx
+    ON threepid_validation_sessions (client_secret, medium, address);
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN is_deactivated BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN is_erased BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+DROP TABLE room_aliases;
+DROP TABLE events;
+DROP TABLE rooms;
@@ -0,0 +1,34 @@
+CREATE TABLE rooms (
+    id           BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    room_id      VARCHAR(255)             NOT NULL UNIQUE,
+    room_version VARCHAR(32)              NOT NULL,
+    creator      VARCHAR(255)             NOT NULL,
+    is_public    BOOLEAN                  NOT NULL DEFAULT FALSE,
+    created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE events (
+    id               BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    event_id         VARCHAR(255)             NOT NULL UNIQUE,
+    room_id          VARCHAR(255)             NOT NULL
+        REFERENCES rooms (room_id),
+    sender           VARCHAR(255)             NOT NULL,
+    event_type       VARCHAR(255)             NOT NULL,
+    state_key        TEXT,
+    content          JSONB                    NOT NULL,
+    depth            BIGINT                   NOT NULL,
+    origin_server_ts BIGINT                   NOT NULL,
+    created_at       TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX events_room_id_idx ON events (room_id, depth);
+
+CREATE TABLE room_aliases (
+    id         BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    alias      VARCHAR(255)             NOT NULL UNIQUE,
+    room_id    VARCHAR(255)             NOT NULL
+        REFERENCES rooms (room_id),
+    creator    VARCHAR(255)             NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,10 @@
+DROP TABLE event_to_state_groups;
+DROP TABLE state_groups_state;
+DROP TABLE state_groups;
+DROP TABLE current_state_events;
+DROP TABLE room_forward_extremities;
+DROP TABLE event_json;
+DROP TABLE event_auth_edges;
+DROP TABLE event_edges;
+ALTER TABLE events DROP COLUMN stream_ordering;
+DROP SEQUENCE events_stream_ordering_seq;
@@ -0,0 +1,68 @@
+CREATE SEQUENCE events_stream_ordering_seq;
+
+ALTER TABLE events ADD COLUMN stream_ordering BIGINT NOT NULL UNIQUE DEFAULT nextval('events_stream_ordering_seq');
+
+CREATE TABLE event_edges (
+    event_id      VARCHAR(255) NOT NULL
+        REFERENCES events (event_id),
+    prev_event_id VARCHAR(255) NOT NULL,
+    PRIMARY KEY (event_id, prev_event_id)
+);
+
+CREATE INDEX event_edges_prev_event_id_idx ON event_edges (prev_event_id);
+
+CREATE TABLE event_auth_edges (
+    event_id      VARCHAR(255) NOT NULL
+        REFERENCES events (event_id),
+    auth_event_id VARCHAR(255) NOT NULL,
+    PRIMARY KEY (event_id, auth_event_id)
+);
+
+CREATE TABLE event_json (
+    event_id VARCHAR(255) PRIMARY KEY
+        REFERENCES events (event_id),
+    room_id  VARCHAR(255) NOT NULL,
+    json     JSONB        NOT NULL
+);
+
+CREATE TABLE room_forward_extremities (
+    room_id  VARCHAR(255) NOT NULL
+        REFERENCES rooms (room_id),
+    event_id VARCHAR(255) NOT NULL
+        REFERENCES events (event_id),
+    PRIMARY KEY (room_id, event_id)
+);
+
+CREATE TABLE current_state_events (
+    room_id    VARCHAR(255) NOT NULL
+        REFERENCES rooms (room_id),
+    event_type VARCHAR(255) NOT NULL,
+    state_key  TEXT         NOT NULL,
+    event_id   VARCHAR(255) NOT NULL
+        REFERENCES events (event_id),
+    PRIMARY KEY (room_id, event_type, state_key)
+);
+
+CREATE TABLE state_groups (
+    id         BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    room_id    VARCHAR(255)             NOT NULL
+        REFERENCES rooms (room_id),
+    event_id   VARCHAR(255)             NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE state_groups_state (
+    state_group BIGINT       NOT NULL
+        REFERENCES state_groups (id),
+    event_type  VARCHAR(255) NOT NULL,
+    state_key   TEXT         NOT NULL,
+    event_id    VARCHAR(255) NOT NULL,
+    PRIMARY KEY (state_group, event_type, state_key)
+);
+
+CREATE TABLE event_to_state_groups (
+    event_id    VARCHAR(255) PRIMARY KEY
+        REFERENCES events (event_id),
+    state_group BIGINT       NOT NULL
+        REFERENCES state_groups (id)
+);
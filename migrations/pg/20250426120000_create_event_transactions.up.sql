@@ -0,0 +1,9 @@
+CREATE TABLE event_transactions (
+    session_id BIGINT                   NOT NULL
+        REFERENCES sessions (id) ON DELETE CASCADE,
+    txn_id     VARCHAR(255)             NOT NULL,
+    event_id   VARCHAR(255)             NOT NULL
+        REFERENCES events (event_id),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (session_id, txn_id)
+);
@@ -0,0 +1 @@
+DROP TABLE forgotten_rooms;
@@ -0,0 +1,7 @@
+CREATE TABLE forgotten_rooms (
+    user_id    VARCHAR(255)             NOT NULL,
+    room_id    VARCHAR(255)             NOT NULL
+        REFERENCES rooms (room_id),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, room_id)
+);
@@ -0,0 +1,4 @@
+DROP TABLE presence;
+DROP TABLE typing;
+DROP TABLE receipts;
+DROP TABLE account_data;
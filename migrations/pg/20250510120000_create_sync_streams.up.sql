@@ -0,0 +1,43 @@
+CREATE TABLE account_data (
+    user_id         VARCHAR(255)             NOT NULL,
+    room_id         VARCHAR(255)             NOT NULL DEFAULT '',
+    event_type      VARCHAR(255)             NOT NULL,
+    content         JSONB                    NOT NULL,
+    stream_ordering BIGINT                   NOT NULL DEFAULT nextval('events_stream_ordering_seq'),
+    updated_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, room_id, event_type)
+);
+
+CREATE INDEX account_data_stream_ordering_idx ON account_data (user_id, stream_ordering);
+
+CREATE TABLE receipts (
+    room_id         VARCHAR(255) NOT NULL
+        REFERENCES rooms (room_id),
+    user_id         VARCHAR(255) NOT NULL,
+    receipt_type    VARCHAR(255) NOT NULL,
+    thread_id       VARCHAR(255) NOT NULL DEFAULT '',
+    event_id        VARCHAR(255) NOT NULL
+        REFERENCES events (event_id),
+    ts              BIGINT       NOT NULL,
+    stream_ordering BIGINT       NOT NULL DEFAULT nextval('events_stream_ordering_seq'),
+    PRIMARY KEY (room_id, user_id, receipt_type, thread_id)
+);
+
+CREATE INDEX receipts_stream_ordering_idx ON receipts (room_id, stream_ordering);
+
+CREATE TABLE typing (
+    room_id         VARCHAR(255)             NOT NULL
+        REFERENCES rooms (room_id),
+    user_id         VARCHAR(255)             NOT NULL,
+    expires_at      TIMESTAMP WITH TIME ZONE NOT NULL,
+    stream_ordering BIGINT                   NOT NULL DEFAULT nextval('events_stream_ordering_seq'),
+    PRIMARY KEY (room_id, user_id)
+);
+
+CREATE TABLE presence (
+    user_id         VARCHAR(255)             PRIMARY KEY,
+    presence        VARCHAR(32)              NOT NULL,
+    status_msg      TEXT,
+    last_active_at  TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    stream_ordering BIGINT                   NOT NULL DEFAULT nextval('events_stream_ordering_seq')
+);
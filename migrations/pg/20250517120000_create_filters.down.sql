@@ -0,0 +1,2 @@
+DROP TABLE lazy_loaded_members;
+DROP TABLE filters;
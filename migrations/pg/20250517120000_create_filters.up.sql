@@ -0,0 +1,20 @@
+CREATE TABLE filters (
+    id         BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    user_id    VARCHAR(255)             NOT NULL,
+    filter     JSONB                    NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX filters_user_id_idx ON filters (user_id);
+
+-- Membership events already sent to a session by syncs that lazy-load members
+CREATE TABLE lazy_loaded_members (
+    session_id BIGINT       NOT NULL
+        REFERENCES sessions (id) ON DELETE CASCADE,
+    room_id    VARCHAR(255) NOT NULL
+        REFERENCES rooms (room_id),
+    user_id    VARCHAR(255) NOT NULL,
+    event_id   VARCHAR(255) NOT NULL
+        REFERENCES events (event_id),
+    PRIMARY KEY (session_id, room_id, user_id)
+);
@@ -0,0 +1,4 @@
+DROP TABLE sliding_sync_rooms;
+DROP TABLE sliding_sync_connections;
+DROP TABLE to_device_transactions;
+DROP TABLE to_device_messages;
@@ -0,0 +1,44 @@
+CREATE TABLE to_device_messages (
+    user_id         VARCHAR(255)             NOT NULL,
+    device_id       VARCHAR(255)             NOT NULL,
+    sender          VARCHAR(255)             NOT NULL,
+    event_type      VARCHAR(255)             NOT NULL,
+    content         JSONB                    NOT NULL,
+    stream_ordering BIGINT                   NOT NULL DEFAULT nextval('events_stream_ordering_seq'),
+    created_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, device_id, stream_ordering)
+);
+
+CREATE TABLE to_device_transactions (
+    session_id BIGINT                   NOT NULL
+        REFERENCES sessions (id) ON DELETE CASCADE,
+    txn_id     VARCHAR(255)             NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (session_id, txn_id)
+);
+
+-- A sliding sync connection of a session, with the position of its latest
+-- response
+CREATE TABLE sliding_sync_connections (
+    id              BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    session_id      BIGINT                   NOT NULL
+        REFERENCES sessions (id) ON DELETE CASCADE,
+    conn_id         VARCHAR(255)             NOT NULL,
+    position        BIGINT                   NOT NULL DEFAULT 0,
+    stream_ordering BIGINT                   NOT NULL DEFAULT 0,
+    created_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE (session_id, conn_id)
+);
+
+-- How far a sliding sync connection has sent each room, and with which
+-- required state
+CREATE TABLE sliding_sync_rooms (
+    connection_id   BIGINT       NOT NULL
+        REFERENCES sliding_sync_connections (id) ON DELETE CASCADE,
+    room_id         VARCHAR(255) NOT NULL
+        REFERENCES rooms (room_id),
+    stream_ordering BIGINT       NOT NULL,
+    required_state  JSONB        NOT NULL,
+    PRIMARY KEY (connection_id, room_id)
+);
@@ -0,0 +1,8 @@
+ALTER TABLE to_device_messages ALTER COLUMN stream_ordering SET DEFAULT nextval('events_stream_ordering_seq');
+ALTER TABLE presence ALTER COLUMN stream_ordering SET DEFAULT nextval('events_stream_ordering_seq');
+ALTER TABLE typing ALTER COLUMN stream_ordering SET DEFAULT nextval('events_stream_ordering_seq');
+ALTER TABLE receipts ALTER COLUMN stream_ordering SET DEFAULT nextval('events_stream_ordering_seq');
+ALTER TABLE account_data ALTER COLUMN stream_ordering SET DEFAULT nextval('events_stream_ordering_seq');
+ALTER TABLE events ALTER COLUMN stream_ordering SET DEFAULT nextval('events_stream_ordering_seq');
+
+DROP TABLE stream_position;
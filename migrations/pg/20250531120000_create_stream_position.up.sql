@@ -0,0 +1,17 @@
+-- The stream ordering up to and including which all writers have committed;
+-- writers take their orderings by advancing it, so that it serialises them
+CREATE TABLE stream_position (
+    id              BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    stream_ordering BIGINT  NOT NULL
+);
+
+INSERT INTO stream_position (stream_ordering)
+SELECT CASE WHEN is_called THEN last_value ELSE 0 END FROM events_stream_ordering_seq;
+
+-- Orderings must come from the stream position rather than the sequence
+ALTER TABLE events ALTER COLUMN stream_ordering DROP DEFAULT;
+ALTER TABLE account_data ALTER COLUMN stream_ordering DROP DEFAULT;
+ALTER TABLE receipts ALTER COLUMN stream_ordering DROP DEFAULT;
+ALTER TABLE typing ALTER COLUMN stream_ordering DROP DEFAULT;
+ALTER TABLE presence ALTER COLUMN stream_ordering DROP DEFAULT;
+ALTER TABLE to_device_messages ALTER COLUMN stream_ordering DROP DEFAULT;
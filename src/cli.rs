@@ -1,8 +1,20 @@
+use crate::config::{Config, JwtConfig, ServerConfig};
+use crate::models::ids::ServerName;
+use crate::services;
+use crate::services::jwt;
+use crate::services::signing;
 use crate::store::pg;
+use chrono::{Duration, Utc};
 use clap::Parser;
 use futures_util::TryStreamExt;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use sqlx::PgPool;
 use std::io::Write;
+use std::path::Path;
+
+/// Length of generated registration tokens
+const REGISTRATION_TOKEN_LENGTH: usize = 16;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -18,41 +30,46 @@ pub fn parse() -> Args {
     Args::parse()
 }
 
-pub async fn run_command(args: &Args, pool: &PgPool) {
+pub async fn run_command(args: &Args, config: &Config, pool: &PgPool) {
     match &args.command {
-        Some(s) if s == "users" => run_users_command(&args, pool).await,
+        Some(s) if s == "users" => run_users_command(args, config, pool).await,
+        Some(s) if s == "tokens" => run_tokens_command(args, pool).await,
         Some(s) => eprintln!("Invalid command: {}", s),
         None => (),
     }
 }
 
-pub async fn run_users_command(args: &Args, pool: &PgPool) {
+pub async fn run_users_command(args: &Args, config: &Config, pool: &PgPool) {
     match &args.subcommand {
         Some(s) if s == "list" => list_users(pool).await,
         Some(s) if s == "create" => create_user(args, pool).await,
+        Some(s) if s == "deactivate" => deactivate_user(args, config, pool).await,
         Some(s) => eprintln!("Invalid `users` subcommand: {}", s),
         None => (),
     }
 }
 
-pub async fn list_users(pool: &PgPool) -> () {
-    let mut stream = pg::auth::users_stream(&pool).await;
+pub async fn list_users(pool: &PgPool) {
+    let mut stream = pg::auth::users_stream(pool).await;
     let mut has_users = false;
 
     while let Ok(Some(user)) = stream.try_next().await {
         if !has_users {
-            println!("{:20}  {:40}", "Name", "Email");
-            println!("{}  {}", "-".repeat(20), "-".repeat(40));
+            println!("{:20}  {:40}  {:11}", "Name", "Email", "Status");
+            println!("{}  {}  {}", "-".repeat(20), "-".repeat(40), "-".repeat(11));
         }
-        println!("{:20}  {:40}", user.name, user.email);
+        let status = match (user.is_deactivated, user.is_erased) {
+            (_, true) => "erased",
+            (true, false) => "deactivated",
+            (false, false) => "active",
+        };
+        println!("{:20}  {:40}  {:11}", user.name, user.email.unwrap_or_default(), status);
         has_users = true;
     }
 
     if !has_users {
         println!("No users found.");
     }
-
-    ()
 }
 
 pub async fn create_user(args: &Args, pool: &PgPool) {
@@ -80,12 +97,278 @@ pub async fn create_user(args: &Args, pool: &PgPool) {
     }
 
     match pg::auth::create_user(
-        args.args.get(0).unwrap(),
+        args.args.first().unwrap(),
         args.args.get(1).unwrap(),
         &password0,
-        &pool
+        pool
     ).await {
         Ok(_) => println!("User created."),
         Err(e) => eprintln!("Error creating user: {}", e),
     }
 }
+
+/// Deactivates a user, who leaves all their rooms; passing `erase` after the
+/// username also hides the user's events from those who join later
+pub async fn deactivate_user(args: &Args, config: &Config, pool: &PgPool) {
+    let erase = match args.args.get(1).map(String::as_str) {
+        None => false,
+        Some("erase") if args.args.len() == 2 => true,
+        Some(_) => {
+            eprintln!("`users deactivate` requires 1 argument, username, optionally followed by `erase`");
+            return;
+        }
+    };
+
+    let username = match args.args.first() {
+        Some(username) => username,
+        None => {
+            eprintln!("`users deactivate` requires 1 argument, username, optionally followed by `erase`");
+            return;
+        }
+    };
+
+    let user = match pg::auth::get_user_by_name(username, pool).await {
+        Ok(Some(user)) if user.is_deactivated => {
+            eprintln!("User is already deactivated.");
+            return;
+        }
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            eprintln!("User not found.");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Error deactivating user: {}", e);
+            return;
+        }
+    };
+
+    // The user's leave events are signed by the server.
+    let keys = match signing::SigningKeys::load(config) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Error loading signing keys: {}", e);
+            return;
+        }
+    };
+
+    match services::account::deactivate(user.id, erase, config, &keys, pool).await {
+        Ok(()) => println!("User deactivated."),
+        Err(e) => eprintln!("Error deactivating user: {}", e),
+    }
+}
+
+pub async fn run_tokens_command(args: &Args, pool: &PgPool) {
+    match &args.subcommand {
+        Some(s) if s == "list" => list_tokens(pool).await,
+        Some(s) if s == "create" => create_token(args, pool).await,
+        Some(s) if s == "revoke" => revoke_token(args, pool).await,
+        Some(s) => eprintln!("Invalid `tokens` subcommand: {}", s),
+        None => (),
+    }
+}
+
+pub async fn list_tokens(pool: &PgPool) {
+    let mut stream = pg::registration_tokens::tokens_stream(pool).await;
+    let mut has_tokens = false;
+
+    while let Ok(Some(token)) = stream.try_next().await {
+        if !has_tokens {
+            println!("{:20}  {:>12}  {:>8}  {:>9}  {:25}", "Token", "Uses allowed", "Pending", "Completed", "Expires");
+            println!("{}  {}  {}  {}  {}", "-".repeat(20), "-".repeat(12), "-".repeat(8), "-".repeat(9), "-".repeat(25));
+        }
+        println!(
+            "{:20}  {:>12}  {:>8}  {:>9}  {:25}",
+            token.token,
+            token.uses_allowed.map_or(String::from("unlimited"), |n| n.to_string()),
+            token.pending,
+            token.completed,
+            token.expiry_time.map_or(String::from("never"), |t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+        );
+        has_tokens = true;
+    }
+
+    if !has_tokens {
+        println!("No registration tokens found.");
+    }
+}
+
+/// Creates a random registration token; optional arguments are the number of
+/// uses allowed and the number of days until the token expires
+pub async fn create_token(args: &Args, pool: &PgPool) {
+    if args.args.len() > 2 {
+        eprintln!("`tokens create` accepts up to 2 arguments: uses allowed and days valid");
+        return;
+    }
+
+    let uses_allowed = match args.args.first().map(|a| a.parse::<i32>()) {
+        Some(Ok(n)) if n > 0 => Some(n),
+        Some(_) => {
+            eprintln!("Uses allowed must be a positive integer.");
+            return;
+        }
+        None => None,
+    };
+
+    let expiry_time = match args.args.get(1).map(|a| a.parse::<i64>()) {
+        Some(Ok(n)) if n > 0 => Some(Utc::now() + Duration::days(n)),
+        Some(_) => {
+            eprintln!("Days valid must be a positive integer.");
+            return;
+        }
+        None => None,
+    };
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(REGISTRATION_TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+
+    match pg::registration_tokens::create_token(&token, uses_allowed, expiry_time, pool).await {
+        Ok(token) => println!("Registration token created: {}", token.token),
+        Err(e) => eprintln!("Error creating registration token: {}", e),
+    }
+}
+
+pub async fn revoke_token(args: &Args, pool: &PgPool) {
+    if args.args.len() != 1 {
+        eprintln!("`tokens revoke` requires 1 argument: token");
+        return;
+    }
+
+    match pg::registration_tokens::delete_token(&args.args[0], pool).await {
+        Ok(true) => println!("Registration token revoked."),
+        Ok(false) => eprintln!("Registration token not found."),
+        Err(e) => eprintln!("Error revoking registration token: {}", e),
+    }
+}
+
+/// Manages the key pairs used to sign JWTs; unlike the other commands, this
+/// doesn't use the database
+pub fn run_keys_command(args: &Args, config: &JwtConfig) {
+    let key_dir = Path::new(&config.key_dir);
+
+    match &args.subcommand {
+        Some(s) if s == "list" => list_keys(key_dir),
+        Some(s) if s == "generate" => generate_key(key_dir),
+        Some(s) if s == "rotate" => rotate_keys(key_dir),
+        Some(s) => eprintln!("Invalid `keys` subcommand: {}", s),
+        None => (),
+    }
+}
+
+pub fn list_keys(key_dir: &Path) {
+    let (signing_kids, verification_kids) = match (jwt::signing_key_ids(key_dir), jwt::verification_key_ids(key_dir)) {
+        (Ok(signing_kids), Ok(verification_kids)) => (signing_kids, verification_kids),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Error reading keys: {}", e);
+            return;
+        }
+    };
+
+    if verification_kids.is_empty() {
+        println!("No keys found in {}.", key_dir.display());
+        return;
+    }
+
+    println!("{:20}  {:12}", "Key ID", "Use");
+    println!("{}  {}", "-".repeat(20), "-".repeat(12));
+    for kid in verification_kids {
+        let key_use = if signing_kids.last() == Some(&kid) { "signing" } else { "verification" };
+        println!("{:20}  {:12}", kid, key_use);
+    }
+}
+
+/// Generates the first key pair; use `keys rotate` to replace existing keys
+pub fn generate_key(key_dir: &Path) {
+    match jwt::signing_key_ids(key_dir) {
+        Ok(kids) if !kids.is_empty() => {
+            eprintln!("Keys already exist in {}; use `keys rotate` to replace them.", key_dir.display());
+            return;
+        }
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("Error reading keys: {}", e);
+            return;
+        }
+    }
+
+    match jwt::generate_key(key_dir) {
+        Ok(kid) => println!("Key {} generated in {}.", kid, key_dir.display()),
+        Err(e) => eprintln!("Error generating key: {}", e),
+    }
+}
+
+/// Generates a new signing key and deletes the old private keys, keeping the
+/// old public keys so that existing access tokens remain valid
+pub fn rotate_keys(key_dir: &Path) {
+    match jwt::rotate_keys(key_dir) {
+        Ok(kid) => println!("Key {} generated; restart the server to start using it.", kid),
+        Err(e) => eprintln!("Error rotating keys: {}", e),
+    }
+}
+
+/// Manages the keys this server signs events with; like `keys`, this doesn't
+/// use the database
+pub fn run_signing_keys_command(args: &Args, config: &ServerConfig) {
+    let key_dir = Path::new(&config.signing_key_dir);
+
+    match &args.subcommand {
+        Some(s) if s == "list" => list_signing_keys(key_dir, &config.server_name),
+        Some(s) if s == "generate" => generate_signing_key(key_dir),
+        Some(s) if s == "rotate" => rotate_signing_keys(key_dir),
+        Some(s) => eprintln!("Invalid `signing-keys` subcommand: {}", s),
+        None => (),
+    }
+}
+
+/// Lists the key that signs and the public keys it replaced, with the time
+/// each was replaced
+pub fn list_signing_keys(key_dir: &Path, server_name: &ServerName) {
+    let keys = match signing::SigningKeys::load_dir(key_dir, server_name) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Error reading keys: {}", e);
+            return;
+        }
+    };
+
+    println!("{:28}  {:43}  {:23}", "Key ID", "Public key", "Expired");
+    println!("{}  {}  {}", "-".repeat(28), "-".repeat(43), "-".repeat(23));
+    for old_key in keys.old_verify_keys() {
+        let expired = chrono::DateTime::from_timestamp_millis(old_key.expired_ts)
+            .map_or(String::new(), |t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string());
+        println!("{:28}  {:43}  {:23}", old_key.key_id, signing::encode_verify_key(&old_key.key), expired);
+    }
+    println!("{:28}  {:43}  {:23}", keys.key_id(), signing::encode_verify_key(&keys.verify_key()), "signing");
+}
+
+/// Generates the first signing key; use `signing-keys rotate` to replace it
+pub fn generate_signing_key(key_dir: &Path) {
+    match signing::signing_key_versions(key_dir) {
+        Ok(versions) if !versions.is_empty() => {
+            eprintln!("Keys already exist in {}; use `signing-keys rotate` to replace them.", key_dir.display());
+            return;
+        }
+        Ok(_) => (),
+        Err(e) => {
+            eprintln!("Error reading keys: {}", e);
+            return;
+        }
+    }
+
+    match signing::generate_key(key_dir) {
+        Ok(key_id) => println!("Key {} generated in {}.", key_id, key_dir.display()),
+        Err(e) => eprintln!("Error generating key: {}", e),
+    }
+}
+
+/// Generates a new signing key and deletes the old private keys, keeping the
+/// old public keys so that other servers can still verify what they signed
+pub fn rotate_signing_keys(key_dir: &Path) {
+    match signing::rotate_keys(key_dir) {
+        Ok(key_id) => println!("Key {} generated; restart the server to start using it.", key_id),
+        Err(e) => eprintln!("Error rotating keys: {}", e),
+    }
+}
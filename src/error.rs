@@ -1,3 +1,4 @@
+use crate::services::uia::UiaResponse;
 use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse, ResponseError};
 use serde::Serialize;
@@ -23,6 +24,20 @@ pub enum Error {
     /// endpoint requiring it
     #[error("Authentication failed: {0}")]
     Auth(String),
+
+    /// Represents a request body that is not valid JSON or doesn't match the
+    /// expected structure
+    #[error("Bad JSON: {0}")]
+    BadJson(String),
+
+    /// Represents a username from the client that doesn't form a valid user ID
+    #[error("Invalid username: {0}")]
+    InvalidUsername(String),
+
+    /// Represents a request that requires further user-interactive
+    /// authentication
+    #[error("User-interactive authentication required")]
+    Uia(Box<UiaResponse>),
 }
 
 /// JSON response payload in the case of an error, per the Matrix spec
@@ -36,7 +51,8 @@ impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match self {
             Error::Config(_) | Error::Db(_) | Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::Auth(_) => StatusCode::UNAUTHORIZED,
+            Error::Auth(_) | Error::Uia(_) => StatusCode::UNAUTHORIZED,
+            Error::BadJson(_) | Error::InvalidUsername(_) => StatusCode::BAD_REQUEST,
         }
     }
 
@@ -54,6 +70,21 @@ impl ResponseError for Error {
                         errcode: String::from("M_UNKNOWN_TOKEN"),
                         error: e.to_string()
                     })),
+            Error::BadJson(e) =>
+                HttpResponse::build(self.status_code())
+                    .json(web::Json(ErrorResponse {
+                        errcode: String::from("M_BAD_JSON"),
+                        error: e.to_string()
+                    })),
+            Error::InvalidUsername(e) =>
+                HttpResponse::build(self.status_code())
+                    .json(web::Json(ErrorResponse {
+                        errcode: String::from("M_INVALID_USERNAME"),
+                        error: e.to_string()
+                    })),
+            Error::Uia(uia_response) =>
+                HttpResponse::build(self.status_code())
+                    .json(uia_response),
         }
     }
 }
@@ -1,17 +1,18 @@
-use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
 use futures_util::future::{err, ok, Ready};
-use crate::error;
-use crate::models::auth::Session;
+use crate::models::auth::{Session, User};
+use crate::models::ids::{DeviceId, UserId};
+use crate::{error, services, AppState};
 
-/// An Actix extractor that retrieves the current authenticated User ID and
-/// Session ID
+/// An Actix extractor that retrieves the current authenticated User and
+/// Session
 ///
 /// Endpoints that require authentication must include this as a parameter in
 /// their handler functions.
 ///
 /// This extractor relies on the middleware function [`authenticator()`] to have
 /// found and validated an Authorization header, which places the authenticated
-/// [`Session`] in the request Extensions.
+/// [`Session`] and [`User`] in the request Extensions.
 ///
 /// If this extractor fails to find a [`Session`] in the request Extensions,
 /// then there is no valid authentication for the request, and this will return
@@ -32,6 +33,11 @@ use crate::models::auth::Session;
 pub struct AuthenticatedUser {
     pub user_id: i64,
     pub session_id: i64,
+    /// The Session's device identifier
+    pub device_id: DeviceId,
+    /// The fully-qualified Matrix user ID, e.g. `@alice:chat.spelt.io`
+    pub matrix_user_id: UserId,
+    pub is_guest: bool,
 }
 
 impl FromRequest for AuthenticatedUser {
@@ -40,14 +46,26 @@ impl FromRequest for AuthenticatedUser {
 
     fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
         let extensions = req.extensions();
-        match extensions.get::<Session>() {
-            Some(session) =>
+        let (session, user) = match (extensions.get::<Session>(), extensions.get::<User>()) {
+            (Some(session), Some(user)) => (session, user),
+            _ => return err(error::Error::Auth(String::from("Request not authenticated"))),
+        };
+
+        let state = match req.app_data::<web::Data<AppState>>() {
+            Some(state) => state,
+            None => return err(error::Error::Config(String::from("Application state not found"))),
+        };
+
+        match services::auth::user_id(&user.name, &state.config) {
+            Ok(matrix_user_id) =>
                 ok(Self {
                     user_id: session.user_id,
                     session_id: session.id,
+                    device_id: session.device_identifier.clone(),
+                    matrix_user_id,
+                    is_guest: user.is_guest,
                 }),
-            None =>
-                err(error::Error::Auth(String::from("Request not authenticated"))),
+            Err(error) => err(error),
         }
     }
 }
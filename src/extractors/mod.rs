@@ -1 +1,2 @@
 pub mod authenticated_user;
+pub mod uia_authenticated;
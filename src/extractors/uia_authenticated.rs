@@ -0,0 +1,153 @@
+use crate::error::Error;
+use crate::models::auth::Session;
+use crate::services::uia::{UiaContext, UiaRequest, UiaResult};
+use crate::{services, AppState};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+
+/// An Actix extractor that deserializes a JSON request body and requires that
+/// the authenticated user has completed user-interactive authentication
+///
+/// Endpoints that require UIA, such as changing a password, must include this
+/// as a parameter in their handler functions in place of both
+/// [`AuthenticatedUser`] and [`Json`]. The body type must implement
+/// [`UiaRequest`], which supplies the `auth` field and the flows the endpoint
+/// accepts.
+///
+/// If the request is not authenticated, this returns a 401/Unauthorized. If
+/// UIA is not yet complete, this returns a 401 with the flows, params, session
+/// and completed stages, per the Matrix spec.
+///
+/// # Example
+///
+/// ```
+/// #[post("/_matrix/client/v3/account/password")]
+/// async fn change_password(uia: UiaAuthenticated<PasswordRequest>, data: web::Data<AppState>) -> impl Responder {
+///     //...
+/// }
+/// ```
+///
+/// [`AuthenticatedUser`]: crate::extractors::authenticated_user::AuthenticatedUser
+/// [`Json`]: actix_web::web::Json
+pub struct UiaAuthenticated<T> {
+    pub user_id: i64,
+    pub body: T,
+}
+
+impl<T: UiaRequest + DeserializeOwned + 'static> FromRequest for UiaAuthenticated<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let user_id = req.extensions().get::<Session>().map(|s| s.user_id);
+        let json = web::Json::<T>::from_request(req, payload);
+        let req = req.clone();
+
+        Box::pin(async move {
+            let user_id = user_id.ok_or_else(|| Error::Auth(String::from("Request not authenticated")))?;
+            let body = json.await.map_err(|e| Error::BadJson(e.to_string()))?.into_inner();
+
+            let state = req.app_data::<web::Data<AppState>>()
+                .ok_or_else(|| Error::Config(String::from("Application state not found")))?;
+            let pool = state.db_pool.as_ref()
+                .ok_or_else(|| Error::Config(String::from("Database pool not found")))?;
+            let context = UiaContext { user_id: Some(user_id), config: &state.config, pool };
+
+            match services::uia::authenticate(body.auth(), &T::flows(&state.config), req.path(), &context).await? {
+                UiaResult::Completed => Ok(Self { user_id, body }),
+                UiaResult::Incomplete(uia_response) => Err(Error::Uia(Box::new(uia_response))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+    use crate::routes::auth::AuthenticationData;
+    use crate::store::pg::auth::tests::{create_test_session, create_test_user};
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{post, test, App, HttpResponse, Responder};
+    use serde::Deserialize;
+    use sqlx::PgPool;
+    use twelf::reexports::serde_json;
+
+    #[derive(Deserialize)]
+    struct TestRequest {
+        auth: Option<AuthenticationData>,
+        value: String,
+    }
+
+    impl UiaRequest for TestRequest {
+        fn auth(&self) -> &Option<AuthenticationData> {
+            &self.auth
+        }
+    }
+
+    #[post("/test")]
+    async fn handler(uia: UiaAuthenticated<TestRequest>) -> impl Responder {
+        HttpResponse::Ok().body(uia.body.value)
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_uia_authenticated(pool: PgPool) {
+        let (user, password) = create_test_user(&pool).await;
+        let (_session, jwt) = create_test_session(user.id, 0, &pool).await;
+
+        let state = AppState::test(&pool);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(handler)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({"value": "foo"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["flows"][0]["stages"][0], "m.login.password");
+        let session = json["session"].as_str().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "value": "foo",
+                "auth": {"type": "m.login.password", "session": session, "password": password}
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(test::read_body(resp).await, "foo");
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_uia_authenticated_without_authentication(pool: PgPool) {
+        let state = AppState::test(&pool);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(handler)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .set_json(serde_json::json!({"value": "foo"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["errcode"], "M_UNKNOWN_TOKEN");
+    }
+}
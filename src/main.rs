@@ -22,6 +22,24 @@ mod store;
 struct AppState {
     config: config::Config,
     db_pool: Option<Pool<Postgres>>,
+    jwt_keys: services::jwt::KeyManager,
+    signing_keys: services::signing::SigningKeys,
+    notifier: services::notifier::Notifier,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// Returns the state of an app that uses `pool`, with the test config and
+    /// keys
+    fn test(pool: &sqlx::PgPool) -> Self {
+        AppState {
+            config: config::Config::test(),
+            db_pool: Some(pool.clone()),
+            jwt_keys: services::jwt::KeyManager::test(),
+            signing_keys: services::signing::SigningKeys::test(),
+            notifier: services::notifier::Notifier::default(),
+        }
+    }
 }
 
 /// TODO: Redact secrets or remove this.
@@ -37,6 +55,25 @@ async fn log_request_params(
 async fn main() -> Result<(), error::Error> {
     let args = cli::parse();
     let conf = config::load(PathBuf::from(&args.config_file))?;
+    if models::room_version::get(&conf.server.default_room_version).is_none() {
+        return Err(error::Error::Config(format!(
+            "Unsupported default_room_version: {}",
+            conf.server.default_room_version
+        )));
+    }
+    if let Some(version) = conf.server.unstable_room_versions.iter().find(|v| models::room_version::get(v).is_none()) {
+        return Err(error::Error::Config(format!("Unsupported room version in unstable_room_versions: {}", version)));
+    }
+
+    // Key management doesn't need the database, which may not be set up yet.
+    if args.command.as_deref() == Some("keys") {
+        cli::run_keys_command(&args, &conf.jwt);
+        return Ok(());
+    }
+    if args.command.as_deref() == Some("signing-keys") {
+        cli::run_signing_keys_command(&args, &conf.server);
+        return Ok(());
+    }
 
     // Make copies of these to use in the bind() call.
     let bind_address = conf.server.bind_address.clone();
@@ -44,17 +81,21 @@ async fn main() -> Result<(), error::Error> {
 
     let pool = PgPoolOptions::new()
         .max_connections(5)
-        .connect(&conf.database.dev_uri.as_ref().expect("Value not found for config key: database.dev_uri"))
+        .connect(conf.database.dev_uri.as_ref().expect("Value not found for config key: database.dev_uri"))
         .await?;
 
     // If command is given, run it and exit.
     if args.command.is_some() {
-        cli::run_command(&args, &pool).await;
+        cli::run_command(&args, &conf, &pool).await;
         return Ok(());
     }
 
     env_logger::Builder::new().filter_level(LevelFilter::Debug).init();
 
+    let jwt_keys = services::jwt::KeyManager::load(&conf)?;
+    let signing_keys = services::signing::SigningKeys::load(&conf)?;
+    let notifier = services::notifier::Notifier::start(&pool).await?;
+
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
@@ -63,13 +104,54 @@ async fn main() -> Result<(), error::Error> {
             .app_data(web::Data::new(AppState {
                 config: conf.clone(),
                 db_pool: Some(pool.clone()),
+                jwt_keys: jwt_keys.clone(),
+                signing_keys: signing_keys.clone(),
+                notifier: notifier.clone(),
             }))
             .service(routes::info::versions)
             .service(routes::info::server_names)
+            .service(routes::info::capabilities)
             .service(routes::auth::check_validity)
             .service(routes::auth::login_types)
             .service(routes::auth::log_in)
+            .service(routes::auth::register)
+            .service(routes::auth::refresh)
             .service(routes::auth::log_out)
+            .service(routes::auth::log_out_all)
+            .service(routes::account::whoami)
+            .service(routes::account::change_password)
+            .service(routes::account::deactivate)
+            .service(routes::account::request_password_token)
+            .service(routes::account::submit_email_token)
+            .service(routes::rooms::create_room)
+            .service(routes::rooms::send_message)
+            .service(routes::rooms::set_state)
+            .service(routes::history::get_messages)
+            .service(routes::history::get_context)
+            .service(routes::history::get_event)
+            .service(routes::membership::join)
+            .service(routes::membership::join_by_room_id)
+            .service(routes::membership::knock)
+            .service(routes::membership::leave)
+            .service(routes::membership::invite)
+            .service(routes::membership::kick)
+            .service(routes::membership::ban)
+            .service(routes::membership::unban)
+            .service(routes::membership::forget)
+            .service(routes::membership::joined_rooms)
+            .service(routes::membership::get_members)
+            .service(routes::membership::get_joined_members)
+            .service(routes::filter::create_filter)
+            .service(routes::filter::get_filter)
+            .service(routes::sync::sync)
+            .service(routes::sliding_sync::sliding_sync)
+            .service(routes::to_device::send_to_device)
+            .service(routes::typing::set_typing)
+            .service(routes::receipts::send_receipt)
+            .service(routes::account_data::set_account_data)
+            .service(routes::account_data::get_account_data)
+            .service(routes::account_data::set_room_account_data)
+            .service(routes::account_data::get_room_account_data)
     })
         .bind((bind_address, port))?
         .run()
@@ -10,7 +10,8 @@ use crate::{services, AppState};
 ///
 /// Looks for an `Authorization: Bearer xxx` header in the request and, if
 /// found, validates the token and looks up the referenced Session. If
-/// successful, adds the authenticated [`Session`] to the request [`Extensions`].
+/// successful, adds the authenticated [`Session`] and its [`User`] to the
+/// request [`Extensions`].
 ///
 /// See [`AuthenticatedUser`] for the mechanism that request handlers must use
 /// to enforce authentication.
@@ -20,20 +21,21 @@ use crate::{services, AppState};
 ///   alternative of including an `access_token` query parameter.
 ///   See https://spec.matrix.org/v1.13/client-server-api/#using-access-tokens
 ///
-/// [`Session`]: crate::store::pg::auth::Session
+/// [`Session`]: crate::models::auth::Session
+/// [`User`]: crate::models::auth::User
 /// [`Extensions`]: actix_web::dev::Extensions
 /// [`AuthenticatedUser`]: crate::extractors::authenticated_user::AuthenticatedUser
 pub async fn authenticator(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
     if let Some(auth) = req.headers().get("Authorization") {
         if let Ok(auth) = auth.to_str() {
-            if auth.starts_with("Bearer") {
-                let token = &auth[7..auth.len()].trim();
+            if let Some(token) = auth.strip_prefix("Bearer ") {
+                let token = token.trim();
                 log::info!("Bearer token found");
 
                 if let Some(state) = req.app_data::<Data<AppState>>() {
                     if let Some(pool) = state.db_pool.as_ref() {
-                        match services::auth::authorize_request(&token.to_string(), pool).await {
-                            Ok(session) => {
+                        match services::auth::authorize_request(token, &state.jwt_keys, pool).await {
+                            Ok((session, user)) => {
                                 log::info!(
                                     "Authenticated user {} with session {} on device {}",
                                     session.user_id,
@@ -43,6 +45,7 @@ pub async fn authenticator(req: ServiceRequest, next: Next<impl MessageBody>) ->
 
                                 let mut extensions = req.extensions_mut();
                                 extensions.insert(session);
+                                extensions.insert(user);
                             },
                             Err(err) =>
                                 log::info!("{}", err.to_string()),
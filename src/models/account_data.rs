@@ -0,0 +1,12 @@
+use crate::models::ids::RoomId;
+use twelf::reexports::serde_json;
+
+/// Model for database `account_data` table
+///
+/// `room_id` is `None` for global account data.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AccountData {
+    pub room_id: Option<RoomId>,
+    pub event_type: String,
+    pub content: serde_json::Value,
+}
@@ -1,3 +1,4 @@
+use crate::models::ids::DeviceId;
 use uuid::Uuid;
 
 /// Model for database `users` table
@@ -5,10 +6,13 @@ use uuid::Uuid;
 pub struct User {
     pub id: i64,
     pub name: String,
-    pub email: String,
-    pub encrypted_password: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub email: Option<String>,
+    pub is_guest: bool,
+    /// Deactivated accounts can't log in, and their user IDs can't be reused
+    pub is_deactivated: bool,
+    /// Set if the user asked for their events to be hidden from future
+    /// viewers when deactivating
+    pub is_erased: bool,
 }
 
 /// Model for database `sessions` table
@@ -16,9 +20,6 @@ pub struct User {
 pub struct Session {
     pub id: i64,
     pub uuid: Uuid,
-    pub device_identifier: String,
-    pub device_name: Option<String>,
+    pub device_identifier: DeviceId,
     pub user_id: i64,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub updated_at: chrono::DateTime<chrono::Utc>,
 }
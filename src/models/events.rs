@@ -0,0 +1,20 @@
+use crate::models::ids::{EventId, RoomId, UserId};
+use twelf::reexports::serde_json;
+
+/// Model for database `events` table
+///
+/// `state_key` is `None` for message events; state events have a state key,
+/// which is often empty. `depth` orders events within the DAG, whereas
+/// `stream_ordering` is the order in which this server stored them.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Event {
+    pub event_id: EventId,
+    pub room_id: RoomId,
+    pub sender: UserId,
+    pub event_type: String,
+    pub state_key: Option<String>,
+    pub content: serde_json::Value,
+    pub depth: i64,
+    pub origin_server_ts: i64,
+    pub stream_ordering: i64,
+}
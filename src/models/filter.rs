@@ -0,0 +1,66 @@
+use serde::Deserialize;
+
+/// A filter that a client applies to the events it receives from syncs and
+/// other endpoints, usually stored with `POST /user/{userId}/filter`
+///
+/// Absent fields don't filter anything. See
+/// https://spec.matrix.org/v1.13/client-server-api/#filtering
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Fields to include in events, as dot-separated paths such as
+    /// `content.body`, where a literal dot is escaped with a backslash
+    pub event_fields: Option<Vec<String>>,
+    pub presence: EventFilter,
+    pub account_data: EventFilter,
+    pub room: RoomFilter,
+}
+
+/// Filters events by type and sender
+///
+/// Types may contain `*` as a wildcard for any sequence of characters. The
+/// `not_` lists take precedence over the others.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct EventFilter {
+    /// The maximum number of events to return
+    pub limit: Option<usize>,
+    pub types: Option<Vec<String>>,
+    pub not_types: Vec<String>,
+    pub senders: Option<Vec<String>>,
+    pub not_senders: Vec<String>,
+}
+
+/// Filters the rooms in a sync and the events of each kind in them
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RoomFilter {
+    pub rooms: Option<Vec<String>>,
+    pub not_rooms: Vec<String>,
+    pub timeline: RoomEventFilter,
+    pub state: RoomEventFilter,
+    pub ephemeral: RoomEventFilter,
+    pub account_data: RoomEventFilter,
+}
+
+/// Filters events in rooms, by room and content as well as type and sender
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RoomEventFilter {
+    #[serde(flatten)]
+    pub events: EventFilter,
+    pub rooms: Option<Vec<String>>,
+    pub not_rooms: Vec<String>,
+    /// If set, only events with (`true`) or without (`false`) a URL in their
+    /// content match
+    pub contains_url: Option<bool>,
+    /// Whether to only send the membership events of the senders of the events
+    /// being sent, rather than of every member
+    pub lazy_load_members: bool,
+    /// Whether lazy-loading resends membership events that the client already
+    /// has
+    pub include_redundant_members: bool,
+    /// Whether to count unread notifications of each thread separately from
+    /// the main timeline
+    pub unread_thread_notifications: bool,
+}
@@ -0,0 +1,368 @@
+//! Validated Matrix identifiers
+//!
+//! Each type holds the identifier as a string that has been checked against
+//! the grammar in the Matrix spec appendix, and converts to and from JSON and
+//! database strings. Parsing accepts the historical user ID grammar, since
+//! other servers may still use it; new user IDs must satisfy
+//! [`is_valid_localpart()`].
+//!
+//! See https://spec.matrix.org/v1.13/appendices/#identifier-grammar
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Maximum length in bytes of any identifier that includes a server name
+const MAX_ID_LENGTH: usize = 255;
+
+/// Maximum length of a hostname in a server name
+const MAX_HOSTNAME_LENGTH: usize = 255;
+
+/// Length of the random part of generated room and event IDs
+const OPAQUE_ID_LENGTH: usize = 18;
+
+/// A string that doesn't match the grammar of the identifier it was parsed as
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Invalid {kind}: {value}")]
+pub struct IdError {
+    pub kind: &'static str,
+    pub value: String,
+}
+
+macro_rules! id_type {
+    ($(#[$attr:meta])* $name:ident, $kind:literal, $validate:path) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+        #[serde(try_from = "String", into = "String")]
+        #[sqlx(transparent)]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = IdError;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                if $validate(&value) {
+                    Ok(Self(value))
+                } else {
+                    Err(IdError { kind: $kind, value })
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = IdError;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                Self::try_from(value.to_string())
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    };
+}
+
+id_type!(
+    /// The name of a homeserver, e.g. `chat.spelt.io` or `[::1]:8448`
+    ServerName, "server name", is_valid_server_name
+);
+
+id_type!(
+    /// A user ID, e.g. `@alice:chat.spelt.io`
+    UserId, "user ID", is_valid_user_id
+);
+
+id_type!(
+    /// A room ID, e.g. `!OpaqueId:chat.spelt.io`
+    RoomId, "room ID", is_valid_room_id
+);
+
+id_type!(
+    /// An event ID; `$opaque_id:server_name` in room versions 1 and 2, and
+    /// `$` followed by a hash in later versions
+    EventId, "event ID", is_valid_event_id
+);
+
+id_type!(
+    /// A room alias, e.g. `#lobby:chat.spelt.io`
+    RoomAliasId, "room alias", is_valid_room_alias_id
+);
+
+id_type!(
+    /// A device ID, which is chosen by the client or generated by the server
+    DeviceId, "device ID", is_valid_device_id
+);
+
+impl Default for ServerName {
+    fn default() -> Self {
+        Self(String::from("localhost"))
+    }
+}
+
+impl UserId {
+    /// Returns the user ID with `localpart` on `server_name`
+    pub fn new(localpart: &str, server_name: &ServerName) -> Result<Self, IdError> {
+        format!("@{}:{}", localpart, server_name).parse()
+    }
+
+    pub fn localpart(&self) -> &str {
+        sigil_localpart(&self.0)
+    }
+
+    pub fn server_name(&self) -> &str {
+        sigil_server_name(&self.0)
+    }
+}
+
+impl RoomId {
+    /// Returns a new random room ID on `server_name`
+    pub fn generate(server_name: &ServerName) -> Self {
+        Self(format!("!{}:{}", opaque_id(), server_name))
+    }
+
+    pub fn server_name(&self) -> &str {
+        sigil_server_name(&self.0)
+    }
+}
+
+impl EventId {
+    /// Returns a new random event ID on `server_name`, in the format used by
+    /// room versions 1 and 2
+    pub fn generate(server_name: &ServerName) -> Self {
+        Self(format!("${}:{}", opaque_id(), server_name))
+    }
+
+    /// Returns the server name, which only event IDs in room versions 1 and 2
+    /// have
+    pub fn server_name(&self) -> Option<&str> {
+        self.0.split_once(':').map(|(_, server_name)| server_name)
+    }
+}
+
+impl RoomAliasId {
+    pub fn alias(&self) -> &str {
+        sigil_localpart(&self.0)
+    }
+
+    pub fn server_name(&self) -> &str {
+        sigil_server_name(&self.0)
+    }
+}
+
+impl DeviceId {
+    /// Returns a new random device ID
+    pub fn generate() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+/// Returns `true` if `localpart` may be used as the localpart of a new user ID
+///
+/// See https://spec.matrix.org/v1.13/appendices/#user-identifiers
+pub fn is_valid_localpart(localpart: &str) -> bool {
+    !localpart.is_empty()
+        && localpart.bytes().all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'=' | b'-' | b'/' | b'+'))
+}
+
+/// Returns a random string for the opaque part of a generated ID
+fn opaque_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(OPAQUE_ID_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Returns the part of `id` between the sigil and the first colon
+fn sigil_localpart(id: &str) -> &str {
+    id[1..].split_once(':').map_or(&id[1..], |(localpart, _)| localpart)
+}
+
+/// Returns the part of `id` after the first colon
+fn sigil_server_name(id: &str) -> &str {
+    id.split_once(':').map_or("", |(_, server_name)| server_name)
+}
+
+/// Splits `id` into the parts before and after the first colon, checking the
+/// sigil, the total length and the server name
+fn split_id(id: &str, sigil: char) -> Option<&str> {
+    if id.len() > MAX_ID_LENGTH {
+        return None;
+    }
+
+    let (localpart, server_name) = id.strip_prefix(sigil)?.split_once(':')?;
+    if localpart.is_empty() || !is_valid_server_name(server_name) {
+        return None;
+    }
+
+    Some(localpart)
+}
+
+fn is_valid_user_id(id: &str) -> bool {
+    // The historical grammar allows any printable ASCII other than a colon.
+    split_id(id, '@').is_some_and(|localpart| localpart.bytes().all(|b| matches!(b, 0x21..=0x39 | 0x3B..=0x7E)))
+}
+
+fn is_valid_room_id(id: &str) -> bool {
+    split_id(id, '!').is_some()
+}
+
+fn is_valid_room_alias_id(id: &str) -> bool {
+    split_id(id, '#').is_some_and(|alias| !alias.contains('\0'))
+}
+
+fn is_valid_event_id(id: &str) -> bool {
+    id.len() <= MAX_ID_LENGTH
+        && id.strip_prefix('$').is_some_and(|rest| !rest.is_empty())
+}
+
+fn is_valid_device_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LENGTH
+}
+
+/// Returns `true` if `name` is a valid server name: a hostname, IPv4 address
+/// or bracketed IPv6 address, optionally followed by a port
+///
+/// See https://spec.matrix.org/v1.13/appendices/#server-name
+fn is_valid_server_name(name: &str) -> bool {
+    let (host, port) = if name.starts_with('[') {
+        match name.find(']') {
+            Some(end) => (&name[..=end], &name[end + 1..]),
+            None => return false,
+        }
+    } else {
+        match name.find(':') {
+            Some(colon) => (&name[..colon], &name[colon..]),
+            None => (name, ""),
+        }
+    };
+
+    let valid_port = match port.strip_prefix(':') {
+        Some(digits) => (1..=5).contains(&digits.len()) && digits.bytes().all(|b| b.is_ascii_digit()),
+        None => port.is_empty(),
+    };
+
+    let valid_host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ipv6) =>
+            (2..=45).contains(&ipv6.len())
+                && ipv6.bytes().all(|b| b.is_ascii_hexdigit() || b == b':' || b == b'.'),
+        None =>
+            (1..=MAX_HOSTNAME_LENGTH).contains(&host.len())
+                && host.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.'),
+    };
+
+    valid_port && valid_host
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twelf::reexports::serde_json;
+
+    #[test]
+    fn test_server_name() {
+        for name in ["chat.spelt.io", "localhost:8448", "1.2.3.4", "[::1]", "[1234:5678::abcd]:443"] {
+            assert!(name.parse::<ServerName>().is_ok(), "{}", name);
+        }
+        for name in ["", "https://chat.spelt.io", "chat.spelt.io/", "host:", "host:123456", "[::1", "a_b.org"] {
+            assert!(name.parse::<ServerName>().is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_user_id() {
+        let server_name: ServerName = "chat.spelt.io".parse().unwrap();
+        let user_id = UserId::new("alice", &server_name).unwrap();
+
+        assert_eq!(user_id.as_str(), "@alice:chat.spelt.io");
+        assert_eq!(user_id.localpart(), "alice");
+        assert_eq!(user_id.server_name(), "chat.spelt.io");
+
+        // Historical user IDs may contain uppercase letters.
+        assert!("@Alice:chat.spelt.io".parse::<UserId>().is_ok());
+        assert!("@alice:localhost:8448".parse::<UserId>().is_ok());
+
+        for id in ["alice:chat.spelt.io", "@alice", "@:chat.spelt.io", "@al ice:chat.spelt.io", "@alice:https://chat.spelt.io"] {
+            assert!(id.parse::<UserId>().is_err(), "{}", id);
+        }
+        assert!(format!("@{}:chat.spelt.io", "a".repeat(250)).parse::<UserId>().is_err());
+    }
+
+    #[test]
+    fn test_room_id() {
+        let server_name: ServerName = "chat.spelt.io".parse().unwrap();
+        let room_id = RoomId::generate(&server_name);
+
+        assert!(room_id.as_str().starts_with('!'));
+        assert_eq!(room_id.server_name(), "chat.spelt.io");
+        assert_eq!(room_id.as_str().parse::<RoomId>().unwrap(), room_id);
+        assert!("!abc".parse::<RoomId>().is_err());
+        assert!("#abc:chat.spelt.io".parse::<RoomId>().is_err());
+    }
+
+    #[test]
+    fn test_event_id() {
+        let server_name: ServerName = "chat.spelt.io".parse().unwrap();
+        assert_eq!(EventId::generate(&server_name).server_name(), Some("chat.spelt.io"));
+
+        assert_eq!("$abc:chat.spelt.io".parse::<EventId>().unwrap().server_name(), Some("chat.spelt.io"));
+        assert_eq!("$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg".parse::<EventId>().unwrap().server_name(), None);
+        assert!("$".parse::<EventId>().is_err());
+        assert!("abc".parse::<EventId>().is_err());
+    }
+
+    #[test]
+    fn test_room_alias_id() {
+        let alias: RoomAliasId = "#lobby:chat.spelt.io".parse().unwrap();
+
+        assert_eq!(alias.alias(), "lobby");
+        assert_eq!(alias.server_name(), "chat.spelt.io");
+        assert!("#lobby".parse::<RoomAliasId>().is_err());
+    }
+
+    #[test]
+    fn test_device_id() {
+        assert!(!DeviceId::generate().as_str().is_empty());
+        assert!("".parse::<DeviceId>().is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let user_id: UserId = serde_json::from_str(r#""@alice:chat.spelt.io""#).unwrap();
+        assert_eq!(serde_json::to_string(&user_id).unwrap(), r#""@alice:chat.spelt.io""#);
+        assert!(serde_json::from_str::<UserId>(r#""alice""#).is_err());
+    }
+
+    #[test]
+    fn test_is_valid_localpart() {
+        assert!(is_valid_localpart("alice"));
+        assert!(is_valid_localpart("a.l-i_c=e/+9"));
+        assert!(!is_valid_localpart(""));
+        assert!(!is_valid_localpart("Alice"));
+        assert!(!is_valid_localpart("alice:example.org"));
+    }
+}
@@ -1 +1,15 @@
+pub mod account_data;
 pub mod auth;
+pub mod events;
+pub mod filter;
+pub mod ids;
+pub mod presence;
+pub mod receipts;
+pub mod registration_tokens;
+pub mod room_version;
+pub mod rooms;
+pub mod sliding_sync;
+pub mod stream_token;
+pub mod threepid;
+pub mod to_device;
+pub mod uia;
@@ -0,0 +1,31 @@
+use crate::models::ids::UserId;
+use serde::Deserialize;
+
+/// Model for database `presence` table
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Presence {
+    pub user_id: UserId,
+    /// `online`, `unavailable` or `offline`
+    pub presence: String,
+    pub status_msg: Option<String>,
+    pub last_active_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A user's presence, as a client sets it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Offline,
+    Online,
+    Unavailable,
+}
+
+impl PresenceState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceState::Offline => "offline",
+            PresenceState::Online => "online",
+            PresenceState::Unavailable => "unavailable",
+        }
+    }
+}
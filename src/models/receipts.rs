@@ -0,0 +1,14 @@
+use crate::models::ids::{EventId, RoomId, UserId};
+
+/// Model for database `receipts` table
+///
+/// `thread_id` is `None` for unthreaded receipts.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Receipt {
+    pub room_id: RoomId,
+    pub user_id: UserId,
+    pub receipt_type: String,
+    pub thread_id: Option<String>,
+    pub event_id: EventId,
+    pub ts: i64,
+}
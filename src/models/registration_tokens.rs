@@ -0,0 +1,14 @@
+/// Model for database `registration_tokens` table
+///
+/// A token may be used for `uses_allowed` registrations, or without limit if
+/// `uses_allowed` is `None`. Registrations that have completed the
+/// `m.login.registration_token` stage but not yet created the account are
+/// counted in `pending`.
+#[derive(Debug, sqlx::FromRow)]
+pub struct RegistrationToken {
+    pub token: String,
+    pub uses_allowed: Option<i32>,
+    pub pending: i32,
+    pub completed: i32,
+    pub expiry_time: Option<chrono::DateTime<chrono::Utc>>,
+}
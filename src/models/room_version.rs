@@ -0,0 +1,262 @@
+//! The room versions this server supports and the rules that differ between
+//! them
+//!
+//! See https://spec.matrix.org/v1.13/rooms/
+
+use serde::Serialize;
+
+/// Whether clients should use a room version for new rooms
+///
+/// Every version in [`ROOM_VERSIONS`] is stable, but the server can be
+/// configured with `unstable_room_versions` to mark some as unstable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomVersionStability {
+    Stable,
+    Unstable,
+}
+
+/// How the IDs of events in a room are formed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventIdFormat {
+    /// `$opaque_id:server_name`, chosen by the server that created the event,
+    /// which is also referenced by its hash in `prev_events` and `auth_events`
+    ServerChosen,
+
+    /// `$` followed by the standard base64 reference hash of the event
+    Base64Hash,
+
+    /// `$` followed by the URL-safe base64 reference hash of the event
+    UrlSafeBase64Hash,
+}
+
+/// Which keys of an event survive redaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RedactionRules {
+    /// Rules of room versions 1 to 5
+    V1,
+
+    /// Room version 6 no longer keeps the content of `m.room.aliases`
+    V6,
+
+    /// Room version 8 keeps `allow` in `m.room.join_rules`
+    V8,
+
+    /// Room version 9 keeps `join_authorised_via_users_server` in
+    /// `m.room.member`
+    V9,
+
+    /// Room version 11 keeps all of `m.room.create`, `invite` in
+    /// `m.room.power_levels`, `redacts` in `m.room.redaction` and the signed
+    /// part of third-party invites, but no longer keeps the top-level
+    /// `origin`, `membership` and `prev_state`
+    V11,
+}
+
+/// The algorithm that resolves conflicting room state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateResolution {
+    V1,
+    V2,
+}
+
+/// A room version and its rules
+#[derive(Debug, PartialEq, Eq)]
+pub struct RoomVersion {
+    pub id: &'static str,
+    pub stability: RoomVersionStability,
+    pub event_id_format: EventIdFormat,
+    pub redaction_rules: RedactionRules,
+    pub state_resolution: StateResolution,
+    /// Signing keys must be valid at the time an event was sent
+    pub enforce_key_validity: bool,
+    /// `m.room.aliases` events are authorized by their state key alone
+    pub special_case_aliases_auth: bool,
+    /// Events must be valid canonical JSON, so floats and large integers are
+    /// rejected
+    pub strict_canonical_json: bool,
+    /// `notifications` in `m.room.power_levels` is checked like the other
+    /// power levels
+    pub limit_notifications_power_levels: bool,
+    /// Users may ask to join with the `knock` join rule and membership
+    pub knocking: bool,
+    /// Users may join without an invite by being in another room, with the
+    /// `restricted` join rule
+    pub restricted_joins: bool,
+    /// The `knock_restricted` join rule combines knocking and restricted joins
+    pub knock_restricted: bool,
+    /// Power levels must be integers, not strings holding integers
+    pub integer_power_levels: bool,
+    /// The room creator is the sender of `m.room.create` rather than its
+    /// `creator` content
+    pub implicit_room_creator: bool,
+}
+
+impl RoomVersion {
+    /// Returns `true` if the redaction rules of this version are at least
+    /// `rules`
+    pub fn redacts_like(&self, rules: RedactionRules) -> bool {
+        self.redaction_rules >= rules
+    }
+}
+
+/// Room version 1, whose rules the later versions change
+const V1: RoomVersion = RoomVersion {
+    id: "1",
+    stability: RoomVersionStability::Stable,
+    event_id_format: EventIdFormat::ServerChosen,
+    redaction_rules: RedactionRules::V1,
+    state_resolution: StateResolution::V1,
+    enforce_key_validity: false,
+    special_case_aliases_auth: true,
+    strict_canonical_json: false,
+    limit_notifications_power_levels: false,
+    knocking: false,
+    restricted_joins: false,
+    knock_restricted: false,
+    integer_power_levels: false,
+    implicit_room_creator: false,
+};
+
+/// Room versions this server supports, oldest first
+pub static ROOM_VERSIONS: [RoomVersion; 11] = [
+    V1,
+    RoomVersion {
+        id: "2",
+        state_resolution: StateResolution::V2,
+        ..V1
+    },
+    RoomVersion {
+        id: "3",
+        event_id_format: EventIdFormat::Base64Hash,
+        state_resolution: StateResolution::V2,
+        ..V1
+    },
+    RoomVersion {
+        id: "4",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        state_resolution: StateResolution::V2,
+        ..V1
+    },
+    RoomVersion {
+        id: "5",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        state_resolution: StateResolution::V2,
+        enforce_key_validity: true,
+        ..V1
+    },
+    RoomVersion {
+        id: "6",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        redaction_rules: RedactionRules::V6,
+        state_resolution: StateResolution::V2,
+        enforce_key_validity: true,
+        special_case_aliases_auth: false,
+        strict_canonical_json: true,
+        limit_notifications_power_levels: true,
+        ..V1
+    },
+    RoomVersion {
+        id: "7",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        redaction_rules: RedactionRules::V6,
+        state_resolution: StateResolution::V2,
+        enforce_key_validity: true,
+        special_case_aliases_auth: false,
+        strict_canonical_json: true,
+        limit_notifications_power_levels: true,
+        knocking: true,
+        ..V1
+    },
+    RoomVersion {
+        id: "8",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        redaction_rules: RedactionRules::V8,
+        state_resolution: StateResolution::V2,
+        enforce_key_validity: true,
+        special_case_aliases_auth: false,
+        strict_canonical_json: true,
+        limit_notifications_power_levels: true,
+        knocking: true,
+        restricted_joins: true,
+        ..V1
+    },
+    RoomVersion {
+        id: "9",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        redaction_rules: RedactionRules::V9,
+        state_resolution: StateResolution::V2,
+        enforce_key_validity: true,
+        special_case_aliases_auth: false,
+        strict_canonical_json: true,
+        limit_notifications_power_levels: true,
+        knocking: true,
+        restricted_joins: true,
+        ..V1
+    },
+    RoomVersion {
+        id: "10",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        redaction_rules: RedactionRules::V9,
+        state_resolution: StateResolution::V2,
+        enforce_key_validity: true,
+        special_case_aliases_auth: false,
+        strict_canonical_json: true,
+        limit_notifications_power_levels: true,
+        knocking: true,
+        restricted_joins: true,
+        knock_restricted: true,
+        integer_power_levels: true,
+        ..V1
+    },
+    RoomVersion {
+        id: "11",
+        event_id_format: EventIdFormat::UrlSafeBase64Hash,
+        redaction_rules: RedactionRules::V11,
+        state_resolution: StateResolution::V2,
+        enforce_key_validity: true,
+        special_case_aliases_auth: false,
+        strict_canonical_json: true,
+        limit_notifications_power_levels: true,
+        knocking: true,
+        restricted_joins: true,
+        knock_restricted: true,
+        integer_power_levels: true,
+        implicit_room_creator: true,
+        ..V1
+    },
+];
+
+/// Room version of rooms created without a `room_version`, unless
+/// `default_room_version` is configured
+pub const DEFAULT_ROOM_VERSION: &str = "10";
+
+/// Returns the room version with ID `id`, or `None` if it isn't supported
+pub fn get(id: &str) -> Option<&'static RoomVersion> {
+    ROOM_VERSIONS.iter().find(|version| version.id == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        assert_eq!(get("1").unwrap().event_id_format, EventIdFormat::ServerChosen);
+        assert_eq!(get("1").unwrap().state_resolution, StateResolution::V1);
+        assert_eq!(get("3").unwrap().event_id_format, EventIdFormat::Base64Hash);
+        assert!(!get("6").unwrap().knocking);
+        assert!(get("7").unwrap().knocking);
+        assert!(get("8").unwrap().restricted_joins);
+        assert!(get("9").unwrap().redacts_like(RedactionRules::V8));
+        assert!(!get("9").unwrap().integer_power_levels);
+        assert!(get("10").unwrap().knock_restricted);
+        assert!(get("11").unwrap().implicit_room_creator);
+        assert!(get(DEFAULT_ROOM_VERSION).is_some());
+        assert!(get("12").is_none());
+        assert!(get("").is_none());
+
+        let ids: Vec<&str> = ROOM_VERSIONS.iter().map(|version| version.id).collect();
+        assert_eq!(ids, vec!["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11"]);
+    }
+}
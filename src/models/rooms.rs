@@ -0,0 +1,8 @@
+use crate::models::ids::RoomId;
+
+/// Model for database `rooms` table
+#[derive(Debug, sqlx::FromRow)]
+pub struct Room {
+    pub room_id: RoomId,
+    pub room_version: String,
+}
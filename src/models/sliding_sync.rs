@@ -0,0 +1,23 @@
+use crate::models::ids::RoomId;
+use twelf::reexports::serde_json;
+
+/// Model for database `sliding_sync_connections` table
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Connection {
+    pub id: i64,
+    /// The number of the connection's latest response, which the client
+    /// passes back as `pos`
+    pub position: i64,
+    /// The stream ordering up to which the latest response reached
+    pub stream_ordering: i64,
+}
+
+/// Model for database `sliding_sync_rooms` table
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ConnectionRoom {
+    pub room_id: RoomId,
+    /// The stream ordering up to which the room has been sent
+    pub stream_ordering: i64,
+    /// The `required_state` with which the room was sent
+    pub required_state: serde_json::Value,
+}
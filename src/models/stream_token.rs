@@ -0,0 +1,184 @@
+use crate::models::ids::{RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A position in the stream of events that this server has stored, given to
+/// clients as an opaque string like `s42`
+///
+/// Events with a `stream_ordering` up to and including `stream_ordering` are
+/// before the position.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct StreamToken {
+    pub stream_ordering: i64,
+}
+
+/// A position in a room's timeline, given to clients as an opaque string like
+/// `t7-42`
+///
+/// Events are ordered by `depth` and then by `stream_ordering`, so that
+/// pagination is stable when events arrive concurrently; events up to and
+/// including this pair are before the position.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TopologicalToken {
+    pub depth: i64,
+    pub stream_ordering: i64,
+}
+
+/// A token from which to paginate a room's timeline: either the `prev_batch`
+/// of a sync response or a token from an earlier page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PaginationToken {
+    Stream(StreamToken),
+    Topological(TopologicalToken),
+}
+
+/// Something stored at position `stream_ordering` that concerns the users with
+/// IDs `user_ids` and everyone in the rooms with IDs `room_ids`
+///
+/// Store writes announce these over Postgres notifications so that requests
+/// waiting for new data can be woken; see [`crate::services::notifier`].
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamUpdate {
+    pub stream_ordering: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub room_ids: Vec<RoomId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_ids: Vec<UserId>,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Invalid stream token: {0}")]
+pub struct StreamTokenError(pub String);
+
+impl FromStr for StreamToken {
+    type Err = StreamTokenError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix('s').and_then(|n| n.parse().ok()) {
+            Some(stream_ordering) => Ok(StreamToken { stream_ordering }),
+            None => Err(StreamTokenError(value.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for StreamToken {
+    type Error = StreamTokenError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<StreamToken> for String {
+    fn from(token: StreamToken) -> Self {
+        token.to_string()
+    }
+}
+
+impl fmt::Display for StreamToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s{}", self.stream_ordering)
+    }
+}
+
+impl FromStr for TopologicalToken {
+    type Err = StreamTokenError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parsed = value.strip_prefix('t')
+            .and_then(|rest| rest.split_once('-'))
+            .and_then(|(depth, stream_ordering)| Some((depth.parse().ok()?, stream_ordering.parse().ok()?)));
+        match parsed {
+            Some((depth, stream_ordering)) => Ok(TopologicalToken { depth, stream_ordering }),
+            None => Err(StreamTokenError(value.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for TopologicalToken {
+    type Error = StreamTokenError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TopologicalToken> for String {
+    fn from(token: TopologicalToken) -> Self {
+        token.to_string()
+    }
+}
+
+impl fmt::Display for TopologicalToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "t{}-{}", self.depth, self.stream_ordering)
+    }
+}
+
+impl FromStr for PaginationToken {
+    type Err = StreamTokenError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.parse() {
+            Ok(token) => Ok(PaginationToken::Stream(token)),
+            Err(_) => value.parse().map(PaginationToken::Topological),
+        }
+    }
+}
+
+impl TryFrom<String> for PaginationToken {
+    type Error = StreamTokenError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PaginationToken> for String {
+    fn from(token: PaginationToken) -> Self {
+        token.to_string()
+    }
+}
+
+impl fmt::Display for PaginationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaginationToken::Stream(token) => token.fmt(f),
+            PaginationToken::Topological(token) => token.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_token() {
+        let token: StreamToken = "s42".parse().unwrap();
+        assert_eq!(token.stream_ordering, 42);
+        assert_eq!(token.to_string(), "s42");
+
+        assert!("42".parse::<StreamToken>().is_err());
+        assert!("sabc".parse::<StreamToken>().is_err());
+        assert!("".parse::<StreamToken>().is_err());
+    }
+
+    #[test]
+    fn test_pagination_token() {
+        let token: PaginationToken = "t7-42".parse().unwrap();
+        assert_eq!(token, PaginationToken::Topological(TopologicalToken { depth: 7, stream_ordering: 42 }));
+        assert_eq!(token.to_string(), "t7-42");
+        let token: PaginationToken = "s42".parse().unwrap();
+        assert_eq!(token, PaginationToken::Stream(StreamToken { stream_ordering: 42 }));
+
+        assert!("t7".parse::<PaginationToken>().is_err());
+        assert!("t7-".parse::<PaginationToken>().is_err());
+        assert!("42".parse::<PaginationToken>().is_err());
+    }
+}
@@ -0,0 +1,15 @@
+/// Model for database `threepid_validation_sessions` table
+///
+/// Tracks the validation of a third-party identifier, such as an email
+/// address, by a token sent to it. The client identifies the session by `sid`
+/// and proves it started the session with `client_secret`.
+#[derive(Debug, sqlx::FromRow)]
+pub struct ThreepidValidationSession {
+    pub id: i64,
+    pub sid: String,
+    pub client_secret: String,
+    pub medium: String,
+    pub address: String,
+    pub token: String,
+    pub send_attempt: i64,
+}
@@ -0,0 +1,11 @@
+use crate::models::ids::UserId;
+use twelf::reexports::serde_json;
+
+/// Model for database `to_device_messages` table
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ToDeviceMessage {
+    pub sender: UserId,
+    pub event_type: String,
+    pub content: serde_json::Value,
+    pub stream_ordering: i64,
+}
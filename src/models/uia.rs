@@ -0,0 +1,13 @@
+/// Model for database `uia_sessions` table
+///
+/// Tracks a client's progress through user-interactive authentication. The
+/// `user_id` is `None` for sessions started before the user has an account,
+/// i.e. during registration.
+#[derive(Debug, sqlx::FromRow)]
+pub struct UiaSession {
+    pub id: i64,
+    pub session_identifier: String,
+    pub endpoint: String,
+    pub user_id: Option<i64>,
+    pub completed_stages: Vec<String>,
+}
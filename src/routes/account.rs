@@ -0,0 +1,352 @@
+use crate::error::{Error, ErrorResponse};
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::extractors::uia_authenticated::UiaAuthenticated;
+use crate::models::ids::{DeviceId, UserId};
+use crate::routes::auth::AuthenticationData;
+use crate::services::account::{ChangePasswordResult, RequestTokenResult};
+use crate::services::uia::UiaRequest;
+use crate::{services, AppState};
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use twelf::reexports::serde_json;
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordRequest {
+    pub auth: Option<AuthenticationData>,
+    pub logout_devices: Option<bool>,
+    pub new_password: String,
+}
+
+/// Body of a request to an `email/requestToken` endpoint
+///
+/// `next_link` isn't supported; after validating the address, the user is
+/// asked to return to their client. Neither are identity servers, so
+/// `id_server` and `id_access_token` are ignored along with it.
+#[derive(Debug, Deserialize)]
+pub struct EmailTokenRequest {
+    pub client_secret: String,
+    pub email: String,
+    pub send_attempt: i64,
+}
+
+#[derive(Serialize)]
+struct RequestTokenSuccess {
+    sid: String,
+}
+
+/// Body of a deactivation request
+///
+/// Identity servers are not supported, so `id_server` is ignored.
+#[derive(Debug, Deserialize)]
+pub struct DeactivateRequest {
+    pub auth: Option<AuthenticationData>,
+    pub erase: Option<bool>,
+}
+
+impl UiaRequest for DeactivateRequest {
+    fn auth(&self) -> &Option<AuthenticationData> {
+        &self.auth
+    }
+}
+
+/// Identity servers are not supported, so no 3PIDs are ever unbound from one
+#[derive(Serialize)]
+struct DeactivateSuccess {
+    id_server_unbind_result: &'static str,
+}
+
+#[derive(Serialize)]
+struct WhoamiResponse {
+    user_id: UserId,
+    device_id: DeviceId,
+    is_guest: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubmitTokenQuery {
+    pub sid: String,
+    pub client_secret: String,
+    pub token: String,
+}
+
+/// Responds with the user ID and device ID of the access token's owner
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3accountwhoami
+#[get("/_matrix/client/v3/account/whoami")]
+async fn whoami(auth: AuthenticatedUser) -> impl Responder {
+    HttpResponse::Ok().json(WhoamiResponse {
+        user_id: auth.matrix_user_id,
+        device_id: auth.device_id,
+        is_guest: auth.is_guest,
+    })
+}
+
+/// Changes the user's password
+///
+/// With an access token, the user must authenticate with their current
+/// password. Without one, the user can reset their password by validating
+/// their email address; see [`request_password_token()`].
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3accountpassword
+#[post("/_matrix/client/v3/account/password")]
+async fn change_password(
+    auth: Option<AuthenticatedUser>,
+    password_request: web::Json<PasswordRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::account::change_password(&password_request, auth.as_ref(), &data.config, pool).await {
+        Ok(ChangePasswordResult::Changed) =>
+            HttpResponse::Ok().json(serde_json::json!({})),
+        Ok(ChangePasswordResult::AuthRequired(uia_response)) =>
+            HttpResponse::Unauthorized().json(uia_response),
+        Ok(ChangePasswordResult::NotAuthenticated) =>
+            Error::Auth(String::from("Request not authenticated")).error_response(),
+        Ok(ChangePasswordResult::ThreepidNotFound) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_THREEPID_NOT_FOUND"),
+                error: String::from("Email address is not associated with an account")
+            }),
+        Err(err) => err.error_response()
+    }
+}
+
+/// Emails a link to validate an address so that the password of the account
+/// with that address can be reset
+///
+/// Requires the `[email]` config section.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3accountpasswordemailrequesttoken
+#[post("/_matrix/client/v3/account/password/email/requestToken")]
+async fn request_password_token(token_request: web::Json<EmailTokenRequest>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::account::request_password_reset_token(&token_request, &data.config, pool).await {
+        Ok(RequestTokenResult::Sent { sid }) =>
+            HttpResponse::Ok().json(RequestTokenSuccess { sid }),
+        Ok(RequestTokenResult::EmailDisabled) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_THREEPID_MEDIUM_NOT_SUPPORTED"),
+                error: String::from("This server does not send email")
+            }),
+        Ok(RequestTokenResult::InvalidClientSecret) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: String::from("Invalid client secret")
+            }),
+        Ok(RequestTokenResult::ThreepidNotFound) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_THREEPID_NOT_FOUND"),
+                error: String::from("Email address is not associated with an account")
+            }),
+        Err(err) => err.error_response()
+    }
+}
+
+/// Deactivates the user's account after they authenticate with their password
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3accountdeactivate
+#[post("/_matrix/client/v3/account/deactivate")]
+async fn deactivate(uia: UiaAuthenticated<DeactivateRequest>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    let erase = uia.body.erase.unwrap_or(false);
+
+    match services::account::deactivate(uia.user_id, erase, &data.config, &data.signing_keys, pool).await {
+        Ok(()) =>
+            HttpResponse::Ok().json(DeactivateSuccess { id_server_unbind_result: "no-support" }),
+        Err(err) => err.error_response()
+    }
+}
+
+/// Validates an email address when the user opens the link sent by
+/// [`request_password_token()`]
+///
+/// This is not part of the Matrix spec; the user opens it in a browser.
+#[get("/_spelt/client/email/submit_token")]
+async fn submit_email_token(query: web::Query<SubmitTokenQuery>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::account::submit_email_token(&query.sid, &query.client_secret, &query.token, pool).await {
+        Ok(true) =>
+            HttpResponse::Ok()
+                .content_type("text/plain; charset=utf-8")
+                .body("Your email address has been validated. Return to your client to continue."),
+        Ok(false) =>
+            HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body("This link is invalid or has expired."),
+        Err(err) => err.error_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::middleware;
+    use crate::services::jwt::KeyManager;
+    use crate::store::pg;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use sqlx::PgPool;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_whoami(pool: PgPool) {
+        let (user, _password) = pg::auth::tests::create_test_user(&pool).await;
+        let (session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
+
+        let mut config = Config::test();
+        config.server.server_name = "chat.spelt.io".parse().unwrap();
+        let state = AppState { config, ..AppState::test(&pool) };
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(whoami)
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/_matrix/client/v3/account/whoami")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["user_id"], format!("@{}:chat.spelt.io", user.name));
+        assert_eq!(body["device_id"], session.device_identifier.as_str());
+        assert_eq!(body["is_guest"], false);
+
+        let req = test::TestRequest::get().uri("/_matrix/client/v3/account/whoami").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_change_password(pool: PgPool) {
+        let (user, password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
+
+        let state = AppState::test(&pool);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(change_password)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/account/password")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({ "new_password": "new password" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/account/password")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "auth": { "type": "m.login.password", "session": body["session"], "password": password },
+                "new_password": "new password",
+                "logout_devices": false,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let new_password = String::from("new password");
+        assert_eq!(pg::auth::validate_user_and_password(&user.name, &new_password, &pool).await.unwrap(), Some(user.id));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_change_password_without_authentication(pool: PgPool) {
+        let state = AppState::test(&pool);
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(change_password)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/account/password")
+            .set_json(serde_json::json!({ "new_password": "new password" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_deactivate(pool: PgPool) {
+        let (user, password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
+
+        let state = AppState::test(&pool);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(deactivate)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/account/deactivate")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/account/deactivate")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "auth": { "type": "m.login.password", "session": body["session"], "password": password },
+                "erase": true,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["id_server_unbind_result"], "no-support");
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_err());
+        assert!(pg::auth::get_user(user.id, &pool).await.unwrap().unwrap().is_erased);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_request_password_token_without_email(pool: PgPool) {
+        let state = AppState::test(&pool);
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(request_password_token)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/account/password/email/requestToken")
+            .set_json(serde_json::json!({ "client_secret": "secret", "email": "a@example.org", "send_attempt": 1 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["errcode"], "M_THREEPID_MEDIUM_NOT_SUPPORTED");
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_submit_email_token(pool: PgPool) {
+        let (sid, secret, token) = (String::from("sid"), String::from("secret"), String::from("token"));
+        pg::threepid::create_session(&sid, &secret, "email", &String::from("a@example.org"), &token, 1, &pool).await.unwrap();
+
+        let state = AppState::test(&pool);
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(submit_email_token)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/_spelt/client/email/submit_token?sid=sid&client_secret=secret&token=wrong")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get()
+            .uri("/_spelt/client/email/submit_token?sid=sid&client_secret=secret&token=token")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(pg::threepid::get_validated_session(&sid, &secret, &pool).await.unwrap().is_some());
+    }
+}
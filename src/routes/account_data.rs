@@ -0,0 +1,93 @@
+use crate::error::{Error, ErrorResponse};
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{RoomId, UserId};
+use crate::services::account_data::AccountDataResult;
+use crate::{services, AppState};
+use actix_web::{get, put, web, HttpResponse, Responder, ResponseError};
+use twelf::reexports::serde_json;
+
+/// Sets global account data for the user
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3useruseridaccount_datatype
+#[put("/_matrix/client/v3/user/{user_id}/account_data/{event_type}")]
+async fn set_account_data(
+    auth: AuthenticatedUser,
+    path: web::Path<(UserId, String)>,
+    content: web::Json<serde_json::Value>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (user_id, event_type) = path.into_inner();
+
+    account_data_response(services::account_data::set_account_data(&user_id, None, &event_type, &content, &auth, pool).await)
+}
+
+/// Responds with global account data for the user
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3useruseridaccount_datatype
+#[get("/_matrix/client/v3/user/{user_id}/account_data/{event_type}")]
+async fn get_account_data(auth: AuthenticatedUser, path: web::Path<(UserId, String)>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (user_id, event_type) = path.into_inner();
+
+    account_data_response(services::account_data::get_account_data(&user_id, None, &event_type, &auth, pool).await)
+}
+
+/// Sets account data for the user in a room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3useruseridroomsroomidaccount_datatype
+#[put("/_matrix/client/v3/user/{user_id}/rooms/{room_id}/account_data/{event_type}")]
+async fn set_room_account_data(
+    auth: AuthenticatedUser,
+    path: web::Path<(UserId, RoomId, String)>,
+    content: web::Json<serde_json::Value>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (user_id, room_id, event_type) = path.into_inner();
+
+    account_data_response(
+        services::account_data::set_account_data(&user_id, Some(&room_id), &event_type, &content, &auth, pool).await
+    )
+}
+
+/// Responds with account data for the user in a room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3useruseridroomsroomidaccount_datatype
+#[get("/_matrix/client/v3/user/{user_id}/rooms/{room_id}/account_data/{event_type}")]
+async fn get_room_account_data(
+    auth: AuthenticatedUser,
+    path: web::Path<(UserId, RoomId, String)>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (user_id, room_id, event_type) = path.into_inner();
+
+    account_data_response(services::account_data::get_account_data(&user_id, Some(&room_id), &event_type, &auth, pool).await)
+}
+
+fn account_data_response(result: Result<AccountDataResult, Error>) -> HttpResponse {
+    match result {
+        Ok(AccountDataResult::Set) =>
+            HttpResponse::Ok().json(serde_json::json!({})),
+        Ok(AccountDataResult::Content(content)) =>
+            HttpResponse::Ok().json(content),
+        Ok(AccountDataResult::NotFound) =>
+            HttpResponse::NotFound().json(ErrorResponse {
+                errcode: String::from("M_NOT_FOUND"),
+                error: String::from("Account data not found")
+            }),
+        Ok(AccountDataResult::Forbidden) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_FORBIDDEN"),
+                error: String::from("Cannot access account data of other users")
+            }),
+        Ok(AccountDataResult::ServerManaged) =>
+            HttpResponse::MethodNotAllowed().json(ErrorResponse {
+                errcode: String::from("M_BAD_JSON"),
+                error: String::from("Account data of this type is managed by the server")
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
@@ -1,59 +1,149 @@
+use crate::config::RegistrationMode;
 use crate::error::ErrorResponse;
 use crate::extractors::authenticated_user::AuthenticatedUser;
-use crate::services::auth::LoginResult;
+use crate::models::ids::{DeviceId, UserId};
+use crate::services::auth::{LoginResult, RefreshResult, RegisterResult};
+use crate::store::pg;
 use crate::{services, AppState};
 use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
 use serde::{Deserialize, Serialize};
 
-/// JSON response to [`check_validity()`]
-const VALIDITY_RESPONSE_JSON: &str = r#"{"valid":false}"#;
-
 /// JSON response to [`login_types()`]
 const SUPPORTED_LOGIN_TYPES_JSON: &str = r#"{"flows":[{"type":"m.login.password"}]}"#;
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub identifier: Option<UserIdentifier>,
-    pub device_id: Option<String>,
+    pub device_id: Option<DeviceId>,
     pub initial_device_display_name: Option<String>,
     pub password: Option<String>,
     pub refresh_token: Option<bool>,
-    pub token: Option<String>,
     pub r#type: String,
     pub user: Option<String>, // Deprecated
     pub address: Option<String>, // Deprecated
-    pub medium: Option<String>, // Deprecated
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UserIdentifier {
     pub r#type: String,
     pub user: Option<String>,
-    pub address: Option<String>,
-    pub medium: Option<String>,
-    pub country: Option<String>,
-    pub phone: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub auth: Option<AuthenticationData>,
+    pub device_id: Option<DeviceId>,
+    pub inhibit_login: Option<bool>,
+    pub initial_device_display_name: Option<String>,
+    pub password: Option<String>,
+    pub refresh_token: Option<bool>,
+    pub username: Option<String>,
+}
+
+/// The `auth` field of a request to an endpoint requiring user-interactive
+/// authentication
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationData {
+    pub session: Option<String>,
+    pub r#type: Option<String>,
+    pub token: Option<String>, // m.login.registration_token
+    pub identifier: Option<UserIdentifier>, // m.login.password
+    pub password: Option<String>, // m.login.password
+    #[serde(alias = "threepidCreds")]
+    pub threepid_creds: Option<ThreepidCredentials>, // m.login.email.identity
+}
+
+/// Identifies a third-party identifier validation session, e.g. one started by
+/// requesting an email token
+#[derive(Debug, Deserialize)]
+pub struct ThreepidCredentials {
+    pub sid: String,
+    pub client_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterQuery {
+    pub kind: Option<String>,
 }
 
 #[derive(Serialize)]
-struct LoginSuccess {
+struct RegisterSuccess {
+    user_id: UserId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<DeviceId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct RefreshSuccess {
     access_token: String,
-    device_id: String,
-    user_id: String,
+    refresh_token: String,
     expires_in_ms: u64,
 }
 
-/// Checks the validity of a login token
+#[derive(Debug, Deserialize)]
+pub struct ValidityQuery {
+    pub token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ValidityResponse {
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct LoginSuccess {
+    access_token: String,
+    device_id: DeviceId,
+    user_id: UserId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in_ms: Option<u64>,
+}
+
+/// Checks the validity of a registration token
 ///
-/// Token Authenticated Registration is not supported by this server, so this
-/// always returns a 200 with `valid: false` in the payload.
+/// A token is valid if it exists, has not expired and has uses remaining. If
+/// registration is disabled, all tokens are invalid, and this responds with a
+/// 403.
 ///
-/// See https://spec.matrix.org/v1.13/client-server-api/#token-authenticated-registration
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv1registermloginregistration_tokenvalidity
 #[get("/_matrix/client/v1/register/m.login.registration_token/validity")]
-async fn check_validity() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .body(VALIDITY_RESPONSE_JSON)
+async fn check_validity(query: web::Query<ValidityQuery>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    if data.config.server.registration == RegistrationMode::Disabled {
+        return HttpResponse::Forbidden().json(ErrorResponse {
+            errcode: String::from("M_FORBIDDEN"),
+            error: String::from("Registration is disabled")
+        });
+    }
+
+    let token = match query.token {
+        Some(ref token) => token,
+        None =>
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_MISSING_PARAM"),
+                error: String::from("Token is required")
+            }),
+    };
+
+    match pg::registration_tokens::is_usable(token, pool).await {
+        Ok(valid) =>
+            HttpResponse::Ok().json(ValidityResponse { valid }),
+        Err(err) => err.error_response()
+    }
 }
 
 /// Responds with the login flows supported by this server
@@ -74,15 +164,15 @@ async fn login_types() -> HttpResponse {
 #[post("/_matrix/client/v3/login")]
 async fn log_in(login_request: web::Json<LoginRequest>, data: web::Data<AppState>) -> impl Responder {
     let pool = data.db_pool.as_ref().unwrap();
-    let homeserver = data.config.server.base_url.clone();
 
-    match services::auth::log_in(login_request, pool).await {
-        Ok(LoginResult::LoggedIn { access_token, device_id, username, expires_in_ms }) =>
+    match services::auth::log_in(login_request, &data.config, &data.jwt_keys, pool).await {
+        Ok(LoginResult::LoggedIn { user_id, tokens }) =>
             HttpResponse::Ok().json(LoginSuccess {
-                access_token,
-                device_id,
-                user_id: format!("@{}:{}", username, homeserver),
-                expires_in_ms
+                access_token: tokens.access_token,
+                device_id: tokens.device_id,
+                user_id,
+                refresh_token: tokens.refresh_token,
+                expires_in_ms: tokens.expires_in_ms
             }),
         Ok(LoginResult::BadRequest) => {
             HttpResponse::BadRequest().json(ErrorResponse {
@@ -104,6 +194,86 @@ async fn log_in(login_request: web::Json<LoginRequest>, data: web::Data<AppState
     }
 }
 
+/// Registers a new account and, unless `inhibit_login` is set, responds with a
+/// token
+///
+/// Whether registration is allowed, and which authentication stages it
+/// requires, is set by `registration` in the `[server]` config section and by
+/// the presence of a `[terms]` section.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3register
+#[post("/_matrix/client/v3/register")]
+async fn register(
+    query: web::Query<RegisterQuery>,
+    register_request: web::Json<RegisterRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    let is_guest = match query.kind.as_deref() {
+        None | Some("user") => false,
+        Some("guest") => true,
+        Some(_) =>
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: String::from("Invalid account kind")
+            }),
+    };
+
+    match services::auth::register(register_request, is_guest, &data.config, &data.jwt_keys, pool).await {
+        Ok(RegisterResult::Registered { user_id, tokens }) =>
+            HttpResponse::Ok().json(RegisterSuccess {
+                user_id,
+                access_token: tokens.as_ref().map(|t| t.access_token.clone()),
+                device_id: tokens.as_ref().map(|t| t.device_id.clone()),
+                refresh_token: tokens.as_ref().and_then(|t| t.refresh_token.clone()),
+                expires_in_ms: tokens.as_ref().and_then(|t| t.expires_in_ms)
+            }),
+        Ok(RegisterResult::AuthRequired(uia_response)) =>
+            HttpResponse::Unauthorized().json(uia_response),
+        Ok(RegisterResult::RegistrationDisabled) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_FORBIDDEN"),
+                error: String::from("Registration is disabled")
+            }),
+        Ok(RegisterResult::UsernameInUse) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_USER_IN_USE"),
+                error: String::from("Username is already taken")
+            }),
+        Ok(RegisterResult::UsernameInvalid) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_USERNAME"),
+                error: String::from("Username contains invalid characters")
+            }),
+        Ok(RegisterResult::MissingPassword) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_MISSING_PARAM"),
+                error: String::from("Password is required")
+            }),
+        Err(err) => err.error_response()
+    }
+}
+
+/// Exchanges a refresh token for a new access token and refresh token
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3refresh
+#[post("/_matrix/client/v3/refresh")]
+async fn refresh(refresh_request: web::Json<RefreshRequest>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::auth::refresh(&refresh_request.refresh_token, &data.jwt_keys, pool).await {
+        Ok(RefreshResult::Refreshed { access_token, refresh_token, expires_in_ms }) =>
+            HttpResponse::Ok().json(RefreshSuccess { access_token, refresh_token, expires_in_ms }),
+        Ok(RefreshResult::TokenInvalid) =>
+            HttpResponse::Unauthorized().json(ErrorResponse {
+                errcode: String::from("M_UNKNOWN_TOKEN"),
+                error: String::from("Refresh token invalid")
+            }),
+        Err(err) => err.error_response()
+    }
+}
+
 /// Logs out a user
 ///
 /// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3logout
@@ -136,7 +306,7 @@ async fn log_out_all(auth: AuthenticatedUser, data: web::Data<AppState>) -> impl
 mod tests {
     use super::*;
     use crate::config::Config;
-    use crate::store::pg;
+    use crate::services::jwt::KeyManager;
     use crate::{middleware, services};
     use actix_web::body::to_bytes;
     use actix_web::dev::ServiceResponse;
@@ -147,12 +317,34 @@ mod tests {
     use sqlx::PgPool;
     use twelf::reexports::serde_json;
 
-    #[actix_web::test]
-    async fn test_check_validity() {
-        let app = test::init_service(App::new().service(check_validity)).await;
-        let req = test::TestRequest::get().uri("/_matrix/client/v1/register/m.login.registration_token/validity").to_request();
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_check_validity(pool: PgPool) {
+        pg::registration_tokens::create_token(&String::from("abc"), Some(1), None, &pool).await.unwrap();
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Token, &pool))).service(check_validity)
+        ).await;
+
+        let req = test::TestRequest::get().uri("/_matrix/client/v1/register/m.login.registration_token/validity?token=abc").to_request();
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["valid"], true);
+
+        let req = test::TestRequest::get().uri("/_matrix/client/v1/register/m.login.registration_token/validity?token=xyz").to_request();
+        let json: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(json["valid"], false);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_check_validity_with_registration_disabled(pool: PgPool) {
+        pg::registration_tokens::create_token(&String::from("abc"), Some(1), None, &pool).await.unwrap();
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Disabled, &pool))).service(check_validity)
+        ).await;
+
+        let req = test::TestRequest::get().uri("/_matrix/client/v1/register/m.login.registration_token/validity?token=abc").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
     }
 
     #[actix_web::test]
@@ -188,7 +380,7 @@ mod tests {
             password
         };
 
-        let state = AppState { config: Config::test(), db_pool: Some(pool.clone()) };
+        let state = AppState::test(&pool);
         let app = test::init_service(App::new().app_data(web::Data::new(state)).service(log_in)).await;
 
         let req = test::TestRequest::post()
@@ -199,7 +391,7 @@ mod tests {
         assert!(resp.status().is_success());
 
         let jwt = access_token_from_body(resp).await;
-        assert!(services::auth::authorize_request(&jwt, &pool).await.is_ok());
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_ok());
     }
 
     #[sqlx::test(migrations = "migrations/pg")]
@@ -214,7 +406,7 @@ mod tests {
             password: String::from("foobar"),
         };
 
-        let state = AppState { config: Config::test(), db_pool: Some(pool.clone()) };
+        let state = AppState::test(&pool);
         let app = test::init_service(App::new().app_data(web::Data::new(state)).service(log_in)).await;
 
         let req = test::TestRequest::post()
@@ -226,6 +418,62 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::FORBIDDEN);
     }
 
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_log_in_without_refresh_token(pool: PgPool) {
+        let (user, password) = pg::auth::tests::create_test_user(&pool).await;
+        let state = AppState::test(&pool);
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(log_in)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/login")
+            .set_json(serde_json::json!({"type": "m.login.password", "user": user.name, "password": password}))
+            .to_request();
+        let json: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert!(json.get("refresh_token").is_none());
+        assert!(json.get("expires_in_ms").is_none());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_refresh(pool: PgPool) {
+        let (user, password) = pg::auth::tests::create_test_user(&pool).await;
+        let state = AppState::test(&pool);
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(log_in).service(refresh)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/login")
+            .set_json(serde_json::json!({"type": "m.login.password", "user": user.name, "password": password, "refresh_token": true}))
+            .to_request();
+        let json: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(json["expires_in_ms"], KeyManager::test().ttl_seconds() * 1000);
+        let refresh_token_1 = json["refresh_token"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/refresh")
+            .set_json(serde_json::json!({"refresh_token": refresh_token_1}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        let jwt = json["access_token"].as_str().unwrap().to_string();
+        let refresh_token_2 = json["refresh_token"].as_str().unwrap().to_string();
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_ok());
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/refresh")
+            .set_json(serde_json::json!({"refresh_token": refresh_token_2}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // Replaying the first refresh token logs out the session.
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/refresh")
+            .set_json(serde_json::json!({"refresh_token": refresh_token_1}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_err());
+    }
+
     #[derive(Serialize)]
     struct RequestWithUser {
         r#type: String,
@@ -242,7 +490,7 @@ mod tests {
             password
         };
 
-        let state = AppState { config: Config::test(), db_pool: Some(pool.clone()) };
+        let state = AppState::test(&pool);
         let app = test::init_service(App::new().app_data(web::Data::new(state)).service(log_in)).await;
 
         let req = test::TestRequest::post()
@@ -253,7 +501,50 @@ mod tests {
         assert!(resp.status().is_success());
 
         let jwt = access_token_from_body(resp).await;
-        assert!(services::auth::authorize_request(&jwt, &pool).await.is_ok());
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_ok());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_log_in_with_user_id(pool: PgPool) {
+        let (user, password) = pg::auth::tests::create_test_user(&pool).await;
+        let config = Config::test();
+        let user_id = format!("@{}:{}", user.name, config.server.server_name);
+        let payload = RequestWithIdentifier {
+            r#type: "m.login.password".to_string(),
+            identifier: RequestIdentifier {
+                r#type: String::from("m.id.user"),
+                user: user_id.clone(),
+            },
+            password: password.clone()
+        };
+
+        let state = AppState { config, ..AppState::test(&pool) };
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(log_in)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/login")
+            .set_json(payload)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["user_id"], user_id);
+
+        // User IDs on other servers are never local users
+        let payload = RequestWithIdentifier {
+            r#type: "m.login.password".to_string(),
+            identifier: RequestIdentifier {
+                r#type: String::from("m.id.user"),
+                user: format!("@{}:elsewhere.example.org", user.name),
+            },
+            password
+        };
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/login")
+            .set_json(payload)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
     }
 
     #[derive(Serialize)]
@@ -272,7 +563,7 @@ mod tests {
             password
         };
 
-        let state = AppState { config: Config::test(), db_pool: Some(pool.clone()) };
+        let state = AppState::test(&pool);
         let app = test::init_service(App::new().app_data(web::Data::new(state)).service(log_in)).await;
 
         let req = test::TestRequest::post()
@@ -283,7 +574,7 @@ mod tests {
         assert!(resp.status().is_success());
 
         let jwt = access_token_from_body(resp).await;
-        assert!(services::auth::authorize_request(&jwt, &pool).await.is_ok());
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_ok());
     }
 
     #[sqlx::test(migrations = "migrations/pg")]
@@ -291,7 +582,7 @@ mod tests {
         let (user, _password) = pg::auth::tests::create_test_user(&pool).await;
         let (_session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
 
-        let state = AppState { config: Config::test(), db_pool: Some(pool.clone()) };
+        let state = AppState::test(&pool);
         let app = test::init_service(
             App::new()
                 .wrap(from_fn(middleware::auth::authenticator))
@@ -306,7 +597,7 @@ mod tests {
         let resp = test::call_service(&app, req).await;
 
         assert!(resp.status().is_success());
-        assert!(services::auth::authorize_request(&jwt, &pool).await.is_err());
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_err());
     }
 
     #[sqlx::test(migrations = "migrations/pg")]
@@ -315,7 +606,7 @@ mod tests {
         let (_session, jwt_1) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
         let (_session, jwt_2) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
 
-        let state = AppState { config: Config::test(), db_pool: Some(pool.clone()) };
+        let state = AppState::test(&pool);
         let app = test::init_service(
             App::new()
                 .wrap(from_fn(middleware::auth::authenticator))
@@ -330,8 +621,173 @@ mod tests {
         let resp = test::call_service(&app, req).await;
 
         assert!(resp.status().is_success());
-        assert!(services::auth::authorize_request(&jwt_1, &pool).await.is_err());
-        assert!(services::auth::authorize_request(&jwt_2, &pool).await.is_err());
+        assert!(services::auth::authorize_request(&jwt_1, &KeyManager::test(), &pool).await.is_err());
+        assert!(services::auth::authorize_request(&jwt_2, &KeyManager::test(), &pool).await.is_err());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register(pool: PgPool) {
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Open, &pool))).service(register)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register")
+            .set_json(serde_json::json!({"username": "alice", "password": "secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["flows"][0]["stages"][0], "m.login.dummy");
+        let session = json["session"].as_str().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register")
+            .set_json(serde_json::json!({
+                "username": "alice",
+                "password": "secret",
+                "auth": {"type": "m.login.dummy", "session": session}
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let jwt = access_token_from_body(resp).await;
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_ok());
+        assert!(pg::auth::validate_user_and_password(&String::from("alice"), &String::from("secret"), &pool).await.unwrap().is_some());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register_with_inhibit_login(pool: PgPool) {
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Open, &pool))).service(register)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register")
+            .set_json(serde_json::json!({"username": "alice", "password": "secret", "inhibit_login": true}))
+            .to_request();
+        let json: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let session = json["session"].as_str().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register")
+            .set_json(serde_json::json!({
+                "username": "alice",
+                "password": "secret",
+                "inhibit_login": true,
+                "auth": {"type": "m.login.dummy", "session": session}
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert!(json["user_id"].as_str().unwrap().starts_with("@alice:"));
+        assert!(json.get("access_token").is_none());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register_with_username_in_use(pool: PgPool) {
+        pg::auth::register_user(&String::from("alice"), &None, false, &pool).await.unwrap();
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Open, &pool))).service(register)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register")
+            .set_json(serde_json::json!({"username": "alice", "password": "secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["errcode"], "M_USER_IN_USE");
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register_with_registration_disabled(pool: PgPool) {
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Disabled, &pool))).service(register)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register")
+            .set_json(serde_json::json!({"username": "alice", "password": "secret"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register_with_registration_token(pool: PgPool) {
+        pg::registration_tokens::create_token(&String::from("abc"), Some(1), None, &pool).await.unwrap();
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Token, &pool))).service(register)
+        ).await;
+
+        for (username, expected_status) in [("alice", StatusCode::OK), ("bob", StatusCode::UNAUTHORIZED)] {
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/v3/register")
+                .set_json(serde_json::json!({"username": username, "password": "secret"}))
+                .to_request();
+            let json: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(json["flows"][0]["stages"][0], "m.login.registration_token");
+            let session = json["session"].as_str().unwrap();
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/v3/register")
+                .set_json(serde_json::json!({
+                    "username": username,
+                    "password": "secret",
+                    "auth": {"type": "m.login.registration_token", "token": "abc", "session": session}
+                }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), expected_status);
+        }
+
+        assert!(!pg::registration_tokens::is_usable(&String::from("abc"), &pool).await.unwrap());
+        assert!(pg::auth::user_exists(&String::from("alice"), &pool).await.unwrap());
+        assert!(!pg::auth::user_exists(&String::from("bob"), &pool).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register_guest(pool: PgPool) {
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Open, &pool))).service(register)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register?kind=guest")
+            .set_json(serde_json::json!({}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let jwt = access_token_from_body(resp).await;
+        assert!(services::auth::authorize_request(&jwt, &KeyManager::test(), &pool).await.is_ok());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register_guest_with_token_registration(pool: PgPool) {
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(registration_state(RegistrationMode::Token, &pool))).service(register)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/register?kind=guest")
+            .set_json(serde_json::json!({}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    fn registration_state(mode: RegistrationMode, pool: &PgPool) -> AppState {
+        let mut state = AppState::test(pool);
+        state.config.server.registration = mode;
+        state
     }
 
     async fn access_token_from_body(resp: ServiceResponse) -> String {
@@ -0,0 +1,59 @@
+use crate::error::{Error, ErrorResponse};
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::UserId;
+use crate::services::filter::FilterResult;
+use crate::{services, AppState};
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
+use twelf::reexports::serde_json;
+
+/// Stores a filter for the user and responds with its ID
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3useruseridfilter
+#[post("/_matrix/client/v3/user/{user_id}/filter")]
+async fn create_filter(
+    auth: AuthenticatedUser,
+    user_id: web::Path<UserId>,
+    definition: web::Json<serde_json::Value>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    filter_response(services::filter::create_filter(&user_id, &definition, &auth, pool).await)
+}
+
+/// Responds with one of the user's filters
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3useruseridfilterfilterid
+#[get("/_matrix/client/v3/user/{user_id}/filter/{filter_id}")]
+async fn get_filter(auth: AuthenticatedUser, path: web::Path<(UserId, String)>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (user_id, filter_id) = path.into_inner();
+
+    filter_response(services::filter::get_filter(&user_id, &filter_id, &auth, pool).await)
+}
+
+fn filter_response(result: Result<FilterResult, Error>) -> HttpResponse {
+    match result {
+        Ok(FilterResult::Created(filter_id)) =>
+            HttpResponse::Ok().json(serde_json::json!({ "filter_id": filter_id })),
+        Ok(FilterResult::Found(definition)) =>
+            HttpResponse::Ok().json(definition),
+        Ok(FilterResult::NotFound) =>
+            HttpResponse::NotFound().json(ErrorResponse {
+                errcode: String::from("M_NOT_FOUND"),
+                error: String::from("Filter not found")
+            }),
+        Ok(FilterResult::Forbidden) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_FORBIDDEN"),
+                error: String::from("Cannot access filters of other users")
+            }),
+        Ok(FilterResult::Invalid(message)) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_BAD_JSON"),
+                error: message
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
@@ -0,0 +1,162 @@
+use crate::error::ErrorResponse;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::filter::RoomEventFilter;
+use crate::models::ids::{EventId, RoomId};
+use crate::models::stream_token::{PaginationToken, TopologicalToken};
+use crate::services::events::client_event;
+use crate::services::history::{ContextResult, EventResult, MessagesResult};
+use crate::{services, AppState};
+use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use twelf::reexports::serde_json;
+
+/// Direction in which [`get_messages()`] paginates
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+pub enum Direction {
+    #[default]
+    #[serde(rename = "b")]
+    Backward,
+    #[serde(rename = "f")]
+    Forward,
+}
+
+/// Query parameters of [`get_messages()`]
+#[derive(Debug, Default, Deserialize)]
+pub struct MessagesQuery {
+    pub from: Option<PaginationToken>,
+    pub to: Option<PaginationToken>,
+    pub dir: Direction,
+    pub limit: Option<usize>,
+    /// A room event filter as JSON
+    pub filter: Option<String>,
+}
+
+/// Query parameters of [`get_context()`]
+#[derive(Debug, Deserialize)]
+pub struct ContextQuery {
+    pub limit: Option<usize>,
+    /// A room event filter as JSON
+    pub filter: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MessagesResponse {
+    start: PaginationToken,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<TopologicalToken>,
+    chunk: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    state: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct ContextResponse {
+    start: TopologicalToken,
+    end: TopologicalToken,
+    events_before: Vec<serde_json::Value>,
+    event: serde_json::Value,
+    events_after: Vec<serde_json::Value>,
+    state: Vec<serde_json::Value>,
+}
+
+/// Responds with a page of a room's timeline that the user may see
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidmessages
+#[get("/_matrix/client/v3/rooms/{room_id}/messages")]
+async fn get_messages(
+    auth: AuthenticatedUser,
+    path: web::Path<RoomId>,
+    query: web::Query<MessagesQuery>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let filter = match parse_filter(query.filter.as_deref()) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+
+    match services::history::get_messages(&path, &query, &filter, &auth, pool).await {
+        Ok(MessagesResult::Messages(messages)) =>
+            HttpResponse::Ok().json(MessagesResponse {
+                start: messages.start,
+                end: messages.end,
+                chunk: messages.chunk.iter().map(client_event).collect(),
+                state: messages.state.iter().map(client_event).collect(),
+            }),
+        Ok(MessagesResult::Forbidden) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_FORBIDDEN"),
+                error: String::from("User is not in the room")
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+/// Responds with an event and the events around it
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidcontexteventid
+#[get("/_matrix/client/v3/rooms/{room_id}/context/{event_id}")]
+async fn get_context(
+    auth: AuthenticatedUser,
+    path: web::Path<(RoomId, EventId)>,
+    query: web::Query<ContextQuery>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (room_id, event_id) = path.into_inner();
+    let filter = match parse_filter(query.filter.as_deref()) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+
+    match services::history::get_context(&room_id, &event_id, query.limit, &filter, &auth, pool).await {
+        Ok(ContextResult::Context(context)) =>
+            HttpResponse::Ok().json(ContextResponse {
+                start: context.start,
+                end: context.end,
+                events_before: context.events_before.iter().map(client_event).collect(),
+                event: client_event(&context.event),
+                events_after: context.events_after.iter().map(client_event).collect(),
+                state: context.state.iter().map(client_event).collect(),
+            }),
+        Ok(ContextResult::NotFound) =>
+            not_found(),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+/// Responds with a single event that the user may see
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomideventeventid
+#[get("/_matrix/client/v3/rooms/{room_id}/event/{event_id}")]
+async fn get_event(auth: AuthenticatedUser, path: web::Path<(RoomId, EventId)>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (room_id, event_id) = path.into_inner();
+
+    match services::history::get_event(&room_id, &event_id, &auth, pool).await {
+        Ok(EventResult::Found(event)) =>
+            HttpResponse::Ok().json(client_event(&event)),
+        Ok(EventResult::NotFound) =>
+            not_found(),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+fn parse_filter(param: Option<&str>) -> Result<RoomEventFilter, HttpResponse> {
+    services::filter::parse_room_event_filter(param).map_err(|message| {
+        HttpResponse::BadRequest().json(ErrorResponse {
+            errcode: String::from("M_INVALID_PARAM"),
+            error: message
+        })
+    })
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse {
+        errcode: String::from("M_NOT_FOUND"),
+        error: String::from("Event not found")
+    })
+}
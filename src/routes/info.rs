@@ -1,7 +1,9 @@
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::room_version::{RoomVersion, RoomVersionStability, ROOM_VERSIONS};
 use crate::AppState;
 use actix_web::{get, web, Responder};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 const VERSIONS: [&str; 1] = ["v1.13"];
 
@@ -27,13 +29,70 @@ async fn server_names(data: web::Data<AppState>) -> actix_web::Result<impl Respo
     Ok(web::Json(names))
 }
 
+#[derive(Serialize)]
+struct CapabilitiesResponse {
+    capabilities: Capabilities,
+}
+
+#[derive(Serialize)]
+struct Capabilities {
+    #[serde(rename = "m.change_password")]
+    change_password: BooleanCapability,
+    #[serde(rename = "m.room_versions")]
+    room_versions: RoomVersionsCapability,
+}
+
+#[derive(Serialize)]
+struct BooleanCapability {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct RoomVersionsCapability {
+    default: String,
+    available: BTreeMap<&'static str, RoomVersionStability>,
+}
+
+/// Returns the optional features the server supports, including the room
+/// versions in which rooms can be created
+///
+/// Room versions listed in `unstable_room_versions` are reported as unstable.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3capabilities
+#[get("/_matrix/client/v3/capabilities")]
+async fn capabilities(_auth: AuthenticatedUser, data: web::Data<AppState>) -> actix_web::Result<impl Responder> {
+    let server = &data.config.server;
+    let stability = |version: &RoomVersion| {
+        if server.unstable_room_versions.iter().any(|id| id == version.id) {
+            RoomVersionStability::Unstable
+        } else {
+            version.stability
+        }
+    };
+    let capabilities = Capabilities {
+        change_password: BooleanCapability { enabled: true },
+        room_versions: RoomVersionsCapability {
+            default: server.default_room_version.clone(),
+            available: ROOM_VERSIONS.iter().map(|version| (version.id, stability(version))).collect(),
+        },
+    };
+
+    Ok(web::Json(CapabilitiesResponse { capabilities }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::Config;
+    use crate::middleware;
+    use crate::store::pg;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
     use actix_web::web::Bytes;
     use actix_web::{test, App};
+    use sqlx::PgPool;
     use std::collections::HashSet;
+    use twelf::reexports::serde_json;
 
     #[actix_web::test]
     async fn test_get_versions() {
@@ -46,17 +105,11 @@ mod tests {
         assert_eq!(result, Bytes::from_static(b"{\"versions\":[\"v1.13\"]}"));
     }
 
-    #[actix_web::test]
-    async fn test_get_server_names() {
-        let conf = Config::test();
-        let app = test::init_service(
-            App::new()
-                .app_data(web::Data::new(AppState {
-                    config: conf.clone(),
-                    db_pool: None,
-                }))
-                .service(server_names)
-        ).await;
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_get_server_names(pool: PgPool) {
+        let state = AppState::test(&pool);
+        let conf = state.config.clone();
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(server_names)).await;
         let req = test::TestRequest::get().uri("/.well-known/matrix/client").to_request();
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
@@ -69,4 +122,39 @@ mod tests {
         assert!(result.get("m.homeserver").unwrap().eq(&conf.server.base_url));
         assert!(result.get("m.identity_server").unwrap().eq(&conf.server.identity_server));
     }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_get_capabilities(pool: PgPool) {
+        let (user, _password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
+
+        let mut config = Config::test();
+        config.server.default_room_version = String::from("11");
+        config.server.unstable_room_versions = vec![String::from("2")];
+        let state = AppState { config, ..AppState::test(&pool) };
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(capabilities)
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/_matrix/client/v3/capabilities")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let room_versions = &body["capabilities"]["m.room_versions"];
+        assert_eq!(room_versions["default"], "11");
+        assert_eq!(room_versions["available"].as_object().unwrap().len(), ROOM_VERSIONS.len());
+        assert_eq!(room_versions["available"]["1"], "stable");
+        assert_eq!(room_versions["available"]["2"], "unstable");
+        assert_eq!(body["capabilities"]["m.change_password"]["enabled"], true);
+
+        let req = test::TestRequest::get().uri("/_matrix/client/v3/capabilities").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
 }
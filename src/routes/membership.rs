@@ -0,0 +1,412 @@
+use crate::error::{Error, ErrorResponse};
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{RoomId, UserId};
+use crate::models::stream_token::StreamToken;
+use crate::services::membership::{ForgetResult, MembersResult, MembershipAction, MembershipResult};
+use crate::{services, AppState};
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use twelf::reexports::serde_json;
+
+/// Body of a request to join, knock on or leave a room
+///
+/// Third-party invites are not supported, so `third_party_signed` is ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct JoinOrLeaveRequest {
+    pub reason: Option<String>,
+}
+
+/// Body of a request to invite, kick, ban or unban a user
+#[derive(Debug, Deserialize)]
+pub struct MembershipRequest {
+    pub user_id: UserId,
+    pub reason: Option<String>,
+}
+
+/// Query parameters of [`get_members()`]
+#[derive(Debug, Deserialize)]
+pub struct MembersQuery {
+    pub at: Option<StreamToken>,
+    pub membership: Option<String>,
+    pub not_membership: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JoinSuccess {
+    room_id: RoomId,
+}
+
+#[derive(Serialize)]
+struct JoinedRoomsResponse {
+    joined_rooms: Vec<RoomId>,
+}
+
+#[derive(Serialize)]
+struct MembersResponse {
+    chunk: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct JoinedMembersResponse {
+    joined: BTreeMap<String, RoomMember>,
+}
+
+#[derive(Serialize)]
+struct RoomMember {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+}
+
+/// Joins a room by its ID or one of its aliases
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3joinroomidoralias
+#[post("/_matrix/client/v3/join/{room_id_or_alias}")]
+async fn join(
+    auth: AuthenticatedUser,
+    path: web::Path<String>,
+    join_request: Option<web::Json<JoinOrLeaveRequest>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let reason = join_request.and_then(|r| r.into_inner().reason);
+
+    membership_response(services::membership::join_room(&path, reason, &auth, &data.config, &data.signing_keys, pool).await)
+}
+
+/// Joins a room by its ID
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidjoin
+#[post("/_matrix/client/v3/rooms/{room_id}/join")]
+async fn join_by_room_id(
+    auth: AuthenticatedUser,
+    path: web::Path<String>,
+    join_request: Option<web::Json<JoinOrLeaveRequest>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let reason = join_request.and_then(|r| r.into_inner().reason);
+
+    if !path.starts_with('!') {
+        return membership_response(Ok(MembershipResult::InvalidRoomIdOrAlias));
+    }
+    membership_response(services::membership::join_room(&path, reason, &auth, &data.config, &data.signing_keys, pool).await)
+}
+
+/// Knocks on a room by its ID or one of its aliases
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3knockroomidoralias
+#[post("/_matrix/client/v3/knock/{room_id_or_alias}")]
+async fn knock(
+    auth: AuthenticatedUser,
+    path: web::Path<String>,
+    knock_request: Option<web::Json<JoinOrLeaveRequest>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let reason = knock_request.and_then(|r| r.into_inner().reason);
+
+    membership_response(services::membership::knock_room(&path, reason, &auth, &data.config, &data.signing_keys, pool).await)
+}
+
+/// Leaves a room, rejects an invite to it or rescinds a knock on it
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidleave
+#[post("/_matrix/client/v3/rooms/{room_id}/leave")]
+async fn leave(
+    auth: AuthenticatedUser,
+    path: web::Path<RoomId>,
+    leave_request: Option<web::Json<JoinOrLeaveRequest>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let reason = leave_request.and_then(|r| r.into_inner().reason);
+
+    empty_membership_response(services::membership::leave_room(&path, reason, &auth, &data.config, &data.signing_keys, pool).await)
+}
+
+/// Invites a user to a room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidinvite
+#[post("/_matrix/client/v3/rooms/{room_id}/invite")]
+async fn invite(
+    auth: AuthenticatedUser,
+    path: web::Path<RoomId>,
+    membership_request: web::Json<MembershipRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    change_membership(auth, &path, MembershipAction::Invite, &membership_request, &data).await
+}
+
+/// Removes a user from a room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidkick
+#[post("/_matrix/client/v3/rooms/{room_id}/kick")]
+async fn kick(
+    auth: AuthenticatedUser,
+    path: web::Path<RoomId>,
+    membership_request: web::Json<MembershipRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    change_membership(auth, &path, MembershipAction::Kick, &membership_request, &data).await
+}
+
+/// Bans a user from a room, removing them from it if they're in it
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidban
+#[post("/_matrix/client/v3/rooms/{room_id}/ban")]
+async fn ban(
+    auth: AuthenticatedUser,
+    path: web::Path<RoomId>,
+    membership_request: web::Json<MembershipRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    change_membership(auth, &path, MembershipAction::Ban, &membership_request, &data).await
+}
+
+/// Unbans a user from a room, so that they may be invited or join again
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidunban
+#[post("/_matrix/client/v3/rooms/{room_id}/unban")]
+async fn unban(
+    auth: AuthenticatedUser,
+    path: web::Path<RoomId>,
+    membership_request: web::Json<MembershipRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    change_membership(auth, &path, MembershipAction::Unban, &membership_request, &data).await
+}
+
+async fn change_membership(
+    auth: AuthenticatedUser,
+    room_id: &RoomId,
+    action: MembershipAction,
+    membership_request: &MembershipRequest,
+    data: &AppState
+) -> HttpResponse {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    empty_membership_response(
+        services::membership::change_membership(room_id, action, membership_request, &auth, &data.config, &data.signing_keys, pool).await
+    )
+}
+
+/// Forgets a room that the user has left
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidforget
+#[post("/_matrix/client/v3/rooms/{room_id}/forget")]
+async fn forget(auth: AuthenticatedUser, path: web::Path<RoomId>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::membership::forget_room(&path, &auth, pool).await {
+        Ok(ForgetResult::Forgotten) =>
+            HttpResponse::Ok().json(serde_json::json!({})),
+        Ok(ForgetResult::StillInRoom) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_UNKNOWN"),
+                error: String::from("User must leave the room before forgetting it")
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+/// Responds with the IDs of the rooms the user has joined
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3joined_rooms
+#[get("/_matrix/client/v3/joined_rooms")]
+async fn joined_rooms(auth: AuthenticatedUser, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::membership::joined_rooms(&auth, pool).await {
+        Ok(joined_rooms) => HttpResponse::Ok().json(JoinedRoomsResponse { joined_rooms }),
+        Err(err) => err.error_response(),
+    }
+}
+
+/// Responds with the `m.room.member` events of a room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidmembers
+#[get("/_matrix/client/v3/rooms/{room_id}/members")]
+async fn get_members(
+    auth: AuthenticatedUser,
+    path: web::Path<RoomId>,
+    query: web::Query<MembersQuery>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::membership::get_members(&path, &query, &auth, pool).await {
+        Ok(MembersResult::Members(members)) =>
+            HttpResponse::Ok().json(MembersResponse {
+                chunk: members.iter().map(services::events::client_event).collect(),
+            }),
+        Ok(MembersResult::Forbidden) =>
+            forbidden("User is not in the room"),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+/// Responds with the display names and avatars of the users who have joined a
+/// room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidjoined_members
+#[get("/_matrix/client/v3/rooms/{room_id}/joined_members")]
+async fn get_joined_members(auth: AuthenticatedUser, path: web::Path<RoomId>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::membership::get_joined_members(&path, &auth, pool).await {
+        Ok(MembersResult::Members(members)) => {
+            let joined = members.into_iter()
+                .filter_map(|e| {
+                    let member = RoomMember {
+                        avatar_url: e.content["avatar_url"].as_str().map(String::from),
+                        display_name: e.content["displayname"].as_str().map(String::from),
+                    };
+                    Some((e.state_key?, member))
+                })
+                .collect();
+            HttpResponse::Ok().json(JoinedMembersResponse { joined })
+        }
+        Ok(MembersResult::Forbidden) =>
+            forbidden("User is not in the room"),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+fn membership_response(result: Result<MembershipResult, Error>) -> HttpResponse {
+    match result {
+        Ok(MembershipResult::Sent(room_id)) =>
+            HttpResponse::Ok().json(JoinSuccess { room_id }),
+        Ok(MembershipResult::RoomNotFound) =>
+            HttpResponse::NotFound().json(ErrorResponse {
+                errcode: String::from("M_NOT_FOUND"),
+                error: String::from("Room not found")
+            }),
+        Ok(MembershipResult::InvalidRoomIdOrAlias) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: String::from("Invalid room ID or alias")
+            }),
+        Ok(MembershipResult::Forbidden(reason)) =>
+            forbidden(&reason),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+/// Responds like [`membership_response()`], but with an empty object on
+/// success
+fn empty_membership_response(result: Result<MembershipResult, Error>) -> HttpResponse {
+    match result {
+        Ok(MembershipResult::Sent(_)) => HttpResponse::Ok().json(serde_json::json!({})),
+        result => membership_response(result),
+    }
+}
+
+fn forbidden(reason: &str) -> HttpResponse {
+    HttpResponse::Forbidden().json(ErrorResponse {
+        errcode: String::from("M_FORBIDDEN"),
+        error: reason.to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::middleware;
+    use crate::routes::rooms::create_room;
+    use crate::store::pg;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use sqlx::PgPool;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_join_and_leave(pool: PgPool) {
+        let (alice, _password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, alice_jwt) = pg::auth::tests::create_test_session(alice.id, 0, &pool).await;
+        let (bob, _password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, bob_jwt) = pg::auth::tests::create_test_session(bob.id, 0, &pool).await;
+
+        let config = Config::test();
+        let server_name = config.server.server_name.clone();
+        let state = AppState { config, ..AppState::test(&pool) };
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(create_room)
+                .service(join)
+                .service(leave)
+                .service(forget)
+                .service(joined_rooms)
+                .service(get_joined_members)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/createRoom")
+            .append_header(("Authorization", format!("Bearer {}", alice_jwt)))
+            .set_json(serde_json::json!({ "preset": "public_chat", "room_alias_name": "lobby" }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let room_id = body["room_id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/_matrix/client/v3/join/%23lobby:{}", server_name))
+            .append_header(("Authorization", format!("Bearer {}", bob_jwt)))
+            .set_json(serde_json::json!({ "reason": "Hello" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["room_id"], room_id.as_str());
+
+        let req = test::TestRequest::get()
+            .uri("/_matrix/client/v3/joined_rooms")
+            .append_header(("Authorization", format!("Bearer {}", bob_jwt)))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, serde_json::json!({ "joined_rooms": [room_id] }));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/joined_members", room_id))
+            .append_header(("Authorization", format!("Bearer {}", bob_jwt)))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["joined"].as_object().unwrap().len(), 2);
+
+        // Bob must leave before forgetting the room.
+        let req = test::TestRequest::post()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/forget", room_id))
+            .append_header(("Authorization", format!("Bearer {}", bob_jwt)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/leave", room_id))
+            .append_header(("Authorization", format!("Bearer {}", bob_jwt)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/joined_members", room_id))
+            .append_header(("Authorization", format!("Bearer {}", bob_jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/forget", room_id))
+            .append_header(("Authorization", format!("Bearer {}", bob_jwt)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
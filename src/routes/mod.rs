@@ -1,3 +1,13 @@
+pub mod account;
+pub mod account_data;
 pub mod auth;
+pub mod filter;
+pub mod history;
 pub mod info;
+pub mod membership;
+pub mod receipts;
 pub mod rooms;
+pub mod sliding_sync;
+pub mod sync;
+pub mod to_device;
+pub mod typing;
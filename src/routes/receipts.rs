@@ -0,0 +1,50 @@
+use crate::error::ErrorResponse;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{EventId, RoomId};
+use crate::services::receipts::ReceiptResult;
+use crate::{services, AppState};
+use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use twelf::reexports::serde_json;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReceiptRequest {
+    pub thread_id: Option<String>,
+}
+
+/// Records that the user has read up to and including an event
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidreceiptreceipttypeeventid
+#[post("/_matrix/client/v3/rooms/{room_id}/receipt/{receipt_type}/{event_id}")]
+async fn send_receipt(
+    auth: AuthenticatedUser,
+    path: web::Path<(RoomId, String, EventId)>,
+    receipt_request: Option<web::Json<ReceiptRequest>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (room_id, receipt_type, event_id) = path.into_inner();
+    let thread_id = receipt_request.and_then(|r| r.into_inner().thread_id);
+
+    match services::receipts::send_receipt(&room_id, &receipt_type, &event_id, thread_id.as_deref(), &auth, pool).await {
+        Ok(ReceiptResult::Sent) =>
+            HttpResponse::Ok().json(serde_json::json!({})),
+        Ok(ReceiptResult::InvalidReceiptType) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: String::from("Invalid receipt type")
+            }),
+        Ok(ReceiptResult::EventNotFound) =>
+            HttpResponse::NotFound().json(ErrorResponse {
+                errcode: String::from("M_NOT_FOUND"),
+                error: String::from("Event not found")
+            }),
+        Ok(ReceiptResult::Forbidden) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_FORBIDDEN"),
+                error: String::from("User is not in the room")
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
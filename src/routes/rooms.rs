@@ -1,58 +1,321 @@
+use crate::error::ErrorResponse;
 use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::error::Error;
+use crate::models::ids::{EventId, RoomId, UserId};
+use crate::services::rooms::{ClientEvent, CreateRoomResult, SendEventResult};
 use crate::{services, AppState};
-use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+use actix_web::{post, put, web, HttpResponse, Responder, ResponseError};
 use serde::{Deserialize, Serialize};
 use twelf::reexports::serde_json;
 
 #[derive(Debug, Deserialize)]
 pub struct CreateRoomRequest {
-    creation_content: serde_json::Value,
-    initial_state: Option<StateEvent>,
-    invite: Vec<String>,
-    invite_3pid: Option<Invite3Pid>,
-    is_direct: Option<bool>,
-    name: Option<String>,
-    // power_level_content_override: Option<String>, // TODO
-    preset: Option<String>,
-    room_alias_name: Option<String>,
-    room_version: Option<String>,
-    topic: Option<String>,
-    visibility: Option<String>,
+    pub creation_content: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    pub initial_state: Vec<StateEvent>,
+    #[serde(default)]
+    pub invite: Vec<UserId>,
+    /// Third-party invites, which are rejected because the server doesn't
+    /// support identity servers
+    #[serde(default)]
+    pub invite_3pid: Vec<serde_json::Value>,
+    pub is_direct: Option<bool>,
+    pub name: Option<String>,
+    pub power_level_content_override: Option<serde_json::Map<String, serde_json::Value>>,
+    pub preset: Option<RoomPreset>,
+    pub room_alias_name: Option<String>,
+    pub room_version: Option<String>,
+    pub topic: Option<String>,
+    pub visibility: Option<RoomVisibility>,
 }
 
+/// A state event in the `initial_state` of a [`CreateRoomRequest`]
 #[derive(Debug, Deserialize)]
 pub struct StateEvent {
-    content: String,
-    r#type: String,
-    state_key: Option<String>,
+    pub content: serde_json::Value,
+    pub r#type: String,
+    #[serde(default)]
+    pub state_key: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Invite3Pid {
-    address: String,
-    id_access_token: String,
-    id_server: String,
-    medium: String,
+/// Sets the join rules, history visibility and guest access of a new room
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomPreset {
+    PrivateChat,
+    PublicChat,
+    TrustedPrivateChat,
+}
+
+/// Whether a new room is published in the room directory
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomVisibility {
+    Public,
+    Private,
 }
 
 #[derive(Debug, Serialize)]
 struct CreateRoomSuccess {
-    room_id: String,
+    room_id: RoomId,
 }
 
+/// Creates a room with the requested initial state and invites
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3createroom
 #[post("/_matrix/client/v3/createRoom")]
 async fn create_room(
     auth: AuthenticatedUser,
     creation_request: web::Json<CreateRoomRequest>,
-    state: web::Data<AppState>
+    data: web::Data<AppState>
 ) -> impl Responder {
-    match services::rooms::create_room(creation_request.into_inner(), auth.user_id, state.as_ref()).await {
-        Ok(room_id) =>
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::rooms::create_room(&creation_request, &auth, &data.config, &data.signing_keys, pool).await {
+        Ok(CreateRoomResult::Created(room_id)) =>
             HttpResponse::Ok().json(CreateRoomSuccess { room_id }),
+        Ok(CreateRoomResult::GuestAccessForbidden) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_GUEST_ACCESS_FORBIDDEN"),
+                error: String::from("Guests cannot create rooms")
+            }),
+        Ok(CreateRoomResult::UnsupportedRoomVersion) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_UNSUPPORTED_ROOM_VERSION"),
+                error: String::from("Room version is not supported")
+            }),
+        Ok(CreateRoomResult::InvalidRoomAlias) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: String::from("Invalid room alias")
+            }),
+        Ok(CreateRoomResult::RoomAliasInUse) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_ROOM_IN_USE"),
+                error: String::from("Room alias is already in use")
+            }),
+        Ok(CreateRoomResult::ThreepidInviteUnsupported) =>
+            HttpResponse::NotFound().json(ErrorResponse {
+                errcode: String::from("M_UNRECOGNIZED"),
+                error: String::from("Third-party invites are not supported")
+            }),
+        Ok(CreateRoomResult::InvalidInitialState) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: String::from("Initial state cannot include m.room.create or m.room.member events")
+            }),
+        Ok(CreateRoomResult::Rejected(rejection)) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: rejection.to_string()
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SendEventSuccess {
+    event_id: EventId,
+}
+
+/// Sends a message event to a room
+///
+/// Retries with the same `txn_id` from the same device return the event sent
+/// by the first request rather than sending another.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid
+#[put("/_matrix/client/v3/rooms/{room_id}/send/{event_type}/{txn_id}")]
+async fn send_message(
+    auth: AuthenticatedUser,
+    path: web::Path<(RoomId, String, String)>,
+    content: web::Json<serde_json::Map<String, serde_json::Value>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (room_id, event_type, txn_id) = path.into_inner();
+    let event = ClientEvent {
+        event_type,
+        state_key: None,
+        content: serde_json::Value::Object(content.into_inner()),
+    };
+
+    send_event_response(
+        services::rooms::send_event(&room_id, event, Some(&txn_id), &auth, &data.config, &data.signing_keys, pool).await
+    )
+}
+
+/// Sends a state event to a room; the state key may be empty
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3roomsroomidstateeventtypestatekey
+#[put("/_matrix/client/v3/rooms/{room_id}/state/{event_type}/{state_key:.*}")]
+async fn set_state(
+    auth: AuthenticatedUser,
+    path: web::Path<(RoomId, String, String)>,
+    content: web::Json<serde_json::Map<String, serde_json::Value>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (room_id, event_type, state_key) = path.into_inner();
+    let event = ClientEvent {
+        event_type,
+        state_key: Some(state_key),
+        content: serde_json::Value::Object(content.into_inner()),
+    };
+
+    send_event_response(
+        services::rooms::send_event(&room_id, event, None, &auth, &data.config, &data.signing_keys, pool).await
+    )
+}
+
+fn send_event_response(result: Result<SendEventResult, Error>) -> HttpResponse {
+    match result {
+        Ok(SendEventResult::Sent(event_id)) =>
+            HttpResponse::Ok().json(SendEventSuccess { event_id }),
+        Ok(SendEventResult::RoomNotFound) =>
+            HttpResponse::NotFound().json(ErrorResponse {
+                errcode: String::from("M_NOT_FOUND"),
+                error: String::from("Room not found")
+            }),
+        Ok(SendEventResult::Forbidden(rejection)) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_FORBIDDEN"),
+                error: rejection.to_string()
+            }),
         Err(err) =>
             err.error_response(),
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::middleware;
+    use crate::store::pg;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use sqlx::PgPool;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room(pool: PgPool) {
+        let (user, _password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
+
+        let state = AppState::test(&pool);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(create_room)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/createRoom")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({ "name": "Lobby", "preset": "public_chat", "room_alias_name": "lobby" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let room_id: RoomId = body["room_id"].as_str().unwrap().parse().unwrap();
+        assert!(pg::rooms::get_room(&room_id, &pool).await.unwrap().is_some());
+
+        // The alias is now taken.
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/createRoom")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({ "room_alias_name": "lobby" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["errcode"], "M_ROOM_IN_USE");
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/createRoom")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({
+                "invite_3pid": [{ "medium": "email", "address": "carol@example.com", "id_server": "id.example.com", "id_access_token": "abc" }]
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["errcode"], "M_UNRECOGNIZED");
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room_without_authentication(pool: PgPool) {
+        let state = AppState::test(&pool);
+        let app = test::init_service(App::new().app_data(web::Data::new(state)).service(create_room)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/createRoom")
+            .set_json(serde_json::json!({}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_send_message_and_set_state(pool: PgPool) {
+        let (user, _password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
+
+        let state = AppState::test(&pool);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(create_room)
+                .service(send_message)
+                .service(set_state)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/createRoom")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({}))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let room_id = body["room_id"].as_str().unwrap().to_string();
+
+        let send = || test::TestRequest::put()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/send/m.room.message/txn1", room_id))
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({ "msgtype": "m.text", "body": "hi" }))
+            .to_request();
+        let resp = test::call_service(&app, send()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let event_id = body["event_id"].as_str().unwrap().to_string();
+
+        // Retrying the transaction doesn't send another event.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, send()).await;
+        assert_eq!(body["event_id"], event_id);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/state/m.room.topic/", room_id))
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({ "topic": "Say hello" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let room_id: RoomId = room_id.parse().unwrap();
+        let topic = pg::events::get_current_state_event(&room_id, "m.room.topic", "", &pool).await.unwrap().unwrap();
+        assert_eq!(topic.content["topic"], "Say hello");
+
+        // The message content must be a JSON object.
+        let req = test::TestRequest::put()
+            .uri(&format!("/_matrix/client/v3/rooms/{}/send/m.room.message/txn2", room_id))
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!(["hi"]))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::error::ErrorResponse;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::RoomId;
+use crate::services::sliding_sync::SlidingSyncResult;
+use crate::{services, AppState};
+use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Query parameters of [`sliding_sync()`]
+#[derive(Debug, Default, Deserialize)]
+pub struct SlidingSyncQuery {
+    /// The `pos` of the connection's previous response
+    pub pos: Option<String>,
+    /// Milliseconds to wait for something new if there's nothing yet
+    pub timeout: Option<u64>,
+}
+
+/// What a client wants from a sliding sync request, none of which carries
+/// over to the connection's next request
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SlidingSyncRequest {
+    /// Distinguishes concurrent connections of the same device
+    pub conn_id: Option<String>,
+    pub txn_id: Option<String>,
+    pub lists: HashMap<String, SlidingSyncList>,
+    pub room_subscriptions: HashMap<RoomId, RoomSubscription>,
+    pub extensions: Extensions,
+}
+
+/// What to send about each room that's included
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct RoomSubscription {
+    /// `[event_type, state_key]` pairs, where either may be `*`, and the
+    /// state key may be `$ME` for the user or `$LAZY` for the members who
+    /// sent events in the timeline
+    pub required_state: Vec<(String, String)>,
+    pub timeline_limit: usize,
+}
+
+/// A list of the user's rooms, most recently active first, of which the rooms
+/// in `ranges` are included
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SlidingSyncList {
+    /// Inclusive `[start, end]` indexes into the list
+    pub ranges: Vec<(usize, usize)>,
+    #[serde(flatten)]
+    pub room: RoomSubscription,
+    pub filters: ListFilters,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ListFilters {
+    /// Whether rooms must (`true`) or mustn't (`false`) be direct messages
+    pub is_dm: Option<bool>,
+    /// Whether rooms must (`true`) or mustn't (`false`) be invites
+    pub is_invite: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Extensions {
+    pub to_device: ToDeviceExtension,
+    pub e2ee: E2eeExtension,
+    pub account_data: RoomsExtension,
+    pub receipts: RoomsExtension,
+    pub typing: RoomsExtension,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ToDeviceExtension {
+    pub enabled: Option<bool>,
+    /// The `next_batch` of the previous to-device response, which
+    /// acknowledges the messages in it
+    pub since: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct E2eeExtension {
+    pub enabled: Option<bool>,
+}
+
+/// An extension that applies to some of the included rooms
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RoomsExtension {
+    pub enabled: Option<bool>,
+    /// Names of the lists whose rooms to cover, or `*` for all; all if absent
+    pub lists: Option<Vec<String>>,
+    /// IDs of the subscribed rooms to cover, or `*` for all; all if absent
+    pub rooms: Option<Vec<String>>,
+}
+
+/// Responds with the requested windows of the user's room lists, what's new
+/// in those rooms since the connection's previous response, and the data of
+/// the enabled extensions, waiting for something new if there's nothing yet
+///
+/// See https://github.com/matrix-org/matrix-spec-proposals/pull/4186
+#[post("/_matrix/client/unstable/org.matrix.simplified_msc3575/sync")]
+async fn sliding_sync(
+    auth: AuthenticatedUser,
+    query: web::Query<SlidingSyncQuery>,
+    request: web::Json<SlidingSyncRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+
+    match services::sliding_sync::sliding_sync(&query, &request, &auth, &data.notifier, pool).await {
+        Ok(SlidingSyncResult::Synced(response)) =>
+            HttpResponse::Ok().json(response),
+        Ok(SlidingSyncResult::UnknownPos) =>
+            HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_UNKNOWN_POS"),
+                error: String::from("Unknown position; start a new connection")
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
@@ -0,0 +1,121 @@
+use crate::error::ErrorResponse;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::presence::PresenceState;
+use crate::models::stream_token::StreamToken;
+use crate::{services, AppState};
+use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+
+/// Query parameters of [`sync()`]
+#[derive(Debug, Default, Deserialize)]
+pub struct SyncQuery {
+    /// The ID of a stored filter or a filter as JSON
+    pub filter: Option<String>,
+    pub since: Option<StreamToken>,
+    /// Milliseconds to wait for something new if there's nothing yet
+    pub timeout: Option<u64>,
+    pub full_state: Option<bool>,
+    pub set_presence: Option<PresenceState>,
+}
+
+/// Responds with what's new for the user since the `since` token, waiting for
+/// something new if there's nothing yet
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3sync
+#[get("/_matrix/client/v3/sync")]
+async fn sync(auth: AuthenticatedUser, query: web::Query<SyncQuery>, data: web::Data<AppState>) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let filter = match services::filter::resolve_filter(query.filter.as_deref(), &auth, pool).await {
+        Ok(Ok(filter)) => filter,
+        Ok(Err(message)) =>
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                errcode: String::from("M_INVALID_PARAM"),
+                error: message
+            }),
+        Err(err) => return err.error_response(),
+    };
+
+    match services::sync::sync(&query, &filter, &auth, &data.notifier, pool).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(err) => err.error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+    use crate::routes::rooms::create_room;
+    use crate::store::pg;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use sqlx::PgPool;
+    use twelf::reexports::serde_json;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync(pool: PgPool) {
+        let (user, _password) = pg::auth::tests::create_test_user(&pool).await;
+        let (_session, jwt) = pg::auth::tests::create_test_session(user.id, 0, &pool).await;
+
+        let state = AppState::test(&pool);
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware::auth::authenticator))
+                .app_data(web::Data::new(state))
+                .service(create_room)
+                .service(sync)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/_matrix/client/v3/createRoom")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .set_json(serde_json::json!({ "name": "Lobby" }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let room_id = body["room_id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get()
+            .uri("/_matrix/client/v3/sync?set_presence=offline")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let timeline = &body["rooms"]["join"][&room_id]["timeline"];
+        assert_eq!(timeline["events"][0]["type"], "m.room.create");
+        assert_eq!(timeline["limited"], false);
+        let next_batch = body["next_batch"].as_str().unwrap();
+        assert!(next_batch.starts_with('s'));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/_matrix/client/v3/sync?since={}&timeout=0&set_presence=offline", next_batch))
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["next_batch"], next_batch);
+        assert_eq!(body["rooms"]["join"], serde_json::json!({}));
+
+        // An inline filter limits the timeline.
+        let req = test::TestRequest::get()
+            .uri("/_matrix/client/v3/sync?set_presence=offline&filter=%7B%22room%22%3A%7B%22timeline%22%3A%7B%22limit%22%3A1%7D%7D%7D")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let timeline = &body["rooms"]["join"][&room_id]["timeline"];
+        assert_eq!(timeline["events"].as_array().unwrap().len(), 1);
+        assert_eq!(timeline["limited"], true);
+
+        let req = test::TestRequest::get()
+            .uri("/_matrix/client/v3/sync?filter=42")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get()
+            .uri("/_matrix/client/v3/sync?since=bogus")
+            .append_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
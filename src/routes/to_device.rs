@@ -0,0 +1,33 @@
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::UserId;
+use crate::{services, AppState};
+use actix_web::{put, web, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use twelf::reexports::serde_json;
+
+#[derive(Debug, Deserialize)]
+pub struct ToDeviceRequest {
+    /// Message content by recipient user ID and device ID, where `*` means
+    /// all the user's devices
+    pub messages: HashMap<UserId, HashMap<String, serde_json::Value>>,
+}
+
+/// Sends messages directly to devices rather than to rooms
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3sendtodeviceeventtypetxnid
+#[put("/_matrix/client/v3/sendToDevice/{event_type}/{txn_id}")]
+async fn send_to_device(
+    auth: AuthenticatedUser,
+    path: web::Path<(String, String)>,
+    request: web::Json<ToDeviceRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (event_type, txn_id) = path.into_inner();
+
+    match services::to_device::send_to_device(&event_type, &txn_id, &request, &auth, &data.config, pool).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({})),
+        Err(err) => err.error_response(),
+    }
+}
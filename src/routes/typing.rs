@@ -0,0 +1,41 @@
+use crate::error::ErrorResponse;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{RoomId, UserId};
+use crate::services::typing::TypingResult;
+use crate::{services, AppState};
+use actix_web::{put, web, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+use twelf::reexports::serde_json;
+
+#[derive(Debug, Deserialize)]
+pub struct TypingRequest {
+    pub typing: bool,
+    /// Milliseconds for which the user is typing
+    pub timeout: Option<i64>,
+}
+
+/// Records that the user has started or stopped typing in a room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3roomsroomidtypinguserid
+#[put("/_matrix/client/v3/rooms/{room_id}/typing/{user_id}")]
+async fn set_typing(
+    auth: AuthenticatedUser,
+    path: web::Path<(RoomId, UserId)>,
+    typing_request: web::Json<TypingRequest>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    let pool = data.db_pool.as_ref().unwrap();
+    let (room_id, user_id) = path.into_inner();
+
+    match services::typing::set_typing(&room_id, &user_id, &typing_request, &auth, pool).await {
+        Ok(TypingResult::Set) =>
+            HttpResponse::Ok().json(serde_json::json!({})),
+        Ok(TypingResult::Forbidden(reason)) =>
+            HttpResponse::Forbidden().json(ErrorResponse {
+                errcode: String::from("M_FORBIDDEN"),
+                error: reason
+            }),
+        Err(err) =>
+            err.error_response(),
+    }
+}
@@ -0,0 +1,412 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::routes::account::{EmailTokenRequest, PasswordRequest};
+use crate::services;
+use crate::services::signing::SigningKeys;
+use crate::services::uia::{AuthFlow, UiaContext, UiaResponse, UiaResult};
+use crate::store::pg;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sqlx::PgPool;
+use twelf::reexports::log;
+
+/// Possible results of calling [`request_password_reset_token()`]
+pub enum RequestTokenResult {
+    /// A token was sent, or had already been sent for this `send_attempt`
+    Sent { sid: String },
+    /// The server has no `[email]` config section
+    EmailDisabled,
+    /// The client secret is empty, too long or contains invalid characters
+    InvalidClientSecret,
+    /// No account has the email address
+    ThreepidNotFound,
+}
+
+/// Possible results of calling [`change_password()`]
+pub enum ChangePasswordResult {
+    Changed,
+    /// The client must complete user-interactive authentication before the
+    /// password is changed
+    AuthRequired(UiaResponse),
+    /// The request has no access token, and password reset by email is
+    /// disabled
+    NotAuthenticated,
+    /// The validated email address no longer belongs to an account
+    ThreepidNotFound,
+}
+
+/// Endpoint passed to [`services::uia::authenticate()`] by [`change_password()`]
+const PASSWORD_ENDPOINT: &str = "/_matrix/client/v3/account/password";
+
+/// Path of the link sent by email to validate an address
+const SUBMIT_TOKEN_PATH: &str = "/_spelt/client/email/submit_token";
+
+/// Medium of email address validation sessions
+const EMAIL_MEDIUM: &str = "email";
+
+/// Length of tokens sent to validate an email address
+const EMAIL_TOKEN_LENGTH: usize = 32;
+
+/// Maximum length of a client secret, per the Matrix spec
+const MAX_CLIENT_SECRET_LENGTH: usize = 255;
+
+/// Changes the password of the authenticated user or, for a request without
+/// an access token, of the user whose email address was validated
+///
+/// Authenticated users must complete `m.login.password`. Otherwise, the client
+/// must complete `m.login.email.identity` with a session started by
+/// [`request_password_reset_token()`].
+///
+/// Unless `logout_devices` is `false`, all of the user's other sessions are
+/// logged out.
+pub async fn change_password(
+    password_request: &PasswordRequest,
+    auth: Option<&AuthenticatedUser>,
+    config: &Config,
+    pool: &PgPool
+) -> Result<ChangePasswordResult, Error> {
+    let flows = password_flows(auth.is_some(), config);
+    if flows.is_empty() {
+        return Ok(ChangePasswordResult::NotAuthenticated);
+    }
+
+    let context = UiaContext { user_id: auth.map(|a| a.user_id), config, pool };
+
+    if let UiaResult::Incomplete(uia_response) =
+        services::uia::authenticate(&password_request.auth, &flows, PASSWORD_ENDPOINT, &context).await? {
+        return Ok(ChangePasswordResult::AuthRequired(uia_response));
+    }
+
+    let user_id = match auth {
+        Some(auth) => auth.user_id,
+        None => {
+            // The final stage was m.login.email.identity, so the credentials
+            // refer to a validated session, which is used up here.
+            let creds = password_request.auth.as_ref().and_then(|a| a.threepid_creds.as_ref());
+            let session = match creds {
+                Some(creds) => pg::threepid::get_validated_session(&creds.sid, &creds.client_secret, pool).await?,
+                None => None,
+            };
+            let session = match session {
+                Some(session) => session,
+                None => return Ok(ChangePasswordResult::ThreepidNotFound),
+            };
+            pg::threepid::delete_session(session.id, pool).await?;
+
+            match pg::auth::get_user_by_email(&session.address, pool).await? {
+                Some(user) => user.id,
+                None => return Ok(ChangePasswordResult::ThreepidNotFound),
+            }
+        }
+    };
+
+    pg::auth::set_password(user_id, &password_request.new_password, pool).await?;
+
+    if password_request.logout_devices.unwrap_or(true) {
+        pg::auth::log_out_all(user_id, auth.map(|a| a.session_id), pool).await?;
+    }
+
+    Ok(ChangePasswordResult::Changed)
+}
+
+/// Returns the flows that authenticate a password change
+///
+/// There are none for unauthenticated requests if the server can't send email.
+pub fn password_flows(authenticated: bool, config: &Config) -> Vec<AuthFlow> {
+    if authenticated {
+        vec![AuthFlow::new(&["m.login.password"])]
+    } else if config.email.is_some() {
+        vec![AuthFlow::new(&["m.login.email.identity"])]
+    } else {
+        vec![]
+    }
+}
+
+/// Emails a link to validate an address for resetting the password of the
+/// account that has it
+///
+/// Repeating a request with the same `client_secret` and `send_attempt`
+/// returns the same session without sending another email.
+pub async fn request_password_reset_token(
+    token_request: &EmailTokenRequest,
+    config: &Config,
+    pool: &PgPool
+) -> Result<RequestTokenResult, Error> {
+    let email_config = match config.email {
+        Some(ref email_config) => email_config,
+        None => return Ok(RequestTokenResult::EmailDisabled),
+    };
+
+    if !is_valid_client_secret(&token_request.client_secret) {
+        return Ok(RequestTokenResult::InvalidClientSecret);
+    }
+
+    if pg::auth::get_user_by_email(&token_request.email, pool).await?.is_none() {
+        return Ok(RequestTokenResult::ThreepidNotFound);
+    }
+
+    let session = match pg::threepid::find_session(&token_request.client_secret, EMAIL_MEDIUM, &token_request.email, pool).await? {
+        Some(session) if token_request.send_attempt <= session.send_attempt =>
+            return Ok(RequestTokenResult::Sent { sid: session.sid }),
+        Some(session) => {
+            pg::threepid::update_send_attempt(session.id, token_request.send_attempt, pool).await?;
+            session
+        }
+        None =>
+            pg::threepid::create_session(
+                &uuid::Uuid::new_v4().simple().to_string(),
+                &token_request.client_secret,
+                EMAIL_MEDIUM,
+                &token_request.email,
+                &generate_email_token(),
+                token_request.send_attempt,
+                pool
+            ).await?,
+    };
+
+    // The sid, token and client secret contain only URL-safe characters.
+    let link = format!(
+        "{}{}?sid={}&client_secret={}&token={}",
+        config.server.base_url.trim_end_matches('/'),
+        SUBMIT_TOKEN_PATH,
+        session.sid,
+        session.client_secret,
+        session.token,
+    );
+    let body = format!(
+        "A request was made to reset the password of your account on {}.\n\n\
+        To continue, open the link below, then return to your client.\n\n\
+        {}\n\n\
+        If you didn't make this request, you can ignore this email.\n",
+        config.server.base_url,
+        link,
+    );
+
+    services::email::send(email_config, &session.address, "Reset your password", body).await?;
+
+    Ok(RequestTokenResult::Sent { sid: session.sid })
+}
+
+/// Validates the session `sid` if `token` matches the one that was emailed;
+/// returns `Ok(true)` on success
+pub async fn submit_email_token(sid: &String, client_secret: &String, token: &String, pool: &PgPool) -> Result<bool, Error> {
+    pg::threepid::validate_session(sid, client_secret, token, pool).await
+}
+
+/// Deactivates the account of `user_id`, logging it out everywhere, removing
+/// its password, email address and 3PID validation sessions, and leaving all
+/// its rooms
+///
+/// The user has no profile data besides their membership events, which are
+/// replaced by leaves that carry none. If `erase` is set, the user's events are
+/// hidden from anyone who joins a room after they leave it.
+///
+/// The account is deactivated before any room is left. If leaving a room
+/// fails with an error, that error is returned and the rooms not yet left keep
+/// the user's membership; since the user can no longer authenticate and the
+/// account is already deactivated, nothing retries them.
+pub async fn deactivate(user_id: i64, erase: bool, config: &Config, keys: &SigningKeys, pool: &PgPool) -> Result<(), Error> {
+    let user = pg::auth::get_user(user_id, pool).await?
+        .ok_or_else(|| Error::Db(format!("User {} not found", user_id)))?;
+
+    if pg::auth::deactivate_user(user_id, erase, pool).await? {
+        log::info!("Deactivated user {} (erase: {})", user_id, erase);
+    }
+
+    // Leave after deactivating so that the user can't rejoin in between.
+    let matrix_user_id = services::auth::user_id(&user.name, config)?;
+    services::membership::leave_all_rooms(&matrix_user_id, config, keys, pool).await
+}
+
+/// Returns `true` if `client_secret` is a valid client secret
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3accountpasswordemailrequesttoken
+fn is_valid_client_secret(client_secret: &str) -> bool {
+    !client_secret.is_empty()
+        && client_secret.len() <= MAX_CLIENT_SECRET_LENGTH
+        && client_secret.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'=' | b'_' | b'-'))
+}
+
+/// Returns a random token to send to an email address
+fn generate_email_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(EMAIL_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EmailConfig;
+    use crate::routes::auth::{AuthenticationData, ThreepidCredentials};
+    use crate::services::rooms::tests::{authenticated_user, create};
+    use crate::store::pg::auth::tests::{create_test_session, create_test_user};
+    use twelf::reexports::serde_json::json;
+
+    fn email_config() -> Config {
+        let mut config = Config::test();
+        config.email = Some(EmailConfig {
+            smtp_url: String::from("smtp://localhost:1"),
+            from: String::from("noreply@example.org"),
+        });
+        config
+    }
+
+    fn email_auth(session: Option<String>, sid: &str, client_secret: &str) -> Option<AuthenticationData> {
+        Some(AuthenticationData {
+            session,
+            r#type: Some(String::from("m.login.email.identity")),
+            token: None,
+            identifier: None,
+            password: None,
+            threepid_creds: Some(ThreepidCredentials {
+                sid: sid.to_string(),
+                client_secret: client_secret.to_string(),
+            }),
+        })
+    }
+
+    #[test]
+    fn test_is_valid_client_secret() {
+        assert!(is_valid_client_secret("aB3.=_-"));
+        assert!(!is_valid_client_secret(""));
+        assert!(!is_valid_client_secret("a b"));
+        assert!(!is_valid_client_secret(&"a".repeat(MAX_CLIENT_SECRET_LENGTH + 1)));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_request_password_reset_token_with_unknown_email(pool: PgPool) {
+        let request = EmailTokenRequest {
+            client_secret: String::from("secret"),
+            email: String::from("nobody@example.org"),
+            send_attempt: 1,
+        };
+
+        let result = request_password_reset_token(&request, &email_config(), &pool).await.unwrap();
+        assert!(matches!(result, RequestTokenResult::ThreepidNotFound));
+
+        let result = request_password_reset_token(&request, &Config::test(), &pool).await.unwrap();
+        assert!(matches!(result, RequestTokenResult::EmailDisabled));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_request_password_reset_token_repeated(pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let email = user.email.unwrap();
+        let session = pg::threepid::create_session(
+            &String::from("sid"), &String::from("secret"), EMAIL_MEDIUM, &email, &String::from("token"), 1, &pool
+        ).await.unwrap();
+        let request = EmailTokenRequest {
+            client_secret: session.client_secret,
+            email,
+            send_attempt: 1,
+        };
+
+        // The email was already sent for this attempt, so none is sent now.
+        match request_password_reset_token(&request, &email_config(), &pool).await.unwrap() {
+            RequestTokenResult::Sent { sid } => assert_eq!(sid, session.sid),
+            _ => panic!("Expected token to be sent"),
+        }
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_change_password(pool: PgPool) {
+        let (user, password) = create_test_user(&pool).await;
+        let (session, _jwt) = create_test_session(user.id, 0, &pool).await;
+        let (other_session, _jwt) = create_test_session(user.id, 0, &pool).await;
+        let config = Config::test();
+        let auth = AuthenticatedUser {
+            user_id: user.id,
+            session_id: session.id,
+            device_id: session.device_identifier.clone(),
+            matrix_user_id: services::auth::user_id(&user.name, &config).unwrap(),
+            is_guest: false,
+        };
+
+        let mut request = PasswordRequest { auth: None, logout_devices: None, new_password: String::from("new password") };
+        let uia_session = match change_password(&request, Some(&auth), &config, &pool).await.unwrap() {
+            ChangePasswordResult::AuthRequired(uia_response) => uia_response.session,
+            _ => panic!("Expected UIA to be required"),
+        };
+
+        request.auth = Some(AuthenticationData {
+            session: Some(uia_session),
+            r#type: Some(String::from("m.login.password")),
+            token: None,
+            identifier: None,
+            password: Some(password.clone()),
+            threepid_creds: None,
+        });
+        assert!(matches!(change_password(&request, Some(&auth), &config, &pool).await.unwrap(), ChangePasswordResult::Changed));
+
+        assert_eq!(pg::auth::validate_user_and_password(&user.name, &password, &pool).await.unwrap(), None);
+        assert_eq!(pg::auth::validate_user_and_password(&user.name, &request.new_password, &pool).await.unwrap(), Some(user.id));
+        assert!(pg::auth::validate_session(&session.uuid.to_string(), &pool).await.is_ok());
+        assert!(pg::auth::validate_session(&other_session.uuid.to_string(), &pool).await.is_err());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_reset_password_by_email(pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let (session, _jwt) = create_test_session(user.id, 0, &pool).await;
+        let config = email_config();
+        let threepid_session = pg::threepid::create_session(
+            &String::from("sid"), &String::from("secret"), EMAIL_MEDIUM, &user.email.unwrap(), &String::from("token"), 1, &pool
+        ).await.unwrap();
+
+        let mut request = PasswordRequest { auth: None, logout_devices: None, new_password: String::from("new password") };
+        let uia_session = match change_password(&request, None, &config, &pool).await.unwrap() {
+            ChangePasswordResult::AuthRequired(uia_response) => uia_response.session,
+            _ => panic!("Expected UIA to be required"),
+        };
+
+        // Not yet validated
+        request.auth = email_auth(Some(uia_session.clone()), "sid", "secret");
+        assert!(matches!(change_password(&request, None, &config, &pool).await.unwrap(), ChangePasswordResult::AuthRequired(_)));
+
+        assert!(!submit_email_token(&threepid_session.sid, &threepid_session.client_secret, &String::from("wrong"), &pool).await.unwrap());
+        assert!(submit_email_token(&threepid_session.sid, &threepid_session.client_secret, &threepid_session.token, &pool).await.unwrap());
+        assert!(matches!(change_password(&request, None, &config, &pool).await.unwrap(), ChangePasswordResult::Changed));
+
+        assert_eq!(pg::auth::validate_user_and_password(&user.name, &request.new_password, &pool).await.unwrap(), Some(user.id));
+        assert!(pg::auth::validate_session(&session.uuid.to_string(), &pool).await.is_err());
+
+        // The validation session is used up.
+        assert!(pg::threepid::get_validated_session(&threepid_session.sid, &threepid_session.client_secret, &pool).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_change_password_without_authentication(pool: PgPool) {
+        let request = PasswordRequest { auth: None, logout_devices: None, new_password: String::from("new password") };
+
+        assert!(matches!(change_password(&request, None, &Config::test(), &pool).await.unwrap(), ChangePasswordResult::NotAuthenticated));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_deactivate(pool: PgPool) {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let joined_room_id = create(json!({ "preset": "public_chat" }), &bob, &config, &pool).await[0].room_id.clone();
+        services::membership::join_room(joined_room_id.as_str(), None, &alice, &config, &keys, &pool).await.unwrap();
+        let invited_room_id = create(json!({ "invite": [alice.matrix_user_id] }), &bob, &config, &pool).await[0].room_id.clone();
+
+        let email = pg::auth::get_user(alice.user_id, &pool).await.unwrap().unwrap().email.unwrap();
+        pg::threepid::create_session(
+            &String::from("sid"), &String::from("secret"), EMAIL_MEDIUM, &email, &String::from("token"), 1, &pool
+        ).await.unwrap();
+
+        deactivate(alice.user_id, false, &config, &keys, &pool).await.unwrap();
+
+        for room_id in [&joined_room_id, &invited_room_id] {
+            let membership = services::membership::membership(room_id, &alice.matrix_user_id, &pool).await.unwrap();
+            assert_eq!(membership.as_deref(), Some("leave"));
+        }
+        assert!(pg::threepid::find_session(&String::from("secret"), EMAIL_MEDIUM, &email, &pool).await.unwrap().is_none());
+        assert!(pg::auth::get_user(alice.user_id, &pool).await.unwrap().unwrap().is_deactivated);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{RoomId, UserId};
+use crate::store::pg;
+use sqlx::PgPool;
+use twelf::reexports::serde_json::Value;
+
+/// Account data types that the server manages, which clients can't set
+const SERVER_MANAGED_TYPES: [&str; 2] = ["m.fully_read", "m.push_rules"];
+
+/// Possible results of setting or getting account data
+pub enum AccountDataResult {
+    Set,
+    Content(Value),
+    NotFound,
+    /// The account data belongs to another user
+    Forbidden,
+    /// Clients can't set account data of this type
+    ServerManaged,
+}
+
+/// Sets the account data of `event_type` for `user_id`, either global or for
+/// the room with ID `room_id`
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3useruseridaccount_datatype
+pub async fn set_account_data(
+    user_id: &UserId,
+    room_id: Option<&RoomId>,
+    event_type: &str,
+    content: &Value,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<AccountDataResult, Error> {
+    if *user_id != auth.matrix_user_id {
+        return Ok(AccountDataResult::Forbidden);
+    }
+    if SERVER_MANAGED_TYPES.contains(&event_type) {
+        return Ok(AccountDataResult::ServerManaged);
+    }
+
+    pg::account_data::set_account_data(user_id, room_id, event_type, content, pool).await?;
+
+    Ok(AccountDataResult::Set)
+}
+
+/// Returns the account data of `event_type` for `user_id`, either global or
+/// for the room with ID `room_id`
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3useruseridaccount_datatype
+pub async fn get_account_data(
+    user_id: &UserId,
+    room_id: Option<&RoomId>,
+    event_type: &str,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<AccountDataResult, Error> {
+    if *user_id != auth.matrix_user_id {
+        return Ok(AccountDataResult::Forbidden);
+    }
+
+    Ok(match pg::account_data::get_account_data(user_id, room_id, event_type, pool).await? {
+        Some(content) => AccountDataResult::Content(content),
+        None => AccountDataResult::NotFound,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::services::rooms::tests::{authenticated_user, create};
+    use twelf::reexports::serde_json::json;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_set_and_get_account_data(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({}), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let user_id = &alice.matrix_user_id;
+        let content = json!({ "theme": "dark" });
+
+        let result = set_account_data(user_id, None, "org.example.settings", &content, &alice, &pool).await.unwrap();
+        assert!(matches!(result, AccountDataResult::Set));
+        let result = get_account_data(user_id, None, "org.example.settings", &alice, &pool).await.unwrap();
+        assert!(matches!(result, AccountDataResult::Content(c) if c == content));
+
+        // Room account data is separate from global account data.
+        let result = get_account_data(user_id, Some(room_id), "org.example.settings", &alice, &pool).await.unwrap();
+        assert!(matches!(result, AccountDataResult::NotFound));
+        set_account_data(user_id, Some(room_id), "org.example.settings", &json!({}), &alice, &pool).await.unwrap();
+        let result = get_account_data(user_id, Some(room_id), "org.example.settings", &alice, &pool).await.unwrap();
+        assert!(matches!(result, AccountDataResult::Content(c) if c == json!({})));
+
+        let result = get_account_data(user_id, None, "org.example.settings", &bob, &pool).await.unwrap();
+        assert!(matches!(result, AccountDataResult::Forbidden));
+        let result = set_account_data(user_id, Some(room_id), "m.fully_read", &json!({}), &alice, &pool).await.unwrap();
+        assert!(matches!(result, AccountDataResult::ServerManaged));
+    }
+}
@@ -1,39 +1,87 @@
+use crate::config::{Config, RegistrationMode};
 use crate::error::Error;
-use crate::routes::auth::LoginRequest;
+use crate::routes::auth::{LoginRequest, RegisterRequest};
 use crate::services;
+use crate::services::jwt::KeyManager;
+use crate::services::uia::{AuthFlow, UiaContext, UiaResponse, UiaResult};
 use crate::store::pg;
-use crate::models::auth::Session;
+use crate::store::pg::auth::RefreshTokenRotation;
+use crate::models::auth::{Session, User};
+use crate::models::ids::{self, DeviceId, UserId};
 use actix_web::web;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+use twelf::reexports::log;
 
 /// Possible results of calling [`log_in()`]
 pub enum LoginResult {
-    LoggedIn { access_token: String, device_id: String, username: String, expires_in_ms: u64 },
+    LoggedIn { user_id: UserId, tokens: SessionTokens },
     CredentialsInvalid,
     NotSupported,
     BadRequest,
 }
 
+/// Possible results of calling [`register()`]
+pub enum RegisterResult {
+    /// The account was created; `tokens` is `None` if the client passed
+    /// `inhibit_login`
+    Registered { user_id: UserId, tokens: Option<SessionTokens> },
+    /// The client must complete user-interactive authentication before the
+    /// account is created
+    AuthRequired(UiaResponse),
+    RegistrationDisabled,
+    UsernameInUse,
+    UsernameInvalid,
+    MissingPassword,
+}
+
+/// Possible results of calling [`refresh()`]
+pub enum RefreshResult {
+    Refreshed { access_token: String, refresh_token: String, expires_in_ms: u64 },
+    TokenInvalid,
+}
+
+/// Tokens issued to a client for a new `Session`
+///
+/// `refresh_token` and `expires_in_ms` are only set if the client opted in to
+/// refresh tokens; otherwise the access token does not expire.
+pub struct SessionTokens {
+    pub access_token: String,
+    pub device_id: DeviceId,
+    pub refresh_token: Option<String>,
+    pub expires_in_ms: Option<u64>,
+}
+
+/// The endpoint to which registration UIA sessions are bound
+const REGISTER_ENDPOINT: &str = "/_matrix/client/v3/register";
+
+/// Length of generated refresh tokens
+const REFRESH_TOKEN_LENGTH: usize = 40;
+
 /// Authenticates a user and, if successful, returns a `LoginResult` with a token
 ///
 /// If the request specifies a `device_id`, any previous `Session` for that device
 /// will be deleted. If the request does not specify a `device_id`, one will be
 /// generated.
 ///
-pub async fn log_in(login_request: web::Json<LoginRequest>, pool: &PgPool) -> Result<LoginResult, Error> {
+/// The user may be given as a bare username or as a full user ID on this
+/// server.
+pub async fn log_in(login_request: web::Json<LoginRequest>, config: &Config, keys: &KeyManager, pool: &PgPool) -> Result<LoginResult, Error> {
     // Check authentication type
     if login_request.r#type != "m.login.password" {
         return Ok(LoginResult::NotSupported);
     }
 
     // Authenticate User
-    // The `username` should be `address` or `user` or (if `identifier.type` is
+    // The `user` should be `address` or `user` or (if `identifier.type` is
     // "m.id.user") `identifier.user`.
-    let username = match login_request.address {
-        Some(ref address) => address.clone(),
+    let user = match login_request.address {
+        Some(ref address) => address,
         None =>
             match login_request.user {
-                Some(ref user) => user.clone(),
+                Some(ref user) => user,
                 None =>
                     match login_request.identifier {
                         Some(ref identifier) => {
@@ -41,7 +89,7 @@ pub async fn log_in(login_request: web::Json<LoginRequest>, pool: &PgPool) -> Re
                                 return Ok(LoginResult::BadRequest);
                             }
                             match identifier.user {
-                                Some(ref user) => user.clone(),
+                                Some(ref user) => user,
                                 None => return Ok(LoginResult::BadRequest),
                             }
                         }
@@ -54,9 +102,14 @@ pub async fn log_in(login_request: web::Json<LoginRequest>, pool: &PgPool) -> Re
         return Ok(LoginResult::BadRequest);
     }
 
+    let username = match localpart(user, config) {
+        Some(username) => username,
+        None => return Ok(LoginResult::CredentialsInvalid),
+    };
+
     let user_id_opt = pg::auth::validate_user_and_password(
         &username,
-        &login_request.password.as_ref().unwrap(),
+        login_request.password.as_ref().unwrap(),
         pool
     ).await?;
 
@@ -64,38 +117,280 @@ pub async fn log_in(login_request: web::Json<LoginRequest>, pool: &PgPool) -> Re
         return Ok(LoginResult::CredentialsInvalid);
     }
 
-    // Delete existing Session if `device_id` specified; else generate a
-    // `device_id`.
-    let user_id = user_id_opt.unwrap();
-    let device_id = match login_request.device_id.clone() {
+    let tokens = create_session(
+        user_id_opt.unwrap(),
+        &login_request.device_id,
+        &login_request.initial_device_display_name,
+        login_request.refresh_token.unwrap_or(false),
+        keys,
+        pool
+    ).await?;
+
+    Ok(LoginResult::LoggedIn { user_id: user_id(&username, config)?, tokens })
+}
+
+/// Creates a `Session` for a user and returns `Ok(tokens)`
+///
+/// If `device_id` is specified, any previous `Session` for that device will be
+/// deleted. If `device_id` is `None`, one will be generated.
+///
+/// If `use_refresh_token` is set, the access token expires and a refresh token
+/// is issued; otherwise the access token is valid until the `Session` is
+/// logged out.
+pub async fn create_session(
+    user_id: i64,
+    device_id: &Option<DeviceId>,
+    device_name: &Option<String>,
+    use_refresh_token: bool,
+    keys: &KeyManager,
+    pool: &PgPool
+) -> Result<SessionTokens, Error> {
+    let device_id = match device_id.clone() {
         Some(device_id) => {
             pg::auth::invalidate_existing_sessions(user_id, &device_id, pool).await?;
             device_id
         },
         None =>
-            uuid::Uuid::new_v4().to_string()
+            DeviceId::generate()
     };
 
-    // Create Session and JWT
-    let session = pg::auth::create_session(
-        user_id,
-        &device_id,
-        &login_request.initial_device_display_name,
+    let session = pg::auth::create_session(user_id, &device_id, device_name, pool).await?;
+
+    if !use_refresh_token {
+        let access_token = services::jwt::create_jwt(keys, &session, 0, false)?;
+        return Ok(SessionTokens { access_token, device_id, refresh_token: None, expires_in_ms: None });
+    }
+
+    let access_token = services::jwt::create_jwt(keys, &session, 0, true)?;
+    let refresh_token = generate_refresh_token();
+    pg::auth::create_refresh_token(session.id, &hash_refresh_token(&refresh_token), pool).await?;
+
+    Ok(SessionTokens {
+        access_token,
+        device_id,
+        refresh_token: Some(refresh_token),
+        expires_in_ms: Some(keys.ttl_seconds() * 1000),
+    })
+}
+
+/// Exchanges a refresh token for a new access token and refresh token
+///
+/// See [`pg::auth::rotate_refresh_token()`] for when a refresh token may be
+/// used.
+pub async fn refresh(refresh_token: &String, keys: &KeyManager, pool: &PgPool) -> Result<RefreshResult, Error> {
+    let new_refresh_token = generate_refresh_token();
+
+    let session = match pg::auth::rotate_refresh_token(
+        &hash_refresh_token(refresh_token),
+        &hash_refresh_token(&new_refresh_token),
+        pool
+    ).await? {
+        RefreshTokenRotation::Rotated(session) => session,
+        RefreshTokenRotation::Reused => {
+            log::warn!("Refresh token reused; session logged out");
+            return Ok(RefreshResult::TokenInvalid);
+        }
+        RefreshTokenRotation::Unknown => return Ok(RefreshResult::TokenInvalid),
+    };
+
+    let access_token = services::jwt::create_jwt(keys, &session, 0, true)?;
+
+    Ok(RefreshResult::Refreshed {
+        access_token,
+        refresh_token: new_refresh_token,
+        expires_in_ms: keys.ttl_seconds() * 1000,
+    })
+}
+
+/// Returns a random opaque refresh token
+fn generate_refresh_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(REFRESH_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Returns the hex-encoded SHA-256 hash of a refresh token, which is what the
+/// database stores
+fn hash_refresh_token(refresh_token: &String) -> String {
+    format!("{:x}", Sha256::digest(refresh_token.as_bytes()))
+}
+
+/// Registers a new account and, unless the client passed `inhibit_login`,
+/// logs it in
+///
+/// Whether registration is allowed depends on the configured
+/// [`RegistrationMode`]. In `open` mode, guest accounts are also allowed. See
+/// [`registration_flows()`] for the authentication stages required.
+///
+/// For guest accounts, everything in the request other than
+/// `initial_device_display_name` is ignored, and the username is generated.
+pub async fn register(
+    register_request: web::Json<RegisterRequest>,
+    is_guest: bool,
+    config: &Config,
+    keys: &KeyManager,
+    pool: &PgPool
+) -> Result<RegisterResult, Error> {
+    let mode = config.server.registration;
+    if mode == RegistrationMode::Disabled || (is_guest && mode != RegistrationMode::Open) {
+        return Ok(RegisterResult::RegistrationDisabled);
+    }
+
+    if is_guest {
+        let username = format!("guest-{}", uuid::Uuid::new_v4().simple());
+        let user = pg::auth::register_user(&username, &None, true, pool).await?
+            .ok_or_else(|| Error::Db(format!("Guest user {} already exists", username)))?;
+        let tokens = create_session(
+            user.id,
+            &None,
+            &register_request.initial_device_display_name,
+            register_request.refresh_token.unwrap_or(false),
+            keys,
+            pool
+        ).await?;
+
+        return Ok(RegisterResult::Registered { user_id: user_id(&username, config)?, tokens: Some(tokens) });
+    }
+
+    // Check the username before requiring authentication so that the client
+    // can report a conflict without the user completing any stages.
+    let username = match register_request.username {
+        Some(ref username) => {
+            if !ids::is_valid_localpart(username) || UserId::new(username, &config.server.server_name).is_err() {
+                return Ok(RegisterResult::UsernameInvalid);
+            }
+            if pg::auth::user_exists(username, pool).await? {
+                return Ok(RegisterResult::UsernameInUse);
+            }
+            username.clone()
+        }
+        None => uuid::Uuid::new_v4().simple().to_string(),
+    };
+
+    if register_request.password.is_none() {
+        return Ok(RegisterResult::MissingPassword);
+    }
+
+    let context = UiaContext { user_id: None, config, pool };
+    let flows = registration_flows(config);
+
+    if let UiaResult::Incomplete(uia_response) =
+        services::uia::authenticate(&register_request.auth, &flows, REGISTER_ENDPOINT, &context).await? {
+        return Ok(RegisterResult::AuthRequired(uia_response));
+    }
+
+    // Create User, settling the registration token reserved by the final UIA
+    // stage, if any.
+    let token = match mode {
+        RegistrationMode::Token => register_request.auth.as_ref().and_then(|a| a.token.clone()),
+        _ => None,
+    };
+
+    let user = match pg::auth::register_user(&username, &register_request.password, false, pool).await {
+        Ok(Some(user)) => {
+            if let Some(ref token) = token {
+                pg::registration_tokens::complete_use(token, pool).await?;
+            }
+            user
+        }
+        // Another registration took the username after it was checked above.
+        Ok(None) => {
+            if let Some(ref token) = token {
+                pg::registration_tokens::release_use(token, pool).await?;
+            }
+            return Ok(RegisterResult::UsernameInUse);
+        }
+        Err(err) => {
+            if let Some(ref token) = token {
+                pg::registration_tokens::release_use(token, pool).await?;
+            }
+            return Err(err);
+        }
+    };
+
+    // Create Session unless inhibited
+    let user_id = user_id(&username, config)?;
+    if register_request.inhibit_login.unwrap_or(false) {
+        return Ok(RegisterResult::Registered { user_id, tokens: None });
+    }
+
+    let tokens = create_session(
+        user.id,
+        &register_request.device_id,
+        &register_request.initial_device_display_name,
+        register_request.refresh_token.unwrap_or(false),
+        keys,
         pool
     ).await?;
 
-    let access_token = services::jwt::create_jwt(&session.uuid.to_string(), 0)?;
+    Ok(RegisterResult::Registered { user_id, tokens: Some(tokens) })
+}
+
+/// Returns the user-interactive authentication flows required to register
+///
+/// If terms of service are configured, the `m.login.terms` stage is required.
+/// In `token` mode, the `m.login.registration_token` stage is required; it is
+/// always the last stage, so a token is only reserved by a request that goes on
+/// to create the account. If neither applies, clients complete the
+/// `m.login.dummy` stage.
+pub fn registration_flows(config: &Config) -> Vec<AuthFlow> {
+    let mut stages = vec![];
 
-    Ok(LoginResult::LoggedIn { access_token, device_id, username, expires_in_ms: services::jwt::JWT_TTL_SECONDS })
+    if config.terms.is_some() {
+        stages.push("m.login.terms");
+    }
+    if config.server.registration == RegistrationMode::Token {
+        stages.push("m.login.registration_token");
+    }
+    if stages.is_empty() {
+        stages.push("m.login.dummy");
+    }
+
+    vec![AuthFlow::new(&stages)]
 }
 
-/// Validates the JWT signature and validates the referenced `Session`; returns
-/// `Ok(s: Session)` on success
-pub async fn authorize_request(access_token: &String, pool: &PgPool) -> Result<Session, Error> {
-    let claims = services::jwt::validate_jwt(&access_token)?;
-    let uuid = claims.sub;
+/// Validates the JWT signature and claims and validates the referenced
+/// `Session`; returns `Ok((session, user))` on success
+///
+/// The token's `device_id` claim must match the `Session`.
+pub async fn authorize_request(access_token: &str, keys: &KeyManager, pool: &PgPool) -> Result<(Session, User), Error> {
+    let claims = services::jwt::validate_jwt(keys, access_token)?;
+    let session = pg::auth::validate_session(&claims.sub, pool).await?;
 
-    pg::auth::validate_session(&uuid, pool).await
+    if session.device_identifier.as_str() != claims.device_id {
+        return Err(Error::Auth(String::from("Token does not match session device")));
+    }
+
+    match pg::auth::get_user(session.user_id, pool).await? {
+        Some(user) => Ok((session, user)),
+        None => Err(Error::Auth(String::from("Session user not found"))),
+    }
+}
+
+/// Returns the Matrix user ID of the local user named `localpart`, e.g.
+/// `@alice:chat.spelt.io`, or `Err(Error::InvalidUsername)` if it doesn't form
+/// a valid one
+pub fn user_id(localpart: &str, config: &Config) -> Result<UserId, Error> {
+    UserId::new(localpart, &config.server.server_name)
+        .map_err(|err| Error::InvalidUsername(err.to_string()))
+}
+
+/// Returns the username of a local user given as either a bare username or a
+/// full user ID like `@alice:chat.spelt.io`, or `None` if `user` is a user ID
+/// on another server
+pub fn localpart(user: &str, config: &Config) -> Option<String> {
+    if !user.starts_with('@') {
+        return Some(user.to_string());
+    }
+
+    let user_id: UserId = user.parse().ok()?;
+    if user_id.server_name() != config.server.server_name.as_str() {
+        return None;
+    }
+
+    Some(user_id.localpart().to_string())
 }
 
 /// Logs out a user, invalidating any held access tokens
@@ -105,7 +400,7 @@ pub async fn log_out(session_id: i64, pool: &PgPool) -> Result<(), Error> {
 
 /// Logs out a user from all devices, invalidating any held access tokens
 pub async fn log_out_all(user_id: i64, pool: &PgPool) -> Result<(), Error> {
-    pg::auth::log_out_all(user_id, pool).await
+    pg::auth::log_out_all(user_id, None, pool).await
 }
 
 #[cfg(test)]
@@ -114,6 +409,8 @@ mod tests {
     use crate::services;
     use crate::store::pg::auth::invalidate_existing_sessions;
     use crate::store::pg::auth::tests::{create_test_session, create_test_user};
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
     use sqlx::PgPool;
 
     #[sqlx::test(migrations = "migrations/pg")]
@@ -121,7 +418,7 @@ mod tests {
         let (user, _password) = create_test_user(&pool).await;
         let (_session, jwt) = create_test_session(user.id, 0, &pool).await;
 
-        let result = authorize_request(&jwt, &pool).await;
+        let result = authorize_request(&jwt, &KeyManager::test(), &pool).await;
         assert!(result.is_ok());
     }
 
@@ -131,26 +428,74 @@ mod tests {
         let (session, jwt) = create_test_session(user.id, 0, &pool).await;
         let _ = invalidate_existing_sessions(user.id, &session.device_identifier, &pool).await;
 
-        let result = authorize_request(&jwt, &pool).await;
+        let result = authorize_request(&jwt, &KeyManager::test(), &pool).await;
         assert!(result.is_err());
     }
 
     #[sqlx::test(migrations = "migrations/pg")]
     async fn test_authorize_request_with_expired (pool: PgPool) {
         let (user, _password) = create_test_user(&pool).await;
-        let (_session, jwt) = create_test_session(user.id, -(services::jwt::JWT_TTL_SECONDS as i64) - 300, &pool).await;
+        let (_session, jwt) = create_test_session(user.id, -(KeyManager::test().ttl_seconds() as i64) - 300, &pool).await;
 
-        let result = authorize_request(&jwt, &pool).await;
+        let result = authorize_request(&jwt, &KeyManager::test(), &pool).await;
         assert!(result.is_err());
     }
 
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authorize_request_with_other_device (pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let (mut session, _jwt) = create_test_session(user.id, 0, &pool).await;
+        session.device_identifier = "OTHERDEVICE".parse().unwrap();
+        let keys = KeyManager::test();
+        let jwt = services::jwt::create_jwt(&keys, &session, 0, true).unwrap();
+
+        let result = authorize_request(&jwt, &keys, &pool).await;
+        assert!(result.is_err());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authorize_request_with_deactivated_user (pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let (_session, jwt) = create_test_session(user.id, 0, &pool).await;
+        sqlx::query("UPDATE users SET is_deactivated = TRUE WHERE id = $1").bind(user.id).execute(&pool).await.unwrap();
+
+        let result = authorize_request(&jwt, &KeyManager::test(), &pool).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_user_id() {
+        let mut config = Config::test();
+
+        config.server.server_name = "chat.spelt.io".parse().unwrap();
+        assert_eq!(user_id("alice", &config).unwrap().as_str(), "@alice:chat.spelt.io");
+
+        config.server.server_name = "localhost:8080".parse().unwrap();
+        assert_eq!(user_id("alice", &config).unwrap().as_str(), "@alice:localhost:8080");
+
+        let err = user_id("al ice", &config).unwrap_err();
+        assert!(matches!(err, Error::InvalidUsername(_)));
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_localpart() {
+        let mut config = Config::test();
+        config.server.server_name = "chat.spelt.io".parse().unwrap();
+
+        assert_eq!(localpart("alice", &config).as_deref(), Some("alice"));
+        assert_eq!(localpart("@alice:chat.spelt.io", &config).as_deref(), Some("alice"));
+        assert_eq!(localpart("@alice:example.org", &config), None);
+        assert_eq!(localpart("@alice", &config), None);
+    }
+
     #[sqlx::test(migrations = "migrations/pg")]
     async fn test_log_out(pool: PgPool) {
         let (user, _password) = create_test_user(&pool).await;
         let (session, jwt) = create_test_session(user.id, 0, &pool).await;
-        assert!(authorize_request(&jwt, &pool).await.is_ok());
+        assert!(authorize_request(&jwt, &KeyManager::test(), &pool).await.is_ok());
 
         assert!(log_out(session.id, &pool).await.is_ok());
-        assert!(authorize_request(&jwt, &pool).await.is_err());
+        assert!(authorize_request(&jwt, &KeyManager::test(), &pool).await.is_err());
     }
 }
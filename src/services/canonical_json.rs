@@ -0,0 +1,204 @@
+//! Canonical JSON and the hashes derived from it
+//!
+//! Servers hash and sign events in canonical JSON, so that every server gets
+//! the same bytes for the same event: object keys are sorted by codepoint,
+//! there is no insignificant whitespace, and numbers must be integers that are
+//! exactly representable as doubles.
+//!
+//! See https://spec.matrix.org/v1.13/appendices/#canonical-json
+
+use crate::error::Error;
+use crate::models::ids::{EventId, IdError};
+use crate::models::room_version::{EventIdFormat, RoomVersion};
+use crate::services;
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use twelf::reexports::serde_json::Value;
+
+/// Largest integer that canonical JSON allows, 2^53 - 1
+const MAX_SAFE_INTEGER: i64 = 9_007_199_254_740_991;
+
+/// Returns `value` as canonical JSON
+///
+/// Fails if `value` contains a number that isn't an integer in the range
+/// `[-(2^53)+1, (2^53)-1]`.
+pub fn to_canonical_json(value: &Value) -> Result<String, Error> {
+    let mut json = String::new();
+    write_value(value, &mut json)?;
+
+    Ok(json)
+}
+
+fn write_value(value: &Value, json: &mut String) -> Result<(), Error> {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) =>
+            json.push_str(&value.to_string()),
+        Value::Number(number) => {
+            // Numbers with exponents, like 1e10, parse as floats.
+            let integer = number.as_i64().or_else(|| {
+                number.as_f64()
+                    .filter(|f| f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER as f64)
+                    .map(|f| f as i64)
+            });
+            match integer {
+                Some(n) if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(&n) => json.push_str(&n.to_string()),
+                _ => return Err(Error::BadJson(format!("Number out of canonical JSON range: {}", number))),
+            }
+        }
+        Value::Array(values) => {
+            json.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_value(value, json)?;
+            }
+            json.push(']');
+        }
+        Value::Object(map) => {
+            // Rust compares strings by bytes, which for UTF-8 is codepoint
+            // order.
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+
+            json.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                json.push_str(&Value::String(key.clone()).to_string());
+                json.push(':');
+                write_value(value, json)?;
+            }
+            json.push('}');
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the unpadded base64 SHA-256 content hash of an event, which goes in
+/// its `hashes.sha256`
+///
+/// See https://spec.matrix.org/v1.13/server-server-api/#calculating-the-content-hash-for-an-event
+pub fn content_hash(event: &Value) -> Result<String, Error> {
+    let mut event = event.clone();
+    if let Some(map) = event.as_object_mut() {
+        map.remove("unsigned");
+        map.remove("signatures");
+        map.remove("hashes");
+    }
+
+    Ok(STANDARD_NO_PAD.encode(Sha256::digest(to_canonical_json(&event)?.as_bytes())))
+}
+
+/// Returns the SHA-256 reference hash of an event, which is the hash of its
+/// redacted form without signatures
+///
+/// See https://spec.matrix.org/v1.13/server-server-api/#calculating-the-reference-hash-for-an-event
+pub fn reference_hash(event: &Value, room_version: &RoomVersion) -> Result<[u8; 32], Error> {
+    let mut event = services::events::redact(event, room_version);
+    if let Some(map) = event.as_object_mut() {
+        map.remove("unsigned");
+        map.remove("signatures");
+    }
+
+    Ok(Sha256::digest(to_canonical_json(&event)?.as_bytes()).into())
+}
+
+/// Returns the ID of an event in room version 3 or later, which is `$`
+/// followed by its base64 reference hash
+///
+/// Room version 3 uses standard base64; later versions use URL-safe base64.
+pub fn event_id(event: &Value, room_version: &RoomVersion) -> Result<EventId, Error> {
+    let hash = reference_hash(event, room_version)?;
+    let encoded = match room_version.event_id_format {
+        EventIdFormat::Base64Hash => STANDARD_NO_PAD.encode(hash),
+        EventIdFormat::ServerChosen | EventIdFormat::UrlSafeBase64Hash => URL_SAFE_NO_PAD.encode(hash),
+    };
+
+    format!("${}", encoded).parse().map_err(|err: IdError| Error::BadJson(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::room_version;
+    use twelf::reexports::serde_json;
+    use twelf::reexports::serde_json::json;
+
+    fn canonicalize(json: &str) -> Result<String, Error> {
+        to_canonical_json(&serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn test_to_canonical_json() {
+        // Examples from the spec appendix
+        assert_eq!(canonicalize("{}").unwrap(), "{}");
+        assert_eq!(
+            canonicalize(r#"{"one": 1, "two": "Two"}"#).unwrap(),
+            r#"{"one":1,"two":"Two"}"#
+        );
+        assert_eq!(
+            canonicalize(r#"{"b": "2", "a": "1"}"#).unwrap(),
+            r#"{"a":"1","b":"2"}"#
+        );
+        assert_eq!(
+            canonicalize(r#"{"auth": {"success": true, "mxid": "@john.doe:example.com", "profile": {"display_name": "John Doe", "three_pids": [{"medium": "email", "address": "john.doe@example.org"}, {"medium": "msisdn", "address": "123456789"}]}}}"#).unwrap(),
+            r#"{"auth":{"mxid":"@john.doe:example.com","profile":{"display_name":"John Doe","three_pids":[{"address":"john.doe@example.org","medium":"email"},{"address":"123456789","medium":"msisdn"}]},"success":true}}"#
+        );
+        assert_eq!(canonicalize(r#"{"a": "日本語"}"#).unwrap(), r#"{"a":"日本語"}"#);
+        assert_eq!(canonicalize(r#"{"本": 2, "日": 1}"#).unwrap(), r#"{"日":1,"本":2}"#);
+        assert_eq!(canonicalize(r#"{"a": "日"}"#).unwrap(), r#"{"a":"日"}"#);
+        assert_eq!(canonicalize(r#"{"a": null}"#).unwrap(), r#"{"a":null}"#);
+        assert_eq!(canonicalize(r#"{"a": -0, "b": 1e10}"#).unwrap(), r#"{"a":0,"b":10000000000}"#);
+    }
+
+    #[test]
+    fn test_to_canonical_json_with_invalid_numbers() {
+        assert!(to_canonical_json(&json!({ "a": 1.5 })).is_err());
+        assert!(to_canonical_json(&json!({ "a": MAX_SAFE_INTEGER })).is_ok());
+        assert!(to_canonical_json(&json!({ "a": MAX_SAFE_INTEGER + 1 })).is_err());
+        assert!(to_canonical_json(&json!({ "a": -MAX_SAFE_INTEGER - 1 })).is_err());
+        assert!(to_canonical_json(&json!([u64::MAX])).is_err());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let event = json!({ "type": "m.room.message", "content": { "body": "hi" }, "unsigned": { "age": 5 } });
+        let mut with_hashes = event.clone();
+        with_hashes["hashes"] = json!({ "sha256": "abc" });
+        with_hashes["unsigned"] = json!({ "age": 10 });
+
+        assert_eq!(content_hash(&event).unwrap(), content_hash(&with_hashes).unwrap());
+        assert_eq!(
+            content_hash(&event).unwrap(),
+            STANDARD_NO_PAD.encode(Sha256::digest(br#"{"content":{"body":"hi"},"type":"m.room.message"}"#))
+        );
+    }
+
+    #[test]
+    fn test_event_id() {
+        let event = json!({
+            "room_id": "!abc:chat.spelt.io",
+            "sender": "@alice:chat.spelt.io",
+            "type": "m.room.message",
+            "content": { "body": "hi" },
+            "depth": 3,
+            "origin_server_ts": 1000,
+            "prev_events": [],
+            "auth_events": [],
+        });
+
+        let event_id = event_id(&event, room_version::get("10").unwrap()).unwrap();
+        assert!(event_id.as_str().starts_with('$'));
+        assert_eq!(event_id.as_str().len(), 44);
+        assert!(!event_id.as_str().contains(['+', '/']));
+
+        // Content is redacted, so it doesn't affect the reference hash.
+        let mut edited = event.clone();
+        edited["content"] = json!({ "body": "bye" });
+        assert_eq!(super::event_id(&edited, room_version::get("10").unwrap()).unwrap(), event_id);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::config::EmailConfig;
+use crate::error::Error;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+/// Sends a plain-text email using the SMTP server in the `[email]` config
+/// section
+pub async fn send(config: &EmailConfig, to: &str, subject: &str, body: String) -> Result<(), Error> {
+    let from = config.from.parse::<Mailbox>()
+        .map_err(|e| Error::Config(format!("Invalid email sender {}: {}", config.from, e)))?;
+    let to = to.parse::<Mailbox>()
+        .map_err(|e| Error::Io(format!("Invalid email recipient {}: {}", to, e)))?;
+
+    let message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .body(body)
+        .map_err(|e| Error::Io(format!("Failed to build email: {}", e)))?;
+
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)
+        .map_err(|e| Error::Config(format!("Invalid SMTP URL: {}", e)))?
+        .build();
+
+    transport.send(message).await
+        .map_err(|e| Error::Io(format!("Failed to send email: {}", e)))?;
+
+    Ok(())
+}
@@ -0,0 +1,1074 @@
+//! The rules that decide whether an event is allowed in a room
+//!
+//! Events are checked against a state map, which is either the state given by
+//! their auth events or the room state before them. Both are keyed by
+//! `(type, state_key)` and hold the events as JSON, in the format in which
+//! servers exchange them.
+//!
+//! Signatures and hashes are checked before these rules; where the rules
+//! require a signature by a particular server, this only checks that the
+//! signature is present.
+//!
+//! See https://spec.matrix.org/v1.13/rooms/v11/#authorization-rules
+
+use crate::models::ids::{RoomId, UserId};
+use crate::models::room_version::{self, EventIdFormat, RoomVersion};
+use crate::services::events;
+use crate::services::signing;
+use std::collections::HashMap;
+use thiserror::Error;
+use twelf::reexports::serde_json::Value;
+
+/// Events keyed by `(type, state_key)`
+pub type StateMap<'a> = HashMap<(String, String), &'a Value>;
+
+/// Why an event isn't allowed
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Event not allowed: {0}")]
+pub struct Rejection(pub String);
+
+/// Power level of the room creator before there's an `m.room.power_levels`
+/// event
+const CREATOR_POWER_LEVEL: i64 = 100;
+
+/// Keys of `m.room.power_levels` content that hold a single power level
+const POWER_LEVEL_KEYS: [&str; 7] = ["users_default", "events_default", "state_default", "ban", "redact", "kick", "invite"];
+
+macro_rules! reject {
+    ($($arg:tt)*) => {
+        return Err(Rejection(format!($($arg)*)))
+    };
+}
+
+/// Checks the authorization rules of `room_version` for `event` against
+/// `state`
+///
+/// Redactions are only checked here in room versions 1 and 2; in later
+/// versions, any redaction is allowed, and whether it applies is decided
+/// separately once the redacted event is known.
+pub fn authorize(event: &Value, room_version: &RoomVersion, state: &StateMap) -> Result<(), Rejection> {
+    let event_type = event_type(event);
+    let sender = sender(event)?;
+
+    // m.room.create
+    if event_type == "m.room.create" {
+        return authorize_create(event, room_version, &sender);
+    }
+
+    let create = match get(state, "m.room.create", "") {
+        Some(create) => create,
+        None => reject!("No m.room.create event in auth events"),
+    };
+
+    if create["content"]["m.federate"] == Value::Bool(false) {
+        let create_sender = self::sender(create)?;
+        if create_sender.server_name() != sender.server_name() {
+            reject!("Room is not federated");
+        }
+    }
+
+    // m.room.aliases
+    if event_type == "m.room.aliases" && room_version.special_case_aliases_auth {
+        return match event["state_key"].as_str() {
+            Some(state_key) if state_key == sender.server_name() => Ok(()),
+            Some(_) => reject!("Aliases may only be set for the sender's server"),
+            None => reject!("m.room.aliases event has no state key"),
+        };
+    }
+
+    let power_levels = PowerLevels::new(state, create, room_version);
+
+    // m.room.member
+    if event_type == "m.room.member" {
+        return authorize_member(event, room_version, state, &sender, create, &power_levels);
+    }
+
+    if membership(state, sender.as_str()) != "join" {
+        reject!("Sender is not in the room");
+    }
+
+    let sender_level = power_levels.user_level(sender.as_str());
+
+    // m.room.third_party_invite
+    if event_type == "m.room.third_party_invite" {
+        if sender_level < power_levels.level("invite") {
+            reject!("Sender cannot invite users");
+        }
+        return Ok(());
+    }
+
+    let state_key = event["state_key"].as_str();
+    if power_levels.event_level(event_type, state_key.is_some()) > sender_level {
+        reject!("Sender cannot send {} events", event_type);
+    }
+
+    if let Some(state_key) = state_key {
+        if state_key.starts_with('@') && state_key != sender.as_str() {
+            reject!("Sender cannot set state keyed by another user");
+        }
+    }
+
+    // m.room.power_levels
+    if event_type == "m.room.power_levels" {
+        return authorize_power_levels(event, room_version, state, &sender, &power_levels);
+    }
+
+    // m.room.redaction
+    if event_type == "m.room.redaction" && room_version.event_id_format == EventIdFormat::ServerChosen {
+        if sender_level >= power_levels.level("redact") {
+            return Ok(());
+        }
+
+        let event_id_domain = event["event_id"].as_str().and_then(|id| id.split_once(':')).map(|(_, domain)| domain);
+        let redacts_domain = event["redacts"].as_str().and_then(|id| id.split_once(':')).map(|(_, domain)| domain);
+        if event_id_domain.is_some() && event_id_domain == redacts_domain {
+            return Ok(());
+        }
+        reject!("Sender cannot redact events from other servers");
+    }
+
+    Ok(())
+}
+
+/// Returns the power level of `user` in a room with `state`, or 0 if `state`
+/// has no `m.room.create` event
+pub fn user_power_level(user: &str, room_version: &RoomVersion, state: &StateMap) -> i64 {
+    match get(state, "m.room.create", "") {
+        Some(create) => PowerLevels::new(state, create, room_version).user_level(user),
+        None => 0,
+    }
+}
+
+fn authorize_create(event: &Value, room_version: &RoomVersion, sender: &UserId) -> Result<(), Rejection> {
+    if event["prev_events"].as_array().is_some_and(|prev_events| !prev_events.is_empty()) {
+        reject!("m.room.create event has prev events");
+    }
+
+    let room_id: RoomId = match event["room_id"].as_str().and_then(|id| id.parse().ok()) {
+        Some(room_id) => room_id,
+        None => reject!("Invalid room ID"),
+    };
+    if room_id.server_name() != sender.server_name() {
+        reject!("Room ID and sender of m.room.create event are on different servers");
+    }
+
+    if let Some(version) = event["content"].get("room_version") {
+        if version.as_str().and_then(room_version::get).is_none() {
+            reject!("Unsupported room version {}", version);
+        }
+    }
+
+    if !room_version.implicit_room_creator && !event["content"]["creator"].is_string() {
+        reject!("m.room.create event has no creator");
+    }
+
+    Ok(())
+}
+
+fn authorize_member(
+    event: &Value,
+    room_version: &RoomVersion,
+    state: &StateMap,
+    sender: &UserId,
+    create: &Value,
+    power_levels: &PowerLevels
+) -> Result<(), Rejection> {
+    let content = &event["content"];
+    let (target, membership) = match (event["state_key"].as_str(), content["membership"].as_str()) {
+        (Some(target), Some(membership)) => (target, membership),
+        _ => reject!("m.room.member event has no state key or membership"),
+    };
+
+    if room_version.restricted_joins {
+        if let Some(authorising_user) = content.get("join_authorised_via_users_server") {
+            let server_name = authorising_user.as_str()
+                .and_then(|user| user.parse::<UserId>().ok())
+                .map(|user| user.server_name().to_string());
+            let signed = server_name.is_some_and(|server_name| event["signatures"].get(&server_name).is_some());
+            if !signed {
+                reject!("Join is not signed by the server of the authorising user");
+            }
+        }
+    }
+
+    let join_rule = get(state, "m.room.join_rules", "")
+        .and_then(|e| e["content"]["join_rule"].as_str())
+        .unwrap_or("invite");
+    let sender_membership = self::membership(state, sender.as_str());
+    let target_membership = self::membership(state, target);
+    let sender_level = power_levels.user_level(sender.as_str());
+    let target_level = power_levels.user_level(target);
+
+    match membership {
+        "join" => {
+            if is_first_join(event, room_version, create, target)? {
+                return Ok(());
+            }
+            if sender.as_str() != target {
+                reject!("Users cannot join on behalf of others");
+            }
+            if sender_membership == "ban" {
+                reject!("Sender is banned");
+            }
+
+            let invite_only = join_rule == "invite" || (room_version.knocking && join_rule == "knock");
+            let restricted = (room_version.restricted_joins && join_rule == "restricted")
+                || (room_version.knock_restricted && join_rule == "knock_restricted");
+
+            if invite_only {
+                if matches!(sender_membership, "invite" | "join") {
+                    return Ok(());
+                }
+                reject!("Room is invite only");
+            }
+
+            if restricted {
+                if matches!(sender_membership, "invite" | "join") {
+                    return Ok(());
+                }
+
+                let authorising_user = content["join_authorised_via_users_server"].as_str().unwrap_or_default();
+                if self::membership(state, authorising_user) != "join" {
+                    reject!("Authorising user is not in the room");
+                }
+                if power_levels.user_level(authorising_user) < power_levels.level("invite") {
+                    reject!("Authorising user cannot invite users");
+                }
+                return Ok(());
+            }
+
+            if join_rule == "public" {
+                return Ok(());
+            }
+            reject!("Join rule {} does not allow joining", join_rule)
+        }
+        "invite" => {
+            if let Some(third_party_invite) = content.get("third_party_invite") {
+                return authorize_third_party_invite(third_party_invite, state, sender, target, target_membership);
+            }
+
+            if sender_membership != "join" {
+                reject!("Sender is not in the room");
+            }
+            if matches!(target_membership, "join" | "ban") {
+                reject!("Target user is already in the room or banned");
+            }
+            if sender_level < power_levels.level("invite") {
+                reject!("Sender cannot invite users");
+            }
+            Ok(())
+        }
+        "leave" => {
+            if sender.as_str() == target {
+                let can_leave = matches!(sender_membership, "invite" | "join")
+                    || (room_version.knocking && sender_membership == "knock");
+                if can_leave {
+                    return Ok(());
+                }
+                reject!("Sender is not in the room");
+            }
+
+            if sender_membership != "join" {
+                reject!("Sender is not in the room");
+            }
+            if target_membership == "ban" && sender_level < power_levels.level("ban") {
+                reject!("Sender cannot unban users");
+            }
+            if sender_level >= power_levels.level("kick") && target_level < sender_level {
+                return Ok(());
+            }
+            reject!("Sender cannot kick the target user")
+        }
+        "ban" => {
+            if sender_membership != "join" {
+                reject!("Sender is not in the room");
+            }
+            if sender_level >= power_levels.level("ban") && target_level < sender_level {
+                return Ok(());
+            }
+            reject!("Sender cannot ban the target user")
+        }
+        "knock" if room_version.knocking => {
+            let can_knock = join_rule == "knock" || (room_version.knock_restricted && join_rule == "knock_restricted");
+            if !can_knock {
+                reject!("Join rule {} does not allow knocking", join_rule);
+            }
+            if sender.as_str() != target {
+                reject!("Users cannot knock on behalf of others");
+            }
+            if matches!(sender_membership, "ban" | "invite" | "join") {
+                reject!("Sender cannot knock with membership {}", sender_membership);
+            }
+            Ok(())
+        }
+        _ => reject!("Unknown membership {}", membership),
+    }
+}
+
+/// Returns `true` if `event` is the creator's join that directly follows the
+/// `m.room.create` event
+fn is_first_join(event: &Value, room_version: &RoomVersion, create: &Value, target: &str) -> Result<bool, Rejection> {
+    let prev_events = match event["prev_events"].as_array() {
+        Some(prev_events) if prev_events.len() == 1 => prev_events,
+        _ => return Ok(false),
+    };
+    // Room versions 1 and 2 reference events as [event_id, hashes].
+    let prev_event_id = prev_events[0].as_str().or_else(|| prev_events[0][0].as_str());
+
+    let create_event_id = events::event_id(create, room_version).map_err(|err| Rejection(err.to_string()))?;
+    let creator = if room_version.implicit_room_creator {
+        create["sender"].as_str()
+    } else {
+        create["content"]["creator"].as_str()
+    };
+
+    Ok(prev_event_id == Some(create_event_id.as_str()) && creator == Some(target))
+}
+
+fn authorize_third_party_invite(
+    third_party_invite: &Value,
+    state: &StateMap,
+    sender: &UserId,
+    target: &str,
+    target_membership: &str
+) -> Result<(), Rejection> {
+    if target_membership == "ban" {
+        reject!("Target user is banned");
+    }
+
+    let signed = match third_party_invite.get("signed") {
+        Some(signed) => signed,
+        None => reject!("Third-party invite is not signed"),
+    };
+    let (mxid, token) = match (signed["mxid"].as_str(), signed["token"].as_str()) {
+        (Some(mxid), Some(token)) => (mxid, token),
+        _ => reject!("Third-party invite has no mxid or token"),
+    };
+    if mxid != target {
+        reject!("Third-party invite is for another user");
+    }
+
+    let invite = match get(state, "m.room.third_party_invite", token) {
+        Some(invite) => invite,
+        None => reject!("No m.room.third_party_invite event for token"),
+    };
+    if invite["sender"].as_str() != Some(sender.as_str()) {
+        reject!("Third-party invite was sent by another user");
+    }
+
+    let public_keys = invite["content"]["public_key"].as_str().into_iter()
+        .chain(invite["content"]["public_keys"].as_array().into_iter().flatten().filter_map(|k| k["public_key"].as_str()))
+        .filter_map(signing::decode_verify_key)
+        .collect::<Vec<_>>();
+
+    let signatures = signed["signatures"].as_object().into_iter().flatten()
+        .flat_map(|(server_name, keys)| keys.as_object().into_iter().flatten().map(move |(key_id, _)| (server_name, key_id)));
+    for (server_name, key_id) in signatures {
+        let Ok(server_name) = server_name.parse() else {
+            continue;
+        };
+        if public_keys.iter().any(|key| signing::verify_json(signed, &server_name, key_id, key).is_ok()) {
+            return Ok(());
+        }
+    }
+
+    reject!("Third-party invite has no valid signature")
+}
+
+fn authorize_power_levels(
+    event: &Value,
+    room_version: &RoomVersion,
+    state: &StateMap,
+    sender: &UserId,
+    power_levels: &PowerLevels
+) -> Result<(), Rejection> {
+    let content = &event["content"];
+
+    if room_version.integer_power_levels {
+        for key in POWER_LEVEL_KEYS {
+            if content.get(key).is_some_and(|value| !value.is_i64()) {
+                reject!("Power level {} is not an integer", key);
+            }
+        }
+        for key in ["events", "notifications"] {
+            if let Some(levels) = content.get(key) {
+                let valid = levels.as_object().is_some_and(|levels| levels.values().all(Value::is_i64));
+                if !valid {
+                    reject!("Power levels {} are not integers", key);
+                }
+            }
+        }
+    }
+
+    if let Some(users) = content.get("users") {
+        let valid = users.as_object().is_some_and(|users| users.iter().all(|(user, level)| {
+            user.parse::<UserId>().is_ok() && power_level(level, room_version).is_some()
+        }));
+        if !valid {
+            reject!("Power levels of users are not integers keyed by user ID");
+        }
+    }
+
+    let previous = match get(state, "m.room.power_levels", "") {
+        Some(previous) => &previous["content"],
+        None => return Ok(()),
+    };
+
+    let sender_level = power_levels.user_level(sender.as_str());
+    let level = |content: &Value, key: &str| content.get(key).and_then(|level| power_level(level, room_version));
+
+    for key in POWER_LEVEL_KEYS {
+        let (old, new) = (level(previous, key), level(content, key));
+        if old != new && (old > Some(sender_level) || new > Some(sender_level)) {
+            reject!("Sender cannot change power level {}", key);
+        }
+    }
+
+    let mut maps = vec!["events"];
+    if room_version.limit_notifications_power_levels {
+        maps.push("notifications");
+    }
+    for map in maps {
+        for key in changed_keys(&previous[map], &content[map]) {
+            let (old, new) = (level(&previous[map], key), level(&content[map], key));
+            if old > Some(sender_level) || new > Some(sender_level) {
+                reject!("Sender cannot change power level of {} {}", map, key);
+            }
+        }
+    }
+
+    for user in changed_keys(&previous["users"], &content["users"]) {
+        let (old, new) = (level(&previous["users"], user), level(&content["users"], user));
+        if user != sender.as_str() && old >= Some(sender_level) {
+            reject!("Sender cannot change power level of {}", user);
+        }
+        if new > Some(sender_level) {
+            reject!("Sender cannot raise power level of {} above their own", user);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the keys of the objects `old` and `new` whose values differ,
+/// including keys in only one of them
+fn changed_keys<'a>(old: &'a Value, new: &'a Value) -> Vec<&'a str> {
+    let keys = |value: &'a Value| value.as_object().into_iter().flat_map(|map| map.keys());
+
+    let mut changed: Vec<&str> = keys(old).chain(keys(new))
+        .filter(|key| old.get(key.as_str()) != new.get(key.as_str()))
+        .map(String::as_str)
+        .collect();
+    changed.sort_unstable();
+    changed.dedup();
+
+    changed
+}
+
+/// Returns `true` if `user` has the power level to invite users to a room with
+/// `state`
+pub fn can_invite(user: &str, room_version: &RoomVersion, state: &StateMap) -> bool {
+    match get(state, "m.room.create", "") {
+        Some(create) => {
+            let power_levels = PowerLevels::new(state, create, room_version);
+            power_levels.user_level(user) >= power_levels.level("invite")
+        }
+        None => false,
+    }
+}
+
+/// Returns the state events in `events` keyed by `(type, state_key)`
+pub fn state_map(events: &[Value]) -> StateMap<'_> {
+    events.iter()
+        .filter_map(|e| Some(((e["type"].as_str()?.to_string(), e["state_key"].as_str()?.to_string()), e)))
+        .collect()
+}
+
+/// The power levels of a room, from its `m.room.power_levels` event or, if
+/// there is none, the defaults that give the creator full power
+struct PowerLevels<'a> {
+    content: Option<&'a Value>,
+    creator: Option<&'a str>,
+    room_version: &'a RoomVersion,
+}
+
+impl<'a> PowerLevels<'a> {
+    fn new(state: &StateMap<'a>, create: &'a Value, room_version: &'a RoomVersion) -> Self {
+        let creator = if room_version.implicit_room_creator {
+            create["sender"].as_str()
+        } else {
+            create["content"]["creator"].as_str()
+        };
+
+        PowerLevels {
+            content: get(state, "m.room.power_levels", "").map(|e| &e["content"]),
+            creator,
+            room_version,
+        }
+    }
+
+    fn user_level(&self, user: &str) -> i64 {
+        match self.content {
+            Some(content) => self.get(&content["users"][user])
+                .or_else(|| self.get(&content["users_default"]))
+                .unwrap_or(0),
+            None if self.creator == Some(user) => CREATOR_POWER_LEVEL,
+            None => 0,
+        }
+    }
+
+    /// Returns the level needed to `ban`, `invite`, `kick` or `redact`
+    fn level(&self, action: &str) -> i64 {
+        let default = if action == "invite" { 0 } else { 50 };
+        self.content.and_then(|content| self.get(&content[action])).unwrap_or(default)
+    }
+
+    fn event_level(&self, event_type: &str, is_state: bool) -> i64 {
+        let content = match self.content {
+            Some(content) => content,
+            None => return 0,
+        };
+
+        self.get(&content["events"][event_type]).unwrap_or_else(|| {
+            if is_state {
+                self.get(&content["state_default"]).unwrap_or(50)
+            } else {
+                self.get(&content["events_default"]).unwrap_or(0)
+            }
+        })
+    }
+
+    fn get(&self, value: &Value) -> Option<i64> {
+        power_level(value, self.room_version)
+    }
+}
+
+/// Returns a power level, which before room version 10 may be a string
+/// holding an integer
+fn power_level(value: &Value, room_version: &RoomVersion) -> Option<i64> {
+    match value {
+        Value::Number(number) => number.as_i64(),
+        Value::String(string) if !room_version.integer_power_levels => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn get<'a>(state: &StateMap<'a>, event_type: &str, state_key: &str) -> Option<&'a Value> {
+    state.get(&(event_type.to_string(), state_key.to_string())).copied()
+}
+
+fn membership<'a>(state: &StateMap<'a>, user: &str) -> &'a str {
+    get(state, "m.room.member", user)
+        .and_then(|e| e["content"]["membership"].as_str())
+        .unwrap_or("leave")
+}
+
+fn event_type(event: &Value) -> &str {
+    event["type"].as_str().unwrap_or_default()
+}
+
+fn sender(event: &Value) -> Result<UserId, Rejection> {
+    match event["sender"].as_str().and_then(|sender| sender.parse().ok()) {
+        Some(sender) => Ok(sender),
+        None => reject!("Invalid sender"),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::services::signing::SigningKeys;
+    use std::collections::HashSet;
+    use std::ops::RangeInclusive;
+    use twelf::reexports::serde_json::json;
+
+    const ROOM_ID: &str = "!room:a.example";
+
+    /// Creator of the room, with power level 100
+    const ALICE: &str = "@alice:a.example";
+    /// Joined, with power level 50
+    const BOB: &str = "@bob:b.example";
+    /// Joined, with power level 0
+    const CAROL: &str = "@carol:a.example";
+    /// Invited
+    const DAVE: &str = "@dave:c.example";
+    /// Banned
+    const ERIN: &str = "@erin:c.example";
+    /// Never in the room
+    const FRANK: &str = "@frank:c.example";
+
+    const ALL: RangeInclusive<u32> = 1..=11;
+
+    /// An event to authorize against the state of [`room()`] with `state`
+    /// applied, in each room version in `versions`
+    struct Case {
+        name: &'static str,
+        versions: RangeInclusive<u32>,
+        state: Vec<Value>,
+        event: Value,
+        allowed: bool,
+    }
+
+    fn case(name: &'static str, versions: RangeInclusive<u32>, state: Vec<Value>, event: Value, allowed: bool) -> Case {
+        Case { name, versions, state, event, allowed }
+    }
+
+    fn check(cases: Vec<Case>) {
+        for case in cases {
+            for id in case.versions.clone() {
+                let room_version = version(id);
+                let events = room(room_version, case.state.clone());
+                let result = authorize(&case.event, room_version, &state_map(&events));
+
+                assert_eq!(result.is_ok(), case.allowed, "{} in room version {}: {:?}", case.name, id, result);
+            }
+        }
+    }
+
+    fn version(version: u32) -> &'static RoomVersion {
+        room_version::get(&version.to_string()).unwrap()
+    }
+
+    fn event(sender: &str, event_type: &str, state_key: Option<&str>, content: Value) -> Value {
+        let mut event = json!({
+            "room_id": ROOM_ID,
+            "sender": sender,
+            "type": event_type,
+            "content": content,
+            "depth": 5,
+            "origin_server_ts": 0,
+            "prev_events": [],
+            "auth_events": [],
+        });
+        if let Some(state_key) = state_key {
+            event["state_key"] = json!(state_key);
+        }
+
+        event
+    }
+
+    fn member(sender: &str, target: &str, membership: &str) -> Value {
+        event(sender, "m.room.member", Some(target), json!({ "membership": membership }))
+    }
+
+    fn join_rules(join_rule: &str) -> Value {
+        event(ALICE, "m.room.join_rules", Some(""), json!({ "join_rule": join_rule }))
+    }
+
+    fn power_levels(content: Value) -> Value {
+        event(ALICE, "m.room.power_levels", Some(""), content)
+    }
+
+    fn default_power_levels() -> Value {
+        json!({
+            "users": { ALICE: 100, BOB: 50 },
+            "users_default": 0,
+            "events": { "m.room.name": 50, "m.room.power_levels": 50 },
+            "events_default": 0,
+            "state_default": 50,
+            "ban": 50,
+            "kick": 50,
+            "redact": 50,
+            "invite": 0,
+        })
+    }
+
+    /// Returns the default power levels with `key` set to `value`
+    fn power_levels_with(key: &str, value: Value) -> Value {
+        let mut content = default_power_levels();
+        content[key] = value;
+        power_levels(content)
+    }
+
+    fn create(room_version: &RoomVersion) -> Value {
+        let mut content = json!({ "room_version": room_version.id });
+        if !room_version.implicit_room_creator {
+            content["creator"] = json!(ALICE);
+        }
+
+        let mut create = event(ALICE, "m.room.create", Some(""), content);
+        create["event_id"] = json!("$create:a.example");
+        create
+    }
+
+    /// Returns the state of an invite-only room created by Alice, which Bob
+    /// and Carol have joined, Dave is invited to and Erin is banned from, with
+    /// `state` replacing or adding to those events
+    fn room(room_version: &RoomVersion, state: Vec<Value>) -> Vec<Value> {
+        let mut events = vec![
+            create(room_version),
+            member(ALICE, ALICE, "join"),
+            power_levels(default_power_levels()),
+            join_rules("invite"),
+            member(BOB, BOB, "join"),
+            member(CAROL, CAROL, "join"),
+            member(BOB, DAVE, "invite"),
+            member(ALICE, ERIN, "ban"),
+        ];
+
+        for event in state {
+            match events.iter_mut().find(|e| e["type"] == event["type"] && e["state_key"] == event["state_key"]) {
+                Some(existing) => *existing = event,
+                None => events.push(event),
+            }
+        }
+
+        events
+    }
+
+    /// Returns a join of `user` authorised by `authorising_user`, signed by
+    /// the authorising user's server
+    fn restricted_join(user: &str, authorising_user: &str) -> Value {
+        let mut join = member(user, user, "join");
+        join["content"]["join_authorised_via_users_server"] = json!(authorising_user);
+        let server_name = authorising_user.split_once(':').unwrap().1;
+        join["signatures"] = json!({ server_name: { "ed25519:1": "signature" } });
+        join
+    }
+
+    #[test]
+    fn test_authorize_create() {
+        let mut without_creator = event(ALICE, "m.room.create", Some(""), json!({}));
+        without_creator["event_id"] = json!("$create:a.example");
+
+        let mut with_prev_events = create(version(10));
+        with_prev_events["prev_events"] = json!(["$other:a.example"]);
+
+        let mut unknown_version = create(version(10));
+        unknown_version["content"]["room_version"] = json!("99");
+
+        let mut other_server = create(version(10));
+        other_server["sender"] = json!(BOB);
+        other_server["content"]["creator"] = json!(BOB);
+
+        check(vec![
+            case("create", 1..=10, vec![], create(version(1)), true),
+            case("create", 11..=11, vec![], create(version(11)), true),
+            case("create without creator", 1..=10, vec![], without_creator.clone(), false),
+            case("create without creator", 11..=11, vec![], without_creator, true),
+            case("create with prev events", ALL, vec![], with_prev_events, false),
+            case("create with unknown room version", ALL, vec![], unknown_version, false),
+            case("create by another server", ALL, vec![], other_server, false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_without_create() {
+        let events = room(version(10), vec![]);
+        let mut state = state_map(&events);
+        state.remove(&(String::from("m.room.create"), String::new()));
+
+        let message = event(ALICE, "m.room.message", None, json!({ "body": "hi" }));
+        assert!(authorize(&message, version(10), &state).is_err());
+    }
+
+    #[test]
+    fn test_authorize_with_unfederated_room() {
+        let mut create = create(version(10));
+        create["content"]["m.federate"] = json!(false);
+
+        check(vec![
+            case("message from creator's server", ALL, vec![create.clone()], event(CAROL, "m.room.message", None, json!({})), true),
+            case("message from another server", ALL, vec![create], event(BOB, "m.room.message", None, json!({})), false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_aliases() {
+        let aliases = json!({ "aliases": ["#room:a.example"] });
+
+        check(vec![
+            case("aliases for own server", ALL, vec![], event(BOB, "m.room.aliases", Some("b.example"), aliases.clone()), true),
+            case("aliases for another server", 1..=5, vec![], event(BOB, "m.room.aliases", Some("a.example"), aliases.clone()), false),
+            case("aliases without power", 1..=5, vec![], event(CAROL, "m.room.aliases", Some("a.example"), aliases.clone()), true),
+            case("aliases without power", 6..=11, vec![], event(CAROL, "m.room.aliases", Some("a.example"), aliases.clone()), false),
+            case("aliases without state key", 1..=5, vec![], event(BOB, "m.room.aliases", None, aliases), false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_first_join() {
+        for id in ALL {
+            let room_version = version(id);
+            let create = create(room_version);
+            let create_id = events::event_id(&create, room_version).unwrap();
+            let state = state_map(std::slice::from_ref(&create));
+
+            let mut join = member(ALICE, ALICE, "join");
+            join["prev_events"] = if room_version.event_id_format == EventIdFormat::ServerChosen {
+                json!([[create_id, { "sha256": "hash" }]])
+            } else {
+                json!([create_id])
+            };
+            assert!(authorize(&join, room_version, &state).is_ok(), "room version {}", id);
+
+            // Only the creator may join straight after the m.room.create event.
+            let mut other_join = join.clone();
+            other_join["sender"] = json!(CAROL);
+            other_join["state_key"] = json!(CAROL);
+            assert!(authorize(&other_join, room_version, &state).is_err(), "room version {}", id);
+
+            join["prev_events"] = json!([]);
+            assert!(authorize(&join, room_version, &state).is_err(), "room version {}", id);
+        }
+    }
+
+    #[test]
+    fn test_authorize_join() {
+        check(vec![
+            case("join without invite", ALL, vec![], member(FRANK, FRANK, "join"), false),
+            case("join with invite", ALL, vec![], member(DAVE, DAVE, "join"), true),
+            case("join when already joined", ALL, vec![], member(CAROL, CAROL, "join"), true),
+            case("join on behalf of another user", ALL, vec![join_rules("public")], member(ALICE, FRANK, "join"), false),
+            case("join public room", ALL, vec![join_rules("public")], member(FRANK, FRANK, "join"), true),
+            case("join public room when banned", ALL, vec![join_rules("public")], member(ERIN, ERIN, "join"), false),
+            case("join private room", ALL, vec![join_rules("private")], member(FRANK, FRANK, "join"), false),
+            case("join knock room with invite", 1..=6, vec![join_rules("knock")], member(DAVE, DAVE, "join"), false),
+            case("join knock room with invite", 7..=11, vec![join_rules("knock")], member(DAVE, DAVE, "join"), true),
+            case("join knock room without invite", ALL, vec![join_rules("knock")], member(FRANK, FRANK, "join"), false),
+            case("join without membership", ALL, vec![], event(FRANK, "m.room.member", Some(FRANK), json!({})), false),
+            case("join without state key", ALL, vec![], event(FRANK, "m.room.member", None, json!({ "membership": "join" })), false),
+            case("unknown membership", ALL, vec![], member(CAROL, CAROL, "wander"), false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_restricted_join() {
+        let restricted = || vec![join_rules("restricted")];
+        let knock_restricted = || vec![join_rules("knock_restricted")];
+        let mut unsigned = restricted_join(FRANK, BOB);
+        unsigned["signatures"] = json!({ "c.example": { "ed25519:1": "signature" } });
+        let high_invite_level = power_levels_with("invite", json!(60));
+
+        check(vec![
+            case("restricted join", 1..=7, restricted(), restricted_join(FRANK, BOB), false),
+            case("restricted join", 8..=11, restricted(), restricted_join(FRANK, BOB), true),
+            case("restricted join with invite", 8..=11, restricted(), member(DAVE, DAVE, "join"), true),
+            case("restricted join without authorisation", 8..=11, restricted(), member(FRANK, FRANK, "join"), false),
+            case("restricted join authorised by non-member", 8..=11, restricted(), restricted_join(FRANK, DAVE), false),
+            case("restricted join authorised without invite power", 8..=11,
+                vec![join_rules("restricted"), high_invite_level], restricted_join(FRANK, BOB), false),
+            case("restricted join without signature", 8..=11, restricted(), unsigned, false),
+            case("knock_restricted join", 8..=9, knock_restricted(), restricted_join(FRANK, BOB), false),
+            case("knock_restricted join", 10..=11, knock_restricted(), restricted_join(FRANK, BOB), true),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_invite() {
+        let high_invite_level = power_levels_with("invite", json!(60));
+
+        check(vec![
+            case("invite", ALL, vec![], member(CAROL, FRANK, "invite"), true),
+            case("invite by non-member", ALL, vec![], member(DAVE, FRANK, "invite"), false),
+            case("invite banned user", ALL, vec![], member(BOB, ERIN, "invite"), false),
+            case("invite joined user", ALL, vec![], member(BOB, CAROL, "invite"), false),
+            case("invite without invite power", ALL, vec![high_invite_level.clone()], member(BOB, FRANK, "invite"), false),
+            case("invite with invite power", ALL, vec![high_invite_level], member(ALICE, FRANK, "invite"), true),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_third_party_invite() {
+        let keys = SigningKeys::test();
+        let other_keys = SigningKeys::test();
+        let public_key = signing::encode_verify_key(&keys.verify_key());
+
+        let third_party_invite = |sender: &str, public_key: &str| event(
+            sender,
+            "m.room.third_party_invite",
+            Some("token"),
+            json!({ "display_name": "frank", "public_key": public_key, "public_keys": [{ "public_key": public_key }] })
+        );
+        let invite = |sender: &str, mxid: &str, keys: &SigningKeys| {
+            let mut signed = json!({ "mxid": mxid, "token": "token" });
+            keys.sign_json(&mut signed).unwrap();
+
+            let mut invite = member(sender, FRANK, "invite");
+            invite["content"]["third_party_invite"] = json!({ "display_name": "frank", "signed": signed });
+            invite
+        };
+        let mut unsigned = invite(BOB, FRANK, &keys);
+        unsigned["content"]["third_party_invite"].as_object_mut().unwrap().remove("signed");
+
+        check(vec![
+            case("third-party invite", ALL, vec![third_party_invite(BOB, &public_key)], invite(BOB, FRANK, &keys), true),
+            case("third-party invite without m.room.third_party_invite", ALL, vec![], invite(BOB, FRANK, &keys), false),
+            case("third-party invite by another user", ALL,
+                vec![third_party_invite(BOB, &public_key)], invite(CAROL, FRANK, &keys), false),
+            case("third-party invite for another user", ALL,
+                vec![third_party_invite(BOB, &public_key)], invite(BOB, DAVE, &keys), false),
+            case("third-party invite signed by another key", ALL,
+                vec![third_party_invite(BOB, &public_key)], invite(BOB, FRANK, &other_keys), false),
+            case("third-party invite without signed", ALL, vec![third_party_invite(BOB, &public_key)], unsigned, false),
+            case("third-party invite of banned user", ALL,
+                vec![third_party_invite(BOB, &public_key), member(ALICE, FRANK, "ban")], invite(BOB, FRANK, &keys), false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_leave_and_ban() {
+        check(vec![
+            case("leave", ALL, vec![], member(CAROL, CAROL, "leave"), true),
+            case("reject invite", ALL, vec![], member(DAVE, DAVE, "leave"), true),
+            case("leave without membership", ALL, vec![], member(FRANK, FRANK, "leave"), false),
+            case("leave when banned", ALL, vec![], member(ERIN, ERIN, "leave"), false),
+            case("kick", ALL, vec![], member(BOB, CAROL, "leave"), true),
+            case("kick without kick power", ALL, vec![], member(CAROL, BOB, "leave"), false),
+            case("kick user with more power", ALL, vec![], member(BOB, ALICE, "leave"), false),
+            case("kick by non-member", ALL, vec![member(BOB, BOB, "leave")], member(BOB, CAROL, "leave"), false),
+            case("unban", ALL, vec![], member(BOB, ERIN, "leave"), true),
+            case("unban without ban power", ALL, vec![power_levels_with("ban", json!(60))], member(BOB, ERIN, "leave"), false),
+            case("ban", ALL, vec![], member(BOB, FRANK, "ban"), true),
+            case("ban without ban power", ALL, vec![], member(CAROL, FRANK, "ban"), false),
+            case("ban user with more power", ALL, vec![], member(BOB, ALICE, "ban"), false),
+            case("ban by non-member", ALL, vec![], member(DAVE, FRANK, "ban"), false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_knock() {
+        let knock = || vec![join_rules("knock")];
+
+        check(vec![
+            case("knock", 1..=6, knock(), member(FRANK, FRANK, "knock"), false),
+            case("knock", 7..=11, knock(), member(FRANK, FRANK, "knock"), true),
+            case("knock on invite-only room", 7..=11, vec![], member(FRANK, FRANK, "knock"), false),
+            case("knock on behalf of another user", 7..=11, knock(), member(BOB, FRANK, "knock"), false),
+            case("knock when invited", 7..=11, knock(), member(DAVE, DAVE, "knock"), false),
+            case("knock when joined", 7..=11, knock(), member(CAROL, CAROL, "knock"), false),
+            case("knock when banned", 7..=11, knock(), member(ERIN, ERIN, "knock"), false),
+            case("knock on knock_restricted room", 7..=9, vec![join_rules("knock_restricted")], member(FRANK, FRANK, "knock"), false),
+            case("knock on knock_restricted room", 10..=11, vec![join_rules("knock_restricted")], member(FRANK, FRANK, "knock"), true),
+            case("rescind knock", 1..=6, vec![member(FRANK, FRANK, "knock")], member(FRANK, FRANK, "leave"), false),
+            case("rescind knock", 7..=11, vec![member(FRANK, FRANK, "knock")], member(FRANK, FRANK, "leave"), true),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_events() {
+        check(vec![
+            case("message", ALL, vec![], event(CAROL, "m.room.message", None, json!({ "body": "hi" })), true),
+            case("message by non-member", ALL, vec![], event(DAVE, "m.room.message", None, json!({ "body": "hi" })), false),
+            case("message without power", ALL,
+                vec![power_levels_with("events_default", json!(10))], event(CAROL, "m.room.message", None, json!({})), false),
+            case("state", ALL, vec![], event(BOB, "m.room.topic", Some(""), json!({ "topic": "Hi" })), true),
+            case("state without power", ALL, vec![], event(CAROL, "m.room.topic", Some(""), json!({ "topic": "Hi" })), false),
+            case("state with event power level", ALL, vec![], event(BOB, "m.room.name", Some(""), json!({ "name": "Hi" })), true),
+            case("state without event power level", ALL, vec![], event(CAROL, "m.room.name", Some(""), json!({ "name": "Hi" })), false),
+            case("state keyed by sender", ALL, vec![], event(BOB, "org.example.status", Some(BOB), json!({})), true),
+            case("state keyed by another user", ALL, vec![], event(BOB, "org.example.status", Some(ALICE), json!({})), false),
+            case("m.room.third_party_invite", ALL, vec![], event(CAROL, "m.room.third_party_invite", Some("token"), json!({})), true),
+            case("m.room.third_party_invite without invite power", ALL,
+                vec![power_levels_with("invite", json!(10))], event(CAROL, "m.room.third_party_invite", Some("token"), json!({})), false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_power_levels() {
+        let change = |key: &str, value: Value| {
+            let mut content = default_power_levels();
+            content[key] = value;
+            event(BOB, "m.room.power_levels", Some(""), content)
+        };
+        let change_user = |user: &str, level: i64| {
+            let mut content = default_power_levels();
+            content["users"][user] = json!(level);
+            event(BOB, "m.room.power_levels", Some(""), content)
+        };
+        let mut add_event = default_power_levels();
+        add_event["events"]["m.room.tombstone"] = json!(100);
+        let mut lower_event = default_power_levels();
+        lower_event["events"]["m.room.name"] = json!(30);
+        let mut string_levels = default_power_levels();
+        string_levels["ban"] = json!("50");
+        string_levels["users"][BOB] = json!("50");
+
+        check(vec![
+            case("lower ban level", ALL, vec![], change("ban", json!(40)), true),
+            case("raise kick level above own", ALL, vec![], change("kick", json!(60)), false),
+            case("change level above own", ALL, vec![power_levels_with("redact", json!(60))], change("redact", json!(40)), false),
+            case("remove level", ALL, vec![], event(BOB, "m.room.power_levels", Some(""), {
+                let mut content = default_power_levels();
+                content.as_object_mut().unwrap().remove("kick");
+                content
+            }), true),
+            case("promote user to own level", ALL, vec![], change_user(CAROL, 50), true),
+            case("promote user above own level", ALL, vec![], change_user(CAROL, 60), false),
+            case("demote user with more power", ALL, vec![], change_user(ALICE, 0), false),
+            case("demote self", ALL, vec![], change_user(BOB, 0), true),
+            case("add event level above own", ALL, vec![], event(BOB, "m.room.power_levels", Some(""), add_event), false),
+            case("lower event level", ALL, vec![], event(BOB, "m.room.power_levels", Some(""), lower_event), true),
+            case("add notifications level above own", 1..=5, vec![], change("notifications", json!({ "room": 100 })), true),
+            case("add notifications level above own", 6..=11, vec![], change("notifications", json!({ "room": 100 })), false),
+            case("string power levels", 1..=9, vec![], event(BOB, "m.room.power_levels", Some(""), string_levels.clone()), true),
+            case("string power levels", 10..=11, vec![], event(BOB, "m.room.power_levels", Some(""), string_levels), false),
+            case("non-integer power level", 10..=11, vec![], change("ban", json!("high")), false),
+            case("user power levels keyed by non-user", ALL, vec![], change("users", json!({ "bob": 0 })), false),
+            case("power levels without power", ALL, vec![], event(CAROL, "m.room.power_levels", Some(""), default_power_levels()), false),
+        ]);
+    }
+
+    #[test]
+    fn test_authorize_redaction() {
+        let redaction = |sender: &str, redacts: &str| {
+            let mut redaction = event(sender, "m.room.redaction", None, json!({}));
+            redaction["event_id"] = json!("$redaction:a.example");
+            redaction["redacts"] = json!(redacts);
+            redaction
+        };
+
+        check(vec![
+            case("redaction", ALL, vec![], redaction(BOB, "$event:c.example"), true),
+            case("redaction of same server's event", ALL, vec![], redaction(CAROL, "$event:a.example"), true),
+            case("redaction of another server's event", 1..=2, vec![], redaction(CAROL, "$event:c.example"), false),
+            case("redaction of another server's event", 3..=11, vec![], redaction(CAROL, "$event:c.example"), true),
+        ]);
+    }
+
+    /// Helper function to return the state map given by the auth events of
+    /// `event`
+    ///
+    /// Fails if there are several auth events with the same type and state
+    /// key, or any that the auth events selection algorithm wouldn't choose for
+    /// `event`.
+    pub fn auth_state<'a>(event: &Value, auth_events: &[&'a Value]) -> Result<StateMap<'a>, Rejection> {
+        let sender = sender(event)?;
+        let state_key = event["state_key"].as_str();
+        let expected: HashSet<(String, String)> =
+            events::auth_event_keys(event_type(event), state_key, &sender, &event["content"]).into_iter().collect();
+
+        let mut state = StateMap::new();
+        for auth_event in auth_events {
+            let key = match auth_event["state_key"].as_str() {
+                Some(state_key) => (event_type(auth_event).to_string(), state_key.to_string()),
+                None => reject!("Auth event of type {} is not a state event", event_type(auth_event)),
+            };
+            if !expected.contains(&key) {
+                reject!("Unexpected auth event ({}, {})", key.0, key.1);
+            }
+            if state.insert(key.clone(), *auth_event).is_some() {
+                reject!("Duplicate auth event ({}, {})", key.0, key.1);
+            }
+        }
+
+        Ok(state)
+    }
+
+    #[test]
+    fn test_auth_state() {
+        let events = room(version(10), vec![]);
+        let [create, alice, power_levels, join_rules, bob, carol, ..] = &events[..] else { unreachable!() };
+        let invite = member(BOB, FRANK, "invite");
+
+        let state = auth_state(&invite, &[create, power_levels, bob, join_rules]).unwrap();
+        assert_eq!(state.len(), 4);
+        assert!(authorize(&invite, version(10), &state).is_ok());
+
+        assert!(auth_state(&invite, &[create, power_levels, bob, alice]).is_err());
+        assert!(auth_state(&invite, &[create, power_levels, bob, bob]).is_err());
+        assert!(auth_state(&invite, &[create, &event(BOB, "m.room.message", None, json!({}))]).is_err());
+        assert!(auth_state(&create.clone(), &[carol]).is_err());
+        assert!(auth_state(&create.clone(), &[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::models::events::Event;
+use crate::models::ids::{EventId, RoomId, UserId};
+use crate::models::room_version::{EventIdFormat, RedactionRules, RoomVersion};
+use crate::services::canonical_json;
+use crate::services::signing::SigningKeys;
+use crate::store::pg::events::NewEvent;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use twelf::reexports::serde_json::{json, Map, Value};
+
+/// The parts of a new event chosen by its sender; see [`build_event()`]
+#[derive(Debug, Clone)]
+pub struct EventTemplate {
+    pub room_id: RoomId,
+    pub sender: UserId,
+    pub event_type: String,
+    pub state_key: Option<String>,
+    pub content: Value,
+    pub origin_server_ts: i64,
+}
+
+/// Builds a new event that follows `prev_events` and is authorized by
+/// `auth_events`, both given as the JSON of the referenced events
+///
+/// The event's depth is one more than the deepest prev event. Its content hash
+/// and this server's signature are added and, in room version 3 and later, its
+/// ID is derived from its reference hash.
+///
+/// See https://spec.matrix.org/v1.13/server-server-api/#pdus
+pub fn build_event(
+    template: EventTemplate,
+    room_version: &RoomVersion,
+    prev_events: &[&Value],
+    auth_events: &[&Value],
+    config: &Config,
+    keys: &SigningKeys
+) -> Result<NewEvent, Error> {
+    let depth = prev_events.iter().filter_map(|e| e["depth"].as_i64()).max().unwrap_or(0) + 1;
+    let prev_event_ids = event_ids(prev_events, room_version)?;
+    let auth_event_ids = event_ids(auth_events, room_version)?;
+
+    let mut pdu = json!({
+        "room_id": template.room_id,
+        "sender": template.sender,
+        "type": template.event_type,
+        "content": template.content,
+        "depth": depth,
+        "origin_server_ts": template.origin_server_ts,
+        "prev_events": event_references(prev_events, &prev_event_ids, room_version)?,
+        "auth_events": event_references(auth_events, &auth_event_ids, room_version)?,
+    });
+    if let Some(ref state_key) = template.state_key {
+        pdu["state_key"] = json!(state_key);
+    }
+
+    let event_id = if room_version.event_id_format == EventIdFormat::ServerChosen {
+        let event_id = EventId::generate(&config.server.server_name);
+        pdu["event_id"] = json!(event_id);
+        Some(event_id)
+    } else {
+        None
+    };
+
+    pdu["hashes"] = json!({ "sha256": canonical_json::content_hash(&pdu)? });
+    keys.sign_event(&mut pdu, room_version)?;
+
+    let event_id = match event_id {
+        Some(event_id) => event_id,
+        None => canonical_json::event_id(&pdu, room_version)?,
+    };
+
+    Ok(NewEvent {
+        event_id,
+        room_id: template.room_id,
+        sender: template.sender,
+        event_type: template.event_type,
+        state_key: template.state_key,
+        content: template.content,
+        depth,
+        origin_server_ts: template.origin_server_ts,
+        prev_events: prev_event_ids,
+        auth_events: auth_event_ids,
+        json: pdu,
+    })
+}
+
+/// Returns `event` in the format in which clients receive it
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#room-event-format
+pub fn client_event(event: &Event) -> Value {
+    let mut json = json!({
+        "content": event.content,
+        "event_id": event.event_id,
+        "origin_server_ts": event.origin_server_ts,
+        "room_id": event.room_id,
+        "sender": event.sender,
+        "type": event.event_type,
+    });
+    if let Some(ref state_key) = event.state_key {
+        json["state_key"] = json!(state_key);
+    }
+
+    json
+}
+
+/// Returns the ID of the event with JSON `pdu`
+///
+/// In room versions 1 and 2, the ID is part of the event; in later versions,
+/// it's derived from the event's reference hash.
+pub fn event_id(pdu: &Value, room_version: &RoomVersion) -> Result<EventId, Error> {
+    if room_version.event_id_format == EventIdFormat::ServerChosen {
+        pdu["event_id"].as_str()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| Error::BadJson(String::from("Event has no valid event_id")))
+    } else {
+        canonical_json::event_id(pdu, room_version)
+    }
+}
+
+fn event_ids(pdus: &[&Value], room_version: &RoomVersion) -> Result<Vec<EventId>, Error> {
+    pdus.iter().map(|pdu| event_id(pdu, room_version)).collect()
+}
+
+/// Returns the `prev_events` or `auth_events` of a new event, which in room
+/// versions 1 and 2 pair each event ID with the event's reference hash
+fn event_references(pdus: &[&Value], event_ids: &[EventId], room_version: &RoomVersion) -> Result<Value, Error> {
+    if room_version.event_id_format != EventIdFormat::ServerChosen {
+        return Ok(json!(event_ids));
+    }
+
+    let references = pdus.iter()
+        .zip(event_ids)
+        .map(|(pdu, event_id)| {
+            let hash = canonical_json::reference_hash(pdu, room_version)?;
+            Ok(json!([event_id, { "sha256": STANDARD_NO_PAD.encode(hash) }]))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(Value::Array(references))
+}
+
+/// Returns the redacted form of an event, keeping only the keys needed to
+/// authorize it and to check its hashes and signatures
+///
+/// See https://spec.matrix.org/v1.13/rooms/v11/#redactions
+pub fn redact(event: &Value, room_version: &RoomVersion) -> Value {
+    let event = match event.as_object() {
+        Some(event) => event,
+        None => return event.clone(),
+    };
+
+    let mut top_level_keys = vec![
+        "event_id", "type", "room_id", "sender", "state_key", "content", "hashes", "signatures", "depth",
+        "prev_events", "auth_events", "origin_server_ts",
+    ];
+    if !room_version.redacts_like(RedactionRules::V11) {
+        top_level_keys.extend(["prev_state", "origin", "membership"]);
+    }
+
+    let content_keys: Vec<&str> = match event.get("type").and_then(Value::as_str).unwrap_or_default() {
+        "m.room.member" => {
+            let mut keys = vec!["membership"];
+            if room_version.redacts_like(RedactionRules::V9) {
+                keys.push("join_authorised_via_users_server");
+            }
+            keys
+        }
+        "m.room.create" if !room_version.redacts_like(RedactionRules::V11) => vec!["creator"],
+        "m.room.join_rules" if room_version.redacts_like(RedactionRules::V8) => vec!["join_rule", "allow"],
+        "m.room.join_rules" => vec!["join_rule"],
+        "m.room.power_levels" => {
+            let mut keys = vec!["ban", "events", "events_default", "kick", "redact", "state_default", "users", "users_default"];
+            if room_version.redacts_like(RedactionRules::V11) {
+                keys.push("invite");
+            }
+            keys
+        }
+        "m.room.aliases" if !room_version.redacts_like(RedactionRules::V6) => vec!["aliases"],
+        "m.room.history_visibility" => vec!["history_visibility"],
+        "m.room.redaction" if room_version.redacts_like(RedactionRules::V11) => vec!["redacts"],
+        _ => vec![],
+    };
+
+    let mut redacted: Map<String, Value> = event.iter()
+        .filter(|(key, _)| top_level_keys.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    let content = event.get("content").and_then(Value::as_object);
+    let event_type = event.get("type").and_then(Value::as_str).unwrap_or_default();
+    let redacted_content: Map<String, Value> = match content {
+        // Version 11 keeps all of the create event's content.
+        Some(content) if event_type == "m.room.create" && room_version.redacts_like(RedactionRules::V11) =>
+            content.clone(),
+        Some(content) => {
+            let mut kept: Map<String, Value> = content.iter()
+                .filter(|(key, _)| content_keys.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+
+            // Version 11 keeps the signed part of third-party invites.
+            if event_type == "m.room.member" && room_version.redacts_like(RedactionRules::V11) {
+                if let Some(signed) = content.get("third_party_invite").and_then(|i| i.get("signed")) {
+                    kept.insert(String::from("third_party_invite"), json!({ "signed": signed }));
+                }
+            }
+
+            kept
+        }
+        None => Map::new(),
+    };
+    redacted.insert(String::from("content"), Value::Object(redacted_content));
+
+    Value::Object(redacted)
+}
+
+/// Returns the `(type, state_key)` of the state events that should be the auth
+/// events of a new event, if they exist in the room
+///
+/// See https://spec.matrix.org/v1.13/server-server-api/#auth-events-selection
+pub fn auth_event_keys(
+    event_type: &str,
+    state_key: Option<&str>,
+    sender: &UserId,
+    content: &Value
+) -> Vec<(String, String)> {
+    if event_type == "m.room.create" {
+        return vec![];
+    }
+
+    let mut keys = vec![
+        (String::from("m.room.create"), String::new()),
+        (String::from("m.room.power_levels"), String::new()),
+        (String::from("m.room.member"), sender.to_string()),
+    ];
+
+    if event_type == "m.room.member" {
+        if let Some(state_key) = state_key {
+            keys.push((String::from("m.room.member"), state_key.to_string()));
+        }
+
+        let membership = content["membership"].as_str().unwrap_or_default();
+        if matches!(membership, "join" | "invite" | "knock") {
+            keys.push((String::from("m.room.join_rules"), String::new()));
+        }
+
+        if membership == "invite" {
+            if let Some(token) = content["third_party_invite"]["signed"]["token"].as_str() {
+                keys.push((String::from("m.room.third_party_invite"), token.to_string()));
+            }
+        }
+
+        if membership == "join" {
+            if let Some(authorising_user) = content["join_authorised_via_users_server"].as_str() {
+                keys.push((String::from("m.room.member"), authorising_user.to_string()));
+            }
+        }
+    }
+
+    keys.dedup();
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::room_version;
+
+    fn version(id: &str) -> &'static RoomVersion {
+        room_version::get(id).unwrap()
+    }
+
+    fn template(event_type: &str, state_key: Option<&str>, content: Value) -> EventTemplate {
+        EventTemplate {
+            room_id: "!abc:chat.spelt.io".parse().unwrap(),
+            sender: "@alice:chat.spelt.io".parse().unwrap(),
+            event_type: event_type.to_string(),
+            state_key: state_key.map(String::from),
+            content,
+            origin_server_ts: 1000,
+        }
+    }
+
+    fn key(event_type: &str, state_key: &str) -> (String, String) {
+        (event_type.to_string(), state_key.to_string())
+    }
+
+    #[test]
+    fn test_auth_event_keys() {
+        let alice: UserId = "@alice:chat.spelt.io".parse().unwrap();
+
+        assert!(auth_event_keys("m.room.create", Some(""), &alice, &json!({})).is_empty());
+
+        assert_eq!(auth_event_keys("m.room.message", None, &alice, &json!({})), vec![
+            key("m.room.create", ""),
+            key("m.room.power_levels", ""),
+            key("m.room.member", "@alice:chat.spelt.io"),
+        ]);
+
+        assert_eq!(
+            auth_event_keys("m.room.member", Some("@bob:chat.spelt.io"), &alice, &json!({ "membership": "invite" })),
+            vec![
+                key("m.room.create", ""),
+                key("m.room.power_levels", ""),
+                key("m.room.member", "@alice:chat.spelt.io"),
+                key("m.room.member", "@bob:chat.spelt.io"),
+                key("m.room.join_rules", ""),
+            ]
+        );
+
+        // A user joining is both the sender and the target.
+        assert_eq!(
+            auth_event_keys("m.room.member", Some("@alice:chat.spelt.io"), &alice, &json!({ "membership": "join" })),
+            vec![
+                key("m.room.create", ""),
+                key("m.room.power_levels", ""),
+                key("m.room.member", "@alice:chat.spelt.io"),
+                key("m.room.join_rules", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_event() {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let create = build_event(template("m.room.create", Some(""), json!({ "room_version": "10" })), version("10"), &[], &[], &config, &keys).unwrap();
+        let message = build_event(template("m.room.message", None, json!({ "body": "hi" })), version("10"), &[&create.json], &[&create.json], &config, &keys).unwrap();
+
+        assert_eq!(create.depth, 1);
+        assert_eq!(message.depth, 2);
+        assert_eq!(message.prev_events, vec![create.event_id.clone()]);
+        assert_eq!(message.json["prev_events"], json!([create.event_id]));
+        assert!(message.json.get("event_id").is_none());
+        assert!(message.json.get("state_key").is_none());
+        assert_eq!(message.json["hashes"]["sha256"], canonical_json::content_hash(&message.json).unwrap());
+        assert_eq!(message.event_id, canonical_json::event_id(&message.json, version("10")).unwrap());
+        assert!(message.json["signatures"]["chat.spelt.io"][keys.key_id()].is_string());
+        assert_eq!(event_id(&message.json, version("10")).unwrap(), message.event_id);
+    }
+
+    #[test]
+    fn test_build_event_in_room_version_1() {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let create = build_event(template("m.room.create", Some(""), json!({})), version("1"), &[], &[], &config, &keys).unwrap();
+        let message = build_event(template("m.room.message", None, json!({})), version("1"), &[&create.json], &[], &config, &keys).unwrap();
+
+        assert_eq!(create.json["event_id"], json!(create.event_id));
+        assert_eq!(event_id(&create.json, version("1")).unwrap(), create.event_id);
+
+        // Prev events pair each ID with the event's reference hash.
+        let reference_hash = STANDARD_NO_PAD.encode(canonical_json::reference_hash(&create.json, version("1")).unwrap());
+        assert_eq!(message.json["prev_events"], json!([[create.event_id, { "sha256": reference_hash }]]));
+    }
+
+    #[test]
+    fn test_redact() {
+        let member = json!({
+            "type": "m.room.member",
+            "state_key": "@alice:chat.spelt.io",
+            "content": {
+                "membership": "join",
+                "displayname": "Alice",
+                "join_authorised_via_users_server": "@bob:chat.spelt.io",
+                "third_party_invite": { "display_name": "alice", "signed": { "token": "abc" } },
+            },
+            "origin": "chat.spelt.io",
+            "unsigned": { "age": 5 },
+        });
+
+        assert_eq!(redact(&member, version("1")), json!({
+            "type": "m.room.member",
+            "state_key": "@alice:chat.spelt.io",
+            "content": { "membership": "join" },
+            "origin": "chat.spelt.io",
+        }));
+        assert_eq!(redact(&member, version("11")), json!({
+            "type": "m.room.member",
+            "state_key": "@alice:chat.spelt.io",
+            "content": {
+                "membership": "join",
+                "join_authorised_via_users_server": "@bob:chat.spelt.io",
+                "third_party_invite": { "signed": { "token": "abc" } },
+            },
+        }));
+
+        let create = json!({ "type": "m.room.create", "content": { "creator": "@alice:chat.spelt.io", "m.federate": false } });
+        assert_eq!(redact(&create, version("10"))["content"], json!({ "creator": "@alice:chat.spelt.io" }));
+        assert_eq!(redact(&create, version("11"))["content"], create["content"]);
+
+        let power_levels = json!({ "type": "m.room.power_levels", "content": { "ban": 50, "invite": 0, "notifications": {} } });
+        assert_eq!(redact(&power_levels, version("10"))["content"], json!({ "ban": 50 }));
+        assert_eq!(redact(&power_levels, version("11"))["content"], json!({ "ban": 50, "invite": 0 }));
+
+        let message = json!({ "type": "m.room.message", "content": { "body": "hi" } });
+        assert_eq!(redact(&message, version("10"))["content"], json!({}));
+    }
+}
@@ -0,0 +1,290 @@
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::filter::{EventFilter, Filter, RoomEventFilter, RoomFilter};
+use crate::models::ids::{RoomId, UserId};
+use crate::store::pg;
+use sqlx::PgPool;
+use twelf::reexports::serde_json::{self, Map, Value};
+
+/// Possible results of storing or getting a filter
+pub enum FilterResult {
+    /// The filter was stored with this ID
+    Created(String),
+    /// The filter as the client stored it
+    Found(Value),
+    NotFound,
+    /// The filter belongs to another user
+    Forbidden,
+    Invalid(String),
+}
+
+/// Stores `definition` as a filter for `user_id`
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3useruseridfilter
+pub async fn create_filter(
+    user_id: &UserId,
+    definition: &Value,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<FilterResult, Error> {
+    if *user_id != auth.matrix_user_id {
+        return Ok(FilterResult::Forbidden);
+    }
+    if let Err(e) = serde_json::from_value::<Filter>(definition.clone()) {
+        return Ok(FilterResult::Invalid(e.to_string()));
+    }
+
+    let filter_id = pg::filters::create_filter(user_id, definition, pool).await?;
+
+    Ok(FilterResult::Created(filter_id.to_string()))
+}
+
+/// Returns the filter of `user_id` with ID `filter_id`
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3useruseridfilterfilterid
+pub async fn get_filter(
+    user_id: &UserId,
+    filter_id: &str,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<FilterResult, Error> {
+    if *user_id != auth.matrix_user_id {
+        return Ok(FilterResult::Forbidden);
+    }
+    let Ok(filter_id) = filter_id.parse() else {
+        return Ok(FilterResult::NotFound);
+    };
+
+    Ok(match pg::filters::get_filter(user_id, filter_id, pool).await? {
+        Some(definition) => FilterResult::Found(definition),
+        None => FilterResult::NotFound,
+    })
+}
+
+/// Returns the filter that a client passed to an endpoint as a `filter` query
+/// parameter, which is either the ID of one of the user's filters or a filter
+/// as JSON, or `Ok(Err(message))` if it's invalid
+pub async fn resolve_filter(
+    param: Option<&str>,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<Result<Filter, String>, Error> {
+    let definition = match param {
+        None => return Ok(Ok(Filter::default())),
+        Some(param) if param.trim_start().starts_with('{') => param.to_string(),
+        Some(param) => {
+            let stored = match param.parse() {
+                Ok(filter_id) => pg::filters::get_filter(&auth.matrix_user_id, filter_id, pool).await?,
+                Err(_) => None,
+            };
+            match stored {
+                Some(definition) => definition.to_string(),
+                None => return Ok(Err(format!("Unknown filter: {}", param))),
+            }
+        }
+    };
+
+    Ok(serde_json::from_str(&definition).map_err(|e| format!("Invalid filter: {}", e)))
+}
+
+/// Returns the room event filter that a client passed as JSON in a `filter`
+/// query parameter, such as that of `/messages`, or `Err(message)` if it's
+/// invalid
+pub fn parse_room_event_filter(param: Option<&str>) -> Result<RoomEventFilter, String> {
+    match param {
+        None => Ok(RoomEventFilter::default()),
+        Some(param) => serde_json::from_str(param).map_err(|e| format!("Invalid filter: {}", e)),
+    }
+}
+
+/// Returns whether the room with ID `room_id` passes `filter`
+pub fn room_matches(filter: &RoomFilter, room_id: &RoomId) -> bool {
+    room_id_matches(filter.rooms.as_deref(), &filter.not_rooms, room_id)
+}
+
+/// Returns whether `event` passes `filter`
+///
+/// Events without a sender, such as presence from the server, pass any sender
+/// criteria.
+pub fn event_matches(filter: &EventFilter, event: &Value) -> bool {
+    let event_type = event["type"].as_str().unwrap_or_default();
+    if filter.not_types.iter().any(|pattern| type_matches(pattern, event_type)) {
+        return false;
+    }
+    if filter.types.as_ref().is_some_and(|types| !types.iter().any(|pattern| type_matches(pattern, event_type))) {
+        return false;
+    }
+
+    match event["sender"].as_str() {
+        Some(sender) =>
+            !filter.not_senders.iter().any(|s| s == sender)
+                && filter.senders.as_ref().is_none_or(|senders| senders.iter().any(|s| s == sender)),
+        None => true,
+    }
+}
+
+/// Returns whether `event` in the room with ID `room_id` passes `filter`
+pub fn room_event_matches(filter: &RoomEventFilter, room_id: &RoomId, event: &Value) -> bool {
+    let contains_url = event["content"].get("url").is_some();
+
+    room_id_matches(filter.rooms.as_deref(), &filter.not_rooms, room_id)
+        && event_matches(&filter.events, event)
+        && filter.contains_url.is_none_or(|wanted| wanted == contains_url)
+}
+
+/// Returns `event` with only the fields at the paths in `event_fields`, or
+/// unchanged if `event_fields` is `None`
+pub fn project_event(event: Value, event_fields: Option<&[String]>) -> Value {
+    let Some(event_fields) = event_fields else {
+        return event;
+    };
+
+    let mut projected = Value::Object(Map::new());
+    for field in event_fields {
+        let path = field_path(field);
+        let Some(value) = path.iter().try_fold(&event, |value, key| value.get(key)) else {
+            continue;
+        };
+
+        let mut target = &mut projected;
+        for key in &path[..path.len() - 1] {
+            if !target[key].is_object() {
+                target[key] = Value::Object(Map::new());
+            }
+            target = &mut target[key];
+        }
+        target[&path[path.len() - 1]] = value.clone();
+    }
+
+    projected
+}
+
+fn room_id_matches(rooms: Option<&[String]>, not_rooms: &[String], room_id: &RoomId) -> bool {
+    !not_rooms.iter().any(|r| r == room_id.as_str())
+        && rooms.is_none_or(|rooms| rooms.iter().any(|r| r == room_id.as_str()))
+}
+
+/// Returns whether `event_type` matches `pattern`, where `*` matches any
+/// sequence of characters
+fn type_matches(pattern: &str, event_type: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == event_type;
+    };
+    let Some(mut remaining) = event_type.strip_prefix(prefix) else {
+        return false;
+    };
+
+    // Each literal part between wildcards must appear in order, and the last
+    // must end the type.
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match remaining.find(part) {
+            Some(index) => remaining = &remaining[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    remaining.len() >= last.len() && remaining.ends_with(last)
+}
+
+/// Splits an `event_fields` entry into its keys at dots that aren't escaped
+/// with a backslash
+fn field_path(field: &str) -> Vec<String> {
+    let mut path = vec![String::new()];
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => path.last_mut().unwrap().extend(chars.next()),
+            '.' => path.push(String::new()),
+            c => path.last_mut().unwrap().push(c),
+        }
+    }
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::services::rooms::tests::authenticated_user;
+    use twelf::reexports::serde_json::json;
+
+    #[test]
+    fn test_event_matches() {
+        let room_id: RoomId = "!room:chat.spelt.io".parse().unwrap();
+        let event = json!({
+            "type": "m.room.message",
+            "sender": "@alice:chat.spelt.io",
+            "content": { "body": "Photo", "url": "mxc://chat.spelt.io/abc" },
+        });
+        let filter = |value: Value| serde_json::from_value::<RoomEventFilter>(value).unwrap();
+
+        assert!(room_event_matches(&filter(json!({})), &room_id, &event));
+        assert!(room_event_matches(&filter(json!({ "types": ["m.room.*"] })), &room_id, &event));
+        assert!(room_event_matches(&filter(json!({ "types": ["*.message"] })), &room_id, &event));
+        assert!(!room_event_matches(&filter(json!({ "types": ["m.room.member"] })), &room_id, &event));
+        assert!(!room_event_matches(&filter(json!({ "types": ["*"], "not_types": ["m.*"] })), &room_id, &event));
+        assert!(!room_event_matches(&filter(json!({ "not_senders": ["@alice:chat.spelt.io"] })), &room_id, &event));
+        assert!(!room_event_matches(&filter(json!({ "senders": ["@bob:chat.spelt.io"] })), &room_id, &event));
+        assert!(!room_event_matches(&filter(json!({ "not_rooms": [room_id] })), &room_id, &event));
+        assert!(room_event_matches(&filter(json!({ "rooms": [room_id], "contains_url": true })), &room_id, &event));
+        assert!(!room_event_matches(&filter(json!({ "contains_url": false })), &room_id, &event));
+
+        assert!(type_matches("m.*.*", "m.room.message"));
+        assert!(!type_matches("m.*.member", "m.room.message"));
+        assert!(!type_matches("m.room*e", "m.room"));
+    }
+
+    #[test]
+    fn test_project_event() {
+        let event = json!({
+            "type": "m.room.message",
+            "sender": "@alice:chat.spelt.io",
+            "content": { "body": "Hello", "m.relates_to": { "rel_type": "m.thread" } },
+        });
+        let fields = vec![
+            String::from("type"),
+            String::from("content.body"),
+            String::from("content.m\\.relates_to.rel_type"),
+            String::from("unsigned.age"),
+        ];
+
+        assert_eq!(project_event(event.clone(), None), event);
+        assert_eq!(
+            project_event(event, Some(&fields)),
+            json!({
+                "type": "m.room.message",
+                "content": { "body": "Hello", "m.relates_to": { "rel_type": "m.thread" } },
+            })
+        );
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_get_and_resolve_filter(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let user_id = &alice.matrix_user_id;
+        let definition = json!({ "room": { "timeline": { "limit": 5, "org.example.custom": true } } });
+
+        let result = create_filter(user_id, &definition, &alice, &pool).await.unwrap();
+        let FilterResult::Created(filter_id) = result else { panic!("Filter not created") };
+        let result = get_filter(user_id, &filter_id, &alice, &pool).await.unwrap();
+        assert!(matches!(result, FilterResult::Found(d) if d == definition));
+
+        let filter = resolve_filter(Some(&filter_id), &alice, &pool).await.unwrap().unwrap();
+        assert_eq!(filter.room.timeline.events.limit, Some(5));
+        let filter = resolve_filter(Some(r#"{"presence":{"types":[]}}"#), &alice, &pool).await.unwrap().unwrap();
+        assert_eq!(filter.presence.types, Some(vec![]));
+
+        // Filters are private to the user who stored them.
+        assert!(matches!(get_filter(user_id, &filter_id, &bob, &pool).await.unwrap(), FilterResult::Forbidden));
+        assert!(resolve_filter(Some(&filter_id), &bob, &pool).await.unwrap().is_err());
+
+        let result = create_filter(user_id, &json!({ "room": { "timeline": { "limit": -1 } } }), &alice, &pool).await.unwrap();
+        assert!(matches!(result, FilterResult::Invalid(_)));
+        assert!(matches!(get_filter(user_id, "abc", &alice, &pool).await.unwrap(), FilterResult::NotFound));
+    }
+}
@@ -0,0 +1,494 @@
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::events::Event;
+use crate::models::filter::RoomEventFilter;
+use crate::models::ids::{EventId, RoomId, UserId};
+use crate::models::stream_token::{PaginationToken, TopologicalToken};
+use crate::routes::history::{Direction, MessagesQuery};
+use crate::services::{events, filter, membership};
+use crate::store::pg;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+
+/// Number of events returned by [`get_messages()`] and [`get_context()`]
+/// unless the client asks for another number
+const DEFAULT_LIMIT: usize = 10;
+
+/// Most events returned by [`get_messages()`] and [`get_context()`]
+const MAX_LIMIT: usize = 1000;
+
+/// Possible results of calling [`get_messages()`]
+pub enum MessagesResult {
+    Messages(Messages),
+    /// The user has never been in the room, and it isn't world-readable
+    Forbidden,
+}
+
+/// A page of a room's timeline
+pub struct Messages {
+    pub start: PaginationToken,
+    /// Where to continue from, or `None` if there are no more events
+    pub end: Option<TopologicalToken>,
+    /// The events in the order of pagination
+    pub chunk: Vec<Event>,
+    /// The membership events of the senders in `chunk` if the filter
+    /// lazy-loads members
+    pub state: Vec<Event>,
+}
+
+/// Possible results of calling [`get_context()`]
+pub enum ContextResult {
+    Context(Box<Context>),
+    /// The event doesn't exist in the room, or the user may not see it
+    NotFound,
+}
+
+/// An event with the events around it
+pub struct Context {
+    /// Where to paginate backwards from, before `events_before`
+    pub start: TopologicalToken,
+    /// Where to paginate forwards from, after `events_after`
+    pub end: TopologicalToken,
+    /// Newest first
+    pub events_before: Vec<Event>,
+    pub event: Event,
+    /// Oldest first
+    pub events_after: Vec<Event>,
+    /// The room state at the last event returned
+    pub state: Vec<Event>,
+}
+
+/// Possible results of calling [`get_event()`]
+pub enum EventResult {
+    Found(Box<Event>),
+    /// The event doesn't exist in the room, or the user may not see it
+    NotFound,
+}
+
+/// The events found by [`paginate()`]
+struct Page {
+    events: Vec<Event>,
+    /// The position just past the last event looked at
+    next: TopologicalToken,
+    /// Whether there are no events left in the direction of pagination
+    exhausted: bool,
+}
+
+/// Returns a page of a room's timeline from `query.from`, or from the end of
+/// the room in the direction of pagination, with the events that the user may
+/// see and that pass `filter`
+///
+/// Pages are ordered topologically, then by stream ordering, so a page doesn't
+/// change when events that belong elsewhere in the room's DAG arrive.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidmessages
+pub async fn get_messages(
+    room_id: &RoomId,
+    query: &MessagesQuery,
+    filter: &RoomEventFilter,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<MessagesResult, Error> {
+    let user_id = &auth.matrix_user_id;
+    let membership = membership::membership(room_id, user_id, pool).await?;
+    if membership.is_none() && !is_world_readable(room_id, pool).await? {
+        return Ok(MessagesResult::Forbidden);
+    }
+
+    let forward = query.dir == Direction::Forward;
+    let from = match query.from {
+        Some(token) => resolve_token(room_id, token, pool).await?,
+        None if forward => TopologicalToken::default(),
+        None => pg::events::get_topological_token_at(room_id, i64::MAX, pool).await?.unwrap_or_default(),
+    };
+    let to = match query.to {
+        Some(token) => Some(resolve_token(room_id, token, pool).await?),
+        None => None,
+    };
+    let limit = query.limit.or(filter.events.limit).unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let is_joined = membership.as_deref() == Some("join");
+    let page = paginate(room_id, from, to, forward, limit, filter, user_id, is_joined, pool).await?;
+    let state = match page.events.iter().max_by_key(|e| (e.depth, e.stream_ordering)) {
+        Some(latest) if filter.lazy_load_members => sender_members(&page.events, &latest.event_id, pool).await?,
+        _ => vec![],
+    };
+
+    Ok(MessagesResult::Messages(Messages {
+        start: query.from.unwrap_or(PaginationToken::Topological(from)),
+        end: (!page.exhausted).then_some(page.next),
+        chunk: page.events,
+        state,
+    }))
+}
+
+/// Returns the event with ID `event_id` in a room along with up to `limit`
+/// events around it that pass `filter`, and the room state at the last of them
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidcontexteventid
+pub async fn get_context(
+    room_id: &RoomId,
+    event_id: &EventId,
+    limit: Option<usize>,
+    filter: &RoomEventFilter,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<ContextResult, Error> {
+    let user_id = &auth.matrix_user_id;
+    let is_joined = membership::membership(room_id, user_id, pool).await?.as_deref() == Some("join");
+    let event = match get_visible_event(room_id, event_id, user_id, is_joined, pool).await? {
+        Some(event) => event,
+        None => return Ok(ContextResult::NotFound),
+    };
+
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let before = TopologicalToken { depth: event.depth, stream_ordering: event.stream_ordering - 1 };
+    let after = TopologicalToken { depth: event.depth, stream_ordering: event.stream_ordering };
+    let before = paginate(room_id, before, None, false, limit / 2, filter, user_id, is_joined, pool).await?;
+    let after = paginate(room_id, after, None, true, limit - limit / 2, filter, user_id, is_joined, pool).await?;
+
+    let last_event_id = &after.events.last().unwrap_or(&event).event_id;
+    let state = if filter.lazy_load_members {
+        let events: Vec<Event> = before.events.iter().chain([&event]).chain(&after.events).cloned().collect();
+        sender_members(&events, last_event_id, pool).await?
+    } else {
+        pg::events::get_state_after_event(last_event_id, pool).await?
+    };
+
+    Ok(ContextResult::Context(Box::new(Context {
+        start: before.next,
+        end: after.next,
+        events_before: before.events,
+        event,
+        events_after: after.events,
+        state,
+    })))
+}
+
+/// Returns the event with ID `event_id` in a room if the user may see it
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomideventeventid
+pub async fn get_event(room_id: &RoomId, event_id: &EventId, auth: &AuthenticatedUser, pool: &PgPool) -> Result<EventResult, Error> {
+    let user_id = &auth.matrix_user_id;
+    let is_joined = membership::membership(room_id, user_id, pool).await?.as_deref() == Some("join");
+
+    Ok(match get_visible_event(room_id, event_id, user_id, is_joined, pool).await? {
+        Some(event) => EventResult::Found(Box::new(event)),
+        None => EventResult::NotFound,
+    })
+}
+
+/// Returns up to `limit` events from position `from` in a room, forwards or
+/// backwards, stopping at `to` if given, that `user_id` may see and that pass
+/// `filter`
+///
+/// Events are fetched in batches until enough pass, so that the next page
+/// continues right after the last event that was returned.
+#[allow(clippy::too_many_arguments)]
+async fn paginate(
+    room_id: &RoomId,
+    from: TopologicalToken,
+    to: Option<TopologicalToken>,
+    forward: bool,
+    limit: usize,
+    filter: &RoomEventFilter,
+    user_id: &UserId,
+    is_joined: bool,
+    pool: &PgPool
+) -> Result<Page, Error> {
+    let mut page = Page { events: vec![], next: from, exhausted: false };
+    if limit == 0 {
+        return Ok(page);
+    }
+
+    loop {
+        let batch = pg::events::get_room_events_from(room_id, page.next, to, forward, limit as i64, pool).await?;
+        let visible_ids = visible_event_ids(&batch, user_id, is_joined, pool).await?;
+        let batch_len = batch.len();
+
+        for event in batch {
+            page.next = if forward {
+                TopologicalToken { depth: event.depth, stream_ordering: event.stream_ordering }
+            } else {
+                TopologicalToken { depth: event.depth, stream_ordering: event.stream_ordering - 1 }
+            };
+            if visible_ids.contains(&event.event_id) && filter::room_event_matches(filter, room_id, &events::client_event(&event)) {
+                page.events.push(event);
+                if page.events.len() == limit {
+                    return Ok(page);
+                }
+            }
+        }
+
+        if batch_len < limit {
+            page.exhausted = true;
+            return Ok(page);
+        }
+    }
+}
+
+/// Returns the IDs of the `events` that `user_id` may see, given whether they
+/// are currently joined to the room
+///
+/// Users may see events sent while the history visibility allowed it to their
+/// membership at the time, as well as their own membership events. Events sent
+/// by erased users are hidden from users who weren't joined when the erased
+/// user left the room on deactivation.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#history-visibility
+pub async fn visible_event_ids(events: &[Event], user_id: &UserId, is_joined: bool, pool: &PgPool) -> Result<HashSet<EventId>, Error> {
+    let event_ids: Vec<EventId> = events.iter().map(|e| e.event_id.clone()).collect();
+    let keys = [("m.room.history_visibility", ""), ("m.room.member", user_id.as_str())];
+    let states = pg::events::get_state_events_after(&event_ids, &keys, pool).await?;
+
+    let erasures = erasure_event_ids(events, user_id, pool).await?;
+    let erasure_ids: Vec<EventId> = erasures.values().cloned().collect();
+    let states_at_erasure = pg::events::get_state_events_after(&erasure_ids, &keys[1..], pool).await?;
+
+    let visible = events.iter()
+        .filter(|event| {
+            if event.event_type == "m.room.member" && event.state_key.as_deref() == Some(user_id.as_str()) {
+                return true;
+            }
+
+            if let Some(erasure_id) = erasures.get(&event.sender) {
+                let was_joined = states_at_erasure.get(erasure_id)
+                    .and_then(|state| state.first())
+                    .is_some_and(|member| member.content["membership"] == "join");
+                if !was_joined {
+                    return false;
+                }
+            }
+
+            let state = states.get(&event.event_id).map(Vec::as_slice).unwrap_or_default();
+            let content = |event_type: &str| state.iter().find(|e| e.event_type == event_type).map(|e| &e.content);
+            let visibility = content("m.room.history_visibility")
+                .and_then(|c| c["history_visibility"].as_str())
+                .unwrap_or("shared");
+            let membership = content("m.room.member")
+                .and_then(|c| c["membership"].as_str())
+                .unwrap_or_default();
+
+            match visibility {
+                "world_readable" => true,
+                _ if membership == "join" => true,
+                "shared" => is_joined,
+                "invited" => membership == "invite",
+                _ => false,
+            }
+        })
+        .map(|event| event.event_id.clone())
+        .collect();
+
+    Ok(visible)
+}
+
+/// Returns the current membership event of each erased sender of `events`,
+/// which is the leave that deactivating them sent
+///
+/// Only senders on `user_id`'s server, which is this server, can be erased.
+async fn erasure_event_ids(events: &[Event], user_id: &UserId, pool: &PgPool) -> Result<HashMap<UserId, EventId>, Error> {
+    let senders: HashMap<&UserId, &RoomId> = events.iter()
+        .filter(|e| e.sender != *user_id && e.sender.server_name() == user_id.server_name())
+        .map(|e| (&e.sender, &e.room_id))
+        .collect();
+    if senders.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let names: Vec<&str> = senders.keys().map(|sender| sender.localpart()).collect();
+    let erased_names = pg::auth::get_erased_user_names(&names, pool).await?;
+
+    let mut erasures = HashMap::new();
+    for (sender, room_id) in senders.into_iter().filter(|(sender, _)| erased_names.iter().any(|name| name == sender.localpart())) {
+        if let Some(member) = pg::events::get_current_state_event(room_id, "m.room.member", sender.as_str(), pool).await? {
+            erasures.insert(sender.clone(), member.event_id);
+        }
+    }
+
+    Ok(erasures)
+}
+
+/// Returns the event with ID `event_id` if it's in the room with ID `room_id`
+/// and `user_id` may see it
+async fn get_visible_event(
+    room_id: &RoomId,
+    event_id: &EventId,
+    user_id: &UserId,
+    is_joined: bool,
+    pool: &PgPool
+) -> Result<Option<Event>, Error> {
+    let Some(event) = pg::events::get_event(event_id, pool).await?.filter(|e| e.room_id == *room_id) else {
+        return Ok(None);
+    };
+    let visible_ids = visible_event_ids(std::slice::from_ref(&event), user_id, is_joined, pool).await?;
+
+    Ok(visible_ids.contains(event_id).then_some(event))
+}
+
+/// Returns the membership events, as of the event with ID `at_event_id`, of the
+/// senders of `events`
+async fn sender_members(events: &[Event], at_event_id: &EventId, pool: &PgPool) -> Result<Vec<Event>, Error> {
+    let senders: HashSet<&str> = events.iter().map(|e| e.sender.as_str()).collect();
+    let keys: Vec<(&str, &str)> = senders.into_iter().map(|sender| ("m.room.member", sender)).collect();
+
+    Ok(
+        pg::events::get_state_events_after(std::slice::from_ref(at_event_id), &keys, pool).await?
+            .remove(at_event_id)
+            .unwrap_or_default()
+    )
+}
+
+/// Returns the position in a room's timeline that `token` stands for
+async fn resolve_token(room_id: &RoomId, token: PaginationToken, pool: &PgPool) -> Result<TopologicalToken, Error> {
+    Ok(match token {
+        PaginationToken::Topological(token) => token,
+        PaginationToken::Stream(token) =>
+            pg::events::get_topological_token_at(room_id, token.stream_ordering, pool).await?.unwrap_or_default(),
+    })
+}
+
+async fn is_world_readable(room_id: &RoomId, pool: &PgPool) -> Result<bool, Error> {
+    Ok(
+        pg::events::get_current_state_event(room_id, "m.room.history_visibility", "", pool).await?
+            .is_some_and(|e| e.content["history_visibility"] == "world_readable")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::stream_token::StreamToken;
+    use crate::services::rooms::tests::{authenticated_user, create};
+    use crate::services::rooms::{ClientEvent, SendEventResult};
+    use crate::services::signing::SigningKeys;
+    use twelf::reexports::serde_json::{self, json};
+
+    fn query(dir: Direction, from: Option<PaginationToken>, limit: usize) -> MessagesQuery {
+        MessagesQuery { dir, from, limit: Some(limit), ..Default::default() }
+    }
+
+    async fn send_message(room_id: &RoomId, body: &str, auth: &AuthenticatedUser, config: &Config, pool: &PgPool) -> EventId {
+        let event = ClientEvent {
+            event_type: String::from("m.room.message"),
+            state_key: None,
+            content: json!({ "msgtype": "m.text", "body": body }),
+        };
+        match crate::services::rooms::send_event(room_id, event, None, auth, config, &SigningKeys::test(), pool).await.unwrap() {
+            SendEventResult::Sent(event_id) => event_id,
+            _ => panic!("Expected event to be sent"),
+        }
+    }
+
+    async fn messages(room_id: &RoomId, query: &MessagesQuery, filter: &RoomEventFilter, auth: &AuthenticatedUser, pool: &PgPool) -> Messages {
+        match get_messages(room_id, query, filter, auth, pool).await.unwrap() {
+            MessagesResult::Messages(messages) => messages,
+            MessagesResult::Forbidden => panic!("Expected messages"),
+        }
+    }
+
+    fn bodies(events: &[Event]) -> Vec<&str> {
+        events.iter().filter_map(|e| e.content["body"].as_str()).collect()
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_get_messages(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let room_id = create(json!({ "preset": "public_chat" }), &alice, &config, &pool).await[0].room_id.clone();
+        let mut event_ids = vec![];
+        for i in 0..5 {
+            event_ids.push(send_message(&room_id, &i.to_string(), &alice, &config, &pool).await);
+        }
+        let only_messages: RoomEventFilter = serde_json::from_value(json!({ "types": ["m.room.message"] })).unwrap();
+
+        // Pages continue where the previous one ended.
+        let page = messages(&room_id, &query(Direction::Backward, None, 3), &only_messages, &alice, &pool).await;
+        assert_eq!(bodies(&page.chunk), vec!["4", "3", "2"]);
+        let from = page.end.map(PaginationToken::Topological);
+        let page = messages(&room_id, &query(Direction::Backward, from, 3), &only_messages, &alice, &pool).await;
+        assert_eq!(bodies(&page.chunk), vec!["1", "0"]);
+        assert!(page.end.is_none());
+
+        let page = messages(&room_id, &query(Direction::Forward, None, 10), &only_messages, &alice, &pool).await;
+        assert_eq!(bodies(&page.chunk), vec!["0", "1", "2", "3", "4"]);
+
+        // A sync token stands for the position after the events it covers.
+        let event = pg::events::get_event(&event_ids[2], &pool).await.unwrap().unwrap();
+        let from = PaginationToken::Stream(StreamToken { stream_ordering: event.stream_ordering });
+        let page = messages(&room_id, &query(Direction::Backward, Some(from), 2), &only_messages, &alice, &pool).await;
+        assert_eq!(bodies(&page.chunk), vec!["2", "1"]);
+        assert_eq!(page.start, from);
+
+        let lazy: RoomEventFilter = serde_json::from_value(json!({ "lazy_load_members": true })).unwrap();
+        let page = messages(&room_id, &query(Direction::Backward, None, 1), &lazy, &alice, &pool).await;
+        assert_eq!(page.state.len(), 1);
+        assert_eq!(page.state[0].state_key.as_deref(), Some(alice.matrix_user_id.as_str()));
+
+        let ContextResult::Context(context) = get_context(&room_id, &event_ids[2], Some(4), &only_messages, &alice, &pool).await.unwrap() else {
+            panic!("Expected context");
+        };
+        assert_eq!(bodies(&context.events_before), vec!["1", "0"]);
+        assert_eq!(bodies(&context.events_after), vec!["3", "4"]);
+        assert!(context.state.iter().any(|e| e.event_type == "m.room.create"));
+        let from = Some(PaginationToken::Topological(context.start));
+        let page = messages(&room_id, &query(Direction::Backward, from, 10), &only_messages, &alice, &pool).await;
+        assert!(page.chunk.is_empty());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_history_visibility(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let events = create(json!({
+            "preset": "public_chat",
+            "initial_state": [
+                { "type": "m.room.history_visibility", "content": { "history_visibility": "joined" } }
+            ]
+        }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let before_id = send_message(room_id, "Before", &alice, &config, &pool).await;
+        membership::join_room(room_id.as_str(), None, &bob, &config, &SigningKeys::test(), &pool).await.unwrap();
+        let after_id = send_message(room_id, "After", &alice, &config, &pool).await;
+
+        // Bob only sees what was sent once Bob joined, and what was sent before
+        // the history visibility was set, which defaults to shared.
+        let page = messages(room_id, &query(Direction::Backward, None, 10), &RoomEventFilter::default(), &bob, &pool).await;
+        assert_eq!(bodies(&page.chunk), vec!["After"]);
+        assert_eq!(page.chunk[1].state_key.as_deref(), Some(bob.matrix_user_id.as_str()));
+        assert!(!page.chunk.iter().any(|e| e.event_type == "m.room.history_visibility"));
+        assert_eq!(page.chunk.last().unwrap().event_type, "m.room.create");
+        assert!(matches!(get_event(room_id, &before_id, &bob, &pool).await.unwrap(), EventResult::NotFound));
+        assert!(matches!(get_event(room_id, &after_id, &bob, &pool).await.unwrap(), EventResult::Found(_)));
+        assert!(matches!(get_event(room_id, &before_id, &alice, &pool).await.unwrap(), EventResult::Found(_)));
+
+        // Carol has never been in the room.
+        assert!(matches!(get_messages(room_id, &query(Direction::Backward, None, 10), &RoomEventFilter::default(), &carol, &pool).await.unwrap(), MessagesResult::Forbidden));
+        assert!(matches!(get_context(room_id, &after_id, None, &RoomEventFilter::default(), &carol, &pool).await.unwrap(), ContextResult::NotFound));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_erased_user_history(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let room_id = create(json!({ "preset": "public_chat" }), &bob, &config, &pool).await[0].room_id.clone();
+        membership::join_room(room_id.as_str(), None, &alice, &config, &SigningKeys::test(), &pool).await.unwrap();
+        let hello_id = send_message(&room_id, "Hello", &alice, &config, &pool).await;
+
+        crate::services::account::deactivate(alice.user_id, true, &config, &SigningKeys::test(), &pool).await.unwrap();
+        membership::join_room(room_id.as_str(), None, &carol, &config, &SigningKeys::test(), &pool).await.unwrap();
+
+        // Bob was in the room when Alice was erased, but Carol joined after.
+        let page = messages(&room_id, &query(Direction::Backward, None, 20), &RoomEventFilter::default(), &bob, &pool).await;
+        assert_eq!(bodies(&page.chunk), vec!["Hello"]);
+        let page = messages(&room_id, &query(Direction::Backward, None, 20), &RoomEventFilter::default(), &carol, &pool).await;
+        assert!(bodies(&page.chunk).is_empty());
+        assert!(!page.chunk.iter().any(|e| e.sender == alice.matrix_user_id));
+        assert!(matches!(get_event(&room_id, &hello_id, &carol, &pool).await.unwrap(), EventResult::NotFound));
+    }
+}
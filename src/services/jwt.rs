@@ -1,79 +1,420 @@
+use crate::config::Config;
 use crate::error::Error;
+use crate::models::auth::Session;
 use chrono::Utc;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 
-pub const JWT_TTL_SECONDS: u64 = 600;
+/// Size in bits of generated RSA keys
+const KEY_BITS: usize = 2048;
+
+/// File name suffixes of the private and public halves of a key pair
+const PRIVATE_KEY_SUFFIX: &str = ".pem";
+const PUBLIC_KEY_SUFFIX: &str = ".pub.pem";
 
 /// The fields ("claims") encoded in a JWT
+///
+/// `sub` is the UUID of the `Session` and `device_id` its device identifier.
+/// `iss` is the configured issuer and `aud` the server's base URL, so tokens
+/// minted by one server are never accepted by another.
+///
+/// `exp` is omitted for clients that don't use refresh tokens, whose access
+/// tokens are valid until the session is logged out.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JwtClaims {
     pub sub: String,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
+    pub device_id: String,
     pub iat: u64,
     pub nbf: u64,
-    pub exp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+/// The keys and settings used to sign and validate JWTs, loaded once at
+/// startup
+///
+/// Keys live in the directory given by `key_dir` in the `[jwt]` config
+/// section as pairs of PEM files named after their key ID (`kid`):
+/// `<kid>.pem` holds the private key and `<kid>.pub.pem` the public key. Key
+/// IDs are UTC timestamps, so the newest private key signs new tokens, while
+/// tokens signed with any key whose public half is still present remain
+/// valid. This lets keys be rotated without logging everyone out.
+#[derive(Clone)]
+pub struct KeyManager {
+    signing_kid: String,
+    signing_key: EncodingKey,
+    verification_keys: HashMap<String, DecodingKey>,
+    issuer: String,
+    audience: String,
+    ttl_seconds: u64,
+}
+
+impl KeyManager {
+    pub fn load(config: &Config) -> Result<Self, Error> {
+        Self::load_dir(Path::new(&config.jwt.key_dir), config)
+    }
+
+    fn load_dir(key_dir: &Path, config: &Config) -> Result<Self, Error> {
+        let signing_kid = signing_key_ids(key_dir)?
+            .pop()
+            .ok_or_else(|| Error::Config(format!(
+                "No JWT signing key found in {}; run `spelt keys generate` to create one",
+                key_dir.display()
+            )))?;
+        let signing_key = EncodingKey::from_rsa_pem(&read_key_file(key_dir, &signing_kid, PRIVATE_KEY_SUFFIX)?)
+            .map_err(|e| Error::Config(format!("Invalid JWT signing key {}: {}", signing_kid, e)))?;
+
+        let mut verification_keys = HashMap::new();
+        for kid in key_ids(key_dir, PUBLIC_KEY_SUFFIX)? {
+            let key = DecodingKey::from_rsa_pem(&read_key_file(key_dir, &kid, PUBLIC_KEY_SUFFIX)?)
+                .map_err(|e| Error::Config(format!("Invalid JWT verification key {}: {}", kid, e)))?;
+            verification_keys.insert(kid, key);
+        }
+
+        if !verification_keys.contains_key(&signing_kid) {
+            return Err(Error::Config(format!("No public key found for JWT signing key {}", signing_kid)));
+        }
+
+        Ok(KeyManager {
+            signing_kid,
+            signing_key,
+            verification_keys,
+            issuer: config.jwt.issuer.clone(),
+            audience: config.server.base_url.clone(),
+            ttl_seconds: config.jwt.access_token_ttl_seconds,
+        })
+    }
+
+    /// Lifetime of access tokens issued to clients that use refresh tokens
+    pub fn ttl_seconds(&self) -> u64 {
+        self.ttl_seconds
+    }
+
+    /// Returns a key manager holding a single key pair, generated once per
+    /// test run since RSA key generation is slow
+    #[cfg(test)]
+    pub fn test() -> Self {
+        static KEYS: std::sync::OnceLock<KeyManager> = std::sync::OnceLock::new();
+
+        KEYS.get_or_init(|| {
+            let key_dir = std::env::temp_dir().join(format!("spelt-test-keys-{}", uuid::Uuid::new_v4().simple()));
+            generate_key(&key_dir).unwrap();
+            let keys = KeyManager::load_dir(&key_dir, &Config::test()).unwrap();
+            fs::remove_dir_all(&key_dir).unwrap();
+            keys
+        }).clone()
+    }
+}
+
+impl fmt::Debug for KeyManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut verification_kids: Vec<&String> = self.verification_keys.keys().collect();
+        verification_kids.sort();
+
+        f.debug_struct("KeyManager")
+            .field("signing_kid", &self.signing_kid)
+            .field("verification_kids", &verification_kids)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("ttl_seconds", &self.ttl_seconds)
+            .finish()
+    }
+}
+
+/// Returns the IDs of the keys in `key_dir` that have a private key, oldest
+/// first; the last one is used for signing
+pub fn signing_key_ids(key_dir: &Path) -> Result<Vec<String>, Error> {
+    key_ids(key_dir, PRIVATE_KEY_SUFFIX)
+}
+
+/// Returns the IDs of the keys in `key_dir` that have a public key, oldest
+/// first
+pub fn verification_key_ids(key_dir: &Path) -> Result<Vec<String>, Error> {
+    key_ids(key_dir, PUBLIC_KEY_SUFFIX)
+}
+
+/// Generates a new key pair in `key_dir`, creating the directory if needed,
+/// and returns its key ID
+pub fn generate_key(key_dir: &Path) -> Result<String, Error> {
+    let kid = Utc::now().format("%Y%m%d%H%M%S").to_string();
+
+    let private_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, KEY_BITS)
+        .map_err(|e| Error::Config(format!("Failed to generate key: {}", e)))?;
+    let private_pem = private_key.to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| Error::Config(format!("Failed to encode private key: {}", e)))?;
+    let public_pem = RsaPublicKey::from(&private_key).to_public_key_pem(LineEnding::LF)
+        .map_err(|e| Error::Config(format!("Failed to encode public key: {}", e)))?;
+
+    fs::create_dir_all(key_dir)?;
+    write_key_file(key_dir, &kid, PRIVATE_KEY_SUFFIX, private_pem.as_bytes(), 0o600)?;
+    write_key_file(key_dir, &kid, PUBLIC_KEY_SUFFIX, public_pem.as_bytes(), 0o644)?;
+
+    Ok(kid)
 }
 
-pub fn create_jwt(session_uuid: &String, now_offset: i64) -> Result<String, Error> {
+/// Generates a new signing key and deletes the private keys it replaces
+///
+/// The old public keys are kept so that tokens they signed remain valid.
+/// Returns the ID of the new key.
+pub fn rotate_keys(key_dir: &Path) -> Result<String, Error> {
+    let old_kids = signing_key_ids(key_dir)?;
+    let kid = generate_key(key_dir)?;
+
+    for old_kid in old_kids {
+        fs::remove_file(key_dir.join(format!("{}{}", old_kid, PRIVATE_KEY_SUFFIX)))?;
+    }
+
+    Ok(kid)
+}
+
+fn key_ids(key_dir: &Path, suffix: &str) -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(key_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut kids = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        if let Some(kid) = file_name.to_str().and_then(|name| name.strip_suffix(suffix)) {
+            // Skips public keys when listing private ones, since ".pem" is a
+            // suffix of both.
+            if !kid.is_empty() && !kid.contains('.') {
+                kids.push(kid.to_string());
+            }
+        }
+    }
+    kids.sort();
+
+    Ok(kids)
+}
+
+fn read_key_file(key_dir: &Path, kid: &str, suffix: &str) -> Result<Vec<u8>, Error> {
+    let path = key_dir.join(format!("{}{}", kid, suffix));
+    fs::read(&path).map_err(|e| Error::Config(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+fn write_key_file(key_dir: &Path, kid: &str, suffix: &str, pem: &[u8], mode: u32) -> Result<(), Error> {
+    let path = key_dir.join(format!("{}{}", kid, suffix));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&path)
+        .map_err(|e| Error::Config(format!("Failed to create {}: {}", path.display(), e)))?;
+    file.write_all(pem)?;
+
+    Ok(())
+}
+
+/// Creates an access token for `session`, which expires after the configured
+/// TTL if `expires` is set
+pub fn create_jwt(keys: &KeyManager, session: &Session, now_offset: i64, expires: bool) -> Result<String, Error> {
     let now: u64 = (Utc::now().timestamp() + now_offset) as u64;
     let claims = JwtClaims {
-        sub: session_uuid.into(),
+        sub: session.uuid.to_string(),
+        iss: keys.issuer.clone(),
+        aud: keys.audience.clone(),
+        jti: uuid::Uuid::new_v4().to_string(),
+        device_id: session.device_identifier.to_string(),
         iat: now,
         nbf: now,
-        exp: now + JWT_TTL_SECONDS
+        exp: expires.then_some(now + keys.ttl_seconds),
     };
 
-    // The path to the PEM file is relative to this source file and is loaded at
-    // compile time.
-    Ok(encode(
-        &Header::new(Algorithm::RS256),
-        &claims,
-        &EncodingKey::from_rsa_pem(include_bytes!("../../config/pkey.pem"))?
-    )?)
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(keys.signing_kid.clone());
+
+    Ok(encode(&header, &claims, &keys.signing_key)?)
 }
 
-pub fn validate_jwt(jwt: &String) -> Result<JwtClaims, Error> {
-    Ok(
-        decode::<JwtClaims>(
-            &jwt,
-            &DecodingKey::from_rsa_pem(include_bytes!("../../config/public.pem")).unwrap(),
-            &Validation::new(Algorithm::RS256)
-        )?
-        .claims
-    )
+/// Validates the JWT signature, the `iss` and `aud` claims and, if present,
+/// the `exp` claim
+///
+/// The signature is checked against the key named by the `kid` header. Tokens
+/// without one are checked against every known key.
+pub fn validate_jwt(keys: &KeyManager, jwt: &str) -> Result<JwtClaims, Error> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_required_spec_claims(&["sub", "iss", "aud"]);
+    validation.set_issuer(&[&keys.issuer]);
+    validation.set_audience(&[&keys.audience]);
+
+    match decode_header(jwt)?.kid {
+        Some(kid) => {
+            let key = keys.verification_keys.get(&kid)
+                .ok_or_else(|| Error::Auth(format!("Unknown signing key: {}", kid)))?;
+
+            Ok(decode::<JwtClaims>(jwt, key, &validation)?.claims)
+        },
+        None => keys.verification_keys.values()
+            .find_map(|key| decode::<JwtClaims>(jwt, key, &validation).ok())
+            .map(|data| data.claims)
+            .ok_or_else(|| Error::Auth(String::from("Invalid token"))),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::ids::DeviceId;
+    use std::path::PathBuf;
 
     #[test]
     fn test_create_jwt() {
-        let session_uuid = uuid::Uuid::new_v4().to_string();
-        let jwt = create_jwt(&session_uuid, 0).unwrap();
+        let jwt = create_jwt(&KeyManager::test(), &test_session(), 0, true).unwrap();
 
-        println!("{}", jwt);
-        assert!(jwt.len() > 0);
+        assert!(!jwt.is_empty());
     }
 
     #[test]
     fn test_validate_jwt() {
-        let session_uuid = uuid::Uuid::new_v4().to_string();
-        let jwt = create_jwt(&session_uuid, 0).unwrap();
-        let result = validate_jwt(&jwt);
+        let keys = KeyManager::test();
+        let session = test_session();
+        let jwt = create_jwt(&keys, &session, 0, true).unwrap();
+        let result = validate_jwt(&keys, &jwt);
+
+        let claims = result.unwrap();
+        assert_eq!(claims.sub, session.uuid.to_string());
+        assert_eq!(claims.device_id, session.device_identifier.as_str());
+        assert_eq!(claims.exp, Some(claims.iat + keys.ttl_seconds()));
+    }
+
+    #[test]
+    fn test_validate_jwt_without_expiration() {
+        let keys = KeyManager::test();
+        let jwt = create_jwt(&keys, &test_session(), -(keys.ttl_seconds() as i64) * 10, false).unwrap();
+        let result = validate_jwt(&keys, &jwt);
 
-        println!("{:?}", result);
         assert!(result.is_ok());
+        assert!(result.unwrap().exp.is_none());
+    }
+
+    #[test]
+    fn test_validate_jwt_with_expired() {
+        let keys = KeyManager::test();
+        let jwt = create_jwt(&keys, &test_session(), -(keys.ttl_seconds() as i64) - 300, true).unwrap();
+
+        assert!(validate_jwt(&keys, &jwt).is_err());
     }
 
     #[test]
     fn test_validate_jwt_with_bad_signature() {
-        let session_uuid = uuid::Uuid::new_v4().to_string();
-        let mut jwt = create_jwt(&session_uuid, 0).unwrap();
-        jwt.push_str("x");
-        let result = validate_jwt(&jwt);
+        let keys = KeyManager::test();
+        let mut jwt = create_jwt(&keys, &test_session(), 0, true).unwrap();
+        jwt.push('x');
+
+        assert!(validate_jwt(&keys, &jwt).is_err());
+    }
+
+    #[test]
+    fn test_validate_jwt_with_other_issuer() {
+        let keys = KeyManager::test();
+        let mut staging_keys = keys.clone();
+        staging_keys.issuer = String::from("https://staging.example.org");
+        let jwt = create_jwt(&staging_keys, &test_session(), 0, true).unwrap();
+
+        assert!(validate_jwt(&keys, &jwt).is_err());
+    }
+
+    #[test]
+    fn test_validate_jwt_with_other_audience() {
+        let keys = KeyManager::test();
+        let mut staging_keys = keys.clone();
+        staging_keys.audience = String::from("https://staging.example.org/");
+        let jwt = create_jwt(&staging_keys, &test_session(), 0, true).unwrap();
+
+        assert!(validate_jwt(&keys, &jwt).is_err());
+    }
+
+    #[test]
+    fn test_validate_jwt_with_unknown_kid() {
+        let keys = KeyManager::test();
+        let mut other_keys = keys.clone();
+        other_keys.signing_kid = String::from("19700101000000");
+        let jwt = create_jwt(&other_keys, &test_session(), 0, false).unwrap();
+
+        assert!(validate_jwt(&keys, &jwt).is_err());
+    }
+
+    #[test]
+    fn test_validate_jwt_without_kid() {
+        let keys = KeyManager::test();
+        let claims = JwtClaims {
+            sub: uuid::Uuid::new_v4().to_string(),
+            iss: keys.issuer.clone(),
+            aud: keys.audience.clone(),
+            jti: uuid::Uuid::new_v4().to_string(),
+            device_id: String::from("DEVICE"),
+            iat: 0,
+            nbf: 0,
+            exp: None,
+        };
+        let jwt = encode(&Header::new(Algorithm::RS256), &claims, &keys.signing_key).unwrap();
+
+        assert!(validate_jwt(&keys, &jwt).is_ok());
+    }
+
+    #[test]
+    fn test_jwt_ids_are_unique() {
+        let keys = KeyManager::test();
+        let session = test_session();
+        let claims_1 = validate_jwt(&keys, &create_jwt(&keys, &session, 0, true).unwrap()).unwrap();
+        let claims_2 = validate_jwt(&keys, &create_jwt(&keys, &session, 0, true).unwrap()).unwrap();
+
+        assert_ne!(claims_1.jti, claims_2.jti);
+    }
+
+    #[test]
+    fn test_load_without_keys() {
+        let key_dir = test_key_dir();
+
+        assert!(matches!(KeyManager::load_dir(&key_dir, &Config::test()), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let config = Config::test();
+        let key_dir = test_key_dir();
+        let old_kid = generate_key(&key_dir).unwrap();
+        let old_keys = KeyManager::load_dir(&key_dir, &config).unwrap();
+        let jwt = create_jwt(&old_keys, &test_session(), 0, false).unwrap();
+
+        // Key IDs have one-second resolution.
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let new_kid = rotate_keys(&key_dir).unwrap();
+        let new_keys = KeyManager::load_dir(&key_dir, &config).unwrap();
+
+        assert_ne!(old_kid, new_kid);
+        assert_eq!(signing_key_ids(&key_dir).unwrap(), vec![new_kid.clone()]);
+        assert_eq!(verification_key_ids(&key_dir).unwrap(), vec![old_kid, new_kid.clone()]);
+        assert_eq!(new_keys.signing_kid, new_kid);
+        assert!(validate_jwt(&new_keys, &jwt).is_ok());
+
+        fs::remove_dir_all(&key_dir).unwrap();
+    }
+
+    fn test_session() -> Session {
+        Session {
+            id: 1,
+            uuid: uuid::Uuid::new_v4(),
+            device_identifier: DeviceId::generate(),
+            user_id: 1,
+        }
+    }
 
-        println!("{:?}", result);
-        assert!(result.is_err());
+    fn test_key_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("spelt-test-keys-{}", uuid::Uuid::new_v4().simple()))
     }
 }
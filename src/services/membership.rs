@@ -0,0 +1,638 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::events::Event;
+use crate::models::ids::{RoomAliasId, RoomId, UserId};
+use crate::routes::membership::{MembersQuery, MembershipRequest};
+use crate::models::room_version;
+use crate::services::event_auth;
+use crate::services::rooms::{self, ClientEvent, SendEventResult};
+use crate::services::signing::SigningKeys;
+use crate::store::pg;
+use sqlx::PgPool;
+use twelf::reexports::log;
+use twelf::reexports::serde_json::{json, Value};
+
+/// State event types included in stripped state, besides the user's own
+/// membership
+const STRIPPED_STATE_TYPES: [&str; 7] = [
+    "m.room.avatar",
+    "m.room.canonical_alias",
+    "m.room.create",
+    "m.room.encryption",
+    "m.room.join_rules",
+    "m.room.name",
+    "m.room.topic",
+];
+
+/// Possible results of changing a user's membership of a room
+pub enum MembershipResult {
+    /// The membership event was sent to the room with this ID
+    Sent(RoomId),
+    RoomNotFound,
+    /// `room_id_or_alias` is neither a room ID nor a room alias
+    InvalidRoomIdOrAlias,
+    Forbidden(String),
+}
+
+/// Possible results of calling [`forget_room()`]
+pub enum ForgetResult {
+    Forgotten,
+    /// The user hasn't left the room
+    StillInRoom,
+}
+
+/// Possible results of calling [`get_members()`] or [`get_joined_members()`]
+pub enum MembersResult {
+    /// The room's `m.room.member` events
+    Members(Vec<Event>),
+    /// The user isn't allowed to see the room's members
+    Forbidden,
+}
+
+/// A change that one user makes to the membership of another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MembershipAction {
+    Invite,
+    Kick,
+    Ban,
+    Unban,
+}
+
+impl MembershipAction {
+    fn membership(&self) -> &'static str {
+        match self {
+            MembershipAction::Kick | MembershipAction::Unban => "leave",
+            MembershipAction::Invite => "invite",
+            MembershipAction::Ban => "ban",
+        }
+    }
+}
+
+/// Joins the room with ID or alias `room_id_or_alias` and returns
+/// `Ok(MembershipResult::Sent(room_id))`
+///
+/// Only rooms known to this server can be joined. Joining a room the user had
+/// forgotten makes it visible again.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3joinroomidoralias
+pub async fn join_room(
+    room_id_or_alias: &str,
+    reason: Option<String>,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<MembershipResult, Error> {
+    let room_id = match resolve_room_id(room_id_or_alias, pool).await? {
+        Ok(room_id) => room_id,
+        Err(result) => return Ok(result),
+    };
+
+    let mut content = membership_content("join", reason);
+    if let Some(authoriser) = join_authoriser(&room_id, &auth.matrix_user_id, config, pool).await? {
+        content["join_authorised_via_users_server"] = Value::String(authoriser.to_string());
+    }
+
+    let result = send_membership(&room_id, &auth.matrix_user_id, content, auth, config, keys, pool).await?;
+    if let MembershipResult::Sent(ref room_id) = result {
+        pg::rooms::set_room_forgotten(&auth.matrix_user_id, room_id, false, pool).await?;
+    }
+
+    Ok(result)
+}
+
+/// Knocks on the room with ID or alias `room_id_or_alias`, asking its
+/// moderators for an invite, and returns `Ok(MembershipResult::Sent(room_id))`
+///
+/// The room's join rule must be `knock` or `knock_restricted`. Moderators
+/// accept a knock by inviting the user and deny it by kicking them.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3knockroomidoralias
+pub async fn knock_room(
+    room_id_or_alias: &str,
+    reason: Option<String>,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<MembershipResult, Error> {
+    let room_id = match resolve_room_id(room_id_or_alias, pool).await? {
+        Ok(room_id) => room_id,
+        Err(result) => return Ok(result),
+    };
+
+    let content = membership_content("knock", reason);
+    let result = send_membership(&room_id, &auth.matrix_user_id, content, auth, config, keys, pool).await?;
+    if let MembershipResult::Sent(ref room_id) = result {
+        pg::rooms::set_room_forgotten(&auth.matrix_user_id, room_id, false, pool).await?;
+    }
+
+    Ok(result)
+}
+
+/// Leaves a room, rejects an invite to it or rescinds a knock on it
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidleave
+pub async fn leave_room(
+    room_id: &RoomId,
+    reason: Option<String>,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<MembershipResult, Error> {
+    let content = membership_content("leave", reason);
+    send_membership(room_id, &auth.matrix_user_id, content, auth, config, keys, pool).await
+}
+
+/// Leaves every room that `user_id` has joined, been invited to or knocked on,
+/// on their behalf
+///
+/// Rooms whose authorization rules don't allow the user to leave are skipped.
+pub async fn leave_all_rooms(user_id: &UserId, config: &Config, keys: &SigningKeys, pool: &PgPool) -> Result<(), Error> {
+    for membership in ["join", "invite", "knock"] {
+        for room_id in pg::rooms::get_room_ids_by_membership(user_id, membership, pool).await? {
+            let event = ClientEvent {
+                event_type: String::from("m.room.member"),
+                state_key: Some(user_id.to_string()),
+                content: membership_content("leave", None),
+            };
+            if let SendEventResult::Forbidden(rejection) = rooms::send_event_as(&room_id, event, user_id, config, keys, pool).await? {
+                log::warn!("{} could not leave {}: {}", user_id, room_id, rejection);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Changes the membership of `request.user_id` in a room and returns
+/// `Ok(MembershipResult::Sent(room_id))`
+///
+/// Besides the room's authorization rules, only banned users can be unbanned
+/// and only users in the room, invited to it or knocking on it can be kicked.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#room-membership
+pub async fn change_membership(
+    room_id: &RoomId,
+    action: MembershipAction,
+    request: &MembershipRequest,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<MembershipResult, Error> {
+    let target_membership = membership(room_id, &request.user_id, pool).await?;
+    match action {
+        MembershipAction::Unban if target_membership.as_deref() != Some("ban") =>
+            return Ok(MembershipResult::Forbidden(String::from("User is not banned"))),
+        MembershipAction::Kick if !matches!(target_membership.as_deref(), Some("join" | "invite" | "knock")) =>
+            return Ok(MembershipResult::Forbidden(String::from("User is not in the room"))),
+        _ => (),
+    }
+
+    let content = membership_content(action.membership(), request.reason.clone());
+    send_membership(room_id, &request.user_id, content, auth, config, keys, pool).await
+}
+
+/// Forgets a room that the user has left, so that it's no longer returned
+/// with their other rooms
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidforget
+pub async fn forget_room(room_id: &RoomId, auth: &AuthenticatedUser, pool: &PgPool) -> Result<ForgetResult, Error> {
+    match membership(room_id, &auth.matrix_user_id, pool).await?.as_deref() {
+        Some("join" | "invite" | "knock") => Ok(ForgetResult::StillInRoom),
+        _ => {
+            if pg::rooms::get_room(room_id, pool).await?.is_some() {
+                pg::rooms::set_room_forgotten(&auth.matrix_user_id, room_id, true, pool).await?;
+            }
+            Ok(ForgetResult::Forgotten)
+        }
+    }
+}
+
+/// Returns the IDs of the rooms the user has joined
+pub async fn joined_rooms(auth: &AuthenticatedUser, pool: &PgPool) -> Result<Vec<RoomId>, Error> {
+    pg::rooms::get_room_ids_by_membership(&auth.matrix_user_id, "join", pool).await
+}
+
+/// Returns the `m.room.member` events of a room, as of `query.at` if given,
+/// filtered by membership
+///
+/// Users who have left the room see its members as they were when they left.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidmembers
+pub async fn get_members(room_id: &RoomId, query: &MembersQuery, auth: &AuthenticatedUser, pool: &PgPool) -> Result<MembersResult, Error> {
+    let membership_event = pg::events::get_current_state_event(room_id, "m.room.member", auth.matrix_user_id.as_str(), pool).await?;
+    let membership = membership_event.as_ref().and_then(|e| e.content["membership"].as_str());
+
+    let state = match (membership, query.at) {
+        (Some("join"), None) => pg::events::get_current_state(room_id, pool).await?,
+        (Some("join"), Some(at)) => match pg::events::get_latest_event_id_at(room_id, at.stream_ordering, pool).await? {
+            Some(event_id) => pg::events::get_state_after_event(&event_id, pool).await?,
+            None => vec![],
+        },
+        (Some("leave" | "ban"), _) =>
+            pg::events::get_state_after_event(&membership_event.as_ref().unwrap().event_id, pool).await?,
+        _ => return Ok(MembersResult::Forbidden),
+    };
+
+    let members = state.into_iter()
+        .filter(|e| e.event_type == "m.room.member")
+        .filter(|e| {
+            let membership = e.content["membership"].as_str().unwrap_or_default();
+            query.membership.as_ref().is_none_or(|m| m == membership)
+                && query.not_membership.as_ref().is_none_or(|m| m != membership)
+        })
+        .collect();
+
+    Ok(MembersResult::Members(members))
+}
+
+/// Returns the `m.room.member` events of the users who have joined a room,
+/// which only members of the room may see
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3roomsroomidjoined_members
+pub async fn get_joined_members(room_id: &RoomId, auth: &AuthenticatedUser, pool: &PgPool) -> Result<MembersResult, Error> {
+    if membership(room_id, &auth.matrix_user_id, pool).await?.as_deref() != Some("join") {
+        return Ok(MembersResult::Forbidden);
+    }
+
+    let members = pg::events::get_current_state(room_id, pool).await?
+        .into_iter()
+        .filter(|e| e.event_type == "m.room.member" && e.content["membership"] == "join")
+        .collect();
+
+    Ok(MembersResult::Members(members))
+}
+
+/// Returns the stripped state events that describe a room to `user_id` while
+/// they're invited to it or knocking on it, including their own membership
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#stripped-state
+pub async fn stripped_state(room_id: &RoomId, user_id: &UserId, pool: &PgPool) -> Result<Vec<Value>, Error> {
+    Ok(
+        pg::events::get_current_state_json(room_id, pool).await?
+            .into_iter()
+            .filter(|e| {
+                let event_type = e["type"].as_str().unwrap_or_default();
+                STRIPPED_STATE_TYPES.contains(&event_type)
+                    || (event_type == "m.room.member" && e["state_key"] == user_id.as_str())
+            })
+            .map(|e| json!({
+                "content": e["content"],
+                "sender": e["sender"],
+                "state_key": e["state_key"],
+                "type": e["type"],
+            }))
+            .collect()
+    )
+}
+
+/// Resolves `room_id_or_alias` to a room ID, or returns `Err` with the result
+/// to respond with if it can't be
+async fn resolve_room_id(room_id_or_alias: &str, pool: &PgPool) -> Result<Result<RoomId, MembershipResult>, Error> {
+    if room_id_or_alias.starts_with('#') {
+        let Ok(alias) = room_id_or_alias.parse::<RoomAliasId>() else {
+            return Ok(Err(MembershipResult::InvalidRoomIdOrAlias));
+        };
+        Ok(pg::rooms::get_room_id_for_alias(&alias, pool).await?.ok_or(MembershipResult::RoomNotFound))
+    } else {
+        Ok(room_id_or_alias.parse::<RoomId>().map_err(|_| MembershipResult::InvalidRoomIdOrAlias))
+    }
+}
+
+/// Returns a local user who can authorise `user_id` to join a room with the
+/// `restricted` or `knock_restricted` join rule, if `user_id` needs one and is
+/// in a room that the join rule allows
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#restricted-rooms
+async fn join_authoriser(room_id: &RoomId, user_id: &UserId, config: &Config, pool: &PgPool) -> Result<Option<UserId>, Error> {
+    let Some(room) = pg::rooms::get_room(room_id, pool).await? else {
+        return Ok(None);
+    };
+    let Some(room_version) = room_version::get(&room.room_version) else {
+        return Ok(None);
+    };
+
+    let state_events = pg::events::get_current_state_json(room_id, pool).await?;
+    let state = event_auth::state_map(&state_events);
+    let Some(join_rules) = state.get(&(String::from("m.room.join_rules"), String::new())) else {
+        return Ok(None);
+    };
+    let restricted = match join_rules["content"]["join_rule"].as_str() {
+        Some("restricted") => room_version.restricted_joins,
+        Some("knock_restricted") => room_version.knock_restricted,
+        _ => false,
+    };
+    if !restricted {
+        return Ok(None);
+    }
+
+    let membership = state.get(&(String::from("m.room.member"), user_id.to_string()))
+        .and_then(|e| e["content"]["membership"].as_str());
+    if matches!(membership, Some("join" | "invite")) {
+        return Ok(None);
+    }
+
+    let joined_rooms = pg::rooms::get_room_ids_by_membership(user_id, "join", pool).await?;
+    let allowed = join_rules["content"]["allow"].as_array().into_iter().flatten().any(|rule| {
+        rule["type"] == "m.room_membership"
+            && joined_rooms.iter().any(|room_id| rule["room_id"] == room_id.as_str())
+    });
+    if !allowed {
+        return Ok(None);
+    }
+
+    Ok(
+        state.iter()
+            .filter(|((event_type, _), e)| event_type == "m.room.member" && e["content"]["membership"] == "join")
+            .filter_map(|((_, state_key), _)| state_key.parse::<UserId>().ok())
+            .filter(|member| member.server_name() == config.server.server_name.as_str())
+            .find(|member| event_auth::can_invite(member.as_str(), room_version, &state))
+    )
+}
+
+/// Returns the current membership of `user_id` in a room, if any
+pub async fn membership(room_id: &RoomId, user_id: &UserId, pool: &PgPool) -> Result<Option<String>, Error> {
+    Ok(
+        pg::events::get_current_state_event(room_id, "m.room.member", user_id.as_str(), pool).await?
+            .and_then(|e| e.content["membership"].as_str().map(String::from))
+    )
+}
+
+fn membership_content(membership: &str, reason: Option<String>) -> Value {
+    let mut content = json!({ "membership": membership });
+    if let Some(reason) = reason {
+        content["reason"] = Value::String(reason);
+    }
+
+    content
+}
+
+/// Sends an `m.room.member` event for `target` with `content`
+async fn send_membership(
+    room_id: &RoomId,
+    target: &UserId,
+    content: Value,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<MembershipResult, Error> {
+    let event = ClientEvent {
+        event_type: String::from("m.room.member"),
+        state_key: Some(target.to_string()),
+        content,
+    };
+
+    Ok(match rooms::send_event(room_id, event, None, auth, config, keys, pool).await? {
+        SendEventResult::Sent(_) => MembershipResult::Sent(room_id.clone()),
+        SendEventResult::RoomNotFound => MembershipResult::RoomNotFound,
+        SendEventResult::Forbidden(rejection) => MembershipResult::Forbidden(rejection.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::stream_token::StreamToken;
+    use crate::services::rooms::tests::{authenticated_user, create};
+
+    fn membership_request(user_id: &UserId, reason: Option<&str>) -> MembershipRequest {
+        MembershipRequest { user_id: user_id.clone(), reason: reason.map(String::from) }
+    }
+
+    fn members_query(membership: Option<&str>, not_membership: Option<&str>) -> MembersQuery {
+        MembersQuery { at: None, membership: membership.map(String::from), not_membership: not_membership.map(String::from) }
+    }
+
+    async fn current_membership(room_id: &RoomId, user_id: &UserId, pool: &PgPool) -> Value {
+        pg::events::get_current_state_event(room_id, "m.room.member", user_id.as_str(), pool).await.unwrap().unwrap().content
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_join_leave_and_forget(pool: PgPool) {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "preset": "public_chat", "room_alias_name": "lobby" }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let alias = format!("#lobby:{}", config.server.server_name);
+
+        let result = join_room(&alias, Some(String::from("Hello")), &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(ref id) if id == room_id));
+        assert_eq!(current_membership(room_id, &bob.matrix_user_id, &pool).await, json!({ "membership": "join", "reason": "Hello" }));
+        assert_eq!(joined_rooms(&bob, &pool).await.unwrap(), vec![room_id.clone()]);
+        assert!(matches!(forget_room(room_id, &bob, &pool).await.unwrap(), ForgetResult::StillInRoom));
+
+        let result = leave_room(room_id, None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert_eq!(current_membership(room_id, &bob.matrix_user_id, &pool).await, json!({ "membership": "leave" }));
+        assert!(joined_rooms(&bob, &pool).await.unwrap().is_empty());
+
+        assert!(matches!(forget_room(room_id, &bob, &pool).await.unwrap(), ForgetResult::Forgotten));
+        assert!(pg::rooms::is_room_forgotten(&bob.matrix_user_id, room_id, &pool).await.unwrap());
+
+        // Joining again makes the room visible again.
+        join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(!pg::rooms::is_room_forgotten(&bob.matrix_user_id, room_id, &pool).await.unwrap());
+
+        let result = join_room("lobby", None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::InvalidRoomIdOrAlias));
+        let unknown_alias = format!("#nowhere:{}", config.server.server_name);
+        let result = join_room(&unknown_alias, None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::RoomNotFound));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_invite_kick_ban_and_unban(pool: PgPool) {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "preset": "private_chat" }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let bob_request = membership_request(&bob.matrix_user_id, Some("Spam"));
+
+        // The room is invite-only.
+        let result = join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+
+        let result = change_membership(room_id, MembershipAction::Invite, &bob_request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert_eq!(current_membership(room_id, &bob.matrix_user_id, &pool).await["membership"], "invite");
+        let result = join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+
+        // Bob lacks the power to kick Alice.
+        let alice_request = membership_request(&alice.matrix_user_id, None);
+        let result = change_membership(room_id, MembershipAction::Kick, &alice_request, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+
+        let result = change_membership(room_id, MembershipAction::Unban, &bob_request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+
+        let result = change_membership(room_id, MembershipAction::Kick, &bob_request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert_eq!(current_membership(room_id, &bob.matrix_user_id, &pool).await, json!({ "membership": "leave", "reason": "Spam" }));
+        let result = change_membership(room_id, MembershipAction::Kick, &bob_request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+
+        let result = change_membership(room_id, MembershipAction::Ban, &bob_request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert_eq!(current_membership(room_id, &bob.matrix_user_id, &pool).await["membership"], "ban");
+        let result = change_membership(room_id, MembershipAction::Invite, &bob_request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+
+        let result = change_membership(room_id, MembershipAction::Unban, &bob_request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert_eq!(current_membership(room_id, &bob.matrix_user_id, &pool).await["membership"], "leave");
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_knock(pool: PgPool) {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let events = create(json!({
+            "name": "Club",
+            "initial_state": [{ "type": "m.room.join_rules", "content": { "join_rule": "knock" } }],
+        }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+
+        let result = knock_room(room_id.as_str(), Some(String::from("Let me in")), &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert_eq!(current_membership(room_id, &bob.matrix_user_id, &pool).await, json!({ "membership": "knock", "reason": "Let me in" }));
+        assert_eq!(pg::rooms::get_room_ids_by_membership(&bob.matrix_user_id, "knock", &pool).await.unwrap(), vec![room_id.clone()]);
+
+        let mut types: Vec<String> = stripped_state(room_id, &bob.matrix_user_id, &pool).await.unwrap()
+            .iter()
+            .map(|e| format!("{} {}", e["type"].as_str().unwrap(), e["state_key"].as_str().unwrap()))
+            .collect();
+        types.sort();
+        assert_eq!(types, vec![
+            String::from("m.room.create "),
+            String::from("m.room.join_rules "),
+            format!("m.room.member {}", bob.matrix_user_id),
+            String::from("m.room.name "),
+        ]);
+
+        // Knocking isn't joining.
+        let result = join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+
+        // Alice accepts Bob's knock by inviting Bob.
+        let request = membership_request(&bob.matrix_user_id, None);
+        let result = change_membership(room_id, MembershipAction::Invite, &request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert!(pg::rooms::get_room_ids_by_membership(&bob.matrix_user_id, "knock", &pool).await.unwrap().is_empty());
+        let result = join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+
+        // Alice denies Carol's knock by kicking Carol.
+        knock_room(room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+        let request = membership_request(&carol.matrix_user_id, None);
+        let result = change_membership(room_id, MembershipAction::Kick, &request, &alice, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        assert_eq!(current_membership(room_id, &carol.matrix_user_id, &pool).await["membership"], "leave");
+
+        // Joined users can't knock.
+        let result = knock_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+
+        let events = create(json!({ "preset": "private_chat" }), &alice, &config, &pool).await;
+        let result = knock_room(events[0].room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_join_knock_restricted_room(pool: PgPool) {
+        // The authorising user's server must sign the join, and the test keys
+        // are for `chat.spelt.io`.
+        let mut config = Config::test();
+        config.server.server_name = "chat.spelt.io".parse().unwrap();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "preset": "public_chat" }), &alice, &config, &pool).await;
+        let space_id = &events[0].room_id;
+        join_room(space_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+
+        let events = create(json!({
+            "room_version": "10",
+            "initial_state": [{
+                "type": "m.room.join_rules",
+                "content": { "join_rule": "knock_restricted", "allow": [{ "type": "m.room_membership", "room_id": space_id }] },
+            }],
+        }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+
+        // Bob may join because Bob is in the allowed room.
+        let result = join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+        let content = current_membership(room_id, &bob.matrix_user_id, &pool).await;
+        assert_eq!(content["membership"], "join");
+        assert_eq!(content["join_authorised_via_users_server"], alice.matrix_user_id.as_str());
+
+        // Carol isn't, so must knock.
+        let result = join_room(room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Forbidden(_)));
+        let result = knock_room(room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+        assert!(matches!(result, MembershipResult::Sent(_)));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_get_members(pool: PgPool) {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "preset": "public_chat" }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+
+        join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        leave_room(room_id, None, &bob, &config, &keys, &pool).await.unwrap();
+        join_room(room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+
+        let state_keys = |result: MembersResult| match result {
+            MembersResult::Members(members) => {
+                let mut keys: Vec<String> = members.into_iter().filter_map(|e| e.state_key).collect();
+                keys.sort();
+                keys
+            }
+            MembersResult::Forbidden => panic!("Expected members"),
+        };
+        let mut all = vec![alice.matrix_user_id.to_string(), bob.matrix_user_id.to_string(), carol.matrix_user_id.to_string()];
+        all.sort();
+        let mut joined = vec![alice.matrix_user_id.to_string(), carol.matrix_user_id.to_string()];
+        joined.sort();
+
+        assert_eq!(state_keys(get_members(room_id, &members_query(None, None), &alice, &pool).await.unwrap()), all);
+        assert_eq!(state_keys(get_members(room_id, &members_query(Some("join"), None), &alice, &pool).await.unwrap()), joined);
+        assert_eq!(
+            state_keys(get_members(room_id, &members_query(None, Some("join")), &alice, &pool).await.unwrap()),
+            vec![bob.matrix_user_id.to_string()]
+        );
+        assert_eq!(state_keys(get_joined_members(room_id, &alice, &pool).await.unwrap()), joined);
+
+        // As of the end of room creation, only Alice was a member.
+        let query = MembersQuery { at: Some(StreamToken { stream_ordering: events.last().unwrap().stream_ordering }), ..members_query(None, None) };
+        assert_eq!(state_keys(get_members(room_id, &query, &alice, &pool).await.unwrap()), vec![alice.matrix_user_id.to_string()]);
+
+        // Bob sees the members as they were when Bob left.
+        let mut when_left = vec![alice.matrix_user_id.to_string(), bob.matrix_user_id.to_string()];
+        when_left.sort();
+        assert_eq!(state_keys(get_members(room_id, &members_query(None, None), &bob, &pool).await.unwrap()), when_left);
+        assert!(matches!(get_joined_members(room_id, &bob, &pool).await.unwrap(), MembersResult::Forbidden));
+
+        let outsider = authenticated_user(&config, &pool).await;
+        assert!(matches!(get_members(room_id, &members_query(None, None), &outsider, &pool).await.unwrap(), MembersResult::Forbidden));
+    }
+}
@@ -1,3 +1,22 @@
+pub mod account;
+pub mod account_data;
 pub mod auth;
+pub mod canonical_json;
+pub mod email;
+pub mod event_auth;
+pub mod events;
+pub mod filter;
+pub mod history;
 pub mod jwt;
+pub mod membership;
+pub mod notifier;
+pub mod password;
+pub mod receipts;
 pub mod rooms;
+pub mod signing;
+pub mod sliding_sync;
+pub mod state_res;
+pub mod sync;
+pub mod to_device;
+pub mod typing;
+pub mod uia;
@@ -0,0 +1,184 @@
+use crate::error::Error;
+use crate::models::ids::{RoomId, UserId};
+use crate::models::stream_token::StreamUpdate;
+use crate::store::pg;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time;
+use twelf::reexports::log;
+use twelf::reexports::serde_json;
+
+/// How long to wait before listening again after the listener fails
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wakes requests that wait for new data, such as long-polling syncs
+///
+/// Store writes announce a [`StreamUpdate`] on the Postgres channel
+/// [`pg::streams::CHANNEL`], so updates from every server process sharing the
+/// database are heard. The notifier listens on the channel and passes each
+/// update's stream ordering to the [`Subscription`]s of the users concerned.
+///
+/// Clones share the same subscriptions.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: AtomicU64,
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+}
+
+/// What a [`Subscription`] waits for, and how to wake it
+struct Subscriber {
+    user_id: UserId,
+    room_ids: HashSet<RoomId>,
+    sender: watch::Sender<i64>,
+}
+
+/// Interest in the updates for one user, which ends when dropped
+pub struct Subscription {
+    notifier: Notifier,
+    id: u64,
+    receiver: watch::Receiver<i64>,
+}
+
+impl fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notifier")
+            .field("subscribers", &self.inner.subscribers.lock().map(|s| s.len()).unwrap_or_default())
+            .finish()
+    }
+}
+
+impl Notifier {
+    /// Returns a notifier that listens on `pool` for updates in a background
+    /// task, which runs until the pool is closed
+    pub async fn start(pool: &PgPool) -> Result<Self, Error> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(pg::streams::CHANNEL).await?;
+
+        let notifier = Self::default();
+        let task_notifier = notifier.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => match serde_json::from_str::<StreamUpdate>(notification.payload()) {
+                        Ok(update) => task_notifier.notify(&update),
+                        Err(e) => log::warn!("Ignoring invalid stream update: {e}"),
+                    },
+                    // Updates may have been missed while reconnecting, so
+                    // everyone has to check for themselves.
+                    Ok(None) => task_notifier.notify_all(),
+                    Err(sqlx::Error::PoolClosed) => break,
+                    Err(e) => {
+                        log::error!("Failed to receive stream updates: {e}");
+                        time::sleep(RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        });
+
+        Ok(notifier)
+    }
+
+    /// Returns a subscription to the updates concerning `user_id` or any of
+    /// the rooms with IDs `room_ids`
+    ///
+    /// Subscribe before reading what's already stored, so that nothing stored
+    /// in between is missed.
+    pub fn subscribe(&self, user_id: &UserId, room_ids: &[RoomId]) -> Subscription {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = watch::channel(0);
+        let subscriber = Subscriber {
+            user_id: user_id.clone(),
+            room_ids: room_ids.iter().cloned().collect(),
+            sender,
+        };
+        self.subscribers().insert(id, subscriber);
+
+        Subscription { notifier: self.clone(), id, receiver }
+    }
+
+    /// Passes the stream ordering of `update` to the subscriptions it concerns
+    pub fn notify(&self, update: &StreamUpdate) {
+        for subscriber in self.subscribers().values() {
+            let concerned = update.user_ids.contains(&subscriber.user_id)
+                || update.room_ids.iter().any(|room_id| subscriber.room_ids.contains(room_id));
+            if concerned {
+                subscriber.sender.send_if_modified(|position| {
+                    let newer = update.stream_ordering > *position;
+                    if newer {
+                        *position = update.stream_ordering;
+                    }
+                    newer
+                });
+            }
+        }
+    }
+
+    /// Wakes every subscription, whatever it's waiting for
+    fn notify_all(&self) {
+        for subscriber in self.subscribers().values() {
+            subscriber.sender.send_replace(i64::MAX);
+        }
+    }
+
+    fn subscribers(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Subscriber>> {
+        // The map stays consistent even if a holder of the lock panicked.
+        self.inner.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Subscription {
+    /// Waits until an update after stream ordering `since` arrives or until
+    /// `deadline`, and returns whether one arrived
+    pub async fn wait(&mut self, since: i64, deadline: Instant) -> bool {
+        let updated = self.receiver.wait_for(|position| *position > since);
+        matches!(time::timeout_at(deadline.into(), updated).await, Ok(Ok(_)))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.notifier.subscribers().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::pg::events::tests::create_test_room;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_notify(pool: PgPool) {
+        let notifier = Notifier::start(&pool).await.unwrap();
+        let (room_id, alice) = create_test_room(&pool).await;
+        let bob: UserId = "@bob:chat.spelt.io".parse().unwrap();
+        let since = pg::streams::get_current_stream_ordering(&pool).await.unwrap();
+
+        let mut alice_subscription = notifier.subscribe(&alice, std::slice::from_ref(&room_id));
+        let mut bob_subscription = notifier.subscribe(&bob, &[]);
+
+        // When Alice starts typing, only the subscription to her room wakes.
+        pg::typing::set_typing(&room_id, &alice, Some(30_000), &pool).await.unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(alice_subscription.wait(since, deadline).await);
+        assert!(!bob_subscription.wait(since, Instant::now() + Duration::from_millis(200)).await);
+
+        // Bob's own account data wakes him.
+        pg::account_data::set_account_data(&bob, None, "m.test", &serde_json::json!({}), &pool).await.unwrap();
+        assert!(bob_subscription.wait(since, deadline).await);
+
+        // Dropping a subscription stops further notifications for it.
+        drop(bob_subscription);
+        assert_eq!(notifier.subscribers().len(), 1);
+    }
+}
@@ -0,0 +1,41 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use twelf::reexports::log;
+
+/// Returns the argon2 hash of `password` in PHC string format
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+/// Returns `true` if `password` matches `hash`, an argon2 hash in PHC string
+/// format
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(e) => {
+            log::error!("Invalid password hash: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_password() {
+        let hash = hash_password("correct horse");
+
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+    }
+
+    #[test]
+    fn test_verify_password_with_invalid_hash() {
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+}
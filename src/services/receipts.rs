@@ -0,0 +1,90 @@
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{EventId, RoomId};
+use crate::services;
+use crate::store::pg;
+use sqlx::PgPool;
+use twelf::reexports::serde_json::json;
+
+/// Receipt types that clients can send
+const RECEIPT_TYPES: [&str; 3] = ["m.fully_read", "m.read", "m.read.private"];
+
+/// Possible results of calling [`send_receipt()`]
+pub enum ReceiptResult {
+    Sent,
+    InvalidReceiptType,
+    /// The event isn't in the room
+    EventNotFound,
+    /// The user isn't in the room
+    Forbidden,
+}
+
+/// Records that the user has read up to and including an event
+///
+/// An `m.fully_read` receipt sets the user's read marker in the room's account
+/// data rather than being shared with other users.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#post_matrixclientv3roomsroomidreceiptreceipttypeeventid
+pub async fn send_receipt(
+    room_id: &RoomId,
+    receipt_type: &str,
+    event_id: &EventId,
+    thread_id: Option<&str>,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<ReceiptResult, Error> {
+    if !RECEIPT_TYPES.contains(&receipt_type) || (receipt_type == "m.fully_read" && thread_id.is_some()) {
+        return Ok(ReceiptResult::InvalidReceiptType);
+    }
+    if services::membership::membership(room_id, &auth.matrix_user_id, pool).await?.as_deref() != Some("join") {
+        return Ok(ReceiptResult::Forbidden);
+    }
+    if !pg::events::get_event(event_id, pool).await?.is_some_and(|e| e.room_id == *room_id) {
+        return Ok(ReceiptResult::EventNotFound);
+    }
+
+    if receipt_type == "m.fully_read" {
+        let content = json!({ "event_id": event_id });
+        pg::account_data::set_account_data(&auth.matrix_user_id, Some(room_id), receipt_type, &content, pool).await?;
+    } else {
+        pg::receipts::set_receipt(room_id, &auth.matrix_user_id, receipt_type, thread_id, event_id, pool).await?;
+    }
+
+    Ok(ReceiptResult::Sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::services::rooms::tests::{authenticated_user, create};
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_send_receipt(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({}), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let event_id = &events.last().unwrap().event_id;
+
+        let result = send_receipt(room_id, "m.read", event_id, None, &alice, &pool).await.unwrap();
+        assert!(matches!(result, ReceiptResult::Sent));
+        let receipts = pg::receipts::get_receipts_between(std::slice::from_ref(room_id), 0, i64::MAX, &pool).await.unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].event_id, *event_id);
+
+        let result = send_receipt(room_id, "m.fully_read", event_id, None, &alice, &pool).await.unwrap();
+        assert!(matches!(result, ReceiptResult::Sent));
+        let marker = pg::account_data::get_account_data(&alice.matrix_user_id, Some(room_id), "m.fully_read", &pool).await.unwrap();
+        assert_eq!(marker, Some(json!({ "event_id": event_id })));
+
+        let result = send_receipt(room_id, "m.unread", event_id, None, &alice, &pool).await.unwrap();
+        assert!(matches!(result, ReceiptResult::InvalidReceiptType));
+        let unknown_event = EventId::generate(&config.server.server_name);
+        let result = send_receipt(room_id, "m.read", &unknown_event, None, &alice, &pool).await.unwrap();
+        assert!(matches!(result, ReceiptResult::EventNotFound));
+        let result = send_receipt(room_id, "m.read", event_id, None, &bob, &pool).await.unwrap();
+        assert!(matches!(result, ReceiptResult::Forbidden));
+    }
+}
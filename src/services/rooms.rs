@@ -1,37 +1,743 @@
+use crate::config::Config;
 use crate::error::Error;
-use crate::routes::rooms::CreateRoomRequest;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{EventId, RoomAliasId, RoomId, UserId};
+use crate::models::room_version::{self, RoomVersion};
+use crate::models::rooms::Room;
+use crate::routes::rooms::{CreateRoomRequest, RoomPreset, RoomVisibility};
+use crate::services;
+use crate::services::event_auth::{self, Rejection};
+use crate::services::events::EventTemplate;
+use crate::services::signing::SigningKeys;
 use crate::store::pg;
-use crate::store::pg::events::CreateRoomEvent;
-use crate::{store, AppState};
-use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
-use twelf::reexports::{log, serde_json};
-use uuid::Uuid;
-
-impl From<CreateRoomRequest> for CreateRoomEvent {
-    fn from(request: CreateRoomRequest) -> Self {
+use crate::store::pg::events::NewEvent;
+use crate::store::pg::rooms::NewRoom;
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashMap;
+use twelf::reexports::serde_json::{json, Value};
+
+/// Possible results of calling [`create_room()`]
+pub enum CreateRoomResult {
+    Created(RoomId),
+    GuestAccessForbidden,
+    UnsupportedRoomVersion,
+    /// `room_alias_name` doesn't form a valid room alias
+    InvalidRoomAlias,
+    RoomAliasInUse,
+    /// `invite_3pid` is not empty; identity servers are not supported
+    ThreepidInviteUnsupported,
+    /// `initial_state` includes an event that only the server may create
+    InvalidInitialState,
+    /// An initial event isn't allowed by the state set by the events before
+    /// it, such as when `power_level_content_override` takes power from the
+    /// creator
+    Rejected(Rejection),
+}
+
+/// Possible results of calling [`send_event()`]
+pub enum SendEventResult {
+    Sent(EventId),
+    RoomNotFound,
+    /// The event isn't allowed by the room's authorization rules
+    Forbidden(Rejection),
+}
+
+/// An event that a client sends to a room
+#[derive(Debug, Clone)]
+pub struct ClientEvent {
+    pub event_type: String,
+    pub state_key: Option<String>,
+    pub content: Value,
+}
+
+/// Power level of the creator of a room and, in a `trusted_private_chat`, of
+/// the users they invite
+const CREATOR_POWER_LEVEL: i64 = 100;
+
+/// Creates a room and returns `Ok(CreateRoomResult::Created(room_id))`
+///
+/// The room's initial events are, in order: `m.room.create`, the creator's
+/// membership, `m.room.power_levels`, `m.room.canonical_alias` if an alias is
+/// requested, the events set by the preset, `initial_state`, `m.room.name`,
+/// `m.room.topic`, and an invite for each invitee. Events in `initial_state`
+/// replace those set by the preset, and `name` and `topic` replace any in
+/// `initial_state`.
+///
+/// Each event must be allowed by the state set by the events before it. The
+/// room, its events and its alias are stored in one transaction, and nothing
+/// is stored if any event is rejected.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#creation
+pub async fn create_room(
+    request: &CreateRoomRequest,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<CreateRoomResult, Error> {
+    if auth.is_guest {
+        return Ok(CreateRoomResult::GuestAccessForbidden);
+    }
+
+    let room_version_id = request.room_version.as_ref().unwrap_or(&config.server.default_room_version);
+    let room_version = match room_version::get(room_version_id) {
+        Some(room_version) => room_version,
+        None => return Ok(CreateRoomResult::UnsupportedRoomVersion),
+    };
+
+    if !request.invite_3pid.is_empty() {
+        return Ok(CreateRoomResult::ThreepidInviteUnsupported);
+    }
+
+    if request.initial_state.iter().any(|e| e.r#type == "m.room.create" || e.r#type == "m.room.member") {
+        return Ok(CreateRoomResult::InvalidInitialState);
+    }
+
+    let alias = match request.room_alias_name {
+        Some(ref name) => {
+            let alias = match room_alias(name, config) {
+                Some(alias) => alias,
+                None => return Ok(CreateRoomResult::InvalidRoomAlias),
+            };
+            if pg::rooms::get_room_id_for_alias(&alias, pool).await?.is_some() {
+                return Ok(CreateRoomResult::RoomAliasInUse);
+            }
+            Some(alias)
+        }
+        None => None,
+    };
+
+    let is_public = request.visibility == Some(RoomVisibility::Public);
+    let preset = request.preset.unwrap_or(if is_public { RoomPreset::PublicChat } else { RoomPreset::PrivateChat });
+
+    let room_id = RoomId::generate(&config.server.server_name);
+    let creator = &auth.matrix_user_id;
+    let mut events = RoomEvents::new(&room_id, room_version, creator, config, keys);
+
+    // m.room.create
+    let mut create_content = request.creation_content.clone().unwrap_or_default();
+    if room_version.implicit_room_creator {
+        create_content.remove("creator");
+    } else {
+        create_content.insert(String::from("creator"), json!(creator));
+    }
+    create_content.insert(String::from("room_version"), json!(room_version.id));
+    events.push_state("m.room.create", "", Value::Object(create_content))?;
+
+    // Creator's membership
+    events.push_state("m.room.member", creator.as_str(), json!({ "membership": "join" }))?;
+
+    // m.room.power_levels
+    let mut power_levels = default_power_levels(creator);
+    if preset == RoomPreset::TrustedPrivateChat {
+        for invitee in &request.invite {
+            power_levels["users"][invitee.as_str()] = json!(CREATOR_POWER_LEVEL);
+        }
+    }
+    for (key, value) in request.power_level_content_override.iter().flatten() {
+        power_levels[key] = value.clone();
+    }
+    events.push_state("m.room.power_levels", "", power_levels)?;
+
+    // m.room.canonical_alias
+    if let Some(ref alias) = alias {
+        events.push_state("m.room.canonical_alias", "", json!({ "alias": alias }))?;
+    }
+
+    // Preset events, unless replaced by initial_state
+    for (event_type, content) in preset_events(preset) {
+        if !request.initial_state.iter().any(|e| e.r#type == event_type && e.state_key.is_empty()) {
+            events.push_state(event_type, "", content)?;
+        }
+    }
+
+    // initial_state
+    for event in &request.initial_state {
+        events.push_state(&event.r#type, &event.state_key, event.content.clone())?;
+    }
+
+    // m.room.name and m.room.topic
+    if let Some(ref name) = request.name {
+        events.push_state("m.room.name", "", json!({ "name": name }))?;
+    }
+    if let Some(ref topic) = request.topic {
+        events.push_state("m.room.topic", "", json!({ "topic": topic }))?;
+    }
+
+    // Invites
+    for invitee in &request.invite {
+        let mut content = json!({ "membership": "invite" });
+        if request.is_direct == Some(true) {
+            content["is_direct"] = json!(true);
+        }
+        events.push_state("m.room.member", invitee.as_str(), content)?;
+    }
+
+    if let Some(rejection) = events.rejection {
+        return Ok(CreateRoomResult::Rejected(rejection));
+    }
+
+    let room = NewRoom {
+        room_id: room_id.clone(),
+        room_version: room_version.id.to_string(),
+        creator: creator.clone(),
+        is_public,
+    };
+    pg::rooms::create_room(&room, alias.as_ref(), &events.events, pool).await?;
+
+    Ok(CreateRoomResult::Created(room_id))
+}
+
+/// Sends an event to a room from `auth`'s user and returns
+/// `Ok(SendEventResult::Sent(event_id))`
+///
+/// If the session has already sent an event with `txn_id`, nothing is sent
+/// and the ID of that event is returned.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid
+pub async fn send_event(
+    room_id: &RoomId,
+    event: ClientEvent,
+    txn_id: Option<&str>,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<SendEventResult, Error> {
+    if let Some(txn_id) = txn_id {
+        if let Some(event_id) = pg::events::get_transaction_event_id(auth.session_id, txn_id, pool).await? {
+            return Ok(SendEventResult::Sent(event_id));
+        }
+    }
+
+    let transaction = txn_id.map(|txn_id| (auth.session_id, txn_id));
+    store_room_event(room_id, event, &auth.matrix_user_id, transaction, config, keys, pool).await
+}
+
+/// Sends an event to a room from `sender` on the server's behalf, without a
+/// session or transaction ID, as when deactivating the sender's account
+pub async fn send_event_as(
+    room_id: &RoomId,
+    event: ClientEvent,
+    sender: &UserId,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<SendEventResult, Error> {
+    store_room_event(room_id, event, sender, None, config, keys, pool).await
+}
+
+/// Builds an event from `sender` on the current state of a room and stores it
+/// with `transaction`, the ID of the sending session and its transaction ID,
+/// if any
+///
+/// The room is locked while the event is built and stored, so that concurrent
+/// events in the room follow one another.
+async fn store_room_event(
+    room_id: &RoomId,
+    event: ClientEvent,
+    sender: &UserId,
+    transaction: Option<(i64, &str)>,
+    config: &Config,
+    keys: &SigningKeys,
+    pool: &PgPool
+) -> Result<SendEventResult, Error> {
+    let mut tx = pool.begin().await?;
+    let room = match pg::rooms::lock_room(room_id, &mut tx).await? {
+        Some(room) => room,
+        None => return Ok(SendEventResult::RoomNotFound),
+    };
+
+    let new_event = match build_room_event(&room, sender, event, config, keys, &mut tx).await? {
+        Ok(new_event) => new_event,
+        Err(rejection) => return Ok(SendEventResult::Forbidden(rejection)),
+    };
+
+    match transaction {
+        Some((session_id, txn_id)) => {
+            if !pg::events::create_transaction_event(&new_event, session_id, txn_id, &mut tx).await? {
+                // A concurrent request with the same transaction ID got there
+                // first.
+                tx.rollback().await?;
+                return match pg::events::get_transaction_event_id(session_id, txn_id, pool).await? {
+                    Some(event_id) => Ok(SendEventResult::Sent(event_id)),
+                    None => Err(Error::Db(format!("Transaction {} not found", txn_id))),
+                };
+            }
+        }
+        None => {
+            pg::events::create_event(&new_event, &mut tx).await?;
+        }
+    }
+    tx.commit().await?;
+
+    Ok(SendEventResult::Sent(new_event.event_id))
+}
+
+/// Builds an event from `sender` that follows the forward extremities of
+/// `room`, and returns `Ok(Ok(event))` if the current room state allows it
+///
+/// The event's auth events are selected from the current room state. The
+/// state and extremities are read on `conn`, which should hold the room's lock.
+async fn build_room_event(
+    room: &Room,
+    sender: &UserId,
+    event: ClientEvent,
+    config: &Config,
+    keys: &SigningKeys,
+    conn: &mut PgConnection
+) -> Result<Result<NewEvent, Rejection>, Error> {
+    let room_version = room_version::get(&room.room_version)
+        .ok_or_else(|| Error::Db(format!("Room {} has unsupported version {}", room.room_id, room.room_version)))?;
+
+    let state_events = pg::events::get_current_state_json(&room.room_id, &mut *conn).await?;
+    let state = event_auth::state_map(&state_events);
+
+    let extremities = pg::events::get_forward_extremities(&room.room_id, &mut *conn).await?;
+    let prev_events = pg::events::get_events_json(&extremities, &mut *conn).await?;
+    let auth_events: Vec<&Value> = services::events::auth_event_keys(&event.event_type, event.state_key.as_deref(), sender, &event.content)
+        .iter()
+        .filter_map(|key| state.get(key).copied())
+        .collect();
+
+    let template = EventTemplate {
+        room_id: room.room_id.clone(),
+        sender: sender.clone(),
+        event_type: event.event_type,
+        state_key: event.state_key,
+        content: event.content,
+        origin_server_ts: chrono::Utc::now().timestamp_millis(),
+    };
+    let new_event = services::events::build_event(template, room_version, &prev_events.iter().collect::<Vec<_>>(), &auth_events, config, keys)?;
+
+    Ok(event_auth::authorize(&new_event.json, room_version, &state).map(|_| new_event))
+}
+
+/// The initial events of a new room, all sent by its creator
+///
+/// Each event's prev event is the one before it, and its auth events are
+/// selected from the state set by the events before it, which must allow it.
+struct RoomEvents<'a> {
+    room_id: &'a RoomId,
+    room_version: &'static RoomVersion,
+    sender: &'a UserId,
+    config: &'a Config,
+    keys: &'a SigningKeys,
+    origin_server_ts: i64,
+    /// Index in `events` of each state event
+    state: HashMap<(String, String), usize>,
+    events: Vec<NewEvent>,
+    /// Why the first event that wasn't allowed was rejected
+    rejection: Option<Rejection>,
+}
+
+impl<'a> RoomEvents<'a> {
+    fn new(
+        room_id: &'a RoomId,
+        room_version: &'static RoomVersion,
+        sender: &'a UserId,
+        config: &'a Config,
+        keys: &'a SigningKeys
+    ) -> Self {
         Self {
-            r#type: "m.room.create".to_string(),
+            room_id,
+            room_version,
+            sender,
+            config,
+            keys,
+            origin_server_ts: chrono::Utc::now().timestamp_millis(),
+            state: HashMap::new(),
+            events: vec![],
+            rejection: None,
         }
     }
+
+    /// Adds a state event after the events added so far if the state they set
+    /// allows it; otherwise records the rejection, after which no more events
+    /// are added
+    fn push_state(&mut self, event_type: &str, state_key: &str, content: Value) -> Result<(), Error> {
+        if self.rejection.is_some() {
+            return Ok(());
+        }
+
+        let auth_events: Vec<&Value> = services::events::auth_event_keys(event_type, Some(state_key), self.sender, &content)
+            .iter()
+            .filter_map(|key| self.state.get(key))
+            .map(|&i| &self.events[i].json)
+            .collect();
+        let prev_events: Vec<&Value> = self.events.last().map(|e| &e.json).into_iter().collect();
+
+        let template = EventTemplate {
+            room_id: self.room_id.clone(),
+            sender: self.sender.clone(),
+            event_type: event_type.to_string(),
+            state_key: Some(state_key.to_string()),
+            content,
+            origin_server_ts: self.origin_server_ts,
+        };
+        let event = services::events::build_event(template, self.room_version, &prev_events, &auth_events, self.config, self.keys)?;
+
+        let state = self.state.iter().map(|(key, &i)| (key.clone(), &self.events[i].json)).collect();
+        if let Err(rejection) = event_auth::authorize(&event.json, self.room_version, &state) {
+            self.rejection = Some(rejection);
+            return Ok(());
+        }
+
+        self.state.insert((event_type.to_string(), state_key.to_string()), self.events.len());
+        self.events.push(event);
+
+        Ok(())
+    }
+}
+
+/// Returns the alias on this server with the localpart `name`, or `None` if
+/// it isn't valid
+fn room_alias(name: &str, config: &Config) -> Option<RoomAliasId> {
+    if name.contains(':') || name.chars().any(char::is_whitespace) {
+        return None;
+    }
+
+    format!("#{}:{}", name, config.server.server_name).parse().ok()
+}
+
+/// Returns the content of the `m.room.power_levels` event of a new room, before
+/// applying any `power_level_content_override`
+fn default_power_levels(creator: &UserId) -> Value {
+    json!({
+        "users": { creator.as_str(): CREATOR_POWER_LEVEL },
+        "users_default": 0,
+        "events": {
+            "m.room.name": 50,
+            "m.room.power_levels": 100,
+            "m.room.history_visibility": 100,
+            "m.room.canonical_alias": 50,
+            "m.room.avatar": 50,
+            "m.room.tombstone": 100,
+            "m.room.server_acl": 100,
+            "m.room.encryption": 100,
+        },
+        "events_default": 0,
+        "state_default": 50,
+        "ban": 50,
+        "kick": 50,
+        "redact": 50,
+        "invite": 0,
+        "notifications": { "room": 50 },
+    })
 }
 
-/// Creates a new Room and returns `Ok(room_id)`
-pub async fn create_room(request: CreateRoomRequest, user_id: i64, state: &AppState) -> Result<String, Error> {
-    let pool = state.db_pool.as_ref().unwrap();
-    let user = pg::auth::get_user(user_id, &pool).await?;
+/// Returns the types and contents of the state events set by `preset`
+fn preset_events(preset: RoomPreset) -> Vec<(&'static str, Value)> {
+    let (join_rule, guest_access) = match preset {
+        RoomPreset::PrivateChat | RoomPreset::TrustedPrivateChat => ("invite", "can_join"),
+        RoomPreset::PublicChat => ("public", "forbidden"),
+    };
+
+    vec![
+        ("m.room.join_rules", json!({ "join_rule": join_rule })),
+        ("m.room.history_visibility", json!({ "history_visibility": "shared" })),
+        ("m.room.guest_access", json!({ "guest_access": guest_access })),
+    ]
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::events::Event;
+    use crate::services;
+    use crate::services::event_auth;
+    use crate::store::pg::auth::tests::{create_test_session, create_test_user};
+    use twelf::reexports::serde_json;
+
+    /// Helper function to create a user and authenticate them for testing
+    pub async fn authenticated_user(config: &Config, pool: &PgPool) -> AuthenticatedUser {
+        let (user, _password) = create_test_user(pool).await;
+        let (session, _jwt) = create_test_session(user.id, 0, pool).await;
+
+        AuthenticatedUser {
+            user_id: user.id,
+            session_id: session.id,
+            device_id: session.device_identifier.clone(),
+            matrix_user_id: services::auth::user_id(&user.name, config).unwrap(),
+            is_guest: false,
+        }
+    }
+
+    fn request(body: Value) -> CreateRoomRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    /// Helper function to create a room for testing and return its events
+    pub async fn create(body: Value, auth: &AuthenticatedUser, config: &Config, pool: &PgPool) -> Vec<Event> {
+        match create_room(&request(body), auth, config, &SigningKeys::test(), pool).await.unwrap() {
+            CreateRoomResult::Created(room_id) => pg::events::tests::get_room_events(&room_id, pool).await,
+            _ => panic!("Expected room to be created"),
+        }
+    }
+
+    fn content<'a>(events: &'a [Event], event_type: &str, state_key: &str) -> &'a Value {
+        &events.iter()
+            .rev()
+            .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(state_key))
+            .unwrap()
+            .content
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room(pool: PgPool) {
+        let config = Config::test();
+        let auth = authenticated_user(&config, &pool).await;
+        let invitee = format!("@bob:{}", config.server.server_name);
+
+        let events = create(json!({
+            "creation_content": { "m.federate": false, "creator": "@mallory:example.org" },
+            "name": "Lobby",
+            "topic": "Say hello",
+            "room_alias_name": "lobby",
+            "invite": [invitee],
+            "is_direct": true,
+        }), &auth, &config, &pool).await;
+
+        let types: Vec<&str> = events.iter().map(|e| e.event_type.as_str()).collect();
+        assert_eq!(types, vec![
+            "m.room.create",
+            "m.room.member",
+            "m.room.power_levels",
+            "m.room.canonical_alias",
+            "m.room.join_rules",
+            "m.room.history_visibility",
+            "m.room.guest_access",
+            "m.room.name",
+            "m.room.topic",
+            "m.room.member",
+        ]);
+        assert!(events.iter().all(|e| e.sender == auth.matrix_user_id));
+        for pair in events.windows(2) {
+            assert_eq!(pg::events::tests::get_prev_events(&pair[1].event_id, &pool).await, vec![pair[0].event_id.clone()]);
+        }
+
+        // The invite is authorized by the create, power levels, join rules and
+        // creator's membership events.
+        let mut auth_events = pg::events::tests::get_auth_events(&events[9].event_id, &pool).await;
+        auth_events.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        let mut expected = vec![&events[0], &events[1], &events[2], &events[4]]
+            .into_iter()
+            .map(|e| e.event_id.clone())
+            .collect::<Vec<_>>();
+        expected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(auth_events, expected);
+
+        let state = pg::events::get_current_state(&events[0].room_id, &pool).await.unwrap();
+        assert_eq!(state.len(), events.len());
+
+        let create_content = content(&events, "m.room.create", "");
+        assert_eq!(create_content["creator"], auth.matrix_user_id.as_str());
+        assert_eq!(create_content["room_version"], room_version::DEFAULT_ROOM_VERSION);
+        assert_eq!(create_content["m.federate"], false);
+
+        assert_eq!(content(&events, "m.room.member", auth.matrix_user_id.as_str())["membership"], "join");
+        assert_eq!(content(&events, "m.room.member", &invitee), &json!({ "membership": "invite", "is_direct": true }));
+        assert_eq!(content(&events, "m.room.join_rules", "")["join_rule"], "invite");
+        assert_eq!(content(&events, "m.room.canonical_alias", "")["alias"], format!("#lobby:{}", config.server.server_name));
+
+        let alias: RoomAliasId = format!("#lobby:{}", config.server.server_name).parse().unwrap();
+        assert_eq!(pg::rooms::get_room_id_for_alias(&alias, &pool).await.unwrap(), Some(events[0].room_id.clone()));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room_with_power_level_override(pool: PgPool) {
+        let config = Config::test();
+        let auth = authenticated_user(&config, &pool).await;
+        let invitee = format!("@bob:{}", config.server.server_name);
+
+        let events = create(json!({
+            "preset": "trusted_private_chat",
+            "invite": [invitee],
+            "power_level_content_override": { "ban": 100 },
+        }), &auth, &config, &pool).await;
+
+        let power_levels = content(&events, "m.room.power_levels", "");
+        assert_eq!(power_levels["ban"], 100);
+        assert_eq!(power_levels["kick"], 50);
+        assert_eq!(power_levels["users"][auth.matrix_user_id.as_str()], CREATOR_POWER_LEVEL);
+        assert_eq!(power_levels["users"][invitee.as_str()], CREATOR_POWER_LEVEL);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room_with_initial_state(pool: PgPool) {
+        let config = Config::test();
+        let auth = authenticated_user(&config, &pool).await;
+
+        let events = create(json!({
+            "preset": "public_chat",
+            "room_version": "11",
+            "name": "Named",
+            "initial_state": [
+                { "type": "m.room.history_visibility", "content": { "history_visibility": "joined" } },
+                { "type": "m.room.name", "content": { "name": "Replaced" } },
+            ],
+        }), &auth, &config, &pool).await;
+
+        assert_eq!(events.iter().filter(|e| e.event_type == "m.room.history_visibility").count(), 1);
+        assert_eq!(content(&events, "m.room.history_visibility", "")["history_visibility"], "joined");
+        assert_eq!(content(&events, "m.room.join_rules", "")["join_rule"], "public");
+        assert_eq!(content(&events, "m.room.name", "")["name"], "Named");
+
+        let create_content = content(&events, "m.room.create", "");
+        assert_eq!(create_content["room_version"], "11");
+        assert!(create_content.get("creator").is_none());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room_with_default_room_version(pool: PgPool) {
+        let mut config = Config::test();
+        config.server.default_room_version = String::from("9");
+        let auth = authenticated_user(&config, &pool).await;
+
+        let events = create(json!({}), &auth, &config, &pool).await;
+        assert_eq!(content(&events, "m.room.create", "")["room_version"], "9");
+
+        let events = create(json!({ "room_version": "1" }), &auth, &config, &pool).await;
+        assert_eq!(content(&events, "m.room.create", "")["room_version"], "1");
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room_events_are_authorized(pool: PgPool) {
+        let config = Config::test();
+        let auth = authenticated_user(&config, &pool).await;
+
+        for room_version in room_version::ROOM_VERSIONS.iter() {
+            let events = create(json!({
+                "room_version": room_version.id,
+                "name": "Lobby",
+                "invite": [format!("@bob:{}", config.server.server_name)],
+            }), &auth, &config, &pool).await;
+
+            for event in &events {
+                let json = pg::events::get_events_json(std::slice::from_ref(&event.event_id), &pool).await.unwrap().remove(0);
+                let auth_event_ids = pg::events::tests::get_auth_events(&event.event_id, &pool).await;
+                let auth_events = pg::events::get_events_json(&auth_event_ids, &pool).await.unwrap();
+
+                let state = event_auth::tests::auth_state(&json, &auth_events.iter().collect::<Vec<_>>()).unwrap();
+                let result = event_auth::authorize(&json, room_version, &state);
+                assert!(result.is_ok(), "{} in room version {}: {:?}", event.event_type, room_version.id, result);
+            }
+        }
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_create_room_with_invalid_request(pool: PgPool) {
+        let config = Config::test();
+        let mut auth = authenticated_user(&config, &pool).await;
+
+        let result = create_room(&request(json!({ "room_version": "0" })), &auth, &config, &SigningKeys::test(), &pool).await.unwrap();
+        assert!(matches!(result, CreateRoomResult::UnsupportedRoomVersion));
 
-    if user.is_none() {
-        log::error!("Authenticated user with ID {} not found", user_id);
-        return Err(Error::Auth("Authenticated user is invalid".to_string()));
+        let result = create_room(&request(json!({ "room_alias_name": "a b" })), &auth, &config, &SigningKeys::test(), &pool).await.unwrap();
+        assert!(matches!(result, CreateRoomResult::InvalidRoomAlias));
+
+        let body = json!({ "initial_state": [{ "type": "m.room.create", "content": {} }] });
+        let result = create_room(&request(body), &auth, &config, &SigningKeys::test(), &pool).await.unwrap();
+        assert!(matches!(result, CreateRoomResult::InvalidInitialState));
+
+        // The creator can't send the preset's events once the override puts
+        // state events out of their reach, so no room is stored.
+        let body = json!({ "power_level_content_override": { "state_default": CREATOR_POWER_LEVEL + 1 } });
+        let result = create_room(&request(body), &auth, &config, &SigningKeys::test(), &pool).await.unwrap();
+        assert!(matches!(result, CreateRoomResult::Rejected(_)));
+        let rooms: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rooms").fetch_one(&pool).await.unwrap();
+        assert_eq!(rooms, 0);
+
+        auth.is_guest = true;
+        let result = create_room(&request(json!({})), &auth, &config, &SigningKeys::test(), &pool).await.unwrap();
+        assert!(matches!(result, CreateRoomResult::GuestAccessForbidden));
+    }
+
+    fn client_event(event_type: &str, state_key: Option<&str>, content: Value) -> ClientEvent {
+        ClientEvent { event_type: event_type.to_string(), state_key: state_key.map(String::from), content }
+    }
+
+    async fn send(
+        room_id: &RoomId,
+        event: ClientEvent,
+        txn_id: Option<&str>,
+        auth: &AuthenticatedUser,
+        config: &Config,
+        pool: &PgPool
+    ) -> SendEventResult {
+        send_event(room_id, event, txn_id, auth, config, &SigningKeys::test(), pool).await.unwrap()
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_send_event(pool: PgPool) {
+        let config = Config::test();
+        let auth = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "name": "Lobby" }), &auth, &config, &pool).await;
+        let room_id = &events[0].room_id;
+
+        let message = client_event("m.room.message", None, json!({ "msgtype": "m.text", "body": "hi" }));
+        let SendEventResult::Sent(event_id) = send(room_id, message.clone(), Some("txn1"), &auth, &config, &pool).await else {
+            panic!("Expected event to be sent");
+        };
+        let event = pg::events::get_event(&event_id, &pool).await.unwrap().unwrap();
+        assert_eq!(event.content["body"], "hi");
+        assert_eq!(pg::events::tests::get_prev_events(&event_id, &pool).await, vec![events.last().unwrap().event_id.clone()]);
+        assert_eq!(pg::events::get_forward_extremities(room_id, &pool).await.unwrap(), vec![event_id.clone()]);
+
+        // A retry returns the same event.
+        let SendEventResult::Sent(retry_id) = send(room_id, message.clone(), Some("txn1"), &auth, &config, &pool).await else {
+            panic!("Expected event to be sent");
+        };
+        assert_eq!(retry_id, event_id);
+        let SendEventResult::Sent(other_id) = send(room_id, message, Some("txn2"), &auth, &config, &pool).await else {
+            panic!("Expected event to be sent");
+        };
+        assert_ne!(other_id, event_id);
+
+        let topic = client_event("m.room.topic", Some(""), json!({ "topic": "Say hello" }));
+        let SendEventResult::Sent(topic_id) = send(room_id, topic, None, &auth, &config, &pool).await else {
+            panic!("Expected event to be sent");
+        };
+        let current_topic = pg::events::get_current_state_event(room_id, "m.room.topic", "", &pool).await.unwrap().unwrap();
+        assert_eq!(current_topic.event_id, topic_id);
     }
 
-    let base_url = state.config.server.base_url.clone();
-    let name = format!("!{}:{}", Uuid::new_v4(), base_url);
-    pg::rooms::create_room(&name, &pool).await?;
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_send_event_concurrently(pool: PgPool) {
+        let config = Config::test();
+        let auth = authenticated_user(&config, &pool).await;
+        let events = create(json!({}), &auth, &config, &pool).await;
+        let room_id = &events[0].room_id;
 
-    let event = pg::events::CreateRoomEvent::from(request);
+        // Each event follows the one stored before it rather than the same
+        // extremity.
+        let sends = (0..5).map(|i| {
+            let message = client_event("m.room.message", None, json!({ "body": i.to_string() }));
+            send(room_id, message, None, &auth, &config, &pool)
+        });
+        futures_util::future::join_all(sends).await;
 
-    pg::events::create_event(&event.r#type, &event, &pool).await?;
-    Ok(name)
+        let events = pg::events::tests::get_room_events(room_id, &pool).await;
+        for pair in events.windows(2) {
+            assert_eq!(pg::events::tests::get_prev_events(&pair[1].event_id, &pool).await, vec![pair[0].event_id.clone()]);
+        }
+        assert_eq!(pg::events::get_forward_extremities(room_id, &pool).await.unwrap().len(), 1);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_send_event_forbidden(pool: PgPool) {
+        let config = Config::test();
+        let auth = authenticated_user(&config, &pool).await;
+        let other = authenticated_user(&config, &pool).await;
+        let events = create(json!({}), &auth, &config, &pool).await;
+        let room_id = &events[0].room_id;
+
+        let message = client_event("m.room.message", None, json!({ "body": "hi" }));
+        let result = send(room_id, message.clone(), Some("txn1"), &other, &config, &pool).await;
+        assert!(matches!(result, SendEventResult::Forbidden(_)));
+
+        let unknown_room = RoomId::generate(&config.server.server_name);
+        let result = send(&unknown_room, message, Some("txn1"), &auth, &config, &pool).await;
+        assert!(matches!(result, SendEventResult::RoomNotFound));
+    }
 }
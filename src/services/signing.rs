@@ -0,0 +1,467 @@
+//! Server signing keys, and signing and verifying JSON and events with them
+//!
+//! See https://spec.matrix.org/v1.13/appendices/#signing-json and
+//! https://spec.matrix.org/v1.13/server-server-api/#signing-events
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::models::ids::ServerName;
+use crate::models::room_version::RoomVersion;
+use crate::services::canonical_json;
+use crate::services::events;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use chrono::{NaiveDateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::RngCore;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use twelf::reexports::serde_json::{json, Value};
+
+/// Algorithm of the server's signing keys, which prefixes their key IDs
+pub const ALGORITHM: &str = "ed25519";
+
+/// File name suffixes of the private and public halves of a key pair
+const PRIVATE_KEY_SUFFIX: &str = ".key";
+const PUBLIC_KEY_SUFFIX: &str = ".pub";
+
+/// Format of key versions, which are the UTC time the key was generated
+const VERSION_FORMAT: &str = "%Y%m%d%H%M%S";
+
+/// The ed25519 keys this server signs events and federation requests with,
+/// loaded once at startup
+///
+/// Keys live in the directory given by `signing_key_dir` in the `[server]`
+/// config section as pairs of files named after their version: `<version>.key`
+/// holds the private key as `ed25519 <version> <seed>`, the format Synapse also
+/// uses, and `<version>.pub` the unpadded base64 public key. The key ID is
+/// `ed25519:<version>`. Versions are UTC timestamps, so the newest private key
+/// signs, while the public keys of older ones are still published, with the
+/// time they were replaced, so that other servers can verify what they signed.
+#[derive(Clone)]
+pub struct SigningKeys {
+    server_name: ServerName,
+    key_id: String,
+    signing_key: SigningKey,
+    old_verify_keys: Vec<OldVerifyKey>,
+}
+
+/// A public key whose private key no longer signs, and the time in
+/// milliseconds since the epoch when it was replaced
+#[derive(Debug, Clone)]
+pub struct OldVerifyKey {
+    pub key_id: String,
+    pub key: VerifyingKey,
+    pub expired_ts: i64,
+}
+
+impl SigningKeys {
+    pub fn load(config: &Config) -> Result<Self, Error> {
+        Self::load_dir(Path::new(&config.server.signing_key_dir), &config.server.server_name)
+    }
+
+    /// Loads the keys in `key_dir`, which must include a private key
+    pub fn load_dir(key_dir: &Path, server_name: &ServerName) -> Result<Self, Error> {
+        let version = signing_key_versions(key_dir)?
+            .pop()
+            .ok_or_else(|| Error::Config(format!(
+                "No server signing key found in {}; run `spelt signing-keys generate` to create one",
+                key_dir.display()
+            )))?;
+        let signing_key = read_signing_key(key_dir, &version)?;
+
+        let versions = verify_key_versions(key_dir)?;
+        if !versions.contains(&version) {
+            return Err(Error::Config(format!("No public key found for server signing key {}", version)));
+        }
+
+        let mut old_verify_keys = Vec::new();
+        for (old_version, next_version) in versions.iter().zip(versions.iter().skip(1)) {
+            if *old_version >= version {
+                break;
+            }
+            old_verify_keys.push(OldVerifyKey {
+                key_id: key_id(old_version),
+                key: read_verify_key(key_dir, old_version)?,
+                expired_ts: version_timestamp(next_version)?,
+            });
+        }
+
+        Ok(SigningKeys {
+            server_name: server_name.clone(),
+            key_id: key_id(&version),
+            signing_key,
+            old_verify_keys,
+        })
+    }
+
+    /// ID of the key that signs, e.g. `ed25519:20250426120000`
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Public half of the key that signs
+    pub fn verify_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    /// Public keys that signed in the past, oldest first
+    pub fn old_verify_keys(&self) -> &[OldVerifyKey] {
+        &self.old_verify_keys
+    }
+
+    /// Adds this server's signature of `value` to its `signatures`, keeping
+    /// any other signatures
+    ///
+    /// `unsigned` and existing signatures aren't signed.
+    pub fn sign_json(&self, value: &mut Value) -> Result<(), Error> {
+        let object = value.as_object_mut()
+            .ok_or_else(|| Error::BadJson(String::from("Only JSON objects can be signed")))?;
+        let signatures = object.remove("signatures");
+        let unsigned = object.remove("unsigned");
+
+        let signature = self.signing_key.sign(canonical_json::to_canonical_json(value)?.as_bytes());
+
+        let object = value.as_object_mut().unwrap();
+        let mut signatures = signatures.filter(Value::is_object).unwrap_or_else(|| json!({}));
+        if !signatures[self.server_name.as_str()].is_object() {
+            signatures[self.server_name.as_str()] = json!({});
+        }
+        signatures[self.server_name.as_str()][&self.key_id] = json!(STANDARD_NO_PAD.encode(signature.to_bytes()));
+        object.insert(String::from("signatures"), signatures);
+        if let Some(unsigned) = unsigned {
+            object.insert(String::from("unsigned"), unsigned);
+        }
+
+        Ok(())
+    }
+
+    /// Adds this server's signature to an event that already has its content
+    /// hash
+    ///
+    /// The redacted form of the event is signed, so that the signature remains
+    /// valid if the event is redacted.
+    pub fn sign_event(&self, event: &mut Value, room_version: &RoomVersion) -> Result<(), Error> {
+        let mut redacted = events::redact(event, room_version);
+        self.sign_json(&mut redacted)?;
+        event["signatures"] = redacted["signatures"].take();
+
+        Ok(())
+    }
+
+    /// Returns signing keys that are generated on every call and never stored,
+    /// for `chat.spelt.io`
+    #[cfg(test)]
+    pub fn test() -> Self {
+        SigningKeys {
+            server_name: "chat.spelt.io".parse().unwrap(),
+            key_id: key_id("19700101000000"),
+            signing_key: generate_signing_key(),
+            old_verify_keys: vec![],
+        }
+    }
+}
+
+impl fmt::Debug for SigningKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let old_key_ids: Vec<&String> = self.old_verify_keys.iter().map(|k| &k.key_id).collect();
+
+        f.debug_struct("SigningKeys")
+            .field("server_name", &self.server_name)
+            .field("key_id", &self.key_id)
+            .field("old_key_ids", &old_key_ids)
+            .finish()
+    }
+}
+
+/// Checks the signature of `value` by the key `key_id` of `server_name`
+///
+/// Fails with [`Error::Auth`] if the signature is missing or invalid.
+pub fn verify_json(value: &Value, server_name: &ServerName, key_id: &str, key: &VerifyingKey) -> Result<(), Error> {
+    let encoded = value["signatures"][server_name.as_str()][key_id].as_str()
+        .ok_or_else(|| Error::Auth(format!("No signature by {} with key {}", server_name, key_id)))?;
+    let signature = STANDARD_NO_PAD.decode(encoded)
+        .or_else(|_| STANDARD.decode(encoded))
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| Error::Auth(format!("Malformed signature by {} with key {}", server_name, key_id)))?;
+
+    let mut value = value.clone();
+    if let Some(object) = value.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+
+    key.verify_strict(canonical_json::to_canonical_json(&value)?.as_bytes(), &signature)
+        .map_err(|_| Error::Auth(format!("Invalid signature by {} with key {}", server_name, key_id)))
+}
+
+/// Returns the public key encoded in unpadded base64, as it's published
+pub fn encode_verify_key(key: &VerifyingKey) -> String {
+    STANDARD_NO_PAD.encode(key.as_bytes())
+}
+
+/// Returns the public key encoded in `encoded`, which may be padded
+pub fn decode_verify_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes = STANDARD_NO_PAD.decode(encoded).or_else(|_| STANDARD.decode(encoded)).ok()?;
+    VerifyingKey::from_bytes(&bytes.try_into().ok()?).ok()
+}
+
+/// Returns the versions of the keys in `key_dir` that have a private key,
+/// oldest first; the last one is used for signing
+pub fn signing_key_versions(key_dir: &Path) -> Result<Vec<String>, Error> {
+    key_versions(key_dir, PRIVATE_KEY_SUFFIX)
+}
+
+/// Returns the versions of the keys in `key_dir` that have a public key,
+/// oldest first
+pub fn verify_key_versions(key_dir: &Path) -> Result<Vec<String>, Error> {
+    key_versions(key_dir, PUBLIC_KEY_SUFFIX)
+}
+
+/// Generates a new key pair in `key_dir`, creating the directory if needed,
+/// and returns its key ID
+pub fn generate_key(key_dir: &Path) -> Result<String, Error> {
+    let version = Utc::now().format(VERSION_FORMAT).to_string();
+    let signing_key = generate_signing_key();
+
+    let private_key = format!("{} {} {}\n", ALGORITHM, version, STANDARD_NO_PAD.encode(signing_key.to_bytes()));
+    let public_key = format!("{}\n", encode_verify_key(&signing_key.verifying_key()));
+
+    fs::create_dir_all(key_dir)?;
+    write_key_file(key_dir, &version, PRIVATE_KEY_SUFFIX, private_key.as_bytes(), 0o600)?;
+    write_key_file(key_dir, &version, PUBLIC_KEY_SUFFIX, public_key.as_bytes(), 0o644)?;
+
+    Ok(key_id(&version))
+}
+
+/// Generates a new signing key and deletes the private keys it replaces
+///
+/// The old public keys are kept so that they can still be published. Returns
+/// the ID of the new key.
+pub fn rotate_keys(key_dir: &Path) -> Result<String, Error> {
+    let old_versions = signing_key_versions(key_dir)?;
+    let key_id = generate_key(key_dir)?;
+
+    for old_version in old_versions {
+        fs::remove_file(key_dir.join(format!("{}{}", old_version, PRIVATE_KEY_SUFFIX)))?;
+    }
+
+    Ok(key_id)
+}
+
+/// Returns the key ID of the key with `version`
+pub fn key_id(version: &str) -> String {
+    format!("{}:{}", ALGORITHM, version)
+}
+
+fn generate_signing_key() -> SigningKey {
+    let mut seed = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut seed);
+
+    SigningKey::from_bytes(&seed)
+}
+
+fn version_timestamp(version: &str) -> Result<i64, Error> {
+    NaiveDateTime::parse_from_str(version, VERSION_FORMAT)
+        .map(|time| time.and_utc().timestamp_millis())
+        .map_err(|_| Error::Config(format!("Invalid server signing key version: {}", version)))
+}
+
+fn key_versions(key_dir: &Path, suffix: &str) -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(key_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut versions = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        if let Some(version) = file_name.to_str().and_then(|name| name.strip_suffix(suffix)) {
+            if !version.is_empty() && !version.contains('.') {
+                versions.push(version.to_string());
+            }
+        }
+    }
+    versions.sort();
+
+    Ok(versions)
+}
+
+fn read_signing_key(key_dir: &Path, version: &str) -> Result<SigningKey, Error> {
+    let contents = read_key_file(key_dir, version, PRIVATE_KEY_SUFFIX)?;
+    let seed = match contents.split_whitespace().collect::<Vec<_>>()[..] {
+        [ALGORITHM, v, seed] if v == version => STANDARD_NO_PAD.decode(seed).ok(),
+        _ => None,
+    };
+
+    seed.and_then(|seed| seed.try_into().ok())
+        .map(|seed: [u8; 32]| SigningKey::from_bytes(&seed))
+        .ok_or_else(|| Error::Config(format!("Invalid server signing key {}", version)))
+}
+
+fn read_verify_key(key_dir: &Path, version: &str) -> Result<VerifyingKey, Error> {
+    decode_verify_key(read_key_file(key_dir, version, PUBLIC_KEY_SUFFIX)?.trim())
+        .ok_or_else(|| Error::Config(format!("Invalid server verify key {}", version)))
+}
+
+fn read_key_file(key_dir: &Path, version: &str, suffix: &str) -> Result<String, Error> {
+    let path = key_dir.join(format!("{}{}", version, suffix));
+    fs::read_to_string(&path).map_err(|e| Error::Config(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+fn write_key_file(key_dir: &Path, version: &str, suffix: &str, contents: &[u8], mode: u32) -> Result<(), Error> {
+    let path = key_dir.join(format!("{}{}", version, suffix));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&path)
+        .map_err(|e| Error::Config(format!("Failed to create {}: {}", path.display(), e)))?;
+    file.write_all(contents)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::room_version;
+    use base64::alphabet;
+    use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+    use std::path::PathBuf;
+
+    /// Signing key from the examples in the spec appendix
+    fn spec_keys() -> SigningKeys {
+        // The seed's last character has trailing bits set.
+        let engine = GeneralPurpose::new(&alphabet::STANDARD, GeneralPurposeConfig::new()
+            .with_encode_padding(false)
+            .with_decode_padding_mode(DecodePaddingMode::RequireNone)
+            .with_decode_allow_trailing_bits(true));
+        let seed: [u8; 32] = engine.decode("YJDBA9Xnr2sVqXD9Vj7XVUnmFZcZrlw8Md7kMW+3XA1").unwrap().try_into().unwrap();
+
+        SigningKeys {
+            server_name: "domain".parse().unwrap(),
+            key_id: String::from("ed25519:1"),
+            signing_key: SigningKey::from_bytes(&seed),
+            old_verify_keys: vec![],
+        }
+    }
+
+    fn test_key_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("spelt-test-signing-keys-{}", uuid::Uuid::new_v4().simple()))
+    }
+
+    #[test]
+    fn test_sign_json() {
+        let keys = spec_keys();
+
+        let mut value = json!({});
+        keys.sign_json(&mut value).unwrap();
+        assert_eq!(value, json!({
+            "signatures": {
+                "domain": {
+                    "ed25519:1": "K8280/U9SSy9IVtjBuVeLr+HpOB4BQFWbg+UZaADMtTdGYI7Geitb76LTrr5QV/7Xg4ahLwYGYZzuHGZKM5ZAQ"
+                }
+            }
+        }));
+
+        let mut value = json!({ "one": 1, "two": "Two", "unsigned": { "age": 5 } });
+        keys.sign_json(&mut value).unwrap();
+        assert_eq!(
+            value["signatures"]["domain"]["ed25519:1"],
+            "KqmLSbO39/Bzb0QIYE82zqLwsA+PDzYIpIRA2sRQ4sL53+sN6/fpNSoqE7BP7vBZhG6kYdD13EIMJpvhJI+6Bw"
+        );
+        assert_eq!(value["unsigned"], json!({ "age": 5 }));
+    }
+
+    #[test]
+    fn test_verify_json() {
+        let keys = SigningKeys::test();
+        let other_keys = SigningKeys::test();
+        let mut value = json!({ "one": 1, "signatures": { "other.server": { "ed25519:1": "abc" } } });
+        keys.sign_json(&mut value).unwrap();
+
+        assert_eq!(value["signatures"]["other.server"]["ed25519:1"], "abc");
+        assert!(verify_json(&value, &keys.server_name, keys.key_id(), &keys.verify_key()).is_ok());
+        assert!(verify_json(&value, &keys.server_name, keys.key_id(), &other_keys.verify_key()).is_err());
+        assert!(verify_json(&value, &keys.server_name, "ed25519:other", &keys.verify_key()).is_err());
+
+        value["unsigned"] = json!({ "age": 5 });
+        assert!(verify_json(&value, &keys.server_name, keys.key_id(), &keys.verify_key()).is_ok());
+
+        value["one"] = json!(2);
+        assert!(matches!(
+            verify_json(&value, &keys.server_name, keys.key_id(), &keys.verify_key()),
+            Err(Error::Auth(_))
+        ));
+    }
+
+    #[test]
+    fn test_sign_event() {
+        let keys = SigningKeys::test();
+        let room_version = room_version::get("10").unwrap();
+        let mut event = json!({
+            "room_id": "!abc:chat.spelt.io",
+            "sender": "@alice:chat.spelt.io",
+            "type": "m.room.message",
+            "content": { "body": "hi" },
+            "depth": 2,
+            "origin_server_ts": 1000,
+            "prev_events": [],
+            "auth_events": [],
+        });
+        event["hashes"] = json!({ "sha256": canonical_json::content_hash(&event).unwrap() });
+        keys.sign_event(&mut event, room_version).unwrap();
+
+        let verify = |event: &Value| {
+            verify_json(&events::redact(event, room_version), &keys.server_name, keys.key_id(), &keys.verify_key())
+        };
+        assert!(verify(&event).is_ok());
+
+        // The signature covers the redacted event, so it survives a change to
+        // the content.
+        event["content"]["body"] = json!("bye");
+        assert!(verify(&event).is_ok());
+
+        event["depth"] = json!(3);
+        assert!(verify(&event).is_err());
+    }
+
+    #[test]
+    fn test_load_without_keys() {
+        let key_dir = test_key_dir();
+
+        assert!(matches!(SigningKeys::load_dir(&key_dir, &ServerName::default()), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let key_dir = test_key_dir();
+        let old_key_id = generate_key(&key_dir).unwrap();
+        let old_keys = SigningKeys::load_dir(&key_dir, &ServerName::default()).unwrap();
+        assert_eq!(old_keys.key_id(), old_key_id);
+        assert!(old_keys.old_verify_keys().is_empty());
+
+        // Key versions have one-second resolution.
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let new_key_id = rotate_keys(&key_dir).unwrap();
+        let new_keys = SigningKeys::load_dir(&key_dir, &ServerName::default()).unwrap();
+
+        assert_eq!(new_keys.key_id(), new_key_id);
+        assert_eq!(new_keys.old_verify_keys().len(), 1);
+        assert_eq!(new_keys.old_verify_keys()[0].key_id, old_key_id);
+        assert_eq!(new_keys.old_verify_keys()[0].key, old_keys.verify_key());
+        assert_eq!(
+            new_keys.old_verify_keys()[0].expired_ts,
+            version_timestamp(new_key_id.strip_prefix("ed25519:").unwrap()).unwrap()
+        );
+        assert_eq!(signing_key_versions(&key_dir).unwrap().len(), 1);
+
+        fs::remove_dir_all(&key_dir).unwrap();
+    }
+}
@@ -0,0 +1,786 @@
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::events::Event;
+use crate::models::ids::{EventId, RoomId, UserId};
+use crate::models::receipts::Receipt;
+use crate::models::stream_token::StreamToken;
+use crate::routes::sliding_sync::{RoomSubscription, RoomsExtension, SlidingSyncQuery, SlidingSyncRequest};
+use crate::services;
+use crate::services::notifier::Notifier;
+use crate::services::sync::{account_data_event, receipt_event, sync_event};
+use crate::store::pg;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, Instant};
+use twelf::reexports::serde_json::{self, json, Value};
+
+/// Event types whose arrival moves a room to the top of the lists
+const BUMP_EVENT_TYPES: [&str; 7] = [
+    "m.beacon_info",
+    "m.call.invite",
+    "m.poll.start",
+    "m.room.create",
+    "m.room.encrypted",
+    "m.room.message",
+    "m.sticker",
+];
+
+/// Number of to-device messages in each response, unless the client asks for
+/// another number
+const TO_DEVICE_LIMIT: i64 = 100;
+
+/// Most members to suggest as heroes for naming a room without a name
+const HERO_LIMIT: usize = 5;
+
+/// Possible results of calling [`sliding_sync()`]
+pub enum SlidingSyncResult {
+    Synced(Box<SlidingSyncResponse>),
+    /// `pos` isn't the latest position of the connection, which the client has
+    /// to start again
+    UnknownPos,
+}
+
+/// Response to a sliding sync request
+///
+/// See https://github.com/matrix-org/matrix-spec-proposals/pull/4186
+#[derive(Debug, Default, Serialize)]
+pub struct SlidingSyncResponse {
+    pub pos: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txn_id: Option<String>,
+    pub lists: HashMap<String, ListResponse>,
+    pub rooms: HashMap<RoomId, RoomResponse>,
+    pub extensions: ExtensionsResponse,
+}
+
+impl SlidingSyncResponse {
+    /// Returns `true` if there's nothing new for the client
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty() && self.extensions.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListResponse {
+    /// The number of rooms in the whole list
+    pub count: usize,
+}
+
+/// What's new in a room, or everything requested if `initial` is `true`
+#[derive(Debug, Default, Serialize)]
+pub struct RoomResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    /// Members to name the room after if it has no name
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub heroes: Vec<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub initial: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub is_dm: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invite_state: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub knock_state: Option<Vec<Value>>,
+    pub required_state: Vec<Value>,
+    pub timeline: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev_batch: Option<StreamToken>,
+    /// Whether there are more events between the last response and these
+    pub limited: bool,
+    /// How many of the timeline events are new since the last response
+    pub num_live: usize,
+    /// The stream ordering of the latest event that moved the room up the lists
+    pub bump_stamp: i64,
+    pub joined_count: i64,
+    pub invited_count: i64,
+    pub notification_count: i64,
+    pub highlight_count: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExtensionsResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_device: Option<ToDeviceResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e2ee: Option<E2eeResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<AccountDataResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipts: Option<RoomEventsResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typing: Option<RoomEventsResponse>,
+}
+
+impl ExtensionsResponse {
+    /// Returns `true` if no extension has anything new for the client
+    pub fn is_empty(&self) -> bool {
+        self.to_device.as_ref().is_none_or(|e| e.events.is_empty())
+            && self.account_data.as_ref().is_none_or(|e| e.global.is_empty() && e.rooms.is_empty())
+            && self.receipts.as_ref().is_none_or(|e| e.rooms.is_empty())
+            && self.typing.as_ref().is_none_or(|e| e.rooms.is_empty())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ToDeviceResponse {
+    /// What to pass as the extension's `since` to acknowledge these events
+    pub next_batch: String,
+    pub events: Vec<Value>,
+}
+
+/// End-to-end encryption data for the device
+///
+/// This server doesn't store device keys yet, so these are always empty.
+#[derive(Debug, Default, Serialize)]
+pub struct E2eeResponse {
+    pub device_one_time_keys_count: HashMap<String, i64>,
+    pub device_unused_fallback_key_types: Vec<String>,
+    pub device_lists: DeviceLists,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DeviceLists {
+    pub changed: Vec<UserId>,
+    pub left: Vec<UserId>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct AccountDataResponse {
+    pub global: Vec<Value>,
+    pub rooms: HashMap<RoomId, Vec<Value>>,
+}
+
+/// One event for each room that has any, such as `m.receipt` or `m.typing`
+#[derive(Debug, Default, Serialize)]
+pub struct RoomEventsResponse {
+    pub rooms: HashMap<RoomId, Value>,
+}
+
+/// A room that the user is in or has been in, as it stands for the lists
+struct ListedRoom {
+    room_id: RoomId,
+    membership_event: Event,
+    bump_stamp: i64,
+    is_dm: bool,
+}
+
+/// What to send about a room, combined from the lists and subscriptions that
+/// include it
+#[derive(Default)]
+struct RoomConfig {
+    required_state: BTreeSet<(String, String)>,
+    timeline_limit: usize,
+    /// Names of the lists that include the room
+    lists: HashSet<String>,
+    subscribed: bool,
+}
+
+impl RoomConfig {
+    fn add(&mut self, subscription: &RoomSubscription) {
+        self.required_state.extend(subscription.required_state.iter().cloned());
+        self.timeline_limit = self.timeline_limit.max(subscription.timeline_limit);
+    }
+
+    /// Returns whether `extension` covers the room
+    fn is_covered_by(&self, extension: &RoomsExtension) -> bool {
+        let wildcard = |names: &[String]| names.iter().any(|name| name == "*");
+        let in_lists = extension.lists.as_deref()
+            .is_none_or(|lists| wildcard(lists) || lists.iter().any(|name| self.lists.contains(name)));
+        let in_rooms = extension.rooms.as_deref().is_none_or(wildcard);
+
+        (in_lists && !self.lists.is_empty()) || (in_rooms && self.subscribed)
+    }
+}
+
+/// Returns the requested windows of the user's room lists, what's new in those
+/// rooms and the subscribed rooms since the connection's response at
+/// `query.pos`, or everything if it's absent, and the data of the enabled
+/// extensions
+///
+/// The connection records how far it has sent each room so that later
+/// responses only carry what's new. If there's nothing new, this waits up to
+/// `query.timeout` milliseconds for `notifier` to announce something for the
+/// user.
+///
+/// See https://github.com/matrix-org/matrix-spec-proposals/pull/4186
+pub async fn sliding_sync(
+    query: &SlidingSyncQuery,
+    request: &SlidingSyncRequest,
+    auth: &AuthenticatedUser,
+    notifier: &Notifier,
+    pool: &PgPool
+) -> Result<SlidingSyncResult, Error> {
+    let conn_id = request.conn_id.as_deref().unwrap_or_default();
+    let (connection, since) = match query.pos {
+        None => (pg::sliding_sync::create_connection(auth.session_id, conn_id, pool).await?, None),
+        Some(ref pos) => match pg::sliding_sync::get_connection(auth.session_id, conn_id, pool).await? {
+            Some(connection) if pos.parse() == Ok(connection.position) => {
+                let since = connection.stream_ordering;
+                (connection, Some(since))
+            }
+            _ => return Ok(SlidingSyncResult::UnknownPos),
+        },
+    };
+    let sent_rooms: HashMap<RoomId, (i64, Value)> = pg::sliding_sync::get_connection_rooms(connection.id, pool).await?
+        .into_iter()
+        .map(|room| (room.room_id, (room.stream_ordering, room.required_state)))
+        .collect();
+
+    let deadline = Instant::now() + Duration::from_millis(query.timeout.unwrap_or(0));
+    let room_ids = pg::rooms::get_room_ids_by_membership(&auth.matrix_user_id, "join", pool).await?;
+    let mut subscription = notifier.subscribe(&auth.matrix_user_id, &room_ids);
+
+    loop {
+        let to = pg::streams::get_current_stream_ordering(pool).await?;
+        let (mut response, configs) = sliding_sync_between(since, to, request, &sent_rooms, auth, pool).await?;
+
+        // Wait for something after both positions, so that a token ahead of
+        // the stream position can't make this loop spin.
+        let wait_position = since.map_or(to, |since| since.max(to));
+        if since.is_none() || !response.is_empty() || !subscription.wait(wait_position, deadline).await {
+            let position = if since.is_none() || !response.is_empty() {
+                let (room_ids, required_states): (Vec<RoomId>, Vec<Value>) = response.rooms.keys()
+                    .map(|room_id| (room_id.clone(), required_state_json(&configs[room_id])))
+                    .unzip();
+                let position = connection.position + 1;
+                pg::sliding_sync::advance_connection(connection.id, position, to, &room_ids, &required_states, pool).await?;
+                position
+            } else {
+                connection.position
+            };
+            response.pos = position.to_string();
+            response.txn_id = request.txn_id.clone();
+
+            return Ok(SlidingSyncResult::Synced(Box::new(response)));
+        }
+    }
+}
+
+/// Returns what changed for the user after stream ordering `since`, or
+/// everything if it's `None`, up to and including `to`, along with what was
+/// asked of each included room
+///
+/// `sent_rooms` holds the stream ordering up to which the connection has sent
+/// each room and the required state it was sent with; rooms sent with other
+/// required state are sent again in full.
+async fn sliding_sync_between(
+    since: Option<i64>,
+    to: i64,
+    request: &SlidingSyncRequest,
+    sent_rooms: &HashMap<RoomId, (i64, Value)>,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<(SlidingSyncResponse, HashMap<RoomId, RoomConfig>), Error> {
+    let mut response = SlidingSyncResponse::default();
+    let rooms = listed_rooms(since, to, auth, pool).await?;
+
+    let mut configs: HashMap<RoomId, RoomConfig> = HashMap::new();
+    for (name, list) in &request.lists {
+        let matching: Vec<&ListedRoom> = rooms.iter()
+            .filter(|room| list.filters.is_dm.is_none_or(|is_dm| is_dm == room.is_dm))
+            .filter(|room| {
+                let is_invite = room.membership_event.content["membership"] == "invite";
+                list.filters.is_invite.is_none_or(|wanted| wanted == is_invite)
+            })
+            .collect();
+        for &(start, end) in &list.ranges {
+            for room in matching.iter().skip(start).take((end + 1).saturating_sub(start)) {
+                let config = configs.entry(room.room_id.clone()).or_default();
+                config.add(&list.room);
+                config.lists.insert(name.clone());
+            }
+        }
+        response.lists.insert(name.clone(), ListResponse { count: matching.len() });
+    }
+    for (room_id, room_subscription) in &request.room_subscriptions {
+        if rooms.iter().any(|room| room.room_id == *room_id) {
+            let config = configs.entry(room_id.clone()).or_default();
+            config.add(room_subscription);
+            config.subscribed = true;
+        }
+    }
+
+    for room in &rooms {
+        let Some(config) = configs.get(&room.room_id) else {
+            continue;
+        };
+        let sent = sent_rooms.get(&room.room_id)
+            .filter(|(_, required_state)| *required_state == required_state_json(config))
+            .map(|(stream_ordering, _)| *stream_ordering);
+        if let Some(room_response) = room_response(room, config, since, sent, to, auth, pool).await? {
+            response.rooms.insert(room.room_id.clone(), room_response);
+        }
+    }
+
+    let extensions = &request.extensions;
+    let from = since.unwrap_or(0);
+    let covered = |extension: &RoomsExtension| -> Vec<RoomId> {
+        configs.iter()
+            .filter(|(_, config)| config.is_covered_by(extension))
+            .map(|(room_id, _)| room_id.clone())
+            .collect()
+    };
+    if extensions.to_device.enabled == Some(true) {
+        response.extensions.to_device = Some(to_device_response(extensions.to_device.since.as_deref(), extensions.to_device.limit, to, auth, pool).await?);
+    }
+    if extensions.e2ee.enabled == Some(true) {
+        response.extensions.e2ee = Some(E2eeResponse::default());
+    }
+    if extensions.account_data.enabled == Some(true) {
+        let room_ids = covered(&extensions.account_data);
+        response.extensions.account_data = Some(account_data_response(from, to, &room_ids, &response.rooms, auth, pool).await?);
+    }
+    if extensions.receipts.enabled == Some(true) {
+        let mut receipts: HashMap<RoomId, Vec<Receipt>> = HashMap::new();
+        for receipt in pg::receipts::get_receipts_between(&covered(&extensions.receipts), from, to, pool).await? {
+            receipts.entry(receipt.room_id.clone()).or_default().push(receipt);
+        }
+        let rooms = receipts.into_iter()
+            .filter_map(|(room_id, receipts)| Some((room_id, receipt_event(receipts, auth)?)))
+            .collect();
+        response.extensions.receipts = Some(RoomEventsResponse { rooms });
+    }
+    if extensions.typing.enabled == Some(true) {
+        let mut rooms = HashMap::new();
+        for room_id in pg::typing::get_rooms_with_typing_between(&covered(&extensions.typing), from, to, pool).await? {
+            let user_ids = pg::typing::get_typing_user_ids(&room_id, pool).await?;
+            if since.is_some() || !user_ids.is_empty() {
+                rooms.insert(room_id, json!({ "type": "m.typing", "content": { "user_ids": user_ids } }));
+            }
+        }
+        response.extensions.typing = Some(RoomEventsResponse { rooms });
+    }
+
+    Ok((response, configs))
+}
+
+/// Returns the rooms that the user has joined, been invited to or knocked on,
+/// and those they've left or been banned from after `since` unless they forgot
+/// them, most recently active first
+async fn listed_rooms(since: Option<i64>, to: i64, auth: &AuthenticatedUser, pool: &PgPool) -> Result<Vec<ListedRoom>, Error> {
+    let user_id = &auth.matrix_user_id;
+    let mut memberships = HashMap::new();
+    for event in pg::events::get_membership_events(user_id, to, pool).await? {
+        memberships.insert(event.room_id.clone(), event);
+    }
+
+    let dm_room_ids: HashSet<String> = pg::account_data::get_account_data(user_id, None, "m.direct", pool).await?
+        .and_then(|content| content.as_object().cloned())
+        .into_iter()
+        .flat_map(|by_user| by_user.into_values())
+        .filter_map(|room_ids| room_ids.as_array().cloned())
+        .flatten()
+        .filter_map(|room_id| room_id.as_str().map(String::from))
+        .collect();
+
+    let joined_room_ids: Vec<RoomId> = memberships.values()
+        .filter(|e| e.content["membership"] == "join")
+        .map(|e| e.room_id.clone())
+        .collect();
+    let bump_stamps = pg::events::get_latest_stream_orderings(&joined_room_ids, &BUMP_EVENT_TYPES, to, pool).await?;
+
+    let mut rooms = Vec::new();
+    for (room_id, membership_event) in memberships {
+        let listed = match membership_event.content["membership"].as_str().unwrap_or_default() {
+            "join" | "invite" | "knock" => true,
+            "leave" | "ban" => since.is_some_and(|since| membership_event.stream_ordering > since)
+                && !pg::rooms::is_room_forgotten(user_id, &room_id, pool).await?,
+            _ => false,
+        };
+        if listed {
+            rooms.push(ListedRoom {
+                bump_stamp: bump_stamps.get(&room_id).copied().unwrap_or(membership_event.stream_ordering),
+                is_dm: dm_room_ids.contains(room_id.as_str()),
+                room_id,
+                membership_event,
+            });
+        }
+    }
+    rooms.sort_by(|a, b| b.bump_stamp.cmp(&a.bump_stamp).then_with(|| a.room_id.as_str().cmp(b.room_id.as_str())));
+
+    Ok(rooms)
+}
+
+/// Returns what's new in a room after stream ordering `sent`, up to which the
+/// connection has sent it, or everything asked for if it's `None`, or `None`
+/// if there's nothing new
+async fn room_response(
+    room: &ListedRoom,
+    config: &RoomConfig,
+    since: Option<i64>,
+    sent: Option<i64>,
+    to: i64,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<Option<RoomResponse>, Error> {
+    let room_id = &room.room_id;
+    let user_id = &auth.matrix_user_id;
+    let membership_event = &room.membership_event;
+    let mut response = RoomResponse {
+        initial: sent.is_none(),
+        is_dm: room.is_dm,
+        bump_stamp: room.bump_stamp,
+        ..Default::default()
+    };
+
+    match membership_event.content["membership"].as_str().unwrap_or_default() {
+        "invite" | "knock" => {
+            if sent.is_some_and(|sent| membership_event.stream_ordering <= sent) {
+                return Ok(None);
+            }
+            let stripped_state = services::membership::stripped_state(room_id, user_id, pool).await?;
+            if membership_event.content["membership"] == "invite" {
+                response.invite_state = Some(stripped_state);
+            } else {
+                response.knock_state = Some(stripped_state);
+            }
+            return Ok(Some(response));
+        }
+        "join" => (),
+        // The user may only see the room up to when they left.
+        _ if sent.is_some_and(|sent| membership_event.stream_ordering <= sent) => return Ok(None),
+        _ => (),
+    }
+    let is_joined = membership_event.content["membership"] == "join";
+    let until = if is_joined { to } else { membership_event.stream_ordering };
+
+    // Page back until more events than the limit are visible to the user, to
+    // know whether the timeline is limited.
+    let limit = config.timeline_limit;
+    let from = sent.unwrap_or(0);
+    let mut events = Vec::new();
+    let mut before = until;
+    loop {
+        let batch = pg::events::get_room_events_between(room_id, from, before, limit as i64 + 1, pool).await?;
+        let exhausted = batch.len() <= limit;
+        before = batch.first().map(|e| e.stream_ordering - 1).unwrap_or(from);
+
+        let visible_ids = services::history::visible_event_ids(&batch, user_id, is_joined, pool).await?;
+        let mut visible: Vec<Event> = batch.into_iter().filter(|e| visible_ids.contains(&e.event_id)).collect();
+        visible.append(&mut events);
+        events = visible;
+        if exhausted || events.len() > limit {
+            break;
+        }
+    }
+    let has_new_events = !events.is_empty();
+    response.limited = events.len() > limit;
+    if response.limited {
+        events.drain(..events.len() - limit);
+    }
+
+    let state_events = if is_joined {
+        pg::events::get_current_state(room_id, pool).await?
+    } else {
+        pg::events::get_state_after_event(&membership_event.event_id, pool).await?
+    };
+    let timeline_senders: HashSet<&UserId> = events.iter().map(|e| &e.sender).collect();
+    let required_state: Vec<&Event> = state_events.iter()
+        .filter(|e| sent.is_none_or(|sent| e.stream_ordering > sent) || is_lazy_member(e, &timeline_senders, config))
+        .filter(|e| required_state_matches(e, config, user_id, &timeline_senders))
+        .collect();
+    if sent.is_some() && !has_new_events && required_state.is_empty() {
+        return Ok(None);
+    }
+
+    let event_ids: Vec<EventId> = events.iter().map(|e| e.event_id.clone()).collect();
+    let transaction_ids = pg::events::get_transaction_ids(auth.session_id, &event_ids, pool).await?;
+    response.timeline = events.iter().map(|e| sync_event(e, transaction_ids.get(&e.event_id))).collect();
+    response.required_state = required_state.into_iter().map(|e| sync_event(e, None)).collect();
+    response.num_live = since.map_or(0, |since| events.iter().filter(|e| e.stream_ordering > since).count());
+    response.prev_batch = events.first().map(|e| StreamToken { stream_ordering: e.stream_ordering - 1 });
+
+    let state_content = |event_type: &str| {
+        state_events.iter()
+            .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(""))
+            .map(|e| &e.content)
+    };
+    response.name = state_content("m.room.name").and_then(|c| c["name"].as_str()).map(String::from);
+    response.avatar = state_content("m.room.avatar").and_then(|c| c["url"].as_str()).map(String::from);
+
+    let members: Vec<&Event> = state_events.iter().filter(|e| e.event_type == "m.room.member").collect();
+    let count = |membership: &str| members.iter().filter(|e| e.content["membership"] == membership).count() as i64;
+    response.joined_count = count("join");
+    response.invited_count = count("invite");
+    if response.name.is_none() {
+        response.heroes = members.iter()
+            .filter(|e| e.state_key.as_deref() != Some(user_id.as_str()))
+            .filter(|e| e.content["membership"] == "join" || e.content["membership"] == "invite")
+            .take(HERO_LIMIT)
+            .map(|e| json!({
+                "user_id": e.state_key,
+                "displayname": e.content["displayname"],
+                "avatar_url": e.content["avatar_url"],
+            }))
+            .collect();
+    }
+
+    let (notification_count, highlight_count) = pg::receipts::count_unread_events(room_id, user_id, until, pool).await?;
+    response.notification_count = notification_count;
+    response.highlight_count = highlight_count;
+
+    Ok(Some(response))
+}
+
+/// Returns whether `event` is the membership of a timeline sender that the
+/// room's required state lazy-loads
+fn is_lazy_member(event: &Event, timeline_senders: &HashSet<&UserId>, config: &RoomConfig) -> bool {
+    event.event_type == "m.room.member"
+        && config.required_state.iter().any(|(t, k)| t == "m.room.member" && k == "$LAZY")
+        && timeline_senders.iter().any(|sender| event.state_key.as_deref() == Some(sender.as_str()))
+}
+
+/// Returns whether the room's required state includes `event`
+fn required_state_matches(event: &Event, config: &RoomConfig, user_id: &UserId, timeline_senders: &HashSet<&UserId>) -> bool {
+    let Some(ref state_key) = event.state_key else {
+        return false;
+    };
+
+    config.required_state.iter().any(|(event_type, key)| {
+        (event_type == "*" || *event_type == event.event_type)
+            && match key.as_str() {
+                "*" => true,
+                "$ME" => state_key == user_id.as_str(),
+                "$LAZY" => is_lazy_member(event, timeline_senders, config),
+                key => key == state_key,
+            }
+    })
+}
+
+fn required_state_json(config: &RoomConfig) -> Value {
+    serde_json::to_value(&config.required_state).unwrap_or_default()
+}
+
+/// Returns the to-device messages for the device after the client's `since`,
+/// deleting those up to `since` as the client has received them
+async fn to_device_response(
+    since: Option<&str>,
+    limit: Option<i64>,
+    to: i64,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<ToDeviceResponse, Error> {
+    let user_id = &auth.matrix_user_id;
+    let device_id = &auth.device_id;
+    let since = since.and_then(|since| since.parse().ok()).unwrap_or(0);
+    if since > 0 {
+        pg::to_device::delete_to_device_messages(user_id, device_id, since, pool).await?;
+    }
+
+    let limit = limit.unwrap_or(TO_DEVICE_LIMIT);
+    let messages = pg::to_device::get_to_device_messages(user_id, device_id, since, to, limit, pool).await?;
+    let next_batch = match messages.last() {
+        Some(message) if messages.len() as i64 == limit => message.stream_ordering,
+        _ => to.max(since),
+    };
+
+    Ok(ToDeviceResponse {
+        next_batch: next_batch.to_string(),
+        events: messages.iter()
+            .map(|m| json!({ "sender": m.sender, "type": m.event_type, "content": m.content }))
+            .collect(),
+    })
+}
+
+/// Returns the user's global account data and that of the rooms with IDs
+/// `room_ids` that changed after stream ordering `from`, and all the account
+/// data of those rooms that are sent in full in `rooms`
+async fn account_data_response(
+    from: i64,
+    to: i64,
+    room_ids: &[RoomId],
+    rooms: &HashMap<RoomId, RoomResponse>,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<AccountDataResponse, Error> {
+    let user_id = &auth.matrix_user_id;
+    let mut response = AccountDataResponse::default();
+    for account_data in pg::account_data::get_account_data_between(user_id, from, to, pool).await? {
+        match account_data.room_id {
+            None => response.global.push(account_data_event(&account_data)),
+            Some(ref room_id) if room_ids.contains(room_id) && !rooms.get(room_id).is_some_and(|room| room.initial) =>
+                response.rooms.entry(room_id.clone()).or_default().push(account_data_event(&account_data)),
+            Some(_) => (),
+        }
+    }
+
+    // Rooms sent in full get all their account data.
+    if rooms.iter().any(|(room_id, room)| room.initial && room_ids.contains(room_id)) {
+        for account_data in pg::account_data::get_account_data_between(user_id, 0, to, pool).await? {
+            let Some(ref room_id) = account_data.room_id else {
+                continue;
+            };
+            if room_ids.contains(room_id) && rooms.get(room_id).is_some_and(|room| room.initial) {
+                response.rooms.entry(room_id.clone()).or_default().push(account_data_event(&account_data));
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::routes::to_device::ToDeviceRequest;
+    use crate::services::rooms::tests::{authenticated_user, create};
+    use crate::services::rooms::{ClientEvent, SendEventResult};
+    use crate::services::signing::SigningKeys;
+
+    fn query(pos: Option<&str>) -> SlidingSyncQuery {
+        SlidingSyncQuery { pos: pos.map(String::from), timeout: Some(0) }
+    }
+
+    fn request(value: Value) -> SlidingSyncRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    async fn synced(query: &SlidingSyncQuery, request: &SlidingSyncRequest, auth: &AuthenticatedUser, pool: &PgPool) -> SlidingSyncResponse {
+        match sliding_sync(query, request, auth, &Notifier::default(), pool).await.unwrap() {
+            SlidingSyncResult::Synced(response) => *response,
+            SlidingSyncResult::UnknownPos => panic!("Expected response"),
+        }
+    }
+
+    async fn send_message(room_id: &RoomId, body: &str, auth: &AuthenticatedUser, config: &Config, pool: &PgPool) {
+        let event = ClientEvent {
+            event_type: String::from("m.room.message"),
+            state_key: None,
+            content: json!({ "msgtype": "m.text", "body": body }),
+        };
+        let result = services::rooms::send_event(room_id, event, None, auth, config, &SigningKeys::test(), pool).await.unwrap();
+        assert!(matches!(result, SendEventResult::Sent(_)));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sliding_sync_lists(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let lobby_id = create(json!({ "name": "Lobby" }), &alice, &config, &pool).await[0].room_id.clone();
+        let garden_id = create(json!({ "name": "Garden" }), &alice, &config, &pool).await[0].room_id.clone();
+        send_message(&garden_id, "Hello", &alice, &config, &pool).await;
+        let request = request(json!({
+            "lists": {
+                "all": { "ranges": [[0, 0]], "timeline_limit": 1, "required_state": [["m.room.name", ""]] }
+            }
+        }));
+
+        // The most recently active room comes first.
+        let response = synced(&query(None), &request, &alice, &pool).await;
+        assert_eq!(response.pos, "1");
+        assert_eq!(response.lists["all"].count, 2);
+        assert_eq!(response.rooms.len(), 1);
+        let room = &response.rooms[&garden_id];
+        assert!(room.initial);
+        assert!(room.limited);
+        assert_eq!(room.name.as_deref(), Some("Garden"));
+        assert_eq!(room.timeline.len(), 1);
+        assert_eq!(room.timeline[0]["content"]["body"], "Hello");
+        assert_eq!(room.required_state.len(), 1);
+        assert_eq!(room.joined_count, 1);
+
+        // Nothing has happened since, so the position stays.
+        let response = synced(&query(Some("1")), &request, &alice, &pool).await;
+        assert_eq!(response.pos, "1");
+        assert!(response.rooms.is_empty());
+
+        // The lobby moves to the top and is sent in full, while what's new in
+        // the garden is sent only once it's back in range.
+        send_message(&lobby_id, "Hi", &alice, &config, &pool).await;
+        let response = synced(&query(Some("1")), &request, &alice, &pool).await;
+        assert_eq!(response.pos, "2");
+        assert!(response.rooms[&lobby_id].initial);
+        assert!(!response.rooms.contains_key(&garden_id));
+
+        send_message(&garden_id, "Again", &alice, &config, &pool).await;
+        let response = synced(&query(Some("2")), &request, &alice, &pool).await;
+        let room = &response.rooms[&garden_id];
+        assert!(!room.initial);
+        assert!(room.required_state.is_empty());
+        assert_eq!(room.timeline.len(), 1);
+        assert_eq!(room.num_live, 1);
+
+        // Only the latest position is valid.
+        let result = sliding_sync(&query(Some("1")), &request, &alice, &Notifier::default(), &pool).await.unwrap();
+        assert!(matches!(result, SlidingSyncResult::UnknownPos));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sliding_sync_hides_erased_users_events(pool: PgPool) {
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let room_id = create(json!({ "preset": "public_chat" }), &bob, &config, &pool).await[0].room_id.clone();
+        services::membership::join_room(room_id.as_str(), None, &alice, &config, &keys, &pool).await.unwrap();
+        send_message(&room_id, "Hello", &alice, &config, &pool).await;
+        services::account::deactivate(alice.user_id, true, &config, &keys, &pool).await.unwrap();
+        services::membership::join_room(room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+        let request = request(json!({ "room_subscriptions": { room_id.as_str(): { "timeline_limit": 20 } } }));
+
+        // Bob was in the room when Alice was erased, but Carol joined after.
+        let bodies = |response: &SlidingSyncResponse| -> Vec<Value> {
+            response.rooms[&room_id].timeline.iter().filter_map(|e| e["content"].get("body").cloned()).collect()
+        };
+        assert_eq!(bodies(&synced(&query(None), &request, &bob, &pool).await), vec![json!("Hello")]);
+        let response = synced(&query(None), &request, &carol, &pool).await;
+        assert!(bodies(&response).is_empty());
+        assert!(!response.rooms[&room_id].timeline.iter().any(|e| e["sender"] == alice.matrix_user_id.as_str()));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sliding_sync_subscriptions_and_extensions(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let room_id = create(json!({ "preset": "public_chat" }), &alice, &config, &pool).await[0].room_id.clone();
+        services::account_data::set_account_data(&alice.matrix_user_id, None, "org.example.settings", &json!({}), &alice, &pool).await.unwrap();
+        let to_device: ToDeviceRequest = serde_json::from_value(json!({
+            "messages": { alice.matrix_user_id.as_str(): { alice.device_id.as_str(): { "body": "Hi" } } }
+        })).unwrap();
+        services::to_device::send_to_device("org.example.greeting", "txn1", &to_device, &bob, &config, &pool).await.unwrap();
+        let request = request(json!({
+            "room_subscriptions": {
+                room_id.as_str(): { "timeline_limit": 0, "required_state": [["m.room.member", "$ME"]] }
+            },
+            "extensions": {
+                "to_device": { "enabled": true },
+                "account_data": { "enabled": true },
+            }
+        }));
+
+        let response = synced(&query(None), &request, &alice, &pool).await;
+        let room = &response.rooms[&room_id];
+        assert!(room.timeline.is_empty());
+        assert_eq!(room.required_state.len(), 1);
+        assert_eq!(room.required_state[0]["state_key"], alice.matrix_user_id.as_str());
+        let extension = response.extensions.account_data.unwrap();
+        assert!(extension.global.iter().any(|e| e["type"] == "org.example.settings"));
+        let extension = response.extensions.to_device.unwrap();
+        assert_eq!(extension.events.len(), 1);
+        assert_eq!(extension.events[0]["sender"], bob.matrix_user_id.as_str());
+
+        // Acknowledged messages are gone, and only what changed is sent.
+        let request = SlidingSyncRequest {
+            extensions: serde_json::from_value(json!({
+                "to_device": { "enabled": true, "since": extension.next_batch },
+                "account_data": { "enabled": true },
+            })).unwrap(),
+            ..request
+        };
+        let response = synced(&query(Some(&response.pos)), &request, &alice, &pool).await;
+        assert!(response.extensions.to_device.unwrap().events.is_empty());
+        assert!(response.extensions.account_data.unwrap().global.is_empty());
+        assert!(pg::to_device::get_to_device_messages(&alice.matrix_user_id, &alice.device_id, 0, i64::MAX, 10, &pool).await.unwrap().is_empty());
+
+        // Bob isn't in the room, so nothing is sent for it.
+        let response = synced(&query(None), &request, &bob, &pool).await;
+        assert!(response.rooms.is_empty());
+    }
+}
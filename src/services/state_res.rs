@@ -0,0 +1,507 @@
+//! State resolution v2, which merges the room states of several forks of a
+//! room's DAG into one
+//!
+//! States are maps from `(type, state_key)` to event IDs. The events they
+//! reference, and the events in their auth chains, are looked up through an
+//! [`EventSource`], so resolution doesn't depend on how events are stored.
+//!
+//! Room versions 1 and 2 specify state resolution v1, which isn't implemented;
+//! rooms of those versions are resolved with v2 as well.
+//!
+//! See https://spec.matrix.org/v1.13/rooms/v2/#state-resolution
+
+use crate::models::ids::EventId;
+use crate::models::room_version::RoomVersion;
+use crate::services::event_auth::{self, StateMap};
+use crate::services::events;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use twelf::reexports::serde_json::Value;
+
+/// Event IDs keyed by `(type, state_key)`
+pub type StateIds = HashMap<(String, String), EventId>;
+
+/// Looks up the events that state resolution needs
+pub trait EventSource {
+    /// Returns the event with ID `event_id` as JSON, in the format in which
+    /// servers exchange it, or `None` if it isn't known
+    fn get_event(&self, event_id: &EventId) -> Option<&Value>;
+}
+
+impl EventSource for HashMap<EventId, Value> {
+    fn get_event(&self, event_id: &EventId) -> Option<&Value> {
+        self.get(event_id)
+    }
+}
+
+/// Returns the state that results from resolving `state_sets`
+///
+/// Events that can't be looked up in `events` are left out of the conflicted
+/// state, as are events that fail authorization against the state resolved
+/// before them.
+pub fn resolve(room_version: &RoomVersion, state_sets: &[StateIds], events: &impl EventSource) -> StateIds {
+    let (unconflicted, conflicted) = separate(state_sets);
+    if conflicted.is_empty() {
+        return unconflicted;
+    }
+
+    let mut full_conflicted: HashSet<EventId> = conflicted;
+    full_conflicted.extend(auth_difference(state_sets, events));
+    full_conflicted.retain(|event_id| events.get_event(event_id).is_some());
+
+    // Power events, with the events in their auth chains that are also
+    // conflicted, are resolved first.
+    let mut power_events = HashSet::new();
+    for event_id in &full_conflicted {
+        if is_power_event(events.get_event(event_id).unwrap()) {
+            power_events.insert(event_id.clone());
+            power_events.extend(auth_chain([event_id], events).into_iter().filter(|id| full_conflicted.contains(id)));
+        }
+    }
+    let power_events = reverse_topological_power_order(&power_events, room_version, events);
+    let resolved = iterative_auth_checks(&power_events, unconflicted.clone(), room_version, events);
+
+    // The other events are ordered by the resolved power levels.
+    let power_events: HashSet<&EventId> = power_events.iter().collect();
+    let mut other_events: Vec<EventId> = full_conflicted.iter()
+        .filter(|event_id| !power_events.contains(event_id))
+        .cloned()
+        .collect();
+    let power_levels = resolved.get(&(String::from("m.room.power_levels"), String::new()));
+    sort_by_mainline(&mut other_events, power_levels, events);
+    let mut resolved = iterative_auth_checks(&other_events, resolved, room_version, events);
+
+    resolved.extend(unconflicted);
+    resolved
+}
+
+/// Splits `state_sets` into the state they all agree on and the IDs of the
+/// events of the keys they don't
+///
+/// A key is conflicted if any state set lacks it or has a different event for
+/// it.
+fn separate(state_sets: &[StateIds]) -> (StateIds, HashSet<EventId>) {
+    let mut unconflicted = StateIds::new();
+    let mut conflicted = HashSet::new();
+
+    let keys: HashSet<&(String, String)> = state_sets.iter().flat_map(|state| state.keys()).collect();
+    for key in keys {
+        let event_ids: Vec<Option<&EventId>> = state_sets.iter().map(|state| state.get(key)).collect();
+        match event_ids[0] {
+            Some(event_id) if event_ids.iter().all(|id| *id == Some(event_id)) => {
+                unconflicted.insert(key.clone(), event_id.clone());
+            }
+            _ => conflicted.extend(event_ids.into_iter().flatten().cloned()),
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
+/// Returns the events that are in the auth chains of some of `state_sets` but
+/// not all of them
+fn auth_difference(state_sets: &[StateIds], events: &impl EventSource) -> HashSet<EventId> {
+    let auth_chains: Vec<HashSet<EventId>> = state_sets.iter()
+        .map(|state| auth_chain(state.values(), events))
+        .collect();
+
+    let union: HashSet<&EventId> = auth_chains.iter().flatten().collect();
+    union.into_iter()
+        .filter(|event_id| !auth_chains.iter().all(|chain| chain.contains(*event_id)))
+        .cloned()
+        .collect()
+}
+
+/// Returns the auth events of `event_ids`, their auth events, and so on
+///
+/// Events that can't be looked up end the chain.
+fn auth_chain<'a>(event_ids: impl IntoIterator<Item = &'a EventId>, events: &impl EventSource) -> HashSet<EventId> {
+    let mut chain = HashSet::new();
+    let mut pending: Vec<EventId> = event_ids.into_iter()
+        .filter_map(|event_id| events.get_event(event_id))
+        .flat_map(auth_event_ids)
+        .collect();
+
+    while let Some(event_id) = pending.pop() {
+        if chain.contains(&event_id) {
+            continue;
+        }
+        if let Some(event) = events.get_event(&event_id) {
+            pending.extend(auth_event_ids(event));
+        }
+        chain.insert(event_id);
+    }
+
+    chain
+}
+
+/// Returns `true` if `event` may remove the ability of other users to send
+/// events
+fn is_power_event(event: &Value) -> bool {
+    match (event["type"].as_str(), event["state_key"].as_str()) {
+        (Some("m.room.create" | "m.room.power_levels" | "m.room.join_rules"), Some("")) => true,
+        (Some("m.room.member"), Some(state_key)) =>
+            matches!(event["content"]["membership"].as_str(), Some("leave" | "ban"))
+                && event["sender"].as_str() != Some(state_key),
+        _ => false,
+    }
+}
+
+/// Orders `event_ids` so that every event comes after its auth events
+///
+/// Among events whose auth events have all been ordered, the next is the one
+/// whose sender has the highest power level, then the oldest, then the one
+/// with the lowest ID.
+fn reverse_topological_power_order(
+    event_ids: &HashSet<EventId>,
+    room_version: &RoomVersion,
+    events: &impl EventSource
+) -> Vec<EventId> {
+    // The auth events of each event that are also being ordered, and the
+    // reverse of that
+    let mut auth_events: HashMap<&EventId, usize> = HashMap::new();
+    let mut authorizes: HashMap<EventId, Vec<&EventId>> = HashMap::new();
+    for event_id in event_ids {
+        let event = events.get_event(event_id).unwrap();
+        let mut count = 0;
+        for auth_event_id in auth_event_ids(event).into_iter().filter(|id| event_ids.contains(id)) {
+            authorizes.entry(auth_event_id).or_default().push(event_id);
+            count += 1;
+        }
+        auth_events.insert(event_id, count);
+    }
+
+    let sort_key = |event_id: &EventId| {
+        let event = events.get_event(event_id).unwrap();
+        (Reverse(sender_power_level(event, room_version, events)), origin_server_ts(event), event_id.as_str().to_string())
+    };
+
+    // Keyed by the sort key, which is unique since it includes the event ID
+    let mut ready: BTreeMap<_, &EventId> = auth_events.iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(event_id, _)| (sort_key(event_id), *event_id))
+        .collect();
+    let mut ordered = Vec::with_capacity(event_ids.len());
+
+    while let Some((_, event_id)) = ready.pop_first() {
+        ordered.push(event_id.clone());
+
+        for next in authorizes.get(event_id).into_iter().flatten() {
+            let count = auth_events.get_mut(next).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(sort_key(next), *next);
+            }
+        }
+    }
+
+    ordered
+}
+
+/// Returns the power level of the sender of `event` according to its auth
+/// events
+fn sender_power_level(event: &Value, room_version: &RoomVersion, events: &impl EventSource) -> i64 {
+    let auth_events: Vec<EventId> = auth_event_ids(event);
+    let state: StateMap = auth_events.iter()
+        .filter_map(|event_id| events.get_event(event_id))
+        .filter_map(|auth_event| Some((key(auth_event)?, auth_event)))
+        .collect();
+
+    event_auth::user_power_level(event["sender"].as_str().unwrap_or_default(), room_version, &state)
+}
+
+/// Orders `event_ids` by their closest ancestor on the mainline of
+/// `power_levels`, then by age, then by ID
+///
+/// The mainline is `power_levels` followed by the `m.room.power_levels` event
+/// among its auth events, and so on. Events after older mainline events come
+/// first, and events with no ancestor on the mainline before all others.
+fn sort_by_mainline(event_ids: &mut [EventId], power_levels: Option<&EventId>, events: &impl EventSource) {
+    let mut mainline = vec![];
+    let mut next = power_levels.cloned();
+    while let Some(event_id) = next {
+        next = events.get_event(&event_id).and_then(|event| power_levels_auth_event(event, events));
+        mainline.push(event_id);
+    }
+    let mainline_positions: HashMap<EventId, usize> = mainline.into_iter()
+        .rev()
+        .enumerate()
+        .map(|(i, event_id)| (event_id, i + 1))
+        .collect();
+
+    let mainline_position = |event_id: &EventId| {
+        let mut current = Some(event_id.clone());
+        while let Some(event_id) = current {
+            if let Some(position) = mainline_positions.get(&event_id) {
+                return *position;
+            }
+            current = events.get_event(&event_id).and_then(|event| power_levels_auth_event(event, events));
+        }
+        0
+    };
+
+    event_ids.sort_by_cached_key(|event_id| {
+        let ts = events.get_event(event_id).map(origin_server_ts).unwrap_or_default();
+        (mainline_position(event_id), ts, event_id.as_str().to_string())
+    });
+}
+
+/// Returns the ID of the `m.room.power_levels` event among the auth events of
+/// `event`, if any
+fn power_levels_auth_event(event: &Value, events: &impl EventSource) -> Option<EventId> {
+    auth_event_ids(event).into_iter().find(|event_id| {
+        events.get_event(event_id).and_then(key) == Some((String::from("m.room.power_levels"), String::new()))
+    })
+}
+
+/// Adds `event_ids` in order to `state`, skipping those that fail
+/// authorization
+///
+/// Each event is authorized against its auth events, replaced by any events
+/// in `state` with the same keys that the auth events selection algorithm
+/// would choose.
+fn iterative_auth_checks(
+    event_ids: &[EventId],
+    mut state: StateIds,
+    room_version: &RoomVersion,
+    events: &impl EventSource
+) -> StateIds {
+    for event_id in event_ids {
+        let Some(event) = events.get_event(event_id) else {
+            continue;
+        };
+        let Some(sender) = event["sender"].as_str().and_then(|sender| sender.parse().ok()) else {
+            continue;
+        };
+
+        let auth_event_ids = auth_event_ids(event);
+        let mut auth_state: StateMap = auth_event_ids.iter()
+            .filter_map(|auth_event_id| events.get_event(auth_event_id))
+            .filter_map(|auth_event| Some((key(auth_event)?, auth_event)))
+            .collect();
+
+        let auth_keys = events::auth_event_keys(
+            event["type"].as_str().unwrap_or_default(),
+            event["state_key"].as_str(),
+            &sender,
+            &event["content"]
+        );
+        for auth_key in auth_keys {
+            if let Some(state_event) = state.get(&auth_key).and_then(|event_id| events.get_event(event_id)) {
+                auth_state.insert(auth_key, state_event);
+            }
+        }
+
+        if event_auth::authorize(event, room_version, &auth_state).is_ok() {
+            if let Some(key) = key(event) {
+                state.insert(key, event_id.clone());
+            }
+        }
+    }
+
+    state
+}
+
+/// Returns the IDs of the auth events of `event`, which in room versions 1 and
+/// 2 are paired with the events' hashes
+fn auth_event_ids(event: &Value) -> Vec<EventId> {
+    event["auth_events"].as_array().into_iter().flatten()
+        .filter_map(|reference| reference.as_str().or_else(|| reference[0].as_str()))
+        .filter_map(|event_id| event_id.parse().ok())
+        .collect()
+}
+
+/// Returns the `(type, state_key)` of a state event
+fn key(event: &Value) -> Option<(String, String)> {
+    Some((event["type"].as_str()?.to_string(), event["state_key"].as_str()?.to_string()))
+}
+
+fn origin_server_ts(event: &Value) -> i64 {
+    event["origin_server_ts"].as_i64().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::room_version;
+    use twelf::reexports::serde_json::json;
+
+    const ALICE: &str = "@alice:a.example";
+    const BOB: &str = "@bob:b.example";
+    const CAROL: &str = "@carol:a.example";
+
+    fn version() -> &'static RoomVersion {
+        room_version::get("10").unwrap()
+    }
+
+    /// The events of a room, stored by ID
+    #[derive(Default)]
+    struct Room {
+        events: HashMap<EventId, Value>,
+    }
+
+    /// One fork of a room, with the state after its latest event
+    #[derive(Clone, Default)]
+    struct Fork {
+        state: StateIds,
+        latest: Option<EventId>,
+    }
+
+    impl Room {
+        /// Adds an event after the latest event of `fork`, authorized by the
+        /// state of `fork`, and returns its ID
+        fn send(&mut self, fork: &mut Fork, sender: &str, event_type: &str, state_key: &str, content: Value, ts: i64) -> EventId {
+            let auth_events: Vec<&EventId> = events::auth_event_keys(event_type, Some(state_key), &sender.parse().unwrap(), &content)
+                .iter()
+                .filter_map(|key| fork.state.get(key))
+                .collect();
+
+            let event = json!({
+                "room_id": "!room:a.example",
+                "sender": sender,
+                "type": event_type,
+                "state_key": state_key,
+                "content": content,
+                "depth": 1,
+                "origin_server_ts": ts,
+                "prev_events": fork.latest.iter().collect::<Vec<_>>(),
+                "auth_events": auth_events,
+            });
+            let event_id = events::event_id(&event, version()).unwrap();
+
+            self.events.insert(event_id.clone(), event);
+            fork.state.insert((event_type.to_string(), state_key.to_string()), event_id.clone());
+            fork.latest = Some(event_id.clone());
+
+            event_id
+        }
+
+        /// Returns a fork with a room created by Alice, which Bob and Carol
+        /// have joined, with Bob at power level 50
+        fn create() -> (Room, Fork) {
+            let mut room = Room::default();
+            let mut fork = Fork::default();
+
+            room.send(&mut fork, ALICE, "m.room.create", "", json!({ "room_version": "10", "creator": ALICE }), 0);
+            room.send(&mut fork, ALICE, "m.room.member", ALICE, json!({ "membership": "join" }), 1);
+            room.send(&mut fork, ALICE, "m.room.power_levels", "", power_levels(50), 2);
+            room.send(&mut fork, ALICE, "m.room.join_rules", "", json!({ "join_rule": "public" }), 3);
+            room.send(&mut fork, BOB, "m.room.member", BOB, json!({ "membership": "join" }), 4);
+            room.send(&mut fork, CAROL, "m.room.member", CAROL, json!({ "membership": "join" }), 5);
+
+            (room, fork)
+        }
+    }
+
+    fn power_levels(bob: i64) -> Value {
+        json!({ "users": { ALICE: 100, BOB: bob }, "state_default": 50 })
+    }
+
+    fn get<'a>(state: &'a StateIds, event_type: &str, state_key: &str) -> Option<&'a EventId> {
+        state.get(&(event_type.to_string(), state_key.to_string()))
+    }
+
+    #[test]
+    fn test_resolve_without_conflicts() {
+        let (room, fork) = Room::create();
+
+        assert!(resolve(version(), &[], &room.events).is_empty());
+        assert_eq!(resolve(version(), &[fork.state.clone(), fork.state.clone()], &room.events), fork.state);
+
+        // An event that's only in some of the state sets is conflicted, but
+        // resolves to itself when it's allowed.
+        let (mut room, fork) = Room::create();
+        let mut topic_fork = fork.clone();
+        let topic = room.send(&mut topic_fork, BOB, "m.room.topic", "", json!({ "topic": "Hi" }), 10);
+
+        let resolved = resolve(version(), &[fork.state, topic_fork.state], &room.events);
+        assert_eq!(get(&resolved, "m.room.topic", ""), Some(&topic));
+    }
+
+    #[test]
+    fn test_resolve_power_events_first() {
+        let (mut room, fork) = Room::create();
+
+        // Bob sets the topic while Alice concurrently demotes him.
+        let mut topic_fork = fork.clone();
+        room.send(&mut topic_fork, BOB, "m.room.topic", "", json!({ "topic": "Hi" }), 20);
+        let mut demote_fork = fork.clone();
+        let demote = room.send(&mut demote_fork, ALICE, "m.room.power_levels", "", power_levels(0), 10);
+
+        let resolved = resolve(version(), &[topic_fork.state, demote_fork.state], &room.events);
+        assert_eq!(get(&resolved, "m.room.power_levels", ""), Some(&demote));
+        assert_eq!(get(&resolved, "m.room.topic", ""), None);
+        assert_eq!(get(&resolved, "m.room.member", BOB), fork.state.get(&(String::from("m.room.member"), BOB.to_string())));
+    }
+
+    #[test]
+    fn test_resolve_ban() {
+        let (mut room, fork) = Room::create();
+
+        // Carol changes her display name while Bob concurrently bans her.
+        let mut rename_fork = fork.clone();
+        room.send(&mut rename_fork, CAROL, "m.room.member", CAROL, json!({ "membership": "join", "displayname": "C" }), 20);
+        let mut ban_fork = fork.clone();
+        let ban = room.send(&mut ban_fork, BOB, "m.room.member", CAROL, json!({ "membership": "ban" }), 10);
+
+        let resolved = resolve(version(), &[rename_fork.state.clone(), ban_fork.state.clone()], &room.events);
+        assert_eq!(get(&resolved, "m.room.member", CAROL), Some(&ban));
+
+        // The order of the state sets doesn't matter.
+        assert_eq!(resolve(version(), &[ban_fork.state, rename_fork.state], &room.events), resolved);
+    }
+
+    #[test]
+    fn test_resolve_by_mainline() {
+        let (mut room, fork) = Room::create();
+
+        // Without a power levels change, the newest topic wins.
+        let mut old_fork = fork.clone();
+        let old_topic = room.send(&mut old_fork, ALICE, "m.room.topic", "", json!({ "topic": "Old" }), 10);
+        let mut new_fork = fork.clone();
+        let new_topic = room.send(&mut new_fork, ALICE, "m.room.topic", "", json!({ "topic": "New" }), 20);
+
+        let resolved = resolve(version(), &[old_fork.state.clone(), new_fork.state.clone()], &room.events);
+        assert_eq!(get(&resolved, "m.room.topic", ""), Some(&new_topic));
+
+        // A topic that follows a newer power levels event wins, however old.
+        let mut promote_fork = fork.clone();
+        room.send(&mut promote_fork, ALICE, "m.room.power_levels", "", power_levels(60), 5);
+        let promoted_topic = room.send(&mut promote_fork, BOB, "m.room.topic", "", json!({ "topic": "Promoted" }), 6);
+
+        let resolved = resolve(version(), &[new_fork.state, promote_fork.state], &room.events);
+        assert_eq!(get(&resolved, "m.room.topic", ""), Some(&promoted_topic));
+        assert_ne!(get(&resolved, "m.room.topic", ""), Some(&old_topic));
+    }
+
+    #[test]
+    fn test_auth_difference() {
+        let (mut room, fork) = Room::create();
+        let mut demote_fork = fork.clone();
+        let demote = room.send(&mut demote_fork, ALICE, "m.room.power_levels", "", power_levels(0), 10);
+        let topic = room.send(&mut demote_fork, ALICE, "m.room.topic", "", json!({ "topic": "Hi" }), 11);
+
+        // The demotion is only in the auth chain of the topic.
+        let mut topic_only = fork.state.clone();
+        topic_only.insert((String::from("m.room.topic"), String::new()), topic);
+        assert_eq!(auth_difference(&[fork.state.clone(), topic_only], &room.events), HashSet::from([demote]));
+        assert!(auth_difference(&[fork.state.clone(), fork.state], &room.events).is_empty());
+    }
+
+    #[test]
+    fn test_reverse_topological_power_order() {
+        let (mut room, fork) = Room::create();
+        let mut bob_fork = fork.clone();
+        let mut content = power_levels(50);
+        content["users"][CAROL] = json!(10);
+        let bob_pl = room.send(&mut bob_fork, BOB, "m.room.power_levels", "", content, 10);
+        let alice_rules = room.send(&mut bob_fork, ALICE, "m.room.join_rules", "", json!({ "join_rule": "invite" }), 20);
+        let mut alice_fork = fork.clone();
+        let alice_kick = room.send(&mut alice_fork, ALICE, "m.room.member", CAROL, json!({ "membership": "leave" }), 30);
+
+        // Alice has more power than Bob, but her join rules change depends on
+        // his power levels change.
+        let event_ids = HashSet::from([alice_rules.clone(), alice_kick.clone(), bob_pl.clone()]);
+        assert_eq!(reverse_topological_power_order(&event_ids, version(), &room.events), vec![alice_kick, bob_pl, alice_rules]);
+    }
+}
@@ -0,0 +1,849 @@
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::account_data::AccountData;
+use crate::models::events::Event;
+use crate::models::filter::Filter;
+use crate::models::ids::{EventId, RoomId, UserId};
+use crate::models::presence::{Presence, PresenceState};
+use crate::models::receipts::Receipt;
+use crate::models::stream_token::StreamToken;
+use crate::routes::sync::SyncQuery;
+use crate::services;
+use crate::services::filter::{event_matches, project_event, room_event_matches};
+use crate::services::notifier::Notifier;
+use crate::store::pg;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use twelf::reexports::serde_json::{json, Map, Value};
+
+/// Number of events in each room's timeline, unless the filter sets a limit
+const TIMELINE_LIMIT: usize = 10;
+
+/// Users who were active this recently are currently active
+const CURRENTLY_ACTIVE_MS: i64 = 5 * 60 * 1000;
+
+/// Response to a sync request
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3sync
+#[derive(Debug, Default, Serialize)]
+pub struct SyncResponse {
+    pub next_batch: StreamToken,
+    pub rooms: Rooms,
+    pub account_data: Events,
+    pub presence: Events,
+}
+
+impl SyncResponse {
+    /// Returns `true` if there's nothing new for the client
+    pub fn is_empty(&self) -> bool {
+        self.rooms.join.is_empty()
+            && self.rooms.invite.is_empty()
+            && self.rooms.knock.is_empty()
+            && self.rooms.leave.is_empty()
+            && self.account_data.events.is_empty()
+            && self.presence.events.is_empty()
+    }
+}
+
+/// The rooms in a [`SyncResponse`] by the user's membership
+#[derive(Debug, Default, Serialize)]
+pub struct Rooms {
+    pub join: HashMap<RoomId, JoinedRoom>,
+    pub invite: HashMap<RoomId, InvitedRoom>,
+    pub knock: HashMap<RoomId, KnockedRoom>,
+    pub leave: HashMap<RoomId, LeftRoom>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Events {
+    pub events: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Timeline {
+    pub events: Vec<Value>,
+    /// Whether there are more events between the last sync and these
+    pub limited: bool,
+    /// A token for paginating back from the first event
+    pub prev_batch: StreamToken,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JoinedRoom {
+    pub timeline: Timeline,
+    /// State up to the start of the timeline
+    pub state: Events,
+    pub ephemeral: Events,
+    pub account_data: Events,
+    pub unread_notifications: UnreadNotificationCounts,
+    /// Counts for each thread by root event ID, if the filter asks for them
+    /// separately from `unread_notifications`
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub unread_thread_notifications: HashMap<String, UnreadNotificationCounts>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UnreadNotificationCounts {
+    pub notification_count: i64,
+    pub highlight_count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvitedRoom {
+    pub invite_state: Events,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KnockedRoom {
+    pub knock_state: Events,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeftRoom {
+    pub timeline: Timeline,
+    pub state: Events,
+    pub account_data: Events,
+}
+
+/// Returns what's new for the user since `query.since`, or everything if it's
+/// absent, that passes `filter`
+///
+/// If there's nothing new, this waits up to `query.timeout` milliseconds for
+/// `notifier` to announce something for the user before returning an empty
+/// response.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#get_matrixclientv3sync
+pub async fn sync(
+    query: &SyncQuery,
+    filter: &Filter,
+    auth: &AuthenticatedUser,
+    notifier: &Notifier,
+    pool: &PgPool
+) -> Result<SyncResponse, Error> {
+    match query.set_presence.unwrap_or(PresenceState::Online) {
+        PresenceState::Offline => (),
+        presence => pg::presence::set_presence(&auth.matrix_user_id, presence.as_str(), pool).await?,
+    }
+
+    let since = query.since.map(|token| token.stream_ordering);
+    let full_state = query.full_state.unwrap_or(false);
+    if since.is_none() && filter.room.state.lazy_load_members {
+        pg::lazy_loaded_members::clear_lazy_loaded_members(auth.session_id, pool).await?;
+    }
+    let deadline = Instant::now() + Duration::from_millis(query.timeout.unwrap_or(0));
+    let room_ids = pg::rooms::get_room_ids_by_membership(&auth.matrix_user_id, "join", pool).await?;
+    let mut subscription = notifier.subscribe(&auth.matrix_user_id, &room_ids);
+
+    loop {
+        let to = pg::streams::get_current_stream_ordering(pool).await?;
+        let response = match since {
+            Some(since) if to <= since && !full_state =>
+                SyncResponse { next_batch: StreamToken { stream_ordering: since }, ..Default::default() },
+            _ => sync_between(since, to, full_state, filter, auth, pool).await?,
+        };
+
+        // Wait for something after both positions, so that a token ahead of
+        // the stream position can't make this loop spin.
+        let position = since.map_or(to, |since| since.max(to));
+        if since.is_none() || full_state || !response.is_empty() || !subscription.wait(position, deadline).await {
+            return Ok(response);
+        }
+    }
+}
+
+/// Returns what changed for the user after stream ordering `since`, or
+/// everything if it's `None`, up to and including `to`
+async fn sync_between(
+    since: Option<i64>,
+    to: i64,
+    full_state: bool,
+    filter: &Filter,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<SyncResponse, Error> {
+    let user_id = &auth.matrix_user_id;
+    let from = since.unwrap_or(0);
+    let mut response = SyncResponse { next_batch: StreamToken { stream_ordering: to }, ..Default::default() };
+
+    // The user's latest membership of each room, before and after the period
+    let mut memberships_before = HashMap::new();
+    let mut memberships_after = HashMap::new();
+    for event in pg::events::get_membership_events(user_id, to, pool).await? {
+        if since.is_some_and(|since| event.stream_ordering <= since) {
+            memberships_before.insert(event.room_id.clone(), event.clone());
+        }
+        memberships_after.insert(event.room_id.clone(), event);
+    }
+
+    let joined_room_ids: Vec<RoomId> = memberships_after.values()
+        .filter(|e| e.content["membership"] == "join")
+        .map(|e| e.room_id.clone())
+        .collect();
+    let mut receipts: HashMap<RoomId, Vec<Receipt>> = HashMap::new();
+    for receipt in pg::receipts::get_receipts_between(&joined_room_ids, from, to, pool).await? {
+        receipts.entry(receipt.room_id.clone()).or_default().push(receipt);
+    }
+    let typing_room_ids: HashSet<RoomId> = pg::typing::get_rooms_with_typing_between(&joined_room_ids, from, to, pool).await?
+        .into_iter()
+        .collect();
+    let mut room_account_data: HashMap<RoomId, Vec<AccountData>> = HashMap::new();
+    let mut global_account_data = Vec::new();
+    for account_data in pg::account_data::get_account_data_between(user_id, from, to, pool).await? {
+        match account_data.room_id {
+            Some(ref room_id) => room_account_data.entry(room_id.clone()).or_default().push(account_data),
+            None => global_account_data.push(account_data_event(&account_data)),
+        }
+    }
+    response.account_data.events = filtered(global_account_data, filter.account_data.limit, |event| {
+        event_matches(&filter.account_data, event)
+    });
+
+    for (room_id, membership_event) in memberships_after {
+        if !services::filter::room_matches(&filter.room, &room_id) {
+            continue;
+        }
+        let membership = membership_event.content["membership"].as_str().unwrap_or_default();
+        let membership_before = memberships_before.get(&room_id).and_then(|e| e.content["membership"].as_str());
+        let changed = since.is_none_or(|since| membership_event.stream_ordering > since);
+
+        match membership {
+            "join" => {
+                let newly_joined = since.is_some() && membership_before != Some("join");
+                let timeline_from = if newly_joined { 0 } else { from };
+                let (timeline, state) = timeline_and_state(
+                    &room_id, since, timeline_from, to, full_state || newly_joined, true, filter, auth, pool
+                ).await?;
+
+                let mut ephemeral_events = Vec::new();
+                if typing_room_ids.contains(&room_id) {
+                    let user_ids = pg::typing::get_typing_user_ids(&room_id, pool).await?;
+                    ephemeral_events.push(json!({ "type": "m.typing", "content": { "user_ids": user_ids } }));
+                }
+                if let Some(event) = receipt_event(receipts.remove(&room_id).unwrap_or_default(), auth) {
+                    ephemeral_events.push(event);
+                }
+                let ephemeral_filter = &filter.room.ephemeral;
+                let ephemeral = Events {
+                    events: filtered(ephemeral_events, ephemeral_filter.events.limit, |event| {
+                        room_event_matches(ephemeral_filter, &room_id, event)
+                    }),
+                };
+                let account_data = room_account_data_events(&room_id, room_account_data.remove(&room_id), filter);
+                let (unread_notifications, unread_thread_notifications) =
+                    unread_counts(&room_id, to, filter, auth, pool).await?;
+
+                let is_empty = timeline.events.is_empty()
+                    && state.events.is_empty()
+                    && ephemeral.events.is_empty()
+                    && account_data.events.is_empty();
+                if since.is_none() || full_state || newly_joined || !is_empty {
+                    response.rooms.join.insert(room_id, JoinedRoom {
+                        timeline,
+                        state,
+                        ephemeral,
+                        account_data,
+                        unread_notifications,
+                        unread_thread_notifications,
+                    });
+                }
+            }
+            "invite" if changed => {
+                let invite_state = Events { events: services::membership::stripped_state(&room_id, user_id, pool).await? };
+                response.rooms.invite.insert(room_id, InvitedRoom { invite_state });
+            }
+            "knock" if changed => {
+                let knock_state = Events { events: services::membership::stripped_state(&room_id, user_id, pool).await? };
+                response.rooms.knock.insert(room_id, KnockedRoom { knock_state });
+            }
+            "leave" | "ban" if since.is_some() && changed => {
+                if pg::rooms::is_room_forgotten(user_id, &room_id, pool).await? {
+                    continue;
+                }
+                let (timeline, state) = timeline_and_state(
+                    &room_id, since, from, membership_event.stream_ordering, full_state, false, filter, auth, pool
+                ).await?;
+                let account_data = room_account_data_events(&room_id, room_account_data.remove(&room_id), filter);
+                response.rooms.leave.insert(room_id, LeftRoom { timeline, state, account_data });
+            }
+            _ => (),
+        }
+    }
+
+    let user_ids = pg::rooms::get_user_ids_sharing_rooms(user_id, pool).await?;
+    let presence_events = pg::presence::get_presence_between(&user_ids, from, to, pool).await?
+        .iter()
+        .map(presence_event)
+        .collect::<Vec<_>>();
+    response.presence.events = filtered(presence_events, filter.presence.limit, |event| {
+        event_matches(&filter.presence, event)
+    });
+
+    Ok(response)
+}
+
+/// Returns the latest events of a room that the user may see and that pass
+/// the timeline filter, after stream ordering `timeline_from` and up to and
+/// including `to`, and the state at the start of those events that passes the
+/// state filter
+///
+/// Which events the user may see depends on the room's history visibility and
+/// on whether they're joined to it now, `is_joined`. Since the state is taken
+/// at the first event they may see, it's no older than what they could see
+/// when that event was sent.
+///
+/// Unless `full_state` is `true`, the state only includes events after `since`.
+/// If the state filter lazy-loads members, the only membership events are
+/// those of the user and the senders in the timeline, which are included even
+/// if they're older, unless this session has been sent them before and the
+/// filter doesn't ask for redundant members.
+#[allow(clippy::too_many_arguments)]
+async fn timeline_and_state(
+    room_id: &RoomId,
+    since: Option<i64>,
+    timeline_from: i64,
+    to: i64,
+    full_state: bool,
+    is_joined: bool,
+    filter: &Filter,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<(Timeline, Events), Error> {
+    let timeline_filter = &filter.room.timeline;
+    let limit = timeline_filter.events.limit.unwrap_or(TIMELINE_LIMIT);
+
+    // Page back through the room until more events than the limit pass the
+    // filter, to know whether the timeline is limited.
+    let mut events = Vec::new();
+    let mut before = to;
+    loop {
+        let batch = pg::events::get_room_events_between(room_id, timeline_from, before, limit as i64 + 1, pool).await?;
+        let exhausted = batch.len() <= limit;
+        before = batch.first().map(|e| e.stream_ordering - 1).unwrap_or(timeline_from);
+
+        let visible_ids = services::history::visible_event_ids(&batch, &auth.matrix_user_id, is_joined, pool).await?;
+        let mut matching: Vec<Event> = batch.into_iter()
+            .filter(|e| visible_ids.contains(&e.event_id))
+            .filter(|e| room_event_matches(timeline_filter, room_id, &services::events::client_event(e)))
+            .collect();
+        matching.append(&mut events);
+        events = matching;
+        if exhausted || events.len() > limit {
+            break;
+        }
+    }
+    let limited = events.len() > limit;
+    if limited {
+        events.drain(..events.len() - limit);
+    }
+
+    let start = events.first().map(|e| e.stream_ordering - 1).unwrap_or(to);
+    let state_events = match pg::events::get_latest_event_id_at(room_id, start, pool).await? {
+        Some(event_id) => pg::events::get_state_after_event(&event_id, pool).await?,
+        None => vec![],
+    };
+
+    let state_filter = &filter.room.state;
+    let lazy_load_members = state_filter.lazy_load_members;
+    let mut member_ids: HashSet<&UserId> = events.iter().map(|e| &e.sender).collect();
+    member_ids.insert(&auth.matrix_user_id);
+    let member_ids: Vec<UserId> = member_ids.into_iter().cloned().collect();
+    let sent_members = if lazy_load_members && !state_filter.include_redundant_members {
+        pg::lazy_loaded_members::get_lazy_loaded_members(auth.session_id, room_id, &member_ids, pool).await?
+    } else {
+        HashMap::new()
+    };
+
+    let state_events: Vec<&Event> = state_events.iter()
+        .filter(|e| {
+            match (&e.state_key, lazy_load_members && e.event_type == "m.room.member") {
+                (Some(state_key), true) => match member_ids.iter().find(|id| id.as_str() == state_key) {
+                    Some(member_id) => sent_members.get(member_id) != Some(&e.event_id),
+                    None => false,
+                },
+                _ => full_state || since.is_none_or(|since| e.stream_ordering > since),
+            }
+        })
+        .filter(|e| room_event_matches(state_filter, room_id, &services::events::client_event(e)))
+        .collect();
+    if lazy_load_members {
+        let (user_ids, event_ids): (Vec<UserId>, Vec<EventId>) = state_events.iter()
+            .filter(|e| e.event_type == "m.room.member")
+            .filter_map(|e| Some((e.state_key.as_deref()?.parse().ok()?, e.event_id.clone())))
+            .unzip();
+        pg::lazy_loaded_members::set_lazy_loaded_members(auth.session_id, room_id, &user_ids, &event_ids, pool).await?;
+    }
+
+    let event_fields = filter.event_fields.as_deref();
+    let state = Events {
+        events: state_events.into_iter().map(|e| project_event(sync_event(e, None), event_fields)).collect(),
+    };
+
+    let event_ids: Vec<EventId> = events.iter().map(|e| e.event_id.clone()).collect();
+    let transaction_ids = pg::events::get_transaction_ids(auth.session_id, &event_ids, pool).await?;
+    let timeline = Timeline {
+        events: events.iter()
+            .map(|e| project_event(sync_event(e, transaction_ids.get(&e.event_id)), event_fields))
+            .collect(),
+        limited,
+        prev_batch: StreamToken { stream_ordering: start },
+    };
+
+    Ok((timeline, state))
+}
+
+/// Returns the counts of the user's unread notifications in a room up to and
+/// including stream ordering `to`, and those of each thread if the timeline
+/// filter asks for them separately
+async fn unread_counts(
+    room_id: &RoomId,
+    to: i64,
+    filter: &Filter,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<(UnreadNotificationCounts, HashMap<String, UnreadNotificationCounts>), Error> {
+    let user_id = &auth.matrix_user_id;
+    if !filter.room.timeline.unread_thread_notifications {
+        let (notification_count, highlight_count) = pg::receipts::count_unread_events(room_id, user_id, to, pool).await?;
+        return Ok((UnreadNotificationCounts { notification_count, highlight_count }, HashMap::new()));
+    }
+
+    let mut main = UnreadNotificationCounts::default();
+    let mut threads = HashMap::new();
+    for (thread_id, notification_count, highlight_count) in
+        pg::receipts::count_unread_events_by_thread(room_id, user_id, to, pool).await?
+    {
+        let counts = UnreadNotificationCounts { notification_count, highlight_count };
+        if thread_id.is_empty() {
+            main = counts;
+        } else {
+            threads.insert(thread_id, counts);
+        }
+    }
+
+    Ok((main, threads))
+}
+
+/// Returns `event` as it appears in a room in a sync, with the transaction ID
+/// with which the requesting client sent it, if it did
+pub fn sync_event(event: &Event, transaction_id: Option<&String>) -> Value {
+    let mut json = services::events::client_event(event);
+    if let Some(object) = json.as_object_mut() {
+        object.remove("room_id");
+    }
+    if let Some(transaction_id) = transaction_id {
+        json["unsigned"] = json!({ "transaction_id": transaction_id });
+    }
+
+    json
+}
+
+pub fn account_data_event(account_data: &AccountData) -> Value {
+    json!({ "type": account_data.event_type, "content": account_data.content })
+}
+
+/// Returns the room's account data events that pass the room account data
+/// filter
+fn room_account_data_events(room_id: &RoomId, account_data: Option<Vec<AccountData>>, filter: &Filter) -> Events {
+    let account_data_filter = &filter.room.account_data;
+    let events = account_data.unwrap_or_default().iter().map(account_data_event).collect::<Vec<_>>();
+
+    Events {
+        events: filtered(events, account_data_filter.events.limit, |event| {
+            room_event_matches(account_data_filter, room_id, event)
+        }),
+    }
+}
+
+/// Returns the first `limit` events among `events` that `matches` accepts
+fn filtered(events: Vec<Value>, limit: Option<usize>, matches: impl Fn(&Value) -> bool) -> Vec<Value> {
+    events.into_iter()
+        .filter(|event| matches(event))
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+/// Returns an `m.receipt` event with `receipts`, or `None` if there are none
+/// that the user may see
+///
+/// Private read receipts are only visible to the user who sent them.
+pub fn receipt_event(receipts: Vec<Receipt>, auth: &AuthenticatedUser) -> Option<Value> {
+    let mut content = Map::new();
+    for receipt in receipts {
+        if receipt.receipt_type == "m.read.private" && receipt.user_id != auth.matrix_user_id {
+            continue;
+        }
+
+        let mut data = json!({ "ts": receipt.ts });
+        if let Some(thread_id) = receipt.thread_id {
+            data["thread_id"] = Value::String(thread_id);
+        }
+        let by_type = content.entry(receipt.event_id.as_str()).or_insert_with(|| json!({}));
+        if !by_type[&receipt.receipt_type].is_object() {
+            by_type[&receipt.receipt_type] = json!({});
+        }
+        by_type[&receipt.receipt_type][receipt.user_id.as_str()] = data;
+    }
+
+    (!content.is_empty()).then(|| json!({ "type": "m.receipt", "content": content }))
+}
+
+fn presence_event(presence: &Presence) -> Value {
+    let last_active_ago = (chrono::Utc::now() - presence.last_active_at).num_milliseconds().max(0);
+    let mut content = json!({
+        "presence": presence.presence,
+        "last_active_ago": last_active_ago,
+        "currently_active": presence.presence == "online" && last_active_ago < CURRENTLY_ACTIVE_MS,
+    });
+    if let Some(ref status_msg) = presence.status_msg {
+        content["status_msg"] = Value::String(status_msg.clone());
+    }
+
+    json!({ "type": "m.presence", "sender": presence.user_id, "content": content })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::routes::membership::MembershipRequest;
+    use crate::services::membership::{self, MembershipAction};
+    use crate::services::rooms::tests::{authenticated_user, create};
+    use crate::services::rooms::{ClientEvent, SendEventResult};
+    use crate::services::signing::SigningKeys;
+    use actix_web::rt::time;
+    use twelf::reexports::serde_json;
+
+    fn query(since: Option<StreamToken>, timeout: u64) -> SyncQuery {
+        SyncQuery { since, timeout: Some(timeout), ..Default::default() }
+    }
+
+    async fn send_message(room_id: &RoomId, body: &str, txn_id: Option<&str>, auth: &AuthenticatedUser, config: &Config, pool: &PgPool) -> EventId {
+        let event = ClientEvent {
+            event_type: String::from("m.room.message"),
+            state_key: None,
+            content: json!({ "msgtype": "m.text", "body": body }),
+        };
+        match services::rooms::send_event(room_id, event, txn_id, auth, config, &SigningKeys::test(), pool).await.unwrap() {
+            SendEventResult::Sent(event_id) => event_id,
+            _ => panic!("Expected event to be sent"),
+        }
+    }
+
+    fn types(events: &[Value]) -> Vec<&str> {
+        events.iter().map(|e| e["type"].as_str().unwrap()).collect()
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_initial_and_incremental_sync(pool: PgPool) {
+        let notifier = Notifier::default();
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "preset": "public_chat" }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let event_id = send_message(room_id, "Hello", Some("txn1"), &alice, &config, &pool).await;
+
+        let response = sync(&query(None, 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        assert_eq!(room.timeline.events.len(), events.len() + 1);
+        assert!(!room.timeline.limited);
+        assert!(room.state.events.is_empty());
+        let message = room.timeline.events.last().unwrap();
+        assert_eq!(message["event_id"], event_id.as_str());
+        assert_eq!(message["unsigned"]["transaction_id"], "txn1");
+        assert!(message.get("room_id").is_none());
+        assert_eq!(room.unread_notifications.notification_count, 0);
+
+        // Nothing has happened since.
+        let next_batch = response.next_batch;
+        let response = sync(&query(Some(next_batch), 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        assert!(response.rooms.join.is_empty());
+
+        membership::join_room(room_id.as_str(), None, &bob, &config, &SigningKeys::test(), &pool).await.unwrap();
+        let response = sync(&query(Some(next_batch), 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        assert_eq!(types(&room.timeline.events), vec!["m.room.member"]);
+        assert_eq!(room.timeline.events[0]["state_key"], bob.matrix_user_id.as_str());
+        assert!(room.state.events.is_empty());
+
+        // Only Alice's client sent the message, and messages from before Bob
+        // joined don't count as unread.
+        let response = sync(&query(None, 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        assert!(room.timeline.events.iter().all(|e| e.get("unsigned").is_none()));
+        assert_eq!(room.unread_notifications.notification_count, 0);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync_with_history_visibility(pool: PgPool) {
+        let notifier = Notifier::default();
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({
+            "preset": "public_chat",
+            "initial_state": [{ "type": "m.room.history_visibility", "content": { "history_visibility": "joined" } }],
+        }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let next_batch = sync(&query(None, 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap().next_batch;
+        send_message(room_id, "Before", None, &alice, &config, &pool).await;
+        membership::join_room(room_id.as_str(), None, &bob, &config, &SigningKeys::test(), &pool).await.unwrap();
+        send_message(room_id, "After", None, &alice, &config, &pool).await;
+
+        // Bob doesn't see what was sent after the history visibility changed
+        // but before joining, in either an incremental or an initial sync.
+        for since in [Some(next_batch), None] {
+            let response = sync(&query(since, 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap();
+            let timeline = &response.rooms.join[room_id].timeline.events;
+            let bodies: Vec<&Value> = timeline.iter().filter_map(|e| e["content"].get("body")).collect();
+            assert_eq!(bodies, vec!["After"]);
+            assert_eq!(types(&timeline[timeline.len() - 2..]), vec!["m.room.member", "m.room.message"]);
+        }
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync_hides_erased_users_events(pool: PgPool) {
+        let notifier = Notifier::default();
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let room_id = create(json!({ "preset": "public_chat" }), &bob, &config, &pool).await[0].room_id.clone();
+        membership::join_room(room_id.as_str(), None, &alice, &config, &keys, &pool).await.unwrap();
+        send_message(&room_id, "Hello", None, &alice, &config, &pool).await;
+        let next_batch = sync(&query(None, 0), &Filter::default(), &carol, &notifier, &pool).await.unwrap().next_batch;
+        services::account::deactivate(alice.user_id, true, &config, &keys, &pool).await.unwrap();
+        membership::join_room(room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+
+        // Bob was in the room when Alice was erased, but Carol joined after.
+        let bodies = |response: &SyncResponse| -> Vec<Value> {
+            response.rooms.join[&room_id].timeline.events.iter().filter_map(|e| e["content"].get("body").cloned()).collect()
+        };
+        let response = sync(&query(None, 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap();
+        assert_eq!(bodies(&response), vec![json!("Hello")]);
+        for since in [Some(next_batch), None] {
+            let response = sync(&query(since, 0), &Filter::default(), &carol, &notifier, &pool).await.unwrap();
+            assert!(bodies(&response).is_empty());
+            let timeline = &response.rooms.join[&room_id].timeline.events;
+            assert!(!timeline.iter().any(|e| e["sender"] == alice.matrix_user_id.as_str()));
+        }
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync_limited_timeline(pool: PgPool) {
+        let notifier = Notifier::default();
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "name": "Lobby" }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let next_batch = sync(&query(None, 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap().next_batch;
+        for i in 0..12 {
+            send_message(room_id, &i.to_string(), None, &alice, &config, &pool).await;
+        }
+
+        let response = sync(&query(None, 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        assert!(room.timeline.limited);
+        assert_eq!(types(&room.timeline.events), vec!["m.room.message"; 10]);
+        assert_eq!(room.timeline.events[0]["content"]["body"], "2");
+        assert_eq!(room.state.events.len(), events.len());
+
+        // Incremental syncs only include state that changed in the gap.
+        let response = sync(&query(Some(next_batch), 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        assert!(room.timeline.limited);
+        assert!(room.state.events.is_empty());
+
+        let response = sync(&SyncQuery { full_state: Some(true), ..query(Some(next_batch), 0) }, &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        assert_eq!(response.rooms.join[room_id].state.events.len(), events.len());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync_with_filter(pool: PgPool) {
+        let notifier = Notifier::default();
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let carol = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "preset": "public_chat" }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        membership::join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        membership::join_room(room_id.as_str(), None, &carol, &config, &keys, &pool).await.unwrap();
+        let root_id = send_message(room_id, "Hello", None, &alice, &config, &pool).await;
+        let reply = ClientEvent {
+            event_type: String::from("m.room.message"),
+            state_key: None,
+            content: json!({
+                "msgtype": "m.text",
+                "body": "Hi",
+                "m.relates_to": { "rel_type": "m.thread", "event_id": root_id },
+            }),
+        };
+        services::rooms::send_event(room_id, reply, None, &bob, &config, &keys, &pool).await.unwrap();
+        send_message(room_id, "Anyone?", None, &bob, &config, &pool).await;
+
+        let filter = |value: Value| serde_json::from_value::<Filter>(value).unwrap();
+        let response = sync(&query(None, 0), &filter(json!({
+            "event_fields": ["type", "sender", "content.body"],
+            "room": { "timeline": { "types": ["m.room.message"], "limit": 2, "unread_thread_notifications": true } },
+        })), &alice, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        assert!(room.timeline.limited);
+        assert_eq!(room.timeline.events[0], json!({ "type": "m.room.message", "sender": bob.matrix_user_id, "content": { "body": "Hi" } }));
+        assert_eq!(room.unread_notifications.notification_count, 1);
+        assert_eq!(room.unread_thread_notifications[root_id.as_str()].notification_count, 1);
+
+        // Lazy-loading only sends the members who sent events in the timeline
+        // and the user, and only once unless redundant members are requested.
+        let lazy_load = |include_redundant_members: bool| filter(json!({
+            "room": {
+                "state": { "lazy_load_members": true, "include_redundant_members": include_redundant_members },
+                "timeline": { "limit": 1 },
+            },
+        }));
+        let members = |room: &JoinedRoom| {
+            let mut members: Vec<String> = room.state.events.iter()
+                .filter(|e| e["type"] == "m.room.member")
+                .map(|e| e["state_key"].as_str().unwrap().to_string())
+                .collect();
+            members.sort();
+            members
+        };
+        let mut alice_and_bob = vec![alice.matrix_user_id.to_string(), bob.matrix_user_id.to_string()];
+        alice_and_bob.sort();
+
+        let response = sync(&query(None, 0), &lazy_load(false), &alice, &notifier, &pool).await.unwrap();
+        assert_eq!(members(&response.rooms.join[room_id]), alice_and_bob);
+        assert_eq!(response.rooms.join[room_id].state.events.len(), events.len() + 1);
+
+        let next_batch = response.next_batch;
+        send_message(room_id, "Still there?", None, &bob, &config, &pool).await;
+        let response = sync(&query(Some(next_batch), 0), &lazy_load(false), &alice, &notifier, &pool).await.unwrap();
+        assert!(members(&response.rooms.join[room_id]).is_empty());
+        let response = sync(&query(Some(next_batch), 0), &lazy_load(true), &alice, &notifier, &pool).await.unwrap();
+        assert_eq!(members(&response.rooms.join[room_id]), alice_and_bob);
+
+        // Rooms can be left out entirely.
+        let response = sync(&query(None, 0), &filter(json!({ "room": { "not_rooms": [room_id] } })), &alice, &notifier, &pool).await.unwrap();
+        assert!(response.rooms.join.is_empty());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync_invite_knock_and_leave(pool: PgPool) {
+        let notifier = Notifier::default();
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({
+            "name": "Club",
+            "initial_state": [{ "type": "m.room.join_rules", "content": { "join_rule": "knock" } }],
+        }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+
+        membership::knock_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        let response = sync(&query(None, 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap();
+        let knock_state = &response.rooms.knock[room_id].knock_state.events;
+        assert!(types(knock_state).contains(&"m.room.name"));
+        assert!(response.rooms.join.is_empty());
+        let next_batch = response.next_batch;
+
+        let request = MembershipRequest { user_id: bob.matrix_user_id.clone(), reason: None };
+        membership::change_membership(room_id, MembershipAction::Invite, &request, &alice, &config, &keys, &pool).await.unwrap();
+        let response = sync(&query(Some(next_batch), 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap();
+        let invite_state = &response.rooms.invite[room_id].invite_state.events;
+        let invite = invite_state.iter().find(|e| e["type"] == "m.room.member").unwrap();
+        assert_eq!(invite["content"]["membership"], "invite");
+        assert_eq!(invite["sender"], alice.matrix_user_id.as_str());
+        assert!(response.rooms.knock.is_empty());
+
+        membership::join_room(room_id.as_str(), None, &bob, &config, &keys, &pool).await.unwrap();
+        let next_batch = sync(&query(None, 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap().next_batch;
+        send_message(room_id, "Bye", None, &alice, &config, &pool).await;
+        membership::leave_room(room_id, None, &bob, &config, &keys, &pool).await.unwrap();
+        send_message(room_id, "Bob left", None, &alice, &config, &pool).await;
+
+        // Bob sees events up to leaving.
+        let response = sync(&query(Some(next_batch), 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap();
+        let timeline = &response.rooms.leave[room_id].timeline.events;
+        assert_eq!(types(timeline), vec!["m.room.message", "m.room.member"]);
+        assert!(response.rooms.join.is_empty());
+
+        // Forgotten rooms are left out, as are left rooms in a first sync.
+        membership::forget_room(room_id, &bob, &pool).await.unwrap();
+        assert!(sync(&query(Some(next_batch), 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap().rooms.leave.is_empty());
+        assert!(sync(&query(None, 0), &Filter::default(), &bob, &notifier, &pool).await.unwrap().rooms.leave.is_empty());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync_ephemeral_account_data_and_presence(pool: PgPool) {
+        let notifier = Notifier::default();
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({ "preset": "public_chat" }), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        membership::join_room(room_id.as_str(), None, &bob, &config, &SigningKeys::test(), &pool).await.unwrap();
+        let next_batch = sync(&query(None, 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap().next_batch;
+
+        let event_id = send_message(room_id, "Hello", None, &bob, &config, &pool).await;
+        pg::presence::set_presence(&bob.matrix_user_id, "online", &pool).await.unwrap();
+        pg::typing::set_typing(room_id, &bob.matrix_user_id, Some(30_000), &pool).await.unwrap();
+        pg::account_data::set_account_data(&alice.matrix_user_id, None, "m.direct", &json!({}), &pool).await.unwrap();
+        pg::account_data::set_account_data(&alice.matrix_user_id, Some(room_id), "m.tag", &json!({ "tags": {} }), &pool).await.unwrap();
+
+        let response = sync(&SyncQuery { set_presence: Some(PresenceState::Offline), ..query(Some(next_batch), 0) }, &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        assert_eq!(room.ephemeral.events, vec![json!({ "type": "m.typing", "content": { "user_ids": [bob.matrix_user_id] } })]);
+        assert_eq!(types(&room.account_data.events), vec!["m.tag"]);
+        assert_eq!(types(&response.account_data.events), vec!["m.direct"]);
+        assert_eq!(room.unread_notifications.notification_count, 1);
+        assert_eq!(types(&response.presence.events), vec!["m.presence"]);
+        assert_eq!(response.presence.events[0]["sender"], bob.matrix_user_id.as_str());
+        let next_batch = response.next_batch;
+
+        pg::receipts::set_receipt(room_id, &alice.matrix_user_id, "m.read", None, &event_id, &pool).await.unwrap();
+        pg::receipts::set_receipt(room_id, &bob.matrix_user_id, "m.read.private", None, &event_id, &pool).await.unwrap();
+        let response = sync(&SyncQuery { set_presence: Some(PresenceState::Offline), ..query(Some(next_batch), 0) }, &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        let room = &response.rooms.join[room_id];
+        let receipt = &room.ephemeral.events[0];
+        assert_eq!(receipt["type"], "m.receipt");
+        assert!(receipt["content"][event_id.as_str()]["m.read"][alice.matrix_user_id.as_str()]["ts"].is_i64());
+        assert!(receipt["content"][event_id.as_str()].get("m.read.private").is_none());
+        assert_eq!(room.unread_notifications.notification_count, 0);
+        assert!(response.presence.events.is_empty());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_sync_waits_for_events(pool: PgPool) {
+        let notifier = Notifier::start(&pool).await.unwrap();
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let events = create(json!({}), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let next_batch = sync(&query(None, 0), &Filter::default(), &alice, &notifier, &pool).await.unwrap().next_batch;
+
+        let started = Instant::now();
+        let response = sync(&query(Some(next_batch), 200), &Filter::default(), &alice, &notifier, &pool).await.unwrap();
+        assert!(response.is_empty());
+        assert_eq!(response.next_batch, next_batch);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let started = Instant::now();
+        let long_poll = query(Some(next_batch), 10_000);
+        let filter = Filter::default();
+        let (response, _) = futures_util::join!(
+            sync(&long_poll, &filter, &alice, &notifier, &pool),
+            async {
+                time::sleep(Duration::from_millis(100)).await;
+                send_message(room_id, "Hello", None, &alice, &config, &pool).await
+            }
+        );
+        assert_eq!(types(&response.unwrap().rooms.join[room_id].timeline.events), vec!["m.room.message"]);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,88 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::DeviceId;
+use crate::routes::to_device::ToDeviceRequest;
+use crate::store::pg;
+use crate::store::pg::to_device::NewToDeviceMessage;
+use sqlx::PgPool;
+
+/// Stores the messages in `request` for the devices of local users
+///
+/// Messages for users on other servers, or for devices that don't exist, are
+/// dropped, as this server doesn't federate yet. Retrying with the same
+/// `txn_id` doesn't send the messages again.
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3sendtodeviceeventtypetxnid
+pub async fn send_to_device(
+    event_type: &str,
+    txn_id: &str,
+    request: &ToDeviceRequest,
+    auth: &AuthenticatedUser,
+    config: &Config,
+    pool: &PgPool
+) -> Result<(), Error> {
+    let mut messages = Vec::new();
+    for (user_id, by_device) in &request.messages {
+        if user_id.server_name() != config.server.server_name.as_str() {
+            continue;
+        }
+
+        let device_ids = pg::auth::get_device_ids(user_id.localpart(), pool).await?;
+        for (device_id, content) in by_device {
+            let recipients: Vec<&DeviceId> = match device_id.as_str() {
+                "*" => device_ids.iter().collect(),
+                device_id => device_ids.iter().filter(|id| id.as_str() == device_id).collect(),
+            };
+            for device_id in recipients {
+                messages.push(NewToDeviceMessage {
+                    user_id: user_id.clone(),
+                    device_id: device_id.clone(),
+                    content: content.clone(),
+                });
+            }
+        }
+    }
+
+    pg::to_device::add_to_device_messages(&auth.matrix_user_id, event_type, &messages, auth.session_id, txn_id, pool).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::rooms::tests::authenticated_user;
+    use twelf::reexports::serde_json::{self, json};
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_send_to_device(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let request: ToDeviceRequest = serde_json::from_value(json!({
+            "messages": {
+                alice.matrix_user_id.as_str(): { "*": { "body": "Hi" } },
+                "@carol:example.com": { "CAROLDEVICE": { "body": "Hi" } },
+            }
+        })).unwrap();
+
+        // Retrying the transaction doesn't send the message again.
+        send_to_device("org.example.greeting", "txn1", &request, &bob, &config, &pool).await.unwrap();
+        send_to_device("org.example.greeting", "txn1", &request, &bob, &config, &pool).await.unwrap();
+
+        let to = pg::streams::get_current_stream_ordering(&pool).await.unwrap();
+        let messages = pg::to_device::get_to_device_messages(&alice.matrix_user_id, &alice.device_id, 0, to, 10, &pool).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].sender, bob.matrix_user_id);
+        assert_eq!(messages[0].event_type, "org.example.greeting");
+        assert_eq!(messages[0].content, json!({ "body": "Hi" }));
+
+        pg::to_device::delete_to_device_messages(&alice.matrix_user_id, &alice.device_id, to, &pool).await.unwrap();
+        let messages = pg::to_device::get_to_device_messages(&alice.matrix_user_id, &alice.device_id, 0, to, 10, &pool).await.unwrap();
+        assert!(messages.is_empty());
+
+        // Deleting the latest messages doesn't move the stream position back.
+        assert_eq!(pg::streams::get_current_stream_ordering(&pool).await.unwrap(), to);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::error::Error;
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::ids::{RoomId, UserId};
+use crate::routes::typing::TypingRequest;
+use crate::services;
+use crate::store::pg;
+use sqlx::PgPool;
+
+/// How long a user is typing for if their client doesn't say
+const DEFAULT_TIMEOUT_MS: i64 = 30_000;
+
+/// Longest time for which a user may be typing without another notification
+const MAX_TIMEOUT_MS: i64 = 120_000;
+
+/// Possible results of calling [`set_typing()`]
+pub enum TypingResult {
+    Set,
+    /// The user is another user or isn't in the room
+    Forbidden(String),
+}
+
+/// Records that `user_id` has started or stopped typing in a room
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#put_matrixclientv3roomsroomidtypinguserid
+pub async fn set_typing(
+    room_id: &RoomId,
+    user_id: &UserId,
+    request: &TypingRequest,
+    auth: &AuthenticatedUser,
+    pool: &PgPool
+) -> Result<TypingResult, Error> {
+    if *user_id != auth.matrix_user_id {
+        return Ok(TypingResult::Forbidden(String::from("Cannot set typing for other users")));
+    }
+    if services::membership::membership(room_id, user_id, pool).await?.as_deref() != Some("join") {
+        return Ok(TypingResult::Forbidden(String::from("User is not in the room")));
+    }
+
+    let timeout_ms = request.typing.then(|| request.timeout.unwrap_or(DEFAULT_TIMEOUT_MS).clamp(0, MAX_TIMEOUT_MS));
+    pg::typing::set_typing(room_id, user_id, timeout_ms, pool).await?;
+
+    Ok(TypingResult::Set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::services::rooms::tests::{authenticated_user, create};
+    use twelf::reexports::serde_json::json;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_set_typing(pool: PgPool) {
+        let config = Config::test();
+        let alice = authenticated_user(&config, &pool).await;
+        let bob = authenticated_user(&config, &pool).await;
+        let events = create(json!({}), &alice, &config, &pool).await;
+        let room_id = &events[0].room_id;
+        let user_id = &alice.matrix_user_id;
+
+        let result = set_typing(room_id, user_id, &TypingRequest { typing: true, timeout: Some(10_000) }, &alice, &pool).await.unwrap();
+        assert!(matches!(result, TypingResult::Set));
+        assert_eq!(pg::typing::get_typing_user_ids(room_id, &pool).await.unwrap(), vec![user_id.clone()]);
+
+        set_typing(room_id, user_id, &TypingRequest { typing: false, timeout: None }, &alice, &pool).await.unwrap();
+        assert!(pg::typing::get_typing_user_ids(room_id, &pool).await.unwrap().is_empty());
+
+        let request = TypingRequest { typing: true, timeout: None };
+        let result = set_typing(room_id, user_id, &request, &bob, &pool).await.unwrap();
+        assert!(matches!(result, TypingResult::Forbidden(_)));
+        let result = set_typing(room_id, &bob.matrix_user_id, &request, &bob, &pool).await.unwrap();
+        assert!(matches!(result, TypingResult::Forbidden(_)));
+    }
+}
@@ -0,0 +1,479 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::routes::auth::AuthenticationData;
+use crate::services;
+use crate::store::pg;
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use sqlx::PgPool;
+use twelf::reexports::serde_json;
+
+/// The stage handlers available to user-interactive authentication flows
+///
+/// To support another stage type, implement [`StageHandler`] and add it here.
+static STAGE_HANDLERS: [&dyn StageHandler; 5] = [
+    &PasswordStage,
+    &DummyStage,
+    &RegistrationTokenStage,
+    &TermsStage,
+    &EmailIdentityStage,
+];
+
+/// A sequence of user-interactive authentication stages that a client may
+/// complete
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuthFlow {
+    pub stages: Vec<String>,
+}
+
+impl AuthFlow {
+    pub fn new(stages: &[&str]) -> Self {
+        Self { stages: stages.iter().map(|s| s.to_string()).collect() }
+    }
+}
+
+/// JSON response when user-interactive authentication is required or a stage
+/// failed, per the Matrix spec
+///
+/// See https://spec.matrix.org/v1.13/client-server-api/#user-interactive-authentication-api
+#[derive(Debug, Serialize)]
+pub struct UiaResponse {
+    pub flows: Vec<AuthFlow>,
+    pub params: serde_json::Map<String, serde_json::Value>,
+    pub session: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub completed: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errcode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Possible results of calling [`authenticate()`]
+#[derive(Debug)]
+pub enum UiaResult {
+    /// The client has completed one of the flows
+    Completed,
+    /// The client must complete further stages; respond with a 401
+    Incomplete(UiaResponse),
+}
+
+/// A request body that includes an `auth` field for user-interactive
+/// authentication
+///
+/// Implement this for the body of an endpoint to use the [`UiaAuthenticated`]
+/// extractor in its handler.
+///
+/// [`UiaAuthenticated`]: crate::extractors::uia_authenticated::UiaAuthenticated
+pub trait UiaRequest {
+    /// Returns the `auth` field of the request
+    fn auth(&self) -> &Option<AuthenticationData>;
+
+    /// Returns the flows that complete authentication for the endpoint; by
+    /// default, the user's password is required
+    fn flows(_config: &Config) -> Vec<AuthFlow> {
+        vec![AuthFlow::new(&["m.login.password"])]
+    }
+}
+
+/// Information about the request available to stage handlers
+pub struct UiaContext<'a> {
+    /// The authenticated user making the request, if any
+    pub user_id: Option<i64>,
+    pub config: &'a Config,
+    pub pool: &'a PgPool,
+}
+
+/// Implements a single user-interactive authentication stage type
+pub trait StageHandler: Sync {
+    /// The stage type, e.g. `m.login.password`
+    fn stage_type(&self) -> &'static str;
+
+    /// Returns the parameters a client needs to complete this stage, if any
+    fn params(&self, _config: &Config) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Returns `Ok(true)` if `auth` completes this stage
+    fn authenticate<'a>(&'a self, auth: &'a AuthenticationData, context: &'a UiaContext<'a>) -> BoxFuture<'a, Result<bool, Error>>;
+}
+
+/// The `m.login.password` stage, which requires the authenticated user's
+/// password
+struct PasswordStage;
+
+impl StageHandler for PasswordStage {
+    fn stage_type(&self) -> &'static str {
+        "m.login.password"
+    }
+
+    fn authenticate<'a>(&'a self, auth: &'a AuthenticationData, context: &'a UiaContext<'a>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            let (user_id, password) = match (context.user_id, auth.password.as_ref()) {
+                (Some(user_id), Some(password)) => (user_id, password),
+                _ => return Ok(false),
+            };
+
+            // The identifier is optional, since the user is already known.
+            let username = match auth.identifier.as_ref().and_then(|i| i.user.as_ref()) {
+                Some(user) =>
+                    match services::auth::localpart(user, context.config) {
+                        Some(username) => username,
+                        None => return Ok(false),
+                    },
+                None =>
+                    match pg::auth::get_user(user_id, context.pool).await? {
+                        Some(user) => user.name,
+                        None => return Ok(false),
+                    },
+            };
+
+            let validated = pg::auth::validate_user_and_password(&username, password, context.pool).await?;
+            Ok(validated == Some(user_id))
+        })
+    }
+}
+
+/// The `m.login.dummy` stage, which always succeeds
+struct DummyStage;
+
+impl StageHandler for DummyStage {
+    fn stage_type(&self) -> &'static str {
+        "m.login.dummy"
+    }
+
+    fn authenticate<'a>(&'a self, _auth: &'a AuthenticationData, _context: &'a UiaContext<'a>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async { Ok(true) })
+    }
+}
+
+/// The `m.login.registration_token` stage, which requires a valid registration
+/// token
+struct RegistrationTokenStage;
+
+impl StageHandler for RegistrationTokenStage {
+    fn stage_type(&self) -> &'static str {
+        "m.login.registration_token"
+    }
+
+    /// Reserves a use of the token, which the caller must complete or release
+    /// with [`pg::registration_tokens::complete_use()`] or
+    /// [`pg::registration_tokens::release_use()`] once the account is created
+    /// or creation fails.
+    fn authenticate<'a>(&'a self, auth: &'a AuthenticationData, context: &'a UiaContext<'a>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            match auth.token {
+                Some(ref token) => pg::registration_tokens::reserve_use(token, context.pool).await,
+                None => Ok(false),
+            }
+        })
+    }
+}
+
+/// The `m.login.terms` stage, which the client completes by submitting it after
+/// the user accepts the terms of service in the `[terms]` config section
+struct TermsStage;
+
+impl StageHandler for TermsStage {
+    fn stage_type(&self) -> &'static str {
+        "m.login.terms"
+    }
+
+    fn params(&self, config: &Config) -> Option<serde_json::Value> {
+        config.terms.as_ref().map(|terms|
+            serde_json::json!({
+                "policies": {
+                    "terms_of_service": {
+                        "version": terms.version,
+                        "en": { "name": terms.name, "url": terms.url },
+                    }
+                }
+            })
+        )
+    }
+
+    fn authenticate<'a>(&'a self, _auth: &'a AuthenticationData, context: &'a UiaContext<'a>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async { Ok(context.config.terms.is_some()) })
+    }
+}
+
+/// The `m.login.email.identity` stage, which requires a validated email
+/// address session started with a `requestToken` endpoint
+///
+/// An authenticated user may only use an address on their own account.
+struct EmailIdentityStage;
+
+impl StageHandler for EmailIdentityStage {
+    fn stage_type(&self) -> &'static str {
+        "m.login.email.identity"
+    }
+
+    fn authenticate<'a>(&'a self, auth: &'a AuthenticationData, context: &'a UiaContext<'a>) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            let creds = match auth.threepid_creds {
+                Some(ref creds) => creds,
+                None => return Ok(false),
+            };
+
+            let session = match pg::threepid::get_validated_session(&creds.sid, &creds.client_secret, context.pool).await? {
+                Some(session) if session.medium == "email" => session,
+                _ => return Ok(false),
+            };
+
+            match context.user_id {
+                Some(user_id) =>
+                    Ok(pg::auth::get_user_by_email(&session.address, context.pool).await?.is_some_and(|u| u.id == user_id)),
+                None => Ok(true),
+            }
+        })
+    }
+}
+
+/// Performs one step of user-interactive authentication for a request to
+/// `endpoint`
+///
+/// If `auth` is `None`, a new UIA session is started. Otherwise, the stage in
+/// `auth` (if any) is attempted within the given session. Returns
+/// [`UiaResult::Completed`] once the session's completed stages match one of
+/// `flows`, at which point the session is deleted so it can't be reused.
+///
+/// A session can only be continued by the same user for the same endpoint on
+/// which it was started.
+pub async fn authenticate(
+    auth: &Option<AuthenticationData>,
+    flows: &[AuthFlow],
+    endpoint: &str,
+    context: &UiaContext<'_>,
+) -> Result<UiaResult, Error> {
+    let pool = context.pool;
+    let endpoint = endpoint.to_string();
+
+    let session = match auth.as_ref().and_then(|a| a.session.as_ref()) {
+        Some(session_identifier) =>
+            pg::uia::get_session(session_identifier, pool).await?
+                .filter(|s| s.endpoint == endpoint && s.user_id == context.user_id),
+        None => None,
+    };
+
+    let (auth, session) = match (auth, session) {
+        (Some(auth), Some(session)) => (auth, session),
+        (auth, _) => {
+            let session_identifier = uuid::Uuid::new_v4().simple().to_string();
+            let session = pg::uia::create_session(&session_identifier, &endpoint, context.user_id, pool).await?;
+            let error = match auth {
+                Some(auth) if auth.session.is_some() => Some(String::from("Unknown session")),
+                _ => None,
+            };
+            return Ok(UiaResult::Incomplete(response(flows, session.session_identifier, vec![], error, context.config)));
+        }
+    };
+
+    // A request without a stage type just checks the session's progress.
+    let stage_type = match auth.r#type {
+        Some(ref stage_type) => stage_type,
+        None =>
+            return Ok(UiaResult::Incomplete(response(flows, session.session_identifier, session.completed_stages, None, context.config))),
+    };
+
+    let handler = STAGE_HANDLERS.iter().find(|h| h.stage_type() == stage_type);
+    let allowed = flows.iter().any(|flow|
+        flow.stages.len() > session.completed_stages.len()
+            && flow.stages.starts_with(&session.completed_stages)
+            && flow.stages[session.completed_stages.len()] == *stage_type
+    );
+
+    let authenticated = match handler {
+        Some(handler) if allowed => handler.authenticate(auth, context).await?,
+        _ => false,
+    };
+
+    if !authenticated {
+        return Ok(UiaResult::Incomplete(response(
+            flows,
+            session.session_identifier,
+            session.completed_stages,
+            Some(format!("Authentication failed for stage {}", stage_type)),
+            context.config,
+        )));
+    }
+
+    let mut completed_stages = session.completed_stages.clone();
+    completed_stages.push(stage_type.clone());
+
+    // Both transitions are conditional on the stages read above, so a request
+    // racing another one on the same session can't complete it twice.
+    if flows.iter().any(|flow| flow.stages == completed_stages) {
+        if pg::uia::delete_session(session.id, &session.completed_stages, pool).await? {
+            return Ok(UiaResult::Completed);
+        }
+    } else if pg::uia::add_completed_stage(session.id, &session.completed_stages, stage_type, pool).await? {
+        return Ok(UiaResult::Incomplete(response(flows, session.session_identifier, completed_stages, None, context.config)));
+    }
+
+    Ok(UiaResult::Incomplete(response(
+        flows,
+        session.session_identifier,
+        session.completed_stages,
+        Some(String::from("Session was used by another request")),
+        context.config,
+    )))
+}
+
+/// Builds a [`UiaResponse`], including the params for every stage in `flows`
+fn response(flows: &[AuthFlow], session: String, completed: Vec<String>, error: Option<String>, config: &Config) -> UiaResponse {
+    let mut params = serde_json::Map::new();
+
+    for stage_type in flows.iter().flat_map(|f| f.stages.iter()) {
+        if let Some(handler) = STAGE_HANDLERS.iter().find(|h| h.stage_type() == stage_type) {
+            if let Some(value) = handler.params(config) {
+                params.insert(stage_type.clone(), value);
+            }
+        }
+    }
+
+    UiaResponse {
+        flows: flows.to_vec(),
+        params,
+        session,
+        completed,
+        errcode: error.as_ref().map(|_| String::from("M_FORBIDDEN")),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::auth::UserIdentifier;
+    use crate::store::pg::auth::tests::create_test_user;
+    use sqlx::PgPool;
+
+    fn auth_data(stage_type: Option<&str>, session: Option<String>, password: Option<String>) -> Option<AuthenticationData> {
+        Some(AuthenticationData {
+            session,
+            r#type: stage_type.map(String::from),
+            token: None,
+            identifier: None,
+            password,
+            threepid_creds: None,
+        })
+    }
+
+    fn session_of(result: UiaResult) -> UiaResponse {
+        match result {
+            UiaResult::Incomplete(response) => response,
+            UiaResult::Completed => panic!("Expected incomplete UIA"),
+        }
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authenticate_starts_session(pool: PgPool) {
+        let config = Config::test();
+        let context = UiaContext { user_id: None, config: &config, pool: &pool };
+        let flows = vec![AuthFlow::new(&["m.login.dummy"])];
+
+        let response = session_of(authenticate(&None, &flows, "/register", &context).await.unwrap());
+        assert_eq!(response.flows, flows);
+        assert!(response.completed.is_empty());
+        assert!(response.errcode.is_none());
+        assert!(pg::uia::get_session(&response.session, &pool).await.unwrap().is_some());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authenticate_with_multiple_stages(pool: PgPool) {
+        let mut config = Config::test();
+        config.terms = Some(crate::config::TermsConfig {
+            version: String::from("1.0"),
+            name: String::from("Terms of Service"),
+            url: String::from("https://example.org/terms"),
+        });
+        let context = UiaContext { user_id: None, config: &config, pool: &pool };
+        let flows = vec![AuthFlow::new(&["m.login.terms", "m.login.dummy"])];
+
+        let response = session_of(authenticate(&None, &flows, "/register", &context).await.unwrap());
+        assert!(response.params.contains_key("m.login.terms"));
+        let session = Some(response.session);
+
+        // Out of order
+        let auth = auth_data(Some("m.login.dummy"), session.clone(), None);
+        let response = session_of(authenticate(&auth, &flows, "/register", &context).await.unwrap());
+        assert_eq!(response.errcode, Some(String::from("M_FORBIDDEN")));
+
+        let auth = auth_data(Some("m.login.terms"), session.clone(), None);
+        let response = session_of(authenticate(&auth, &flows, "/register", &context).await.unwrap());
+        assert_eq!(response.completed, vec![String::from("m.login.terms")]);
+
+        let auth = auth_data(Some("m.login.dummy"), session.clone(), None);
+        assert!(matches!(authenticate(&auth, &flows, "/register", &context).await.unwrap(), UiaResult::Completed));
+
+        // The session can't be reused.
+        let response = session_of(authenticate(&auth, &flows, "/register", &context).await.unwrap());
+        assert_ne!(Some(response.session), session);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authenticate_completes_session_once(pool: PgPool) {
+        let config = Config::test();
+        let context = UiaContext { user_id: None, config: &config, pool: &pool };
+        let flows = vec![AuthFlow::new(&["m.login.dummy"])];
+
+        let response = session_of(authenticate(&None, &flows, "/register", &context).await.unwrap());
+        let auth = auth_data(Some("m.login.dummy"), Some(response.session), None);
+
+        let (first, second) = futures_util::join!(
+            authenticate(&auth, &flows, "/register", &context),
+            authenticate(&auth, &flows, "/register", &context),
+        );
+        let completed = [first.unwrap(), second.unwrap()].into_iter()
+            .filter(|result| matches!(result, UiaResult::Completed))
+            .count();
+        assert_eq!(completed, 1);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authenticate_with_other_endpoint(pool: PgPool) {
+        let config = Config::test();
+        let context = UiaContext { user_id: None, config: &config, pool: &pool };
+        let flows = vec![AuthFlow::new(&["m.login.dummy"])];
+
+        let response = session_of(authenticate(&None, &flows, "/register", &context).await.unwrap());
+        let auth = auth_data(Some("m.login.dummy"), Some(response.session), None);
+        let response = session_of(authenticate(&auth, &flows, "/account/deactivate", &context).await.unwrap());
+        assert_eq!(response.error, Some(String::from("Unknown session")));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authenticate_with_password(pool: PgPool) {
+        let (user, password) = create_test_user(&pool).await;
+        let config = Config::test();
+        let context = UiaContext { user_id: Some(user.id), config: &config, pool: &pool };
+        let flows = vec![AuthFlow::new(&["m.login.password"])];
+
+        let response = session_of(authenticate(&None, &flows, "/account/password", &context).await.unwrap());
+        let session = Some(response.session);
+
+        let auth = auth_data(Some("m.login.password"), session.clone(), Some(String::from("wrong")));
+        let response = session_of(authenticate(&auth, &flows, "/account/password", &context).await.unwrap());
+        assert_eq!(response.errcode, Some(String::from("M_FORBIDDEN")));
+
+        let mut auth = auth_data(Some("m.login.password"), session.clone(), Some(password));
+        auth.as_mut().unwrap().identifier = Some(UserIdentifier {
+            r#type: String::from("m.id.user"),
+            user: Some(format!("@{}:{}", user.name, config.server.server_name)),
+        });
+        assert!(matches!(authenticate(&auth, &flows, "/account/password", &context).await.unwrap(), UiaResult::Completed));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_authenticate_with_other_users_password(pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let (_other_user, other_password) = create_test_user(&pool).await;
+        let config = Config::test();
+        let context = UiaContext { user_id: Some(user.id), config: &config, pool: &pool };
+        let flows = vec![AuthFlow::new(&["m.login.password"])];
+
+        let response = session_of(authenticate(&None, &flows, "/account/password", &context).await.unwrap());
+        let auth = auth_data(Some("m.login.password"), Some(response.session), Some(other_password));
+        assert!(matches!(authenticate(&auth, &flows, "/account/password", &context).await.unwrap(), UiaResult::Incomplete(_)));
+    }
+}
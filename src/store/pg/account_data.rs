@@ -0,0 +1,81 @@
+use crate::error::Error;
+use crate::models::account_data::AccountData;
+use crate::models::ids::{RoomId, UserId};
+use crate::models::stream_token::StreamUpdate;
+use crate::store::pg;
+use sqlx::PgPool;
+use twelf::reexports::serde_json;
+
+/// Columns selected into [`AccountData`]
+const COLUMNS: &str = "NULLIF(room_id, '') AS room_id, event_type, content";
+
+/// Stores account data of `event_type` for `user_id`, either global or for the
+/// room with ID `room_id`, replacing any with the same type, and wakes the
+/// user's waiting requests
+pub async fn set_account_data(
+    user_id: &UserId,
+    room_id: Option<&RoomId>,
+    event_type: &str,
+    content: &serde_json::Value,
+    pool: &PgPool
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+    let stream_ordering = pg::streams::next_stream_ordering(&mut tx).await?;
+
+    sqlx::query("\
+            INSERT INTO account_data (user_id, room_id, event_type, content, stream_ordering) \
+            VALUES ($1, $2, $3, $4, $5) \
+            ON CONFLICT (user_id, room_id, event_type) DO UPDATE \
+            SET content = EXCLUDED.content, \
+                stream_ordering = EXCLUDED.stream_ordering, \
+                updated_at = NOW()")
+        .bind(user_id)
+        .bind(room_id.map(RoomId::as_str).unwrap_or_default())
+        .bind(event_type)
+        .bind(content)
+        .bind(stream_ordering)
+        .execute(&mut *tx)
+        .await?;
+
+    let update = StreamUpdate { stream_ordering, user_ids: vec![user_id.clone()], ..Default::default() };
+    pg::streams::notify(&update, &mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns the content of the account data of `event_type` for `user_id`,
+/// either global or for the room with ID `room_id`
+pub async fn get_account_data(
+    user_id: &UserId,
+    room_id: Option<&RoomId>,
+    event_type: &str,
+    pool: &PgPool
+) -> Result<Option<serde_json::Value>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, serde_json::Value>("\
+                SELECT content FROM account_data \
+                WHERE user_id = $1 AND room_id = $2 AND event_type = $3")
+            .bind(user_id)
+            .bind(room_id.map(RoomId::as_str).unwrap_or_default())
+            .bind(event_type)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Returns the global and room account data of `user_id` that was stored with a
+/// stream ordering after `from` and up to and including `to`
+pub async fn get_account_data_between(user_id: &UserId, from: i64, to: i64, pool: &PgPool) -> Result<Vec<AccountData>, Error> {
+    Ok(
+        sqlx::query_as::<_, AccountData>(&format!("\
+                SELECT {} FROM account_data \
+                WHERE user_id = $1 AND stream_ordering > $2 AND stream_ordering <= $3 \
+                ORDER BY stream_ordering", COLUMNS))
+            .bind(user_id)
+            .bind(from)
+            .bind(to)
+            .fetch_all(pool)
+            .await?
+    )
+}
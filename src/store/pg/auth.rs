@@ -1,35 +1,54 @@
 use crate::error::Error;
 use crate::models::auth::{Session, User};
+use crate::models::ids::DeviceId;
 use crate::services;
-use argon2::password_hash::rand_core::OsRng;
-use argon2::password_hash::{Salt, SaltString};
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use futures_util::stream::BoxStream;
 use sqlx::PgPool;
-use uuid::Uuid;
 
 /// Query result used by [`validate_user_and_password()`]
 #[derive(Debug, sqlx::FromRow)]
 struct ValidationRow {
     id: i64,
-    encrypted_password: String,
+    encrypted_password: Option<String>,
+}
+
+/// Number of days a used refresh token is kept so that its reuse can be
+/// detected
+const USED_REFRESH_TOKEN_RETENTION_DAYS: i32 = 30;
+
+/// Query result used by [`rotate_refresh_token()`]
+#[derive(Debug, sqlx::FromRow)]
+struct RefreshTokenRow {
+    id: i64,
+    session_id: i64,
+    next_token_id: Option<i64>,
+    used_at: Option<chrono::DateTime<chrono::Utc>>,
+    next_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Possible results of calling [`rotate_refresh_token()`]
+pub enum RefreshTokenRotation {
+    /// The token was replaced; contains the refreshed Session
+    Rotated(Session),
+    /// The token was not found
+    Unknown,
+    /// The token had already been replaced by one that was itself used, so the
+    /// Session has been deleted
+    Reused,
 }
 
 /// Returns a row stream of current users
-pub async fn users_stream(pool: &PgPool) -> BoxStream<Result<User, sqlx::Error>> {
-    sqlx::query_as::<_, User>("SELECT id, name, email, encrypted_password, created_at, updated_at FROM users")
+pub async fn users_stream(pool: &PgPool) -> BoxStream<'_, Result<User, sqlx::Error>> {
+    sqlx::query_as::<_, User>("SELECT id, name, email, is_guest, is_deactivated, is_erased FROM users")
         .fetch(pool)
 }
 
-pub async fn create_user(name: &String, email: &String, password: &String, pool: &PgPool) -> Result<(), Error> {
-    let salt_string = SaltString::generate(&mut OsRng);
-    let salt: Salt = salt_string.as_ref().try_into().unwrap();
-    let argon2 = Argon2::default();
-    let hash = argon2.hash_password(password.as_bytes(), salt).unwrap().to_string();
+pub async fn create_user(name: &String, email: &String, password: &str, pool: &PgPool) -> Result<(), Error> {
+    let hash = services::password::hash_password(password);
 
     sqlx::query("INSERT INTO users (name, email, encrypted_password) VALUES ($1, $2, $3)")
-        .bind(&name)
-        .bind(&email)
+        .bind(name)
+        .bind(email)
         .bind(&hash)
         .execute(pool)
         .await?;
@@ -37,19 +56,71 @@ pub async fn create_user(name: &String, email: &String, password: &String, pool:
     Ok(())
 }
 
+/// Creates a user registered by a client and returns `Ok(Some(user))`, or
+/// `Ok(None)` if the name was taken concurrently
+///
+/// Unlike [`create_user()`], no email is recorded, and `password` is `None` for
+/// guest accounts, which cannot log in with a password.
+pub async fn register_user(name: &String, password: &Option<String>, is_guest: bool, pool: &PgPool) -> Result<Option<User>, Error> {
+    let hash = password.as_deref().map(services::password::hash_password);
+
+    Ok(
+        sqlx::query_as::<_, User>("\
+                INSERT INTO users (name, encrypted_password, is_guest) \
+                VALUES ($1, $2, $3) \
+                ON CONFLICT (name) DO NOTHING \
+                RETURNING id, name, email, is_guest, is_deactivated, is_erased")
+            .bind(name)
+            .bind(&hash)
+            .bind(is_guest)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Returns `Ok(true)` if a user named `name` exists
+pub async fn user_exists(name: &String, pool: &PgPool) -> Result<bool, Error> {
+    let row: (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM users WHERE name = $1)")
+        .bind(name)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.0)
+}
+
 pub async fn get_user(user_id: i64, pool: &PgPool) -> Result<Option<User>, Error> {
     Ok(
-        sqlx::query_as::<_, User>("SELECT id, name, email, encrypted_password, created_at, updated_at FROM users WHERE id = $1")
+        sqlx::query_as::<_, User>("SELECT id, name, email, is_guest, is_deactivated, is_erased FROM users WHERE id = $1")
             .bind(user_id)
             .fetch_optional(pool)
             .await?
     )
 }
 
+pub async fn get_user_by_name(name: &String, pool: &PgPool) -> Result<Option<User>, Error> {
+    Ok(
+        sqlx::query_as::<_, User>("SELECT id, name, email, is_guest, is_deactivated, is_erased FROM users WHERE name = $1")
+            .bind(name)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Looks up the user whose email address is `email`, ignoring case
+pub async fn get_user_by_email(email: &String, pool: &PgPool) -> Result<Option<User>, Error> {
+    Ok(
+        sqlx::query_as::<_, User>("SELECT id, name, email, is_guest, is_deactivated, is_erased FROM users WHERE lower(email) = lower($1)")
+            .bind(email)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
 /// Looks up user by `username` and validates `password`; returns `Ok(Some(user.id))`
-/// if user is found and password is valid; else returns `Ok(None)`
-pub async fn validate_user_and_password(username: &String, password: &String, pool: &PgPool) -> Result<Option<i64>, Error> {
-    let row_option = sqlx::query_as::<_, ValidationRow>("SELECT id, encrypted_password FROM users WHERE name = $1")
+/// if user is found, is not deactivated and password is valid; else returns
+/// `Ok(None)`
+pub async fn validate_user_and_password(username: &String, password: &str, pool: &PgPool) -> Result<Option<i64>, Error> {
+    let row_option = sqlx::query_as::<_, ValidationRow>("SELECT id, encrypted_password FROM users WHERE name = $1 AND NOT is_deactivated")
         .bind(username)
         .fetch_optional(pool)
         .await?;
@@ -58,17 +129,79 @@ pub async fn validate_user_and_password(username: &String, password: &String, po
         return Ok(None)
     }
 
+    // Guest accounts have no password and can't log in with one.
     let row = row_option.unwrap();
-    let hash = PasswordHash::try_from(row.encrypted_password.as_str()).unwrap();
+    let encrypted_password = match row.encrypted_password {
+        Some(encrypted_password) => encrypted_password,
+        None => return Ok(None),
+    };
 
-    match Argon2::default().verify_password(password.as_bytes(), &hash) {
-        Ok(()) => Ok(Some(row.id)),
-        Err(_) => Ok(None),
+    if services::password::verify_password(password, &encrypted_password) {
+        Ok(Some(row.id))
+    } else {
+        Ok(None)
     }
 }
 
+/// Replaces the password of `user_id`
+pub async fn set_password(user_id: i64, new_password: &str, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("UPDATE users SET encrypted_password = $2, updated_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .bind(services::password::hash_password(new_password))
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Deactivates a User, removing their password, email address and 3PID
+/// validation sessions and logging them out of all devices; returns `Ok(true)`
+/// if the User was active
+///
+/// The row is kept so that the user ID can't be registered again. If `erase` is
+/// set, the User is also marked erased.
+pub async fn deactivate_user(user_id: i64, erase: bool, pool: &PgPool) -> Result<bool, Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("\
+            DELETE FROM threepid_validation_sessions \
+            WHERE medium = 'email' AND lower(address) = (SELECT lower(email) FROM users WHERE id = $1)")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let result = sqlx::query("\
+            UPDATE users \
+            SET is_deactivated = TRUE, is_erased = $2, email = NULL, encrypted_password = NULL, updated_at = NOW() \
+            WHERE id = $1 AND NOT is_deactivated")
+        .bind(user_id)
+        .bind(erase)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns the names of the users among those named `names` who were erased
+/// when deactivated
+pub async fn get_erased_user_names(names: &[&str], pool: &PgPool) -> Result<Vec<String>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, String>("SELECT name FROM users WHERE name = ANY($1) AND is_erased")
+            .bind(names)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
 /// Deletes any existing Sessions for `user_id` and `device_id`
-pub async fn invalidate_existing_sessions(user_id: i64, device_id: &String, pool: &PgPool) -> Result<(), Error> {
+pub async fn invalidate_existing_sessions(user_id: i64, device_id: &DeviceId, pool: &PgPool) -> Result<(), Error> {
     sqlx::query("DELETE FROM sessions WHERE user_id = $1 and device_identifier = $2")
         .bind(user_id)
         .bind(device_id)
@@ -79,23 +212,118 @@ pub async fn invalidate_existing_sessions(user_id: i64, device_id: &String, pool
 }
 
 /// Creates a session and returns `Ok(uuid)`
-pub async fn create_session(user_id: i64, device_id: &String, device_name: &Option<String>, pool: &PgPool) -> Result<Session, Error> {
+pub async fn create_session(user_id: i64, device_id: &DeviceId, device_name: &Option<String>, pool: &PgPool) -> Result<Session, Error> {
     Ok(
         sqlx::query_as::<_, Session>("\
                 INSERT INTO sessions (device_identifier, device_name, user_id) \
                 VALUES ($1, $2, $3)\
-                RETURNING id, uuid, device_identifier, device_name, user_id, created_at, updated_at")
-            .bind(&device_id)
-            .bind(&device_name)
+                RETURNING id, uuid, device_identifier, user_id")
+            .bind(device_id)
+            .bind(device_name)
             .bind(user_id)
             .fetch_one(pool)
             .await?
     )
 }
 
-/// Validates the referenced Session; returns `Ok(sessions.uuid)` on success
+/// Stores the hash of the first refresh token issued for a Session
+pub async fn create_refresh_token(session_id: i64, token_hash: &String, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("INSERT INTO refresh_tokens (session_id, token_hash) VALUES ($1, $2)")
+        .bind(session_id)
+        .bind(token_hash)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Replaces the refresh token with hash `token_hash` by one with hash
+/// `new_token_hash`
+///
+/// A used token remains usable until its replacement is used, in case the
+/// client never received the response. If a token is presented after its
+/// replacement has been used, it has likely been stolen, so the whole Session
+/// is deleted.
+pub async fn rotate_refresh_token(token_hash: &String, new_token_hash: &String, pool: &PgPool) -> Result<RefreshTokenRotation, Error> {
+    let mut tx = pool.begin().await?;
+
+    let row_option = sqlx::query_as::<_, RefreshTokenRow>("\
+            SELECT rt.id, rt.session_id, rt.next_token_id, rt.used_at, next.used_at AS next_used_at \
+            FROM refresh_tokens rt \
+            LEFT JOIN refresh_tokens next ON next.id = rt.next_token_id \
+            WHERE rt.token_hash = $1 \
+            FOR UPDATE OF rt")
+        .bind(token_hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let row = match row_option {
+        Some(row) => row,
+        None => return Ok(RefreshTokenRotation::Unknown),
+    };
+
+    if row.used_at.is_some() {
+        if row.next_token_id.is_none() || row.next_used_at.is_some() {
+            sqlx::query("DELETE FROM sessions WHERE id = $1")
+                .bind(row.session_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+
+            return Ok(RefreshTokenRotation::Reused);
+        }
+
+        // The client is retrying; discard the replacement it never used.
+        sqlx::query("DELETE FROM refresh_tokens WHERE id = $1")
+            .bind(row.next_token_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let (new_token_id,): (i64,) = sqlx::query_as("\
+            INSERT INTO refresh_tokens (session_id, token_hash) \
+            VALUES ($1, $2) \
+            RETURNING id")
+        .bind(row.session_id)
+        .bind(new_token_hash)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query("\
+            UPDATE refresh_tokens \
+            SET next_token_id = $2, used_at = COALESCE(used_at, NOW()), updated_at = NOW() \
+            WHERE id = $1")
+        .bind(row.id)
+        .bind(new_token_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM refresh_tokens WHERE session_id = $1 AND used_at < NOW() - make_interval(days => $2)")
+        .bind(row.session_id)
+        .bind(USED_REFRESH_TOKEN_RETENTION_DAYS)
+        .execute(&mut *tx)
+        .await?;
+
+    let session = sqlx::query_as::<_, Session>("\
+            SELECT id, uuid, device_identifier, user_id \
+            FROM sessions \
+            WHERE id = $1")
+        .bind(row.session_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(RefreshTokenRotation::Rotated(session))
+}
+
+/// Validates the referenced Session, which must belong to a user who is not
+/// deactivated; returns `Ok(session)` on success
 pub async fn validate_session(session_uuid: &String, pool: &PgPool) -> Result<Session, Error> {
-    if let Some(session) = sqlx::query_as::<_, Session>("SELECT id, uuid, device_identifier, device_name, user_id, created_at, updated_at FROM sessions WHERE uuid::text = $1")
+    if let Some(session) = sqlx::query_as::<_, Session>("\
+            SELECT s.id, s.uuid, s.device_identifier, s.user_id \
+            FROM sessions s JOIN users u ON u.id = s.user_id \
+            WHERE s.uuid::text = $1 AND NOT u.is_deactivated")
             .bind(session_uuid)
             .fetch_optional(pool)
             .await? {
@@ -105,6 +333,19 @@ pub async fn validate_session(session_uuid: &String, pool: &PgPool) -> Result<Se
     }
 }
 
+/// Returns the device IDs of the Sessions of the user named `name`
+pub async fn get_device_ids(name: &str, pool: &PgPool) -> Result<Vec<DeviceId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, DeviceId>("\
+                SELECT s.device_identifier FROM sessions s JOIN users u ON u.id = s.user_id \
+                WHERE u.name = $1 \
+                ORDER BY s.device_identifier")
+            .bind(name)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
 /// Logs out a User by deleting the authenticated Session
 pub async fn log_out(session_id: i64, pool: &PgPool) -> Result<(), Error> {
     sqlx::query("DELETE FROM sessions WHERE id = $1")
@@ -116,10 +357,11 @@ pub async fn log_out(session_id: i64, pool: &PgPool) -> Result<(), Error> {
 }
 
 /// Deletes any existing Sessions for `user_id`, logging out the user from all
-/// devices
-pub async fn log_out_all(user_id: i64, pool: &PgPool) -> Result<(), Error> {
-    sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+/// devices; the Session `except_session_id`, if given, is kept
+pub async fn log_out_all(user_id: i64, except_session_id: Option<i64>, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("DELETE FROM sessions WHERE user_id = $1 AND id IS DISTINCT FROM $2")
         .bind(user_id)
+        .bind(except_session_id)
         .execute(pool)
         .await?;
 
@@ -129,12 +371,10 @@ pub async fn log_out_all(user_id: i64, pool: &PgPool) -> Result<(), Error> {
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use argon2::password_hash::rand_core::OsRng;
-    use argon2::password_hash::{Salt, SaltString};
-    use argon2::PasswordHasher;
     use faker_rand::en_us::internet::Domain;
     use faker_rand::en_us::names::FirstName;
     use rand::Rng;
+    use uuid::Uuid;
 
     #[sqlx::test(migrations = "migrations/pg")]
     async fn test_validate_user_and_password(pool: PgPool) {
@@ -157,6 +397,14 @@ pub mod tests {
         assert!(validate_user_and_password(&user.name, &not_the_password, &pool).await.unwrap().is_none());
     }
 
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_register_user_with_name_in_use(pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let password = Some(String::from("foobar"));
+
+        assert!(register_user(&user.name, &password, false, &pool).await.unwrap().is_none());
+    }
+
     #[sqlx::test(migrations = "migrations/pg")]
     async fn test_invalidate_existing_sessions(pool: PgPool) {
         let (user, _password) = create_test_user(&pool).await;
@@ -182,32 +430,88 @@ pub mod tests {
     #[sqlx::test(migrations = "migrations/pg")]
     async fn test_create_session (pool: PgPool) {
         let (user, _password) = create_test_user(&pool).await;
-        let device_id = Uuid::new_v4().to_string();
+        let device_id = DeviceId::generate();
         let session = create_session(user.id, &device_id, &None, &pool).await.unwrap();
 
         assert!(session.id > 0);
     }
 
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_rotate_refresh_token(pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let (session, _jwt) = create_test_session(user.id, 0, &pool).await;
+        let (hash_1, hash_2, hash_3) = (String::from("1"), String::from("2"), String::from("3"));
+        create_refresh_token(session.id, &hash_1, &pool).await.unwrap();
+
+        assert!(matches!(rotate_refresh_token(&hash_1, &hash_2, &pool).await.unwrap(), RefreshTokenRotation::Rotated(s) if s.id == session.id));
+        assert!(matches!(rotate_refresh_token(&hash_2, &hash_3, &pool).await.unwrap(), RefreshTokenRotation::Rotated(_)));
+        assert!(matches!(rotate_refresh_token(&String::from("4"), &String::from("5"), &pool).await.unwrap(), RefreshTokenRotation::Unknown));
+        assert_eq!(count_sessions(&pool).await, 1);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_rotate_refresh_token_with_retry(pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let (session, _jwt) = create_test_session(user.id, 0, &pool).await;
+        let (hash_1, hash_2, hash_3) = (String::from("1"), String::from("2"), String::from("3"));
+        create_refresh_token(session.id, &hash_1, &pool).await.unwrap();
+
+        // The first replacement was never used, so the original is still valid,
+        // but the first replacement no longer is.
+        assert!(matches!(rotate_refresh_token(&hash_1, &hash_2, &pool).await.unwrap(), RefreshTokenRotation::Rotated(_)));
+        assert!(matches!(rotate_refresh_token(&hash_1, &hash_3, &pool).await.unwrap(), RefreshTokenRotation::Rotated(_)));
+        assert!(matches!(rotate_refresh_token(&hash_2, &String::from("4"), &pool).await.unwrap(), RefreshTokenRotation::Unknown));
+        assert_eq!(count_sessions(&pool).await, 1);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_rotate_refresh_token_with_reuse(pool: PgPool) {
+        let (user, _password) = create_test_user(&pool).await;
+        let (session, _jwt) = create_test_session(user.id, 0, &pool).await;
+        let (hash_1, hash_2, hash_3) = (String::from("1"), String::from("2"), String::from("3"));
+        create_refresh_token(session.id, &hash_1, &pool).await.unwrap();
+
+        rotate_refresh_token(&hash_1, &hash_2, &pool).await.unwrap();
+        rotate_refresh_token(&hash_2, &hash_3, &pool).await.unwrap();
+        assert!(matches!(rotate_refresh_token(&hash_1, &String::from("4"), &pool).await.unwrap(), RefreshTokenRotation::Reused));
+        assert_eq!(count_sessions(&pool).await, 0);
+        assert!(matches!(rotate_refresh_token(&hash_3, &String::from("5"), &pool).await.unwrap(), RefreshTokenRotation::Unknown));
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_deactivate_user(pool: PgPool) {
+        let (user, password) = create_test_user(&pool).await;
+        let (session, _jwt) = create_test_session(user.id, 0, &pool).await;
+
+        assert!(deactivate_user(user.id, true, &pool).await.unwrap());
+        assert!(!deactivate_user(user.id, false, &pool).await.unwrap());
+
+        let user = get_user(user.id, &pool).await.unwrap().unwrap();
+        assert!(user.is_deactivated);
+        assert!(user.is_erased);
+        assert!(user.email.is_none());
+        assert_eq!(validate_user_and_password(&user.name, &password, &pool).await.unwrap(), None);
+        assert!(validate_session(&session.uuid.to_string(), &pool).await.is_err());
+        assert!(user_exists(&user.name, &pool).await.unwrap());
+    }
+
     /// Helper function to create a User for testing
     pub async fn create_test_user(pool: &PgPool) -> (User, String) {
         let mut rng = rand::thread_rng();
-        let argon2 = Argon2::default();
 
         let username = rng.gen::<FirstName>().to_string();
-        let email = format!("{}@{}", rng.gen::<FirstName>().to_string(), rng.gen::<Domain>().to_string());
+        let email = format!("{}@{}", rng.gen::<FirstName>(), rng.gen::<Domain>());
         let password = Uuid::new_v4().to_string();
-        let salt_string = SaltString::generate(&mut OsRng);
-        let salt: Salt = salt_string.as_ref().try_into().unwrap();
-        let hash = argon2.hash_password(password.as_bytes(), salt).unwrap();
+        let hash = services::password::hash_password(&password);
 
         (
             sqlx::query_as::<_, User>("\
                     INSERT INTO users (name, email, encrypted_password) \
                     VALUES ($1, $2, $3) \
-                    RETURNING id, name, email, encrypted_password, created_at, updated_at")
+                    RETURNING id, name, email, is_guest, is_deactivated, is_erased")
                 .bind(&username)
                 .bind(&email)
-                .bind(hash.to_string())
+                .bind(&hash)
                 .fetch_one(pool)
                 .await
                 .unwrap(),
@@ -217,19 +521,19 @@ pub mod tests {
 
     /// Helper function to create a Session for testing
     pub async fn create_test_session(user_id: i64, jwt_now_offset: i64, pool: &PgPool) -> (Session, String) {
-        let device_identifier = Uuid::new_v4().to_string();
+        let device_identifier = DeviceId::generate();
 
         let session = sqlx::query_as::<_, Session>("\
                 INSERT INTO sessions (device_identifier, user_id)
                 VALUES ($1, $2)
-                RETURNING id, uuid, device_identifier, device_name, user_id, created_at, updated_at")
+                RETURNING id, uuid, device_identifier, user_id")
             .bind(&device_identifier)
             .bind(user_id)
             .fetch_one(pool)
             .await
             .unwrap();
 
-        let jwt = services::jwt::create_jwt(&session.uuid.to_string(), jwt_now_offset).unwrap();
+        let jwt = services::jwt::create_jwt(&services::jwt::KeyManager::test(), &session, jwt_now_offset, true).unwrap();
 
         (session, jwt)
     }
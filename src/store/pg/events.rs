@@ -1,16 +1,841 @@
 use crate::error::Error;
-use serde::Serialize;
-use sqlx::PgPool;
+use crate::models::events::Event;
+use crate::models::ids::{EventId, RoomId, UserId};
+use crate::models::room_version;
+use crate::models::stream_token::{StreamUpdate, TopologicalToken};
+use crate::services::state_res::{self, StateIds};
+use crate::store::pg;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use std::collections::HashMap;
+use twelf::reexports::serde_json;
 
-#[derive(Debug, Serialize)]
-pub struct CreateRoomEvent {
-    pub r#type: String,
+/// Columns selected into [`Event`] from `events e`
+const COLUMNS: &str = "\
+    e.event_id, e.room_id, e.sender, e.event_type, e.state_key, e.content, e.depth, \
+    e.origin_server_ts, e.stream_ordering";
+
+/// Query result used by [`get_state_events_after()`]
+#[derive(Debug, sqlx::FromRow)]
+struct StateAfterEventRow {
+    after_event_id: EventId,
+    #[sqlx(flatten)]
+    event: Event,
 }
 
-pub async fn create_event(
-    event_type: &String,
-    content: &CreateRoomEvent,
-    pool: &PgPool
-) -> Result<(), Error> {
+/// An event to be stored with [`create_event()`]
+#[derive(Debug, Clone)]
+pub struct NewEvent {
+    pub event_id: EventId,
+    pub room_id: RoomId,
+    pub sender: UserId,
+    pub event_type: String,
+    pub state_key: Option<String>,
+    pub content: serde_json::Value,
+    pub depth: i64,
+    pub origin_server_ts: i64,
+    pub prev_events: Vec<EventId>,
+    pub auth_events: Vec<EventId>,
+    /// The event in the format in which servers exchange it, including its
+    /// hashes
+    pub json: serde_json::Value,
+}
+
+/// Stores an event with its edges and JSON and returns `Ok(event)`
+///
+/// The event replaces its `prev_events` as forward extremities of the room.
+/// It's assigned the state group holding the room state after it: the state
+/// of its prev events, resolved if they differ, plus the event itself if it's a
+/// state event. The current room state is then updated, and resolved across
+/// the forward extremities if there are several. Requests waiting in the room
+/// are woken once the event is committed, as is the target of a membership
+/// event, who may not be in the room.
+///
+/// This takes a connection rather than a pool so that callers can store
+/// several events in one transaction.
+pub async fn create_event(event: &NewEvent, conn: &mut PgConnection) -> Result<Event, Error> {
+    let stream_ordering = pg::streams::next_stream_ordering(conn).await?;
+
+    sqlx::query("\
+            INSERT INTO events (event_id, room_id, sender, event_type, state_key, content, depth, origin_server_ts, stream_ordering) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)")
+        .bind(&event.event_id)
+        .bind(&event.room_id)
+        .bind(&event.sender)
+        .bind(&event.event_type)
+        .bind(&event.state_key)
+        .bind(&event.content)
+        .bind(event.depth)
+        .bind(event.origin_server_ts)
+        .bind(stream_ordering)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("INSERT INTO event_edges (event_id, prev_event_id) SELECT $1, UNNEST($2::VARCHAR[])")
+        .bind(&event.event_id)
+        .bind(&event.prev_events)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("INSERT INTO event_auth_edges (event_id, auth_event_id) SELECT $1, UNNEST($2::VARCHAR[])")
+        .bind(&event.event_id)
+        .bind(&event.auth_events)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("INSERT INTO event_json (event_id, room_id, json) VALUES ($1, $2, $3)")
+        .bind(&event.event_id)
+        .bind(&event.room_id)
+        .bind(&event.json)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("DELETE FROM room_forward_extremities WHERE room_id = $1 AND event_id = ANY($2)")
+        .bind(&event.room_id)
+        .bind(&event.prev_events)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("INSERT INTO room_forward_extremities (room_id, event_id) VALUES ($1, $2)")
+        .bind(&event.room_id)
+        .bind(&event.event_id)
+        .execute(&mut *conn)
+        .await?;
+
+    assign_state_group(event, conn).await?;
+    update_current_state(event, conn).await?;
+
+    let stored = sqlx::query_as::<_, Event>(&format!("SELECT {} FROM events e WHERE e.event_id = $1", COLUMNS))
+        .bind(&event.event_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let update = StreamUpdate {
+        stream_ordering: stored.stream_ordering,
+        room_ids: vec![event.room_id.clone()],
+        user_ids: match (event.event_type.as_str(), &event.state_key) {
+            ("m.room.member", Some(state_key)) => state_key.parse().into_iter().collect(),
+            _ => Vec::new(),
+        },
+    };
+    pg::streams::notify(&update, &mut *conn).await?;
+
+    Ok(stored)
+}
+
+/// Stores an event sent by a client with `txn_id` and returns `Ok(true)`, or
+/// `Ok(false)` if the client's session has already sent an event with it
+///
+/// In that case the caller should roll back the transaction on `conn` and
+/// return the ID of the earlier event, so that retried requests don't send
+/// duplicates; see [`get_transaction_event_id()`].
+pub async fn create_transaction_event(
+    event: &NewEvent,
+    session_id: i64,
+    txn_id: &str,
+    conn: &mut PgConnection
+) -> Result<bool, Error> {
+    create_event(event, conn).await?;
+
+    Ok(
+        sqlx::query("\
+                INSERT INTO event_transactions (session_id, txn_id, event_id) VALUES ($1, $2, $3) \
+                ON CONFLICT (session_id, txn_id) DO NOTHING")
+            .bind(session_id)
+            .bind(txn_id)
+            .bind(&event.event_id)
+            .execute(conn)
+            .await?
+            .rows_affected() > 0
+    )
+}
+
+/// Returns the ID of the event that a session sent with `txn_id`, if any
+pub async fn get_transaction_event_id(session_id: i64, txn_id: &str, pool: &PgPool) -> Result<Option<EventId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, EventId>("\
+                SELECT event_id FROM event_transactions \
+                WHERE session_id = $1 AND txn_id = $2")
+            .bind(session_id)
+            .bind(txn_id)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Returns the transaction IDs with which the session with ID `session_id`
+/// sent any of the events with IDs `event_ids`
+pub async fn get_transaction_ids(session_id: i64, event_ids: &[EventId], pool: &PgPool) -> Result<HashMap<EventId, String>, Error> {
+    Ok(
+        sqlx::query_as::<_, (EventId, String)>("\
+                SELECT event_id, txn_id FROM event_transactions \
+                WHERE session_id = $1 AND event_id = ANY($2)")
+            .bind(session_id)
+            .bind(event_ids)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect()
+    )
+}
+
+/// Maps `event` to the state group holding the room state after it, creating
+/// the group if the event changes the state
+///
+/// The state before the event is that of its prev events, resolved if they
+/// differ, or the current room state if none of them is stored.
+async fn assign_state_group(event: &NewEvent, conn: &mut PgConnection) -> Result<(), Error> {
+    let prev_groups = sqlx::query_scalar::<_, i64>("\
+            SELECT DISTINCT state_group FROM event_to_state_groups \
+            WHERE event_id = ANY($1) \
+            ORDER BY state_group")
+        .bind(&event.prev_events)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let state_group = match (prev_groups.as_slice(), event.state_key.as_ref()) {
+        ([prev_group], None) => *prev_group,
+        _ => {
+            let state_group = sqlx::query_scalar::<_, i64>("\
+                    INSERT INTO state_groups (room_id, event_id) VALUES ($1, $2) RETURNING id")
+                .bind(&event.room_id)
+                .bind(&event.event_id)
+                .fetch_one(&mut *conn)
+                .await?;
+
+            match prev_groups.as_slice() {
+                [] => {
+                    sqlx::query("\
+                            INSERT INTO state_groups_state (state_group, event_type, state_key, event_id) \
+                            SELECT $1, event_type, state_key, event_id FROM current_state_events \
+                            WHERE room_id = $2")
+                        .bind(state_group)
+                        .bind(&event.room_id)
+                        .execute(&mut *conn)
+                        .await?;
+                }
+                [prev_group] => {
+                    sqlx::query("\
+                            INSERT INTO state_groups_state (state_group, event_type, state_key, event_id) \
+                            SELECT $1, event_type, state_key, event_id FROM state_groups_state \
+                            WHERE state_group = $2")
+                        .bind(state_group)
+                        .bind(prev_group)
+                        .execute(&mut *conn)
+                        .await?;
+                }
+                prev_groups => {
+                    let state = resolve_state_groups(&event.room_id, prev_groups, conn).await?;
+                    insert_state(state_group, &state, conn).await?;
+                }
+            }
+
+            if let Some(state_key) = event.state_key.as_ref() {
+                sqlx::query("\
+                        INSERT INTO state_groups_state (state_group, event_type, state_key, event_id) \
+                        VALUES ($1, $2, $3, $4) \
+                        ON CONFLICT (state_group, event_type, state_key) DO UPDATE SET event_id = EXCLUDED.event_id")
+                    .bind(state_group)
+                    .bind(&event.event_type)
+                    .bind(state_key)
+                    .bind(&event.event_id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+
+            state_group
+        }
+    };
+
+    sqlx::query("INSERT INTO event_to_state_groups (event_id, state_group) VALUES ($1, $2)")
+        .bind(&event.event_id)
+        .bind(state_group)
+        .execute(&mut *conn)
+        .await?;
+
     Ok(())
 }
+
+/// Updates the current room state after storing `event`
+///
+/// While the room has one forward extremity and `event` follows at most one
+/// event, a state event simply replaces the current state with its key.
+/// Otherwise, the current state is replaced by the resolved state of the
+/// forward extremities.
+async fn update_current_state(event: &NewEvent, conn: &mut PgConnection) -> Result<(), Error> {
+    let extremity_groups = sqlx::query_scalar::<_, i64>("\
+            SELECT DISTINCT g.state_group FROM room_forward_extremities f \
+            JOIN event_to_state_groups g ON g.event_id = f.event_id \
+            WHERE f.room_id = $1 \
+            ORDER BY g.state_group")
+        .bind(&event.room_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    if extremity_groups.len() == 1 && event.prev_events.len() <= 1 {
+        if let Some(ref state_key) = event.state_key {
+            sqlx::query("\
+                    INSERT INTO current_state_events (room_id, event_type, state_key, event_id) \
+                    VALUES ($1, $2, $3, $4) \
+                    ON CONFLICT (room_id, event_type, state_key) DO UPDATE SET event_id = EXCLUDED.event_id")
+                .bind(&event.room_id)
+                .bind(&event.event_type)
+                .bind(state_key)
+                .bind(&event.event_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        return Ok(());
+    }
+
+    let state = resolve_state_groups(&event.room_id, &extremity_groups, conn).await?;
+    let (keys, event_ids): (Vec<_>, Vec<_>) = state.into_iter().unzip();
+    let (event_types, state_keys): (Vec<_>, Vec<_>) = keys.into_iter().unzip();
+
+    sqlx::query("DELETE FROM current_state_events WHERE room_id = $1")
+        .bind(&event.room_id)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query("\
+            INSERT INTO current_state_events (room_id, event_type, state_key, event_id) \
+            SELECT $1, UNNEST($2::VARCHAR[]), UNNEST($3::TEXT[]), UNNEST($4::VARCHAR[])")
+        .bind(&event.room_id)
+        .bind(&event_types)
+        .bind(&state_keys)
+        .bind(&event_ids)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the state that results from resolving the state groups `groups`
+/// of a room with state resolution v2
+///
+/// Events are only loaded, with their auth chains, if the groups conflict.
+async fn resolve_state_groups(room_id: &RoomId, groups: &[i64], conn: &mut PgConnection) -> Result<StateIds, Error> {
+    let mut state_sets = Vec::with_capacity(groups.len());
+    for group in groups {
+        state_sets.push(get_state_group(*group, conn).await?);
+    }
+
+    if let [state, others @ ..] = state_sets.as_slice() {
+        if others.iter().all(|other| other == state) {
+            return Ok(state.clone());
+        }
+    }
+
+    let room_version = sqlx::query_scalar::<_, String>("SELECT room_version FROM rooms WHERE room_id = $1")
+        .bind(room_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    let room_version = room_version::get(&room_version)
+        .ok_or_else(|| Error::Db(format!("Room {} has unsupported version {}", room_id, room_version)))?;
+
+    let event_ids: Vec<&EventId> = state_sets.iter().flat_map(|state| state.values()).collect();
+    let events: HashMap<EventId, serde_json::Value> = sqlx::query_as::<_, (EventId, serde_json::Value)>("\
+            WITH RECURSIVE auth_chain (event_id) AS ( \
+                SELECT UNNEST($1::VARCHAR[]) \
+                UNION \
+                SELECT a.auth_event_id FROM event_auth_edges a \
+                JOIN auth_chain c ON a.event_id = c.event_id \
+            ) \
+            SELECT j.event_id, j.json FROM auth_chain c \
+            JOIN event_json j ON j.event_id = c.event_id")
+        .bind(&event_ids)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .collect();
+
+    Ok(state_res::resolve(room_version, &state_sets, &events))
+}
+
+/// Returns the state event IDs of a state group
+async fn get_state_group(state_group: i64, conn: &mut PgConnection) -> Result<StateIds, Error> {
+    Ok(
+        sqlx::query_as::<_, (String, String, EventId)>("\
+                SELECT event_type, state_key, event_id FROM state_groups_state \
+                WHERE state_group = $1")
+            .bind(state_group)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|(event_type, state_key, event_id)| ((event_type, state_key), event_id))
+            .collect()
+    )
+}
+
+/// Adds the state events `state` to a state group
+async fn insert_state(state_group: i64, state: &StateIds, conn: &mut PgConnection) -> Result<(), Error> {
+    let (event_types, (state_keys, event_ids)): (Vec<&str>, (Vec<&str>, Vec<&EventId>)) = state.iter()
+        .map(|((event_type, state_key), event_id)| (event_type.as_str(), (state_key.as_str(), event_id)))
+        .unzip();
+
+    sqlx::query("\
+            INSERT INTO state_groups_state (state_group, event_type, state_key, event_id) \
+            SELECT $1, UNNEST($2::VARCHAR[]), UNNEST($3::TEXT[]), UNNEST($4::VARCHAR[])")
+        .bind(state_group)
+        .bind(&event_types)
+        .bind(&state_keys)
+        .bind(&event_ids)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Looks up an event by its ID
+pub async fn get_event(event_id: &EventId, pool: &PgPool) -> Result<Option<Event>, Error> {
+    Ok(
+        sqlx::query_as::<_, Event>(&format!("SELECT {} FROM events e WHERE e.event_id = $1", COLUMNS))
+            .bind(event_id)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Returns the JSON of events as servers exchange them, ignoring any that
+/// aren't stored
+pub async fn get_events_json<'c>(event_ids: &[EventId], executor: impl PgExecutor<'c>) -> Result<Vec<serde_json::Value>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, serde_json::Value>("\
+                SELECT j.json FROM event_json j \
+                JOIN events e ON e.event_id = j.event_id \
+                WHERE j.event_id = ANY($1) \
+                ORDER BY e.stream_ordering")
+            .bind(event_ids)
+            .fetch_all(executor)
+            .await?
+    )
+}
+
+/// Returns the IDs of the events in a room that no other event references as
+/// a prev event, which a new event should reference
+pub async fn get_forward_extremities<'c>(room_id: &RoomId, executor: impl PgExecutor<'c>) -> Result<Vec<EventId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, EventId>("\
+                SELECT event_id FROM room_forward_extremities \
+                WHERE room_id = $1 \
+                ORDER BY event_id")
+            .bind(room_id)
+            .fetch_all(executor)
+            .await?
+    )
+}
+
+/// Returns the current state events of a room
+pub async fn get_current_state(room_id: &RoomId, pool: &PgPool) -> Result<Vec<Event>, Error> {
+    Ok(
+        sqlx::query_as::<_, Event>(&format!("\
+                SELECT {} FROM current_state_events c \
+                JOIN events e ON e.event_id = c.event_id \
+                WHERE c.room_id = $1 \
+                ORDER BY e.stream_ordering", COLUMNS))
+            .bind(room_id)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Returns the JSON of the current state events of a room as servers exchange
+/// them
+pub async fn get_current_state_json<'c>(room_id: &RoomId, executor: impl PgExecutor<'c>) -> Result<Vec<serde_json::Value>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, serde_json::Value>("\
+                SELECT j.json FROM current_state_events c \
+                JOIN event_json j ON j.event_id = c.event_id \
+                WHERE c.room_id = $1")
+            .bind(room_id)
+            .fetch_all(executor)
+            .await?
+    )
+}
+
+/// Returns the current state event of a room with the given type and state key
+pub async fn get_current_state_event(
+    room_id: &RoomId,
+    event_type: &str,
+    state_key: &str,
+    pool: &PgPool
+) -> Result<Option<Event>, Error> {
+    Ok(
+        sqlx::query_as::<_, Event>(&format!("\
+                SELECT {} FROM current_state_events c \
+                JOIN events e ON e.event_id = c.event_id \
+                WHERE c.room_id = $1 AND c.event_type = $2 AND c.state_key = $3", COLUMNS))
+            .bind(room_id)
+            .bind(event_type)
+            .bind(state_key)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Returns the state events of a room as they were after `event_id`
+pub async fn get_state_after_event(event_id: &EventId, pool: &PgPool) -> Result<Vec<Event>, Error> {
+    Ok(
+        sqlx::query_as::<_, Event>(&format!("\
+                SELECT {} FROM event_to_state_groups g \
+                JOIN state_groups_state s ON s.state_group = g.state_group \
+                JOIN events e ON e.event_id = s.event_id \
+                WHERE g.event_id = $1 \
+                ORDER BY e.stream_ordering", COLUMNS))
+            .bind(event_id)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Returns the ID of the last event in a room with a stream ordering up to
+/// and including `stream_ordering`
+pub async fn get_latest_event_id_at(room_id: &RoomId, stream_ordering: i64, pool: &PgPool) -> Result<Option<EventId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, EventId>("\
+                SELECT event_id FROM events \
+                WHERE room_id = $1 AND stream_ordering <= $2 \
+                ORDER BY stream_ordering DESC \
+                LIMIT 1")
+            .bind(room_id)
+            .bind(stream_ordering)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Returns the last `limit` events in a room with a stream ordering after
+/// `from` and up to and including `to`, oldest first
+pub async fn get_room_events_between(room_id: &RoomId, from: i64, to: i64, limit: i64, pool: &PgPool) -> Result<Vec<Event>, Error> {
+    let mut events = sqlx::query_as::<_, Event>(&format!("\
+            SELECT {} FROM events e \
+            WHERE e.room_id = $1 AND e.stream_ordering > $2 AND e.stream_ordering <= $3 \
+            ORDER BY e.stream_ordering DESC \
+            LIMIT $4", COLUMNS))
+        .bind(room_id)
+        .bind(from)
+        .bind(to)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    events.reverse();
+
+    Ok(events)
+}
+
+/// Returns the `m.room.member` events for `user_id` in all rooms, up to and
+/// including stream ordering `to`, oldest first
+pub async fn get_membership_events(user_id: &UserId, to: i64, pool: &PgPool) -> Result<Vec<Event>, Error> {
+    Ok(
+        sqlx::query_as::<_, Event>(&format!("\
+                SELECT {} FROM events e \
+                WHERE e.event_type = 'm.room.member' AND e.state_key = $1 AND e.stream_ordering <= $2 \
+                ORDER BY e.stream_ordering", COLUMNS))
+            .bind(user_id)
+            .bind(to)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Returns the stream ordering of the latest event of one of `event_types`,
+/// up to and including `to`, in each of the rooms with IDs `room_ids` that has
+/// one
+pub async fn get_latest_stream_orderings(
+    room_ids: &[RoomId],
+    event_types: &[&str],
+    to: i64,
+    pool: &PgPool
+) -> Result<HashMap<RoomId, i64>, Error> {
+    Ok(
+        sqlx::query_as::<_, (RoomId, i64)>("\
+                SELECT room_id, MAX(stream_ordering) FROM events \
+                WHERE room_id = ANY($1) AND event_type = ANY($2) AND stream_ordering <= $3 \
+                GROUP BY room_id")
+            .bind(room_ids)
+            .bind(event_types)
+            .bind(to)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect()
+    )
+}
+
+/// Returns the position in a room's timeline just after its last event with a
+/// stream ordering up to and including `stream_ordering`, or `None` if there's
+/// no such event
+pub async fn get_topological_token_at(
+    room_id: &RoomId,
+    stream_ordering: i64,
+    pool: &PgPool
+) -> Result<Option<TopologicalToken>, Error> {
+    Ok(
+        sqlx::query_as::<_, (i64, i64)>("\
+                SELECT depth, stream_ordering FROM events \
+                WHERE room_id = $1 AND stream_ordering <= $2 \
+                ORDER BY depth DESC, stream_ordering DESC \
+                LIMIT 1")
+            .bind(room_id)
+            .bind(stream_ordering)
+            .fetch_optional(pool)
+            .await?
+            .map(|(depth, stream_ordering)| TopologicalToken { depth, stream_ordering })
+    )
+}
+
+/// Returns up to `limit` events in a room in topological order from position
+/// `from`: those after it, oldest first, if `forward`, or else those before it,
+/// newest first
+///
+/// If `to` is given, only events between `from` and `to` are returned.
+pub async fn get_room_events_from(
+    room_id: &RoomId,
+    from: TopologicalToken,
+    to: Option<TopologicalToken>,
+    forward: bool,
+    limit: i64,
+    pool: &PgPool
+) -> Result<Vec<Event>, Error> {
+    let (from_op, to_op, order) = if forward { (">", "<=", "ASC") } else { ("<=", ">", "DESC") };
+
+    Ok(
+        sqlx::query_as::<_, Event>(&format!("\
+                SELECT {} FROM events e \
+                WHERE e.room_id = $1 AND (e.depth, e.stream_ordering) {} ($2, $3) \
+                AND ($4::BIGINT IS NULL OR (e.depth, e.stream_ordering) {} ($4, $5)) \
+                ORDER BY e.depth {}, e.stream_ordering {} \
+                LIMIT $6", COLUMNS, from_op, to_op, order, order))
+            .bind(room_id)
+            .bind(from.depth)
+            .bind(from.stream_ordering)
+            .bind(to.map(|to| to.depth))
+            .bind(to.map(|to| to.stream_ordering))
+            .bind(limit)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Returns the state events with the `(event_type, state_key)` pairs in `keys`
+/// as they were after each of the events with IDs `event_ids`
+pub async fn get_state_events_after(
+    event_ids: &[EventId],
+    keys: &[(&str, &str)],
+    pool: &PgPool
+) -> Result<HashMap<EventId, Vec<Event>>, Error> {
+    let (event_types, state_keys): (Vec<&str>, Vec<&str>) = keys.iter().copied().unzip();
+    let rows = sqlx::query_as::<_, StateAfterEventRow>(&format!("\
+            SELECT g.event_id AS after_event_id, {} FROM event_to_state_groups g \
+            JOIN state_groups_state s ON s.state_group = g.state_group \
+            JOIN events e ON e.event_id = s.event_id \
+            WHERE g.event_id = ANY($1) \
+            AND (s.event_type, s.state_key) IN (SELECT * FROM UNNEST($2::VARCHAR[], $3::TEXT[]))", COLUMNS))
+        .bind(event_ids)
+        .bind(event_types)
+        .bind(state_keys)
+        .fetch_all(pool)
+        .await?;
+
+    let mut states: HashMap<EventId, Vec<Event>> = HashMap::new();
+    for row in rows {
+        states.entry(row.after_event_id).or_default().push(row.event);
+    }
+
+    Ok(states)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::ids::ServerName;
+    use crate::services::events::{self, EventTemplate};
+    use crate::services::signing::SigningKeys;
+    use crate::store::pg::rooms::{self, NewRoom};
+    use twelf::reexports::serde_json::json;
+
+    /// Helper function to return all events in a room, oldest first
+    pub async fn get_room_events(room_id: &RoomId, pool: &PgPool) -> Vec<Event> {
+        sqlx::query_as::<_, Event>(&format!("\
+                SELECT {} FROM events e \
+                WHERE e.room_id = $1 \
+                ORDER BY e.depth, e.stream_ordering", COLUMNS))
+            .bind(room_id)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    /// Helper function to return the prev events of an event
+    pub async fn get_prev_events(event_id: &EventId, pool: &PgPool) -> Vec<EventId> {
+        sqlx::query_scalar::<_, EventId>("SELECT prev_event_id FROM event_edges WHERE event_id = $1 ORDER BY prev_event_id")
+            .bind(event_id)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    /// Helper function to return the auth events of an event
+    pub async fn get_auth_events(event_id: &EventId, pool: &PgPool) -> Vec<EventId> {
+        sqlx::query_scalar::<_, EventId>("SELECT auth_event_id FROM event_auth_edges WHERE event_id = $1 ORDER BY auth_event_id")
+            .bind(event_id)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    /// Helper function to store one event in its own transaction
+    async fn persist_event(event: &NewEvent, pool: &PgPool) -> Result<Event, Error> {
+        let mut tx = pool.begin().await?;
+        let event = create_event(event, &mut tx).await?;
+        tx.commit().await?;
+
+        Ok(event)
+    }
+
+    /// Helper function to create an empty room for testing
+    pub async fn create_test_room(pool: &PgPool) -> (RoomId, UserId) {
+        let server_name: ServerName = "chat.spelt.io".parse().unwrap();
+        let room = NewRoom {
+            room_id: RoomId::generate(&server_name),
+            room_version: String::from("10"),
+            creator: UserId::new("alice", &server_name).unwrap(),
+            is_public: false,
+        };
+        rooms::create_room(&room, None, &[], pool).await.unwrap();
+
+        (room.room_id, room.creator)
+    }
+
+    /// Helper function to build an event for testing
+    pub fn new_event(
+        room_id: &RoomId,
+        sender: &UserId,
+        event_type: &str,
+        state_key: Option<&str>,
+        prev_events: &[&Event]
+    ) -> NewEvent {
+        NewEvent {
+            event_id: EventId::generate(&"chat.spelt.io".parse().unwrap()),
+            room_id: room_id.clone(),
+            sender: sender.clone(),
+            event_type: event_type.to_string(),
+            state_key: state_key.map(String::from),
+            content: json!({}),
+            depth: prev_events.iter().map(|e| e.depth).max().unwrap_or(0) + 1,
+            origin_server_ts: 0,
+            prev_events: prev_events.iter().map(|e| e.event_id.clone()).collect(),
+            auth_events: vec![],
+            json: json!({ "type": event_type, "prev_events": prev_events.iter().map(|e| &e.event_id).collect::<Vec<_>>() }),
+        }
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_persist_event(pool: PgPool) {
+        let (room_id, sender) = create_test_room(&pool).await;
+
+        let create = persist_event(&new_event(&room_id, &sender, "m.room.create", Some(""), &[]), &pool).await.unwrap();
+        let mut message = new_event(&room_id, &sender, "m.room.message", None, &[&create]);
+        message.auth_events = vec![create.event_id.clone()];
+        let message = persist_event(&message, &pool).await.unwrap();
+
+        assert_eq!(get_prev_events(&message.event_id, &pool).await, vec![create.event_id.clone()]);
+        assert_eq!(get_auth_events(&message.event_id, &pool).await, vec![create.event_id.clone()]);
+        assert_eq!(message.depth, 2);
+        assert!(message.stream_ordering > create.stream_ordering);
+        assert_eq!(get_event(&message.event_id, &pool).await.unwrap().unwrap().event_id, message.event_id);
+
+        let json = get_events_json(std::slice::from_ref(&message.event_id), &pool).await.unwrap().remove(0);
+        assert_eq!(json["prev_events"], json!([create.event_id]));
+        assert!(json.get("state_key").is_none());
+
+        assert_eq!(get_forward_extremities(&room_id, &pool).await.unwrap(), vec![message.event_id.clone()]);
+        assert!(get_event(&EventId::generate(&"chat.spelt.io".parse().unwrap()), &pool).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_persist_event_with_state(pool: PgPool) {
+        let (room_id, sender) = create_test_room(&pool).await;
+
+        let create = persist_event(&new_event(&room_id, &sender, "m.room.create", Some(""), &[]), &pool).await.unwrap();
+        let name = persist_event(&new_event(&room_id, &sender, "m.room.name", Some(""), &[&create]), &pool).await.unwrap();
+        let message = persist_event(&new_event(&room_id, &sender, "m.room.message", None, &[&name]), &pool).await.unwrap();
+        let new_name = persist_event(&new_event(&room_id, &sender, "m.room.name", Some(""), &[&message]), &pool).await.unwrap();
+
+        let state_ids = |events: Vec<Event>| events.into_iter().map(|e| e.event_id).collect::<Vec<_>>();
+
+        assert_eq!(state_ids(get_state_after_event(&create.event_id, &pool).await.unwrap()), vec![create.event_id.clone()]);
+        assert_eq!(
+            state_ids(get_state_after_event(&message.event_id, &pool).await.unwrap()),
+            vec![create.event_id.clone(), name.event_id.clone()]
+        );
+        assert_eq!(
+            state_ids(get_current_state(&room_id, &pool).await.unwrap()),
+            vec![create.event_id.clone(), new_name.event_id.clone()]
+        );
+
+        let current_name = get_current_state_event(&room_id, "m.room.name", "", &pool).await.unwrap().unwrap();
+        assert_eq!(current_name.event_id, new_name.event_id);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_persist_event_with_fork(pool: PgPool) {
+        let (room_id, sender) = create_test_room(&pool).await;
+
+        let create = persist_event(&new_event(&room_id, &sender, "m.room.create", Some(""), &[]), &pool).await.unwrap();
+        let a = persist_event(&new_event(&room_id, &sender, "m.room.message", None, &[&create]), &pool).await.unwrap();
+        let b = persist_event(&new_event(&room_id, &sender, "m.room.message", None, &[&create]), &pool).await.unwrap();
+
+        let mut extremities = vec![a.event_id.clone(), b.event_id.clone()];
+        extremities.sort_by(|x, y| x.as_str().cmp(y.as_str()));
+        assert_eq!(get_forward_extremities(&room_id, &pool).await.unwrap(), extremities);
+
+        let merge = persist_event(&new_event(&room_id, &sender, "m.room.message", None, &[&a, &b]), &pool).await.unwrap();
+        assert_eq!(get_forward_extremities(&room_id, &pool).await.unwrap(), vec![merge.event_id]);
+        assert_eq!(merge.depth, 3);
+        assert_eq!(get_events_json(&extremities, &pool).await.unwrap().len(), 2);
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_persist_event_with_conflicting_state(pool: PgPool) {
+        let (room_id, sender) = create_test_room(&pool).await;
+        let config = Config::test();
+        let keys = SigningKeys::test();
+        let build = |event_type: &str, state_key: Option<&str>, content: serde_json::Value, ts: i64, prev_events: &[&NewEvent], auth_events: &[&NewEvent]| {
+            let template = EventTemplate {
+                room_id: room_id.clone(),
+                sender: sender.clone(),
+                event_type: event_type.to_string(),
+                state_key: state_key.map(String::from),
+                content,
+                origin_server_ts: ts,
+            };
+            let prev_events: Vec<_> = prev_events.iter().map(|e| &e.json).collect();
+            let auth_events: Vec<_> = auth_events.iter().map(|e| &e.json).collect();
+            events::build_event(template, room_version::get("10").unwrap(), &prev_events, &auth_events, &config, &keys).unwrap()
+        };
+
+        let create = build("m.room.create", Some(""), json!({ "room_version": "10", "creator": sender }), 0, &[], &[]);
+        let join = build("m.room.member", Some(sender.as_str()), json!({ "membership": "join" }), 1, &[&create], &[&create]);
+        let power_levels = build("m.room.power_levels", Some(""), json!({ "users": { sender.as_str(): 100 } }), 2, &[&join], &[&create, &join]);
+        let auth = [&create, &join, &power_levels];
+
+        // Two concurrent topics, the newer one stored first
+        let new_topic = build("m.room.topic", Some(""), json!({ "topic": "New" }), 20, &[&power_levels], &auth);
+        let old_topic = build("m.room.topic", Some(""), json!({ "topic": "Old" }), 10, &[&power_levels], &auth);
+        let merge = build("m.room.message", None, json!({ "body": "hi" }), 30, &[&new_topic, &old_topic], &auth);
+
+        for event in [&create, &join, &power_levels, &new_topic, &old_topic] {
+            persist_event(event, &pool).await.unwrap();
+        }
+        let topic = get_current_state_event(&room_id, "m.room.topic", "", &pool).await.unwrap().unwrap();
+        assert_eq!(topic.event_id, new_topic.event_id);
+
+        persist_event(&merge, &pool).await.unwrap();
+        let state = get_state_after_event(&merge.event_id, &pool).await.unwrap();
+        assert_eq!(state.len(), 4);
+        assert!(state.iter().any(|e| e.event_id == new_topic.event_id));
+        let topic = get_current_state_event(&room_id, "m.room.topic", "", &pool).await.unwrap().unwrap();
+        assert_eq!(topic.event_id, new_topic.event_id);
+    }
+}
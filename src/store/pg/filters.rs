@@ -0,0 +1,26 @@
+use crate::error::Error;
+use crate::models::ids::UserId;
+use sqlx::PgPool;
+use twelf::reexports::serde_json;
+
+/// Stores a filter for `user_id` and returns its ID
+pub async fn create_filter(user_id: &UserId, filter: &serde_json::Value, pool: &PgPool) -> Result<i64, Error> {
+    Ok(
+        sqlx::query_scalar::<_, i64>("INSERT INTO filters (user_id, filter) VALUES ($1, $2) RETURNING id")
+            .bind(user_id)
+            .bind(filter)
+            .fetch_one(pool)
+            .await?
+    )
+}
+
+/// Returns the filter with ID `filter_id` if it belongs to `user_id`
+pub async fn get_filter(user_id: &UserId, filter_id: i64, pool: &PgPool) -> Result<Option<serde_json::Value>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, serde_json::Value>("SELECT filter FROM filters WHERE id = $1 AND user_id = $2")
+            .bind(filter_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+    )
+}
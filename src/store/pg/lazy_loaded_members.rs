@@ -0,0 +1,60 @@
+use crate::error::Error;
+use crate::models::ids::{EventId, RoomId, UserId};
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+/// Returns the IDs of the membership events of the users among `user_ids`
+/// that syncs have already sent to a session in a room, by user ID
+pub async fn get_lazy_loaded_members(
+    session_id: i64,
+    room_id: &RoomId,
+    user_ids: &[UserId],
+    pool: &PgPool
+) -> Result<HashMap<UserId, EventId>, Error> {
+    Ok(
+        sqlx::query_as::<_, (UserId, EventId)>("\
+                SELECT user_id, event_id FROM lazy_loaded_members \
+                WHERE session_id = $1 AND room_id = $2 AND user_id = ANY($3)")
+            .bind(session_id)
+            .bind(room_id)
+            .bind(user_ids)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect()
+    )
+}
+
+/// Records that a sync sent a session the membership events with IDs
+/// `event_ids` of the users with IDs `user_ids` in a room
+pub async fn set_lazy_loaded_members(
+    session_id: i64,
+    room_id: &RoomId,
+    user_ids: &[UserId],
+    event_ids: &[EventId],
+    pool: &PgPool
+) -> Result<(), Error> {
+    sqlx::query("\
+            INSERT INTO lazy_loaded_members (session_id, room_id, user_id, event_id) \
+            SELECT $1, $2, UNNEST($3::VARCHAR[]), UNNEST($4::VARCHAR[]) \
+            ON CONFLICT (session_id, room_id, user_id) DO UPDATE SET event_id = EXCLUDED.event_id")
+        .bind(session_id)
+        .bind(room_id)
+        .bind(user_ids)
+        .bind(event_ids)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Forgets which membership events syncs have sent to a session, as clients
+/// start afresh with an initial sync
+pub async fn clear_lazy_loaded_members(session_id: i64, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("DELETE FROM lazy_loaded_members WHERE session_id = $1")
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
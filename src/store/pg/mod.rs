@@ -1,3 +1,15 @@
+pub mod account_data;
 pub mod auth;
 pub mod events;
+pub mod filters;
+pub mod lazy_loaded_members;
+pub mod presence;
+pub mod receipts;
+pub mod registration_tokens;
 pub mod rooms;
+pub mod sliding_sync;
+pub mod streams;
+pub mod threepid;
+pub mod to_device;
+pub mod typing;
+pub mod uia;
@@ -0,0 +1,71 @@
+use crate::error::Error;
+use crate::models::ids::UserId;
+use crate::models::presence::Presence;
+use crate::models::stream_token::StreamUpdate;
+use crate::store::pg;
+use sqlx::PgPool;
+
+/// Columns selected into [`Presence`]
+const COLUMNS: &str = "user_id, presence, status_msg, last_active_at";
+
+/// Sets the presence of `user_id` and marks them as active now
+///
+/// The presence only takes a new stream ordering if it changes, so that users
+/// who poll don't wake everyone who shares a room with them. When it does
+/// change, the requests waiting in the user's rooms are woken.
+pub async fn set_presence(user_id: &UserId, presence: &str, pool: &PgPool) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    let previous = sqlx::query_scalar::<_, String>("SELECT presence FROM presence WHERE user_id = $1 FOR UPDATE")
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if previous.as_deref() == Some(presence) {
+        sqlx::query("UPDATE presence SET last_active_at = NOW() WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    let stream_ordering = pg::streams::next_stream_ordering(&mut tx).await?;
+    sqlx::query("\
+            INSERT INTO presence (user_id, presence, stream_ordering) \
+            VALUES ($1, $2, $3) \
+            ON CONFLICT (user_id) DO UPDATE \
+            SET presence = EXCLUDED.presence, \
+                last_active_at = NOW(), \
+                stream_ordering = EXCLUDED.stream_ordering")
+        .bind(user_id)
+        .bind(presence)
+        .bind(stream_ordering)
+        .execute(&mut *tx)
+        .await?;
+
+    let update = StreamUpdate {
+        stream_ordering,
+        room_ids: pg::rooms::get_room_ids_by_membership(user_id, "join", &mut *tx).await?,
+        user_ids: vec![user_id.clone()],
+    };
+    pg::streams::notify(&update, &mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns the presence of the users among `user_ids` whose presence changed
+/// with a stream ordering after `from` and up to and including `to`
+pub async fn get_presence_between(user_ids: &[UserId], from: i64, to: i64, pool: &PgPool) -> Result<Vec<Presence>, Error> {
+    Ok(
+        sqlx::query_as::<_, Presence>(&format!("\
+                SELECT {} FROM presence \
+                WHERE user_id = ANY($1) AND stream_ordering > $2 AND stream_ordering <= $3 \
+                ORDER BY stream_ordering", COLUMNS))
+            .bind(user_ids)
+            .bind(from)
+            .bind(to)
+            .fetch_all(pool)
+            .await?
+    )
+}
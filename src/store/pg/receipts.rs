@@ -0,0 +1,143 @@
+use crate::error::Error;
+use crate::models::ids::{EventId, RoomId, UserId};
+use crate::models::receipts::Receipt;
+use crate::models::stream_token::StreamUpdate;
+use crate::store::pg;
+use sqlx::PgPool;
+
+/// Columns selected into [`Receipt`]
+const COLUMNS: &str = "room_id, user_id, receipt_type, NULLIF(thread_id, '') AS thread_id, event_id, ts";
+
+/// Event types that count towards a room's unread notifications
+const NOTIFYING_EVENT_TYPES: [&str; 3] = ["m.room.encrypted", "m.room.message", "m.sticker"];
+
+/// Stores a receipt of `receipt_type` from `user_id` for an event, replacing
+/// the user's previous receipt of that type in the room or thread, and wakes
+/// the room's waiting requests
+pub async fn set_receipt(
+    room_id: &RoomId,
+    user_id: &UserId,
+    receipt_type: &str,
+    thread_id: Option<&str>,
+    event_id: &EventId,
+    pool: &PgPool
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+    let stream_ordering = pg::streams::next_stream_ordering(&mut tx).await?;
+
+    sqlx::query("\
+            INSERT INTO receipts (room_id, user_id, receipt_type, thread_id, event_id, ts, stream_ordering) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7) \
+            ON CONFLICT (room_id, user_id, receipt_type, thread_id) DO UPDATE \
+            SET event_id = EXCLUDED.event_id, \
+                ts = EXCLUDED.ts, \
+                stream_ordering = EXCLUDED.stream_ordering")
+        .bind(room_id)
+        .bind(user_id)
+        .bind(receipt_type)
+        .bind(thread_id.unwrap_or_default())
+        .bind(event_id)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .bind(stream_ordering)
+        .execute(&mut *tx)
+        .await?;
+
+    let update = StreamUpdate { stream_ordering, room_ids: vec![room_id.clone()], ..Default::default() };
+    pg::streams::notify(&update, &mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns the receipts in the rooms with IDs `room_ids` that were stored with
+/// a stream ordering after `from` and up to and including `to`
+pub async fn get_receipts_between(room_ids: &[RoomId], from: i64, to: i64, pool: &PgPool) -> Result<Vec<Receipt>, Error> {
+    Ok(
+        sqlx::query_as::<_, Receipt>(&format!("\
+                SELECT {} FROM receipts \
+                WHERE room_id = ANY($1) AND stream_ordering > $2 AND stream_ordering <= $3 \
+                ORDER BY stream_ordering", COLUMNS))
+            .bind(room_ids)
+            .bind(from)
+            .bind(to)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Returns the number of events in a room, up to and including stream ordering
+/// `to`, that `user_id` hasn't read and that notify them, along with how many
+/// of those mention them
+///
+/// Events are read if the user has a read receipt for them or a later event, or
+/// has sent a later event themselves.
+pub async fn count_unread_events(room_id: &RoomId, user_id: &UserId, to: i64, pool: &PgPool) -> Result<(i64, i64), Error> {
+    Ok(
+        sqlx::query_as::<_, (i64, i64)>("\
+                WITH read AS ( \
+                    SELECT GREATEST( \
+                        (SELECT MAX(e.stream_ordering) FROM receipts r \
+                         JOIN events e ON e.event_id = r.event_id \
+                         WHERE r.room_id = $1 AND r.user_id = $2 AND r.receipt_type IN ('m.read', 'm.read.private')), \
+                        (SELECT MAX(stream_ordering) FROM events WHERE room_id = $1 AND sender = $2) \
+                    ) AS stream_ordering \
+                ) \
+                SELECT COUNT(*), COUNT(*) FILTER (WHERE e.content->'m.mentions'->'user_ids' ? $2) \
+                FROM events e, read \
+                WHERE e.room_id = $1 AND e.sender <> $2 AND e.state_key IS NULL AND e.event_type = ANY($4) \
+                    AND e.stream_ordering > COALESCE(read.stream_ordering, 0) AND e.stream_ordering <= $3")
+            .bind(room_id)
+            .bind(user_id)
+            .bind(to)
+            .bind(&NOTIFYING_EVENT_TYPES[..])
+            .fetch_one(pool)
+            .await?
+    )
+}
+
+/// Like [`count_unread_events()`], but counts the events in each thread
+/// separately, by the ID of the thread's root event, or `""` for the main
+/// timeline
+///
+/// A receipt applies to the thread it's for, with `main` meaning the main
+/// timeline, or to every thread if it's unthreaded. Threads without unread
+/// events are omitted.
+pub async fn count_unread_events_by_thread(
+    room_id: &RoomId,
+    user_id: &UserId,
+    to: i64,
+    pool: &PgPool
+) -> Result<Vec<(String, i64, i64)>, Error> {
+    Ok(
+        sqlx::query_as::<_, (String, i64, i64)>("\
+                WITH threaded AS ( \
+                    SELECT e.sender, e.event_type, e.state_key, e.content, e.stream_ordering, \
+                        CASE WHEN e.content->'m.relates_to'->>'rel_type' = 'm.thread' \
+                            THEN e.content->'m.relates_to'->>'event_id' ELSE '' END AS thread_id \
+                    FROM events e \
+                    WHERE e.room_id = $1 AND e.stream_ordering <= $3 \
+                ), \
+                read AS ( \
+                    SELECT t.thread_id, GREATEST( \
+                        (SELECT MAX(e.stream_ordering) FROM receipts r \
+                         JOIN events e ON e.event_id = r.event_id \
+                         WHERE r.room_id = $1 AND r.user_id = $2 AND r.receipt_type IN ('m.read', 'm.read.private') \
+                            AND r.thread_id IN ('', CASE WHEN t.thread_id = '' THEN 'main' ELSE t.thread_id END)), \
+                        (SELECT MAX(o.stream_ordering) FROM threaded o WHERE o.sender = $2 AND o.thread_id = t.thread_id) \
+                    ) AS stream_ordering \
+                    FROM (SELECT DISTINCT thread_id FROM threaded) t \
+                ) \
+                SELECT t.thread_id, COUNT(*), COUNT(*) FILTER (WHERE t.content->'m.mentions'->'user_ids' ? $2) \
+                FROM threaded t JOIN read ON read.thread_id = t.thread_id \
+                WHERE t.sender <> $2 AND t.state_key IS NULL AND t.event_type = ANY($4) \
+                    AND t.stream_ordering > COALESCE(read.stream_ordering, 0) \
+                GROUP BY t.thread_id \
+                ORDER BY t.thread_id")
+            .bind(room_id)
+            .bind(user_id)
+            .bind(to)
+            .bind(&NOTIFYING_EVENT_TYPES[..])
+            .fetch_all(pool)
+            .await?
+    )
+}
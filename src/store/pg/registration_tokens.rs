@@ -0,0 +1,158 @@
+use crate::error::Error;
+use crate::models::registration_tokens::RegistrationToken;
+use futures_util::stream::BoxStream;
+use sqlx::PgPool;
+
+/// SQL condition matching tokens that have not expired or been used up
+const USABLE_CONDITION: &str = "\
+    (expiry_time IS NULL OR expiry_time > NOW()) \
+    AND (uses_allowed IS NULL OR pending + completed < uses_allowed)";
+
+/// Returns a row stream of all registration tokens
+pub async fn tokens_stream(pool: &PgPool) -> BoxStream<'_, Result<RegistrationToken, sqlx::Error>> {
+    sqlx::query_as::<_, RegistrationToken>("\
+            SELECT token, uses_allowed, pending, completed, expiry_time \
+            FROM registration_tokens \
+            ORDER BY created_at")
+        .fetch(pool)
+}
+
+/// Creates a registration token and returns `Ok(registration_token)`
+pub async fn create_token(
+    token: &String,
+    uses_allowed: Option<i32>,
+    expiry_time: Option<chrono::DateTime<chrono::Utc>>,
+    pool: &PgPool
+) -> Result<RegistrationToken, Error> {
+    Ok(
+        sqlx::query_as::<_, RegistrationToken>("\
+                INSERT INTO registration_tokens (token, uses_allowed, expiry_time) \
+                VALUES ($1, $2, $3) \
+                RETURNING token, uses_allowed, pending, completed, expiry_time")
+            .bind(token)
+            .bind(uses_allowed)
+            .bind(expiry_time)
+            .fetch_one(pool)
+            .await?
+    )
+}
+
+/// Deletes a registration token; returns `Ok(true)` if it existed
+pub async fn delete_token(token: &String, pool: &PgPool) -> Result<bool, Error> {
+    let result = sqlx::query("DELETE FROM registration_tokens WHERE token = $1")
+        .bind(token)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns `Ok(true)` if `token` exists and can still be used to register
+pub async fn is_usable(token: &String, pool: &PgPool) -> Result<bool, Error> {
+    let row: (bool,) = sqlx::query_as(&format!(
+            "SELECT EXISTS (SELECT 1 FROM registration_tokens WHERE token = $1 AND {})",
+            USABLE_CONDITION
+        ))
+        .bind(token)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.0)
+}
+
+/// Reserves a use of `token` for a registration in progress by incrementing
+/// `pending`; returns `Ok(false)` if the token can't be used
+///
+/// The check and the increment are a single statement, so concurrent
+/// registrations can't exceed `uses_allowed`.
+pub async fn reserve_use(token: &String, pool: &PgPool) -> Result<bool, Error> {
+    let result = sqlx::query(&format!(
+            "UPDATE registration_tokens SET pending = pending + 1, updated_at = NOW() WHERE token = $1 AND {}",
+            USABLE_CONDITION
+        ))
+        .bind(token)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Converts a use reserved with [`reserve_use()`] into a completed use after
+/// the account is created
+pub async fn complete_use(token: &String, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("\
+            UPDATE registration_tokens \
+            SET pending = pending - 1, completed = completed + 1, updated_at = NOW() \
+            WHERE token = $1 AND pending > 0")
+        .bind(token)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Releases a use reserved with [`reserve_use()`] if the account could not be
+/// created
+pub async fn release_use(token: &String, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("\
+            UPDATE registration_tokens \
+            SET pending = pending - 1, updated_at = NOW() \
+            WHERE token = $1 AND pending > 0")
+        .bind(token)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_reserve_use(pool: PgPool) {
+        let token = String::from("abc");
+        create_token(&token, Some(2), None, &pool).await.unwrap();
+
+        assert!(reserve_use(&token, &pool).await.unwrap());
+        complete_use(&token, &pool).await.unwrap();
+        assert!(reserve_use(&token, &pool).await.unwrap());
+        assert!(!is_usable(&token, &pool).await.unwrap());
+        assert!(!reserve_use(&token, &pool).await.unwrap());
+
+        release_use(&token, &pool).await.unwrap();
+        assert!(is_usable(&token, &pool).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_is_usable_with_unlimited_uses(pool: PgPool) {
+        let token = String::from("abc");
+        create_token(&token, None, None, &pool).await.unwrap();
+
+        for _ in 0..5 {
+            assert!(reserve_use(&token, &pool).await.unwrap());
+            complete_use(&token, &pool).await.unwrap();
+        }
+        assert!(is_usable(&token, &pool).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_is_usable_with_expired(pool: PgPool) {
+        let token = String::from("abc");
+        create_token(&token, None, Some(Utc::now() - Duration::minutes(1)), &pool).await.unwrap();
+
+        assert!(!is_usable(&token, &pool).await.unwrap());
+        assert!(!reserve_use(&token, &pool).await.unwrap());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_delete_token(pool: PgPool) {
+        let token = String::from("abc");
+        create_token(&token, None, None, &pool).await.unwrap();
+
+        assert!(delete_token(&token, &pool).await.unwrap());
+        assert!(!delete_token(&token, &pool).await.unwrap());
+        assert!(!is_usable(&token, &pool).await.unwrap());
+    }
+}
@@ -1,6 +1,151 @@
 use crate::error::Error;
-use sqlx::PgPool;
+use crate::models::ids::{RoomAliasId, RoomId, UserId};
+use crate::models::rooms::Room;
+use crate::store::pg::events::{self, NewEvent};
+use sqlx::{PgConnection, PgExecutor, PgPool};
+
+/// Columns selected into [`Room`]
+const COLUMNS: &str = "room_id, room_version";
+
+/// A room to be stored with [`create_room()`]
+#[derive(Debug)]
+pub struct NewRoom {
+    pub room_id: RoomId,
+    pub room_version: String,
+    pub creator: UserId,
+    pub is_public: bool,
+}
+
+/// Stores a new room with its initial events and, if any, its alias
+///
+/// Everything is stored in one transaction, so a failure leaves no trace of
+/// the room.
+pub async fn create_room(
+    room: &NewRoom,
+    alias: Option<&RoomAliasId>,
+    initial_events: &[NewEvent],
+    pool: &PgPool
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("INSERT INTO rooms (room_id, room_version, creator, is_public) VALUES ($1, $2, $3, $4)")
+        .bind(&room.room_id)
+        .bind(&room.room_version)
+        .bind(&room.creator)
+        .bind(room.is_public)
+        .execute(&mut *tx)
+        .await?;
+
+    for event in initial_events {
+        events::create_event(event, &mut tx).await?;
+    }
+
+    if let Some(alias) = alias {
+        sqlx::query("INSERT INTO room_aliases (alias, room_id, creator) VALUES ($1, $2, $3)")
+            .bind(alias)
+            .bind(&room.room_id)
+            .bind(&room.creator)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
 
-pub async fn create_room(name: &String, pool: &PgPool) -> Result<(), Error> {
     Ok(())
 }
+
+/// Looks up a room by its ID
+pub async fn get_room(room_id: &RoomId, pool: &PgPool) -> Result<Option<Room>, Error> {
+    Ok(
+        sqlx::query_as::<_, Room>(&format!("SELECT {} FROM rooms WHERE room_id = $1", COLUMNS))
+            .bind(room_id)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Looks up a room by its ID and locks it until the end of the transaction on
+/// `conn`
+///
+/// Writers that build events on the room's current state take this lock
+/// first, so that two events can't be built on the same forward extremities.
+pub async fn lock_room(room_id: &RoomId, conn: &mut PgConnection) -> Result<Option<Room>, Error> {
+    Ok(
+        sqlx::query_as::<_, Room>(&format!("SELECT {} FROM rooms WHERE room_id = $1 FOR UPDATE", COLUMNS))
+            .bind(room_id)
+            .fetch_optional(conn)
+            .await?
+    )
+}
+
+/// Returns the ID of the room that `alias` points to, if any
+pub async fn get_room_id_for_alias(alias: &RoomAliasId, pool: &PgPool) -> Result<Option<RoomId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, RoomId>("SELECT room_id FROM room_aliases WHERE alias = $1")
+            .bind(alias)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Returns the IDs of the rooms in which `user_id` currently has `membership`,
+/// e.g. `join` or `knock`
+pub async fn get_room_ids_by_membership<'c>(user_id: &UserId, membership: &str, executor: impl PgExecutor<'c>) -> Result<Vec<RoomId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, RoomId>("\
+                SELECT c.room_id FROM current_state_events c \
+                JOIN events e ON e.event_id = c.event_id \
+                WHERE c.event_type = 'm.room.member' AND c.state_key = $1 AND e.content->>'membership' = $2 \
+                ORDER BY c.room_id")
+            .bind(user_id)
+            .bind(membership)
+            .fetch_all(executor)
+            .await?
+    )
+}
+
+/// Returns the IDs of the users who have joined any room that `user_id` has
+/// joined, including `user_id`
+pub async fn get_user_ids_sharing_rooms(user_id: &UserId, pool: &PgPool) -> Result<Vec<UserId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, UserId>("\
+                SELECT DISTINCT c2.state_key FROM current_state_events c1 \
+                JOIN events e1 ON e1.event_id = c1.event_id \
+                JOIN current_state_events c2 ON c2.room_id = c1.room_id AND c2.event_type = 'm.room.member' \
+                JOIN events e2 ON e2.event_id = c2.event_id \
+                WHERE c1.event_type = 'm.room.member' AND c1.state_key = $1 \
+                    AND e1.content->>'membership' = 'join' AND e2.content->>'membership' = 'join' \
+                ORDER BY c2.state_key")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Records whether `user_id` has forgotten a room they left
+pub async fn set_room_forgotten(user_id: &UserId, room_id: &RoomId, forgotten: bool, pool: &PgPool) -> Result<(), Error> {
+    let query = if forgotten {
+        "INSERT INTO forgotten_rooms (user_id, room_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+    } else {
+        "DELETE FROM forgotten_rooms WHERE user_id = $1 AND room_id = $2"
+    };
+
+    sqlx::query(query)
+        .bind(user_id)
+        .bind(room_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns `Ok(true)` if `user_id` has forgotten a room
+pub async fn is_room_forgotten(user_id: &UserId, room_id: &RoomId, pool: &PgPool) -> Result<bool, Error> {
+    Ok(
+        sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM forgotten_rooms WHERE user_id = $1 AND room_id = $2)")
+            .bind(user_id)
+            .bind(room_id)
+            .fetch_one(pool)
+            .await?
+    )
+}
@@ -0,0 +1,97 @@
+use crate::error::Error;
+use crate::models::ids::RoomId;
+use crate::models::sliding_sync::{Connection, ConnectionRoom};
+use sqlx::PgPool;
+use twelf::reexports::serde_json;
+
+/// Columns selected into [`Connection`]
+const COLUMNS: &str = "id, position, stream_ordering";
+
+/// Returns a session's sliding sync connection with ID `conn_id`
+pub async fn get_connection(session_id: i64, conn_id: &str, pool: &PgPool) -> Result<Option<Connection>, Error> {
+    Ok(
+        sqlx::query_as::<_, Connection>(&format!("\
+                SELECT {} FROM sliding_sync_connections \
+                WHERE session_id = $1 AND conn_id = $2", COLUMNS))
+            .bind(session_id)
+            .bind(conn_id)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Starts a session's sliding sync connection with ID `conn_id`, replacing
+/// any with the same ID along with what it has sent, and returns it
+pub async fn create_connection(session_id: i64, conn_id: &str, pool: &PgPool) -> Result<Connection, Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM sliding_sync_connections WHERE session_id = $1 AND conn_id = $2")
+        .bind(session_id)
+        .bind(conn_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let connection = sqlx::query_as::<_, Connection>(&format!("\
+            INSERT INTO sliding_sync_connections (session_id, conn_id) VALUES ($1, $2) \
+            RETURNING {}", COLUMNS))
+        .bind(session_id)
+        .bind(conn_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(connection)
+}
+
+/// Records a connection's new response at `position`, reaching up to stream
+/// ordering `stream_ordering`, which sent the rooms with IDs `room_ids` with
+/// the corresponding `required_states`
+pub async fn advance_connection(
+    connection_id: i64,
+    position: i64,
+    stream_ordering: i64,
+    room_ids: &[RoomId],
+    required_states: &[serde_json::Value],
+    pool: &PgPool
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("\
+            UPDATE sliding_sync_connections SET position = $2, stream_ordering = $3, updated_at = NOW() \
+            WHERE id = $1")
+        .bind(connection_id)
+        .bind(position)
+        .bind(stream_ordering)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("\
+            INSERT INTO sliding_sync_rooms (connection_id, room_id, stream_ordering, required_state) \
+            SELECT $1, UNNEST($2::VARCHAR[]), $3, UNNEST($4::JSONB[]) \
+            ON CONFLICT (connection_id, room_id) DO UPDATE \
+            SET stream_ordering = EXCLUDED.stream_ordering, required_state = EXCLUDED.required_state")
+        .bind(connection_id)
+        .bind(room_ids)
+        .bind(stream_ordering)
+        .bind(required_states)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns the rooms that a connection has sent, with how far and with which
+/// required state
+pub async fn get_connection_rooms(connection_id: i64, pool: &PgPool) -> Result<Vec<ConnectionRoom>, Error> {
+    Ok(
+        sqlx::query_as::<_, ConnectionRoom>("\
+                SELECT room_id, stream_ordering, required_state FROM sliding_sync_rooms \
+                WHERE connection_id = $1")
+            .bind(connection_id)
+            .fetch_all(pool)
+            .await?
+    )
+}
@@ -0,0 +1,69 @@
+use crate::error::Error;
+use crate::models::stream_token::StreamUpdate;
+use sqlx::types::Json;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+
+/// The Postgres channel on which [`StreamUpdate`]s are announced
+pub const CHANNEL: &str = "stream_updates";
+
+/// Returns the stream position: the stream ordering up to and including which
+/// everything stored in events, account data, receipts, typing, presence and
+/// to-device messages, which all share one sequence, has been committed
+///
+/// Unlike the highest stored ordering, the position never passes an ordering
+/// whose transaction is still in flight, and isn't lowered by deleting rows.
+pub async fn get_current_stream_ordering(pool: &PgPool) -> Result<i64, Error> {
+    Ok(
+        sqlx::query_scalar::<_, i64>("SELECT stream_ordering FROM stream_position")
+            .fetch_one(pool)
+            .await?
+    )
+}
+
+/// Takes the next stream ordering and advances the stream position to it
+///
+/// This locks the stream position until the caller's transaction ends, so
+/// writers commit in stream order and the position only ever covers committed
+/// rows. Every write of a stream ordering must take it from here.
+pub async fn next_stream_ordering(conn: &mut PgConnection) -> Result<i64, Error> {
+    Ok(
+        sqlx::query_scalar::<_, i64>("\
+                UPDATE stream_position \
+                SET stream_ordering = nextval('events_stream_ordering_seq') \
+                RETURNING stream_ordering")
+            .fetch_one(conn)
+            .await?
+    )
+}
+
+/// Announces `update` on [`CHANNEL`]
+///
+/// Inside a transaction, Postgres holds the notification back until the
+/// transaction commits, so listeners never hear of data they can't read yet.
+pub async fn notify<'c>(update: &StreamUpdate, executor: impl PgExecutor<'c>) -> Result<(), Error> {
+    sqlx::query("SELECT pg_notify($1, $2::TEXT)")
+        .bind(CHANNEL)
+        .bind(Json(update))
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_next_stream_ordering(pool: PgPool) {
+        let before = get_current_stream_ordering(&pool).await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        let stream_ordering = next_stream_ordering(&mut tx).await.unwrap();
+        assert!(stream_ordering > before);
+        assert_eq!(get_current_stream_ordering(&pool).await.unwrap(), before);
+
+        tx.commit().await.unwrap();
+        assert_eq!(get_current_stream_ordering(&pool).await.unwrap(), stream_ordering);
+    }
+}
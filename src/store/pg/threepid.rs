@@ -0,0 +1,153 @@
+use crate::error::Error;
+use crate::models::threepid::ThreepidValidationSession;
+use sqlx::PgPool;
+
+/// Number of seconds after which a validation session expires, whether or not
+/// it was validated
+pub const THREEPID_SESSION_TTL_SECONDS: i64 = 3600;
+
+/// Columns selected into [`ThreepidValidationSession`]
+const COLUMNS: &str = "id, sid, client_secret, medium, address, token, send_attempt";
+
+/// Starts a validation session for `address` and returns `Ok(session)`
+pub async fn create_session(
+    sid: &String,
+    client_secret: &String,
+    medium: &str,
+    address: &String,
+    token: &String,
+    send_attempt: i64,
+    pool: &PgPool
+) -> Result<ThreepidValidationSession, Error> {
+    Ok(
+        sqlx::query_as::<_, ThreepidValidationSession>(&format!("\
+                INSERT INTO threepid_validation_sessions (sid, client_secret, medium, address, token, send_attempt) \
+                VALUES ($1, $2, $3, $4, $5, $6) \
+                RETURNING {}", COLUMNS))
+            .bind(sid)
+            .bind(client_secret)
+            .bind(medium)
+            .bind(address)
+            .bind(token)
+            .bind(send_attempt)
+            .fetch_one(pool)
+            .await?
+    )
+}
+
+/// Looks up the unexpired session a client started for `address` with
+/// `client_secret`, so that repeated requests reuse it
+pub async fn find_session(
+    client_secret: &String,
+    medium: &str,
+    address: &String,
+    pool: &PgPool
+) -> Result<Option<ThreepidValidationSession>, Error> {
+    Ok(
+        sqlx::query_as::<_, ThreepidValidationSession>(&format!("\
+                SELECT {} FROM threepid_validation_sessions \
+                WHERE client_secret = $1 AND medium = $2 AND address = $3 \
+                AND created_at > NOW() - make_interval(secs => $4)", COLUMNS))
+            .bind(client_secret)
+            .bind(medium)
+            .bind(address)
+            .bind(THREEPID_SESSION_TTL_SECONDS as f64)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Records a new `send_attempt` for a session whose token is being sent again
+pub async fn update_send_attempt(session_id: i64, send_attempt: i64, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("UPDATE threepid_validation_sessions SET send_attempt = $2, updated_at = NOW() WHERE id = $1")
+        .bind(session_id)
+        .bind(send_attempt)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Marks an unexpired session validated if `token` matches; returns
+/// `Ok(true)` on success
+pub async fn validate_session(sid: &String, client_secret: &String, token: &String, pool: &PgPool) -> Result<bool, Error> {
+    let result = sqlx::query("\
+            UPDATE threepid_validation_sessions \
+            SET validated_at = COALESCE(validated_at, NOW()), updated_at = NOW() \
+            WHERE sid = $1 AND client_secret = $2 AND token = $3 \
+            AND created_at > NOW() - make_interval(secs => $4)")
+        .bind(sid)
+        .bind(client_secret)
+        .bind(token)
+        .bind(THREEPID_SESSION_TTL_SECONDS as f64)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Looks up an unexpired session that has been validated
+pub async fn get_validated_session(
+    sid: &String,
+    client_secret: &String,
+    pool: &PgPool
+) -> Result<Option<ThreepidValidationSession>, Error> {
+    Ok(
+        sqlx::query_as::<_, ThreepidValidationSession>(&format!("\
+                SELECT {} FROM threepid_validation_sessions \
+                WHERE sid = $1 AND client_secret = $2 AND validated_at IS NOT NULL \
+                AND created_at > NOW() - make_interval(secs => $3)", COLUMNS))
+            .bind(sid)
+            .bind(client_secret)
+            .bind(THREEPID_SESSION_TTL_SECONDS as f64)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Deletes a session once it has been used, along with any sessions that have
+/// expired
+pub async fn delete_session(session_id: i64, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("DELETE FROM threepid_validation_sessions WHERE id = $1 OR created_at <= NOW() - make_interval(secs => $2)")
+        .bind(session_id)
+        .bind(THREEPID_SESSION_TTL_SECONDS as f64)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_validate_session(pool: PgPool) {
+        let (sid, secret, address, token) = (String::from("sid"), String::from("secret"), String::from("a@example.org"), String::from("token"));
+        let session = create_session(&sid, &secret, "email", &address, &token, 1, &pool).await.unwrap();
+        assert_eq!(find_session(&secret, "email", &address, &pool).await.unwrap().unwrap().id, session.id);
+        assert!(get_validated_session(&sid, &secret, &pool).await.unwrap().is_none());
+
+        assert!(!validate_session(&sid, &secret, &String::from("wrong"), &pool).await.unwrap());
+        assert!(!validate_session(&sid, &String::from("wrong"), &token, &pool).await.unwrap());
+        assert!(validate_session(&sid, &secret, &token, &pool).await.unwrap());
+        assert!(get_validated_session(&sid, &secret, &pool).await.unwrap().is_some());
+
+        delete_session(session.id, &pool).await.unwrap();
+        assert!(get_validated_session(&sid, &secret, &pool).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrations = "migrations/pg")]
+    async fn test_validate_expired_session(pool: PgPool) {
+        let (sid, secret, address, token) = (String::from("sid"), String::from("secret"), String::from("a@example.org"), String::from("token"));
+        let session = create_session(&sid, &secret, "email", &address, &token, 1, &pool).await.unwrap();
+        sqlx::query("UPDATE threepid_validation_sessions SET created_at = NOW() - INTERVAL '2 hours' WHERE id = $1")
+            .bind(session.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(find_session(&secret, "email", &address, &pool).await.unwrap().is_none());
+        assert!(!validate_session(&sid, &secret, &token, &pool).await.unwrap());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::error::Error;
+use crate::models::ids::{DeviceId, UserId};
+use crate::models::stream_token::StreamUpdate;
+use crate::models::to_device::ToDeviceMessage;
+use crate::store::pg;
+use sqlx::PgPool;
+use twelf::reexports::serde_json;
+
+/// Columns selected into [`ToDeviceMessage`]
+const COLUMNS: &str = "sender, event_type, content, stream_ordering";
+
+/// A to-device message for one device, to be stored with
+/// [`add_to_device_messages()`]
+#[derive(Debug, Clone)]
+pub struct NewToDeviceMessage {
+    pub user_id: UserId,
+    pub device_id: DeviceId,
+    pub content: serde_json::Value,
+}
+
+/// Stores `messages` of `event_type` from `sender` in one transaction and wakes
+/// the recipients' waiting requests, and returns `Ok(true)`
+///
+/// If the sender's session has already sent messages with `txn_id`, nothing is
+/// stored and `Ok(false)` is returned, so that retried requests don't send
+/// duplicates.
+pub async fn add_to_device_messages(
+    sender: &UserId,
+    event_type: &str,
+    messages: &[NewToDeviceMessage],
+    session_id: i64,
+    txn_id: &str,
+    pool: &PgPool
+) -> Result<bool, Error> {
+    let mut tx = pool.begin().await?;
+
+    let inserted = sqlx::query("\
+            INSERT INTO to_device_transactions (session_id, txn_id) VALUES ($1, $2) \
+            ON CONFLICT (session_id, txn_id) DO NOTHING")
+        .bind(session_id)
+        .bind(txn_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected() > 0;
+    if !inserted {
+        tx.rollback().await?;
+        return Ok(false);
+    }
+
+    let mut update = StreamUpdate::default();
+    for message in messages {
+        update.stream_ordering = pg::streams::next_stream_ordering(&mut tx).await?;
+        sqlx::query("\
+                INSERT INTO to_device_messages (user_id, device_id, sender, event_type, content, stream_ordering) \
+                VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(&message.user_id)
+            .bind(&message.device_id)
+            .bind(sender)
+            .bind(event_type)
+            .bind(&message.content)
+            .bind(update.stream_ordering)
+            .execute(&mut *tx)
+            .await?;
+        if !update.user_ids.contains(&message.user_id) {
+            update.user_ids.push(message.user_id.clone());
+        }
+    }
+    if !messages.is_empty() {
+        pg::streams::notify(&update, &mut *tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Returns up to `limit` of the to-device messages for a device with a stream
+/// ordering after `from` and up to and including `to`, oldest first
+pub async fn get_to_device_messages(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    from: i64,
+    to: i64,
+    limit: i64,
+    pool: &PgPool
+) -> Result<Vec<ToDeviceMessage>, Error> {
+    Ok(
+        sqlx::query_as::<_, ToDeviceMessage>(&format!("\
+                SELECT {} FROM to_device_messages \
+                WHERE user_id = $1 AND device_id = $2 AND stream_ordering > $3 AND stream_ordering <= $4 \
+                ORDER BY stream_ordering \
+                LIMIT $5", COLUMNS))
+            .bind(user_id)
+            .bind(device_id)
+            .bind(from)
+            .bind(to)
+            .bind(limit)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Deletes the to-device messages for a device up to and including stream
+/// ordering `to`, which the device has received
+pub async fn delete_to_device_messages(user_id: &UserId, device_id: &DeviceId, to: i64, pool: &PgPool) -> Result<(), Error> {
+    sqlx::query("DELETE FROM to_device_messages WHERE user_id = $1 AND device_id = $2 AND stream_ordering <= $3")
+        .bind(user_id)
+        .bind(device_id)
+        .bind(to)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
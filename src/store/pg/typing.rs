@@ -0,0 +1,61 @@
+use crate::error::Error;
+use crate::models::ids::{RoomId, UserId};
+use crate::models::stream_token::StreamUpdate;
+use crate::store::pg;
+use sqlx::PgPool;
+
+/// Records that `user_id` is typing in a room for the next `timeout_ms`
+/// milliseconds, or has stopped typing if `timeout_ms` is `None`, and wakes
+/// the room's waiting requests
+pub async fn set_typing(room_id: &RoomId, user_id: &UserId, timeout_ms: Option<i64>, pool: &PgPool) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+    let stream_ordering = pg::streams::next_stream_ordering(&mut tx).await?;
+
+    sqlx::query("\
+            INSERT INTO typing (room_id, user_id, expires_at, stream_ordering) \
+            VALUES ($1, $2, NOW() + make_interval(secs => $3 / 1000.0), $4) \
+            ON CONFLICT (room_id, user_id) DO UPDATE \
+            SET expires_at = EXCLUDED.expires_at, \
+                stream_ordering = EXCLUDED.stream_ordering")
+        .bind(room_id)
+        .bind(user_id)
+        .bind(timeout_ms.unwrap_or(0))
+        .bind(stream_ordering)
+        .execute(&mut *tx)
+        .await?;
+
+    let update = StreamUpdate { stream_ordering, room_ids: vec![room_id.clone()], ..Default::default() };
+    pg::streams::notify(&update, &mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns the IDs of the rooms among `room_ids` in which someone started or
+/// stopped typing with a stream ordering after `from` and up to and including
+/// `to`
+pub async fn get_rooms_with_typing_between(room_ids: &[RoomId], from: i64, to: i64, pool: &PgPool) -> Result<Vec<RoomId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, RoomId>("\
+                SELECT DISTINCT room_id FROM typing \
+                WHERE room_id = ANY($1) AND stream_ordering > $2 AND stream_ordering <= $3")
+            .bind(room_ids)
+            .bind(from)
+            .bind(to)
+            .fetch_all(pool)
+            .await?
+    )
+}
+
+/// Returns the IDs of the users who are currently typing in a room
+pub async fn get_typing_user_ids(room_id: &RoomId, pool: &PgPool) -> Result<Vec<UserId>, Error> {
+    Ok(
+        sqlx::query_scalar::<_, UserId>("\
+                SELECT user_id FROM typing \
+                WHERE room_id = $1 AND expires_at > NOW() \
+                ORDER BY user_id")
+            .bind(room_id)
+            .fetch_all(pool)
+            .await?
+    )
+}
@@ -0,0 +1,77 @@
+use crate::error::Error;
+use crate::models::uia::UiaSession;
+use sqlx::PgPool;
+
+/// Number of seconds after which an unfinished UIA session is abandoned
+pub const UIA_SESSION_TTL_SECONDS: i64 = 1800;
+
+/// Starts a UIA session for a request to `endpoint` and returns `Ok(session)`
+pub async fn create_session(session_identifier: &String, endpoint: &String, user_id: Option<i64>, pool: &PgPool) -> Result<UiaSession, Error> {
+    Ok(
+        sqlx::query_as::<_, UiaSession>("\
+                INSERT INTO uia_sessions (session_identifier, endpoint, user_id) \
+                VALUES ($1, $2, $3) \
+                RETURNING id, session_identifier, endpoint, user_id, completed_stages")
+            .bind(session_identifier)
+            .bind(endpoint)
+            .bind(user_id)
+            .fetch_one(pool)
+            .await?
+    )
+}
+
+/// Looks up an unexpired UIA session by the identifier given to the client
+pub async fn get_session(session_identifier: &String, pool: &PgPool) -> Result<Option<UiaSession>, Error> {
+    Ok(
+        sqlx::query_as::<_, UiaSession>("\
+                SELECT id, session_identifier, endpoint, user_id, completed_stages \
+                FROM uia_sessions \
+                WHERE session_identifier = $1 AND created_at > NOW() - make_interval(secs => $2)")
+            .bind(session_identifier)
+            .bind(UIA_SESSION_TTL_SECONDS as f64)
+            .fetch_optional(pool)
+            .await?
+    )
+}
+
+/// Records that `stage` of the UIA session has been completed and returns
+/// `Ok(true)`
+///
+/// The update only applies while the session's completed stages are still
+/// `completed_stages`, so if another request advanced or finished the session
+/// in the meantime, nothing changes and `Ok(false)` is returned.
+pub async fn add_completed_stage(session_id: i64, completed_stages: &[String], stage: &str, pool: &PgPool) -> Result<bool, Error> {
+    let result = sqlx::query("\
+            UPDATE uia_sessions \
+            SET completed_stages = array_append(completed_stages, $3), updated_at = NOW() \
+            WHERE id = $1 AND completed_stages = $2::varchar[]")
+        .bind(session_id)
+        .bind(completed_stages)
+        .bind(stage)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Deletes a UIA session once its flow is complete, along with any sessions
+/// that have expired, and returns `Ok(true)` if this call deleted it
+///
+/// As with [`add_completed_stage`], the session is only deleted while its
+/// completed stages are still `completed_stages`, so at most one request can
+/// complete a session.
+pub async fn delete_session(session_id: i64, completed_stages: &[String], pool: &PgPool) -> Result<bool, Error> {
+    let deleted = sqlx::query_scalar::<_, i64>("\
+            WITH expired AS ( \
+                DELETE FROM uia_sessions WHERE id <> $1 AND created_at <= NOW() - make_interval(secs => $3) \
+            ) \
+            DELETE FROM uia_sessions WHERE id = $1 AND completed_stages = $2::varchar[] \
+            RETURNING id")
+        .bind(session_id)
+        .bind(completed_stages)
+        .bind(UIA_SESSION_TTL_SECONDS as f64)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(deleted.is_some())
+}